@@ -0,0 +1,14 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // Compiled in Rust with protox, so building with `grpc` doesn't need protoc installed.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        let descriptors = protox::compile(["sound_amp.proto"], ["proto"])
+            .expect("Cannot compile proto/sound_amp.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("Cannot generate the gRPC service");
+    }
+}
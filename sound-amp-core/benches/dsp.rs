@@ -0,0 +1,182 @@
+//! Throughput of the audio path, per block size a device might ask for.
+//!
+//! Run with `cargo bench -p sound-amp-core`; a block that takes longer than its duration at the
+//! stream's rate (1.3 ms for 64 samples at 48 kHz) is a dropout waiting to happen.
+
+use std::f32::consts::TAU;
+use std::hint::black_box;
+use std::sync::atomic::AtomicU32;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use ringbuf::RingBuffer;
+use sound_amp_core::backend::mock::MockBackend;
+use sound_amp_core::ducking::{self, Ducker, DuckingConfig};
+use sound_amp_core::meter::LevelMeter;
+use sound_amp_core::resampler::LinearResampler;
+use sound_amp_core::{
+    setup_stream, EngineState, InputSource, LinkSettings, LinkStatus, PlayerCommand,
+};
+
+const RATE: u32 = 48000;
+const BLOCK_SIZES: [usize; 4] = [64, 256, 1024, 4096];
+
+fn sine(len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| 0.5 * (TAU * 1000.0 * i as f32 / RATE as f32).sin())
+        .collect()
+}
+
+fn stream_config(channels: u16) -> StreamConfig {
+    StreamConfig {
+        channels,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    }
+}
+
+/// Both callbacks of a running link: gain, the ring between them, the output mix and the meters.
+fn link(c: &mut Criterion) {
+    let backend = Arc::new(MockBackend::new(RATE, 2));
+    let status: Arc<Mutex<LinkStatus>> = Default::default();
+    let player = setup_stream(
+        backend.clone(),
+        Default::default(),
+        Default::default(),
+        Arc::clone(&status),
+        Default::default(),
+        LinkSettings {
+            replay: Default::default(),
+            player: Default::default(),
+            soundboard: Default::default(),
+            ducking: Default::default(),
+            sidechain: Default::default(),
+            remix: Default::default(),
+            output_pipe: None,
+            #[cfg(feature = "network")]
+            streams: Default::default(),
+            virtual_sink: None,
+            outputs: Default::default(),
+            session: None,
+            config: None,
+            ceiling: None,
+            fade_in: Default::default(),
+            spl: Default::default(),
+            dose: None,
+            loudness_alert: Default::default(),
+            push_to_talk: Default::default(),
+            vox: Default::default(),
+            metronome: Default::default(),
+            tuner: Default::default(),
+            spectrogram: Default::default(),
+            room_correction: Default::default(),
+            looper: Default::default(),
+            low_latency: Default::default(),
+            standby: Default::default(),
+            workers: Default::default(),
+            #[cfg(feature = "transcribe")]
+            transcription: Default::default(),
+            #[cfg(feature = "transcribe")]
+            captions: Default::default(),
+        },
+    );
+    player.send(PlayerCommand::SetVolume(0.8)).unwrap();
+    player
+        .send(PlayerCommand::Start(InputSource::Device(0)))
+        .unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while status.lock().unwrap().state != EngineState::Running {
+        assert!(Instant::now() < deadline, "The link never started");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut group = c.benchmark_group("link");
+    for size in BLOCK_SIZES {
+        let input = sine(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+            b.iter(|| {
+                backend.feed(input);
+                black_box(backend.pull(input.len()))
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The ring on its own, filled by one block and drained by the next.
+fn ring_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("ring_buffer");
+    for size in BLOCK_SIZES {
+        let (mut producer, mut consumer) = RingBuffer::<f32>::new(RATE as usize).split();
+        let input = sine(size);
+        let mut output = vec![0.0; size];
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| {
+                producer.push_slice(black_box(&input));
+                consumer.pop_slice(&mut output);
+                black_box(&output);
+            })
+        });
+    }
+    group.finish();
+}
+
+/// The ducker's smoothed gain, held in its attack so the gain keeps moving.
+fn ducker(c: &mut Criterion) {
+    let config = DuckingConfig {
+        enabled: true,
+        ..Default::default()
+    };
+    let level = Arc::new(AtomicU32::new(0));
+    ducking::store_level(&level, &[1.0]);
+    let mut group = c.benchmark_group("ducker");
+    for size in BLOCK_SIZES {
+        let mut ducker = Ducker::new(&config, &stream_config(2), &level);
+        let mut block = sine(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_function(BenchmarkId::from_parameter(size), |b| {
+            b.iter(|| ducker.process(black_box(&mut block)))
+        });
+    }
+    group.finish();
+}
+
+fn level_meter(c: &mut Criterion) {
+    let meter = LevelMeter::default();
+    let mut group = c.benchmark_group("level_meter");
+    for size in BLOCK_SIZES {
+        let input = sine(size);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+            b.iter(|| meter.update(black_box(input)))
+        });
+    }
+    group.finish();
+}
+
+/// 44.1 kHz stereo to 48 kHz, as the streaming outputs do for a CD-rate input.
+fn resampler(c: &mut Criterion) {
+    let mut group = c.benchmark_group("resampler");
+    for size in BLOCK_SIZES {
+        let mut resampler = LinearResampler::new(44100, RATE, 2);
+        let input = sine(size);
+        let mut output = Vec::with_capacity(size * 2);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &input, |b, input| {
+            b.iter(|| {
+                output.clear();
+                resampler.process(black_box(input), &mut output);
+                black_box(&output);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, link, ring_buffer, ducker, level_meter, resampler);
+criterion_main!(benches);
@@ -0,0 +1,591 @@
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::dither::Dither;
+use crate::lock;
+use crate::net;
+use crate::recorder::RecordingTap;
+use crate::resampler::{self, LinearResampler};
+
+// RAOP, the protocol AirPlay speakers take audio over: an RTSP session on TCP sets up
+// 44.1 kHz 16-bit stereo ALAC frames sent as RTP over UDP, plus two more UDP channels:
+// the receiver asks ours for the time on the timing port, and we tell it which frame
+// plays when on its control port.
+const DEFAULT_PORT: u16 = 5000;
+const RATE: u32 = 44100;
+const CHANNELS: usize = 2;
+/// Frames per ALAC packet, as announced in the SDP.
+const FRAMES_PER_PACKET: usize = 352;
+const PAYLOAD_TYPE: u8 = 0x60;
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+const SEND_INTERVAL: Duration = Duration::from_millis(5);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+const RTSP_TIMEOUT: Duration = Duration::from_secs(5);
+/// Seconds from the NTP epoch (1900) to the Unix one.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+pub type AirplayError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Only unencrypted RAOP without a password is spoken: AirPlay 1 receivers like shairport-sync
+/// take it, but speakers that want the RSA and AES handshake, a password or AirPlay 2 refuse the
+/// stream, and the sender gives up on them rather than trying again.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AirplayConfig {
+    /// The speaker, as `host` or `host:port`.
+    pub target: Option<String>,
+    /// How far behind the sender the speaker plays, which is what absorbs network jitter.
+    pub latency_ms: u32,
+    /// Shape the [dither](crate::dither) towards the top of the band.
+    pub noise_shaping: bool,
+}
+
+impl Default for AirplayConfig {
+    fn default() -> Self {
+        AirplayConfig {
+            target: None,
+            latency_ms: 2000,
+            noise_shaping: false,
+        }
+    }
+}
+
+/// Streams samples arriving on the tap to an AirPlay speaker, starting a new session
+/// whenever the speaker drops the last one.
+///
+/// Audio goes out unencrypted, which receivers like shairport-sync accept;
+/// speakers that insist on encryption or a password refuse the session, which stops the
+/// sender with a message saying so.
+pub struct AirplaySender {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl AirplaySender {
+    pub fn start(
+        airplay: &AirplayConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<AirplaySender, AirplayError> {
+        let target = airplay
+            .target
+            .as_deref()
+            .ok_or("No AirPlay speaker is set")?;
+        let address = resolve(target)?;
+        let latency = (airplay.latency_ms as u64 * RATE as u64 / 1000) as u32;
+        let mut converter = Converter::new(config, airplay.noise_shaping);
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut reported = false;
+                while !stop.load(Ordering::Acquire) {
+                    let mut session = match Session::open(address, latency) {
+                        Ok(session) => {
+                            eprintln!("Streaming to AirPlay speaker {}", address);
+                            reported = false;
+                            session
+                        }
+                        Err(e) if refused_stream(&e) => {
+                            eprintln!(
+                                "AirPlay speaker {} won't take the stream ({}): sound-amp only \
+                                 sends unencrypted audio without a password, which AirPlay 2 \
+                                 speakers and ones that want encryption or a password refuse",
+                                address, e
+                            );
+                            return;
+                        }
+                        Err(e) => {
+                            if !reported {
+                                eprintln!(
+                                    "Cannot connect to AirPlay speaker {}, retrying: {}",
+                                    address, e
+                                );
+                                reported = true;
+                            }
+                            net::wait_to_reconnect(RECONNECT_INTERVAL, &mut consumer);
+                            continue;
+                        }
+                    };
+                    while !stop.load(Ordering::Acquire) {
+                        let n = consumer.pop_slice(&mut buffer);
+                        converter.push(&buffer[..n]);
+                        let sent = converter
+                            .packets()
+                            .iter()
+                            .try_for_each(|packet| session.send(packet));
+                        if let Err(e) = sent {
+                            eprintln!("Lost AirPlay speaker {}: {}", address, e);
+                            break;
+                        }
+                        thread::sleep(SEND_INTERVAL);
+                    }
+                    session.close();
+                }
+            });
+        }
+        Ok(AirplaySender {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Whether the speaker turned down the stream itself, which trying again won't change, rather than
+/// the connection failing.
+fn refused_stream(e: &AirplayError) -> bool {
+    e.downcast_ref::<Refused>()
+        .is_some_and(|refused| refused.method == "ANNOUNCE")
+}
+
+fn resolve(target: &str) -> Result<SocketAddr, AirplayError> {
+    let with_port = if target.contains(':') {
+        target.to_string()
+    } else {
+        format!("{}:{}", target, DEFAULT_PORT)
+    };
+    with_port
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", target).into())
+}
+
+/// Turns the input format into 44.1 kHz stereo 16-bit packets.
+struct Converter {
+    input_channels: usize,
+    resampler: Option<LinearResampler>,
+    remapped: Vec<f32>,
+    pending: Vec<f32>,
+    dither: Dither,
+}
+
+impl Converter {
+    fn new(config: &StreamConfig, noise_shaping: bool) -> Converter {
+        Converter {
+            input_channels: config.channels as usize,
+            resampler: (config.sample_rate.0 != RATE)
+                .then(|| LinearResampler::new(config.sample_rate.0, RATE, CHANNELS)),
+            remapped: Vec::new(),
+            pending: Vec::new(),
+            dither: Dither::new(CHANNELS as u16, noise_shaping),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32]) {
+        self.remapped.clear();
+        resampler::remap_channels(samples, self.input_channels, CHANNELS, &mut self.remapped);
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&self.remapped, &mut self.pending),
+            None => self.pending.extend_from_slice(&self.remapped),
+        }
+    }
+
+    /// Takes out every whole packet's worth of samples.
+    fn packets(&mut self) -> Vec<Vec<i16>> {
+        let len = FRAMES_PER_PACKET * CHANNELS;
+        let whole = self.pending.len() / len * len;
+        let samples: Vec<i16> = self
+            .pending
+            .drain(..whole)
+            .map(|s| self.dither.quantize(s))
+            .collect();
+        samples.chunks_exact(len).map(<[i16]>::to_vec).collect()
+    }
+}
+
+/// An RTSP session with a speaker and the UDP sockets around it.
+struct Session {
+    rtsp: Rtsp,
+    audio: UdpSocket,
+    /// The speaker's control port, which gets our sync packets.
+    control: UdpSocket,
+    timing_stop: Arc<AtomicBool>,
+    sequence: u16,
+    timestamp: u32,
+    ssrc: u32,
+    latency: u32,
+    first_packet: bool,
+    last_sync: Option<Instant>,
+}
+
+impl Session {
+    fn open(address: SocketAddr, latency: u32) -> Result<Session, AirplayError> {
+        let stream = TcpStream::connect_timeout(&address, RTSP_TIMEOUT)?;
+        stream.set_read_timeout(Some(RTSP_TIMEOUT))?;
+        let local = stream.local_addr()?.ip();
+        let bind = |ip: IpAddr| UdpSocket::bind((ip, 0));
+        let audio = bind(local)?;
+        let control = bind(local)?;
+        let timing = bind(local)?;
+        let session_id = random_u32();
+        let mut rtsp = Rtsp {
+            stream,
+            url: format!("rtsp://{}/{}", local, session_id),
+            cseq: 0,
+            session: None,
+        };
+
+        let sdp = format!(
+            "v=0\r\n\
+             o=sound-amp {session_id} 0 IN IP4 {local}\r\n\
+             s=sound-amp\r\n\
+             c=IN IP4 {remote}\r\n\
+             t=0 0\r\n\
+             m=audio 0 RTP/AVP 96\r\n\
+             a=rtpmap:96 AppleLossless\r\n\
+             a=fmtp:96 {FRAMES_PER_PACKET} 0 16 40 10 14 {CHANNELS} 255 0 0 {RATE}\r\n",
+            remote = address.ip(),
+        );
+        rtsp.request("ANNOUNCE", &[("Content-Type", "application/sdp")], &sdp)?;
+
+        let transport = format!(
+            "RTP/AVP/UDP;unicast;interleaved=0-1;mode=record;control_port={};timing_port={}",
+            control.local_addr()?.port(),
+            timing.local_addr()?.port()
+        );
+        let response = rtsp.request("SETUP", &[("Transport", &transport)], "")?;
+        let session = response
+            .get("session")
+            .ok_or("The speaker didn't open a session")?;
+        rtsp.session = Some(session.split(';').next().unwrap_or(session).to_string());
+        let transport = response
+            .get("transport")
+            .ok_or("The speaker sent no transport")?;
+        let port = |name: &str| -> Result<u16, AirplayError> {
+            transport
+                .split(';')
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix('='))
+                .and_then(|port| port.parse().ok())
+                .ok_or_else(|| format!("The speaker sent no {}", name).into())
+        };
+        audio.connect((address.ip(), port("server_port")?))?;
+        control.connect((address.ip(), port("control_port")?))?;
+
+        let timing_stop = Arc::new(AtomicBool::new(false));
+        spawn_timing_responder(timing, Arc::clone(&timing_stop));
+
+        let sequence = random_u32() as u16;
+        let timestamp = random_u32();
+        rtsp.request(
+            "RECORD",
+            &[
+                ("Range", "npt=0-"),
+                (
+                    "RTP-Info",
+                    &format!("seq={};rtptime={}", sequence, timestamp),
+                ),
+            ],
+            "",
+        )?;
+        // sound-amp sets the level itself; the speaker plays at full volume.
+        rtsp.request(
+            "SET_PARAMETER",
+            &[("Content-Type", "text/parameters")],
+            "volume: 0.000000\r\n",
+        )?;
+        Ok(Session {
+            rtsp,
+            audio,
+            control,
+            timing_stop,
+            sequence,
+            timestamp,
+            ssrc: random_u32(),
+            latency,
+            first_packet: true,
+            last_sync: None,
+        })
+    }
+
+    fn send(&mut self, samples: &[i16]) -> Result<(), AirplayError> {
+        if self
+            .last_sync
+            .is_none_or(|last| last.elapsed() >= SYNC_INTERVAL)
+        {
+            self.check_connection()?;
+            self.sync()?;
+        }
+        let mut packet = Vec::with_capacity(12 + samples.len() * 2 + 8);
+        packet.push(0x80);
+        // The marker bit flags the start of the stream.
+        packet.push(if self.first_packet {
+            0x80 | PAYLOAD_TYPE
+        } else {
+            PAYLOAD_TYPE
+        });
+        packet.extend_from_slice(&self.sequence.to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        packet.extend_from_slice(&self.ssrc.to_be_bytes());
+        alac_uncompressed(samples, &mut packet);
+        self.audio.send(&packet)?;
+        self.first_packet = false;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.timestamp = self.timestamp.wrapping_add(FRAMES_PER_PACKET as u32);
+        Ok(())
+    }
+
+    /// Tells the speaker that the frame about to be sent plays `latency` frames from now.
+    fn sync(&mut self) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(20);
+        packet.push(if self.last_sync.is_none() { 0x90 } else { 0x80 });
+        packet.extend_from_slice(&[0xd4, 0x00, 0x07]);
+        packet.extend_from_slice(&self.timestamp.wrapping_sub(self.latency).to_be_bytes());
+        packet.extend_from_slice(&ntp_now().to_be_bytes());
+        packet.extend_from_slice(&self.timestamp.to_be_bytes());
+        self.control.send(&packet)?;
+        self.last_sync = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Notices a speaker that hung up on the RTSP connection, which UDP alone never would.
+    fn check_connection(&mut self) -> Result<(), AirplayError> {
+        self.rtsp.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let peeked = self.rtsp.stream.peek(&mut byte);
+        self.rtsp.stream.set_nonblocking(false)?;
+        match peeked {
+            Ok(0) => Err("The speaker closed the session".into()),
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn close(mut self) {
+        let _ = self.rtsp.request("TEARDOWN", &[], "");
+        self.timing_stop.store(true, Ordering::Release);
+    }
+}
+
+/// The RTSP side of a session.
+struct Rtsp {
+    stream: TcpStream,
+    url: String,
+    cseq: u32,
+    session: Option<String>,
+}
+
+impl Rtsp {
+    /// Sends a request and returns the response headers, with lowercase names.
+    fn request(
+        &mut self,
+        method: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> Result<HashMap<String, String>, AirplayError> {
+        self.cseq += 1;
+        let mut request = format!(
+            "{} {} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: sound-amp/{}\r\n",
+            method,
+            self.url,
+            self.cseq,
+            env!("CARGO_PKG_VERSION")
+        );
+        if let Some(session) = &self.session {
+            request.push_str(&format!("Session: {}\r\n", session));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(body);
+        self.stream.write_all(request.as_bytes())?;
+
+        let head = net::read_response_head(&mut self.stream)?;
+        let mut lines = head.lines();
+        let status = lines.next().unwrap_or_default();
+        let response: HashMap<String, String> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+            .collect();
+        if let Some(len) = response
+            .get("content-length")
+            .and_then(|len| len.parse().ok())
+        {
+            let mut body = vec![0u8; len];
+            self.stream.read_exact(&mut body)?;
+        }
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(Refused {
+                method: method.to_string(),
+                status: status.to_string(),
+            }
+            .into());
+        }
+        Ok(response)
+    }
+}
+
+/// A request the speaker answered with something other than 200 OK.
+#[derive(Debug, Error)]
+#[error("{method} refused: {status}")]
+struct Refused {
+    method: String,
+    /// The status line, like `RTSP/1.0 403 Forbidden`.
+    status: String,
+}
+
+/// Answers the speaker's timing requests with our clock, which it uses to line up the sync packets.
+fn spawn_timing_responder(socket: UdpSocket, stop: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        if socket.set_read_timeout(Some(SYNC_INTERVAL)).is_err() {
+            return;
+        }
+        let mut request = [0u8; 32];
+        while !stop.load(Ordering::Acquire) {
+            let Ok((len, from)) = socket.recv_from(&mut request) else {
+                continue;
+            };
+            if len < 32 || request[1] & 0x7f != 0x52 {
+                continue;
+            }
+            let received = ntp_now();
+            let mut reply = Vec::with_capacity(32);
+            reply.extend_from_slice(&[0x80, 0xd3, 0x00, 0x07, 0, 0, 0, 0]);
+            // The origin is when the speaker sent its request.
+            reply.extend_from_slice(&request[24..32]);
+            reply.extend_from_slice(&received.to_be_bytes());
+            reply.extend_from_slice(&ntp_now().to_be_bytes());
+            let _ = socket.send_to(&reply, from);
+        }
+    });
+}
+
+/// The wall clock as a 64-bit NTP timestamp: seconds since 1900 and a binary fraction.
+fn ntp_now() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+    let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+    (seconds << 32) | fraction
+}
+
+/// Not for anything secret; RTP only wants session ids and sequence starts that differ between runs.
+fn random_u32() -> u32 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    (nanos as u32) ^ ((nanos >> 32) as u32).rotate_left(13) ^ std::process::id()
+}
+
+/// Writes interleaved stereo samples as an ALAC frame with the escape flag set, meaning
+/// the samples are stored as they are. It costs the bandwidth of PCM but needs no encoder.
+fn alac_uncompressed(samples: &[i16], out: &mut Vec<u8>) {
+    let mut bits = BitWriter::new(out);
+    bits.write(1, 3); // A channel pair element
+    bits.write(0, 4); // with instance tag 0,
+    bits.write(0, 12); // unused bits,
+    bits.write(0, 1); // the frame length as announced,
+    bits.write(0, 2); // no shifted-out low bytes
+    bits.write(1, 1); // and uncompressed samples.
+    for &sample in samples {
+        bits.write(sample as u16 as u32, 16);
+    }
+    bits.write(7, 3); // End of frame.
+    bits.flush();
+}
+
+/// Packs values MSB first.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    current: u32,
+    filled: u32,
+}
+
+impl<'a> BitWriter<'a> {
+    fn new(out: &'a mut Vec<u8>) -> BitWriter<'a> {
+        BitWriter {
+            out,
+            current: 0,
+            filled: 0,
+        }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        for i in (0..bits).rev() {
+            self.current = (self.current << 1) | ((value >> i) & 1);
+            self.filled += 1;
+            if self.filled == 8 {
+                self.out.push(self.current as u8);
+                self.current = 0;
+                self.filled = 0;
+            }
+        }
+    }
+
+    /// Pads the last byte with zeros.
+    fn flush(&mut self) {
+        if self.filled > 0 {
+            self.out.push((self.current << (8 - self.filled)) as u8);
+            self.current = 0;
+            self.filled = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// A speaker that answers every request with `status`, as many times as it's asked.
+    fn speaker(status: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok(head) = net::read_response_head(&mut stream) {
+                let len = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .map_or(0, |len| len.parse().unwrap());
+                let mut body = vec![0u8; len];
+                stream.read_exact(&mut body).unwrap();
+                let response = format!("RTSP/1.0 {}\r\nCSeq: 1\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn a_speaker_that_refuses_the_announce_is_not_tried_again() {
+        let error = Session::open(speaker("403 Forbidden"), 0).err().unwrap();
+        assert_eq!(error.to_string(), "ANNOUNCE refused: RTSP/1.0 403 Forbidden");
+        assert!(refused_stream(&error));
+    }
+
+    #[test]
+    fn a_speaker_that_goes_away_is_tried_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let error = Session::open(address, 0).err().unwrap();
+        assert!(!refused_stream(&error));
+    }
+}
@@ -0,0 +1,359 @@
+//! One application's audio as the input, on Windows, through the process loopback API (Windows 10
+//! 2004 and later): only what the application and the processes it started play is captured,
+//! rather than the whole system mix, so a browser can be amplified without the notification
+//! sounds that play alongside it.
+
+use std::ffi::c_void;
+use std::mem::{self, ManuallyDrop};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::{ptr, slice, thread};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use windows::core::{IUnknown, IUnknown_Vtbl, Interface, Vtable, GUID, HRESULT, PCWSTR};
+use windows::Win32::Foundation::{CloseHandle, E_FAIL, E_NOINTERFACE, HANDLE, S_OK, WAIT_OBJECT_0};
+use windows::Win32::Media::Audio::{
+    ActivateAudioInterfaceAsync, IActivateAudioInterfaceCompletionHandler,
+    IActivateAudioInterfaceCompletionHandler_Vtbl, IAudioCaptureClient, IAudioClient,
+    AUDCLNT_BUFFERFLAGS_SILENT, AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+    AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK, AUDIOCLIENT_ACTIVATION_PARAMS,
+    AUDIOCLIENT_ACTIVATION_PARAMS_0, AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+    AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS, PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+    VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK, WAVEFORMATEX,
+};
+use windows::Win32::Media::Multimedia::WAVE_FORMAT_IEEE_FLOAT;
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+use windows::Win32::System::Com::{
+    CoInitializeEx, IAgileObject, BLOB, COINIT_MULTITHREADED, VT_BLOB,
+};
+use windows::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W, TH32CS_SNAPPROCESS,
+};
+use windows::Win32::System::Threading::{CreateEventW, WaitForSingleObject};
+
+use crate::Error;
+
+/// The loopback stream is converted to this by the audio engine, whatever the application plays.
+const RATE: u32 = 48000;
+const CHANNELS: u16 = 2;
+/// Asked of the audio engine, in 100 ns units; 20 ms, like a shared-mode device.
+const BUFFER_DURATION: i64 = 200_000;
+/// How long a wait for the next packet lasts before the stop flag is looked at again, in ms.
+const WAIT_MS: u32 = 100;
+
+/// The application `--capture-app` names.
+#[derive(Debug, Clone)]
+pub struct AppCapture {
+    pub process_id: u32,
+    /// The executable's name, shown as the link's input.
+    pub name: String,
+}
+
+impl AppCapture {
+    /// The process `target` names, either by its ID or by its executable's name, like
+    /// `firefox.exe` or just `firefox`; of several with that name, the first the system lists is
+    /// taken, which is usually the parent of the rest.
+    pub fn find(target: &str) -> Result<AppCapture, Error> {
+        let processes = processes().map_err(|e| Error::AppCapture {
+            name: target.to_string(),
+            source: e,
+        })?;
+        let wanted = target.to_lowercase();
+        processes
+            .into_iter()
+            .find(|(id, name)| {
+                let name = name.to_lowercase();
+                target.parse::<u32>().ok() == Some(*id)
+                    || name == wanted
+                    || name.strip_suffix(".exe") == Some(wanted.as_str())
+            })
+            .map(|(process_id, name)| AppCapture { process_id, name })
+            .ok_or_else(|| Error::NoApp(target.to_string()))
+    }
+
+    pub fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: CHANNELS,
+            sample_rate: SampleRate(RATE),
+            buffer_size: BufferSize::Default,
+        }
+    }
+}
+
+/// The running processes' IDs and executable names, in the order the system lists them.
+fn processes() -> windows::core::Result<Vec<(u32, String)>> {
+    let mut processes = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0)?;
+        let mut entry = PROCESSENTRY32W {
+            dwSize: mem::size_of::<PROCESSENTRY32W>() as u32,
+            ..Default::default()
+        };
+        let mut more = Process32FirstW(snapshot, &mut entry).as_bool();
+        while more {
+            let len = entry
+                .szExeFile
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(entry.szExeFile.len());
+            processes.push((
+                entry.th32ProcessID,
+                String::from_utf16_lossy(&entry.szExeFile[..len]),
+            ));
+            more = Process32NextW(snapshot, &mut entry).as_bool();
+        }
+        CloseHandle(snapshot);
+    }
+    Ok(processes)
+}
+
+/// Feeds what an application plays to `process`, like an input stream callback would. The capture
+/// stops once this is dropped, within [`WAIT_MS`].
+pub struct AppCaptureReader {
+    stop: Arc<AtomicBool>,
+}
+
+impl AppCaptureReader {
+    /// Fails if the application's audio can't be captured, such as on a Windows older than the
+    /// process loopback API or after the application has quit.
+    pub fn spawn<F>(capture: AppCapture, mut process: F) -> Result<AppCaptureReader, Error>
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (started, start) = mpsc::channel();
+        {
+            let stop = Arc::clone(&stop);
+            let process_id = capture.process_id;
+            // COM objects stay on the thread that made them, so everything is set up there and
+            // only the outcome sent back.
+            thread::spawn(move || unsafe {
+                let stream = match Capture::open(process_id) {
+                    Ok(stream) => {
+                        let _ = started.send(Ok(()));
+                        stream
+                    }
+                    Err(e) => {
+                        let _ = started.send(Err(e));
+                        return;
+                    }
+                };
+                let mut samples: Vec<f32> = Vec::new();
+                while !stop.load(Ordering::Acquire) {
+                    if WaitForSingleObject(stream.event, WAIT_MS) != WAIT_OBJECT_0 {
+                        continue;
+                    }
+                    if stream.drain(&mut samples, &mut process).is_err() {
+                        break;
+                    }
+                }
+                stream.close();
+            });
+        }
+        let failed = |source| Error::AppCapture {
+            name: capture.name.clone(),
+            source,
+        };
+        match start.recv() {
+            Ok(Ok(())) => Ok(AppCaptureReader { stop }),
+            Ok(Err(e)) => Err(failed(e)),
+            // The thread died before it could say.
+            Err(_) => Err(failed(E_FAIL.into())),
+        }
+    }
+}
+
+impl Drop for AppCaptureReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// A started loopback stream of one process tree.
+struct Capture {
+    client: IAudioClient,
+    capture: IAudioCaptureClient,
+    event: HANDLE,
+}
+
+impl Capture {
+    unsafe fn open(process_id: u32) -> windows::core::Result<Capture> {
+        // Already initialised on this thread is fine too.
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let client = activate(process_id)?;
+        let block_align = CHANNELS * mem::size_of::<f32>() as u16;
+        let format = WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_IEEE_FLOAT as u16,
+            nChannels: CHANNELS,
+            nSamplesPerSec: RATE,
+            nAvgBytesPerSec: RATE * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: 32,
+            cbSize: 0,
+        };
+        client.Initialize(
+            AUDCLNT_SHAREMODE_SHARED,
+            AUDCLNT_STREAMFLAGS_LOOPBACK
+                | AUDCLNT_STREAMFLAGS_EVENTCALLBACK
+                | AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM,
+            BUFFER_DURATION,
+            0,
+            &format,
+            None,
+        )?;
+        let event = CreateEventW(None, false, false, PCWSTR::null())?;
+        client.SetEventHandle(event)?;
+        let capture: IAudioCaptureClient = client.GetService()?;
+        client.Start()?;
+        Ok(Capture {
+            client,
+            capture,
+            event,
+        })
+    }
+
+    /// Hands every packet that's waiting to `process`; silence the engine flags rather than
+    /// fills in is passed on as zeros, so the link keeps its timing.
+    unsafe fn drain<F>(&self, samples: &mut Vec<f32>, process: &mut F) -> windows::core::Result<()>
+    where
+        F: FnMut(&[f32]),
+    {
+        while self.capture.GetNextPacketSize()? > 0 {
+            let mut data = ptr::null_mut();
+            let mut frames = 0;
+            let mut flags = 0;
+            self.capture
+                .GetBuffer(&mut data, &mut frames, &mut flags, None, None)?;
+            let len = frames as usize * CHANNELS as usize;
+            samples.clear();
+            if flags & AUDCLNT_BUFFERFLAGS_SILENT.0 as u32 != 0 {
+                samples.resize(len, 0.0);
+            } else {
+                samples.extend_from_slice(slice::from_raw_parts(data as *const f32, len));
+            }
+            self.capture.ReleaseBuffer(frames)?;
+            process(samples);
+        }
+        Ok(())
+    }
+
+    unsafe fn close(self) {
+        let _ = self.client.Stop();
+        CloseHandle(self.event);
+    }
+}
+
+/// An audio client on the loopback of `process_id` and its children. The activation finishes on
+/// another thread, which tells [`CompletionHandler`] when it has.
+unsafe fn activate(process_id: u32) -> windows::core::Result<IAudioClient> {
+    let params = AUDIOCLIENT_ACTIVATION_PARAMS {
+        ActivationType: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+        Anonymous: AUDIOCLIENT_ACTIVATION_PARAMS_0 {
+            ProcessLoopbackParams: AUDIOCLIENT_PROCESS_LOOPBACK_PARAMS {
+                TargetProcessId: process_id,
+                ProcessLoopbackMode: PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+            },
+        },
+    };
+    // The blob points at `params` rather than owning it, so the variant is never cleared.
+    let mut variant = ManuallyDrop::new(PROPVARIANT::default());
+    let inner = &mut variant.Anonymous.Anonymous;
+    inner.vt = VT_BLOB;
+    inner.Anonymous.blob = BLOB {
+        cbSize: mem::size_of_val(&params) as u32,
+        pBlobData: &params as *const _ as *mut u8,
+    };
+    let (done, completed) = mpsc::channel();
+    let handler = CompletionHandler::new(done);
+    let operation = ActivateAudioInterfaceAsync(
+        VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK,
+        &IAudioClient::IID,
+        Some(&*variant as *const _),
+        &handler,
+    )?;
+    let _ = completed.recv();
+    let mut result = HRESULT(0);
+    let mut activated: Option<IUnknown> = None;
+    operation.GetActivateResult(&mut result, Some(&mut activated))?;
+    result.ok()?;
+    activated
+        .ok_or_else(|| windows::core::Error::from(E_NOINTERFACE))?
+        .cast()
+}
+
+/// The callback `ActivateAudioInterfaceAsync` calls when it's done, written out by hand as a COM
+/// object: a pointer to its vtable, then a reference count, then the channel it signals.
+#[repr(C)]
+struct CompletionHandler {
+    vtable: *const IActivateAudioInterfaceCompletionHandler_Vtbl,
+    references: AtomicU32,
+    done: Mutex<mpsc::Sender<()>>,
+}
+
+static COMPLETION_HANDLER_VTABLE: IActivateAudioInterfaceCompletionHandler_Vtbl =
+    IActivateAudioInterfaceCompletionHandler_Vtbl {
+        base__: IUnknown_Vtbl {
+            QueryInterface: CompletionHandler::query_interface,
+            AddRef: CompletionHandler::add_ref,
+            Release: CompletionHandler::release,
+        },
+        ActivateCompleted: CompletionHandler::activate_completed,
+    };
+
+impl CompletionHandler {
+    /// The handler, holding the one reference that dropping it gives up.
+    unsafe fn new(done: mpsc::Sender<()>) -> IActivateAudioInterfaceCompletionHandler {
+        let handler = Box::new(CompletionHandler {
+            vtable: &COMPLETION_HANDLER_VTABLE,
+            references: AtomicU32::new(1),
+            done: Mutex::new(done),
+        });
+        IActivateAudioInterfaceCompletionHandler::from_raw(Box::into_raw(handler) as *mut c_void)
+    }
+
+    /// It can be called from any thread, so it answers to `IAgileObject` as well.
+    unsafe extern "system" fn query_interface(
+        this: *mut c_void,
+        iid: &GUID,
+        interface: *mut *const c_void,
+    ) -> HRESULT {
+        if *iid == IUnknown::IID
+            || *iid == IActivateAudioInterfaceCompletionHandler::IID
+            || *iid == IAgileObject::IID
+        {
+            Self::add_ref(this);
+            *interface = this;
+            S_OK
+        } else {
+            *interface = ptr::null();
+            E_NOINTERFACE
+        }
+    }
+
+    unsafe extern "system" fn add_ref(this: *mut c_void) -> u32 {
+        (*(this as *const CompletionHandler))
+            .references
+            .fetch_add(1, Ordering::Relaxed)
+            + 1
+    }
+
+    unsafe extern "system" fn release(this: *mut c_void) -> u32 {
+        let left = (*(this as *const CompletionHandler))
+            .references
+            .fetch_sub(1, Ordering::AcqRel)
+            - 1;
+        if left == 0 {
+            drop(Box::from_raw(this as *mut CompletionHandler));
+        }
+        left
+    }
+
+    unsafe extern "system" fn activate_completed(this: *mut c_void, _: *mut c_void) -> HRESULT {
+        let _ = (*(this as *const CompletionHandler))
+            .done
+            .lock()
+            .unwrap()
+            .send(());
+        S_OK
+    }
+}
@@ -0,0 +1,151 @@
+//! The auto-gain wizard: the user speaks normally for a few seconds while the raw input is
+//! measured, and the trim, the gate and the compressor are set from what's heard. Levels are taken
+//! as the peak of each short window; the quietest windows are the room's noise, and the ones well
+//! above them the speech.
+
+use std::time::Duration;
+
+use cpal::StreamConfig;
+
+use crate::effects::{CompressorConfig, EffectConfig, GateConfig};
+
+/// How long the user is asked to speak for.
+pub const DURATION: Duration = Duration::from_secs(10);
+/// Where the trim puts the loud end of normal speech, in peak dBFS.
+const TARGET_DB: f32 = -12.0;
+const WINDOW: Duration = Duration::from_millis(50);
+/// Windows at least this far over the noise floor count as speech.
+const SPEECH_MARGIN_DB: f32 = 12.0;
+/// Share of the windows that have to be speech for the measurement to count.
+const MIN_SPEECH: f32 = 0.2;
+/// How far over the noise the gate opens, and under typical speech it has to stay.
+const GATE_MARGIN_DB: f32 = 6.0;
+const MAX_TRIM_DB: f32 = 24.0;
+
+/// Peaks of the raw input, window by window, until it has heard [`DURATION`] of it.
+pub struct GainMeasurement {
+    window: usize,
+    wanted: usize,
+    counted: usize,
+    peak: f32,
+    peaks: Vec<f32>,
+}
+
+impl GainMeasurement {
+    pub fn new(config: &StreamConfig) -> GainMeasurement {
+        let rate = config.sample_rate.0 as f32;
+        GainMeasurement {
+            window: ((rate * WINDOW.as_secs_f32()) as usize * config.channels as usize).max(1),
+            wanted: (DURATION.as_secs_f32() / WINDOW.as_secs_f32()) as usize,
+            counted: 0,
+            peak: 0.0,
+            peaks: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for sample in samples {
+            if self.done() {
+                return;
+            }
+            self.peak = self.peak.max(sample.abs());
+            self.counted += 1;
+            if self.counted == self.window {
+                self.peaks.push(20.0 * self.peak.max(1e-6).log10());
+                self.counted = 0;
+                self.peak = 0.0;
+            }
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.peaks.len() >= self.wanted
+    }
+
+    /// How much longer it has to listen.
+    pub fn remaining(&self) -> Duration {
+        WINDOW * self.wanted.saturating_sub(self.peaks.len()) as u32
+    }
+
+    /// The settings for what was heard, or why there are none.
+    pub fn finish(mut self) -> Result<Calibration, String> {
+        self.peaks.sort_by(f32::total_cmp);
+        let noise = percentile(&self.peaks, 0.1);
+        let speech: Vec<f32> = self
+            .peaks
+            .iter()
+            .copied()
+            .filter(|&peak| peak >= noise + SPEECH_MARGIN_DB)
+            .collect();
+        if (speech.len() as f32) < MIN_SPEECH * self.peaks.len() as f32 {
+            return Err("Heard too little speech over the noise to calibrate; try again nearer the microphone".to_string());
+        }
+        let (typical, loud) = (percentile(&speech, 0.5), percentile(&speech, 0.95));
+        let trim_db = (TARGET_DB - loud).clamp(-MAX_TRIM_DB, MAX_TRIM_DB);
+        // Compressing from typical speech up, the harder the wider it swings, with the makeup
+        // bringing its loud end back to the target.
+        let ratio = (1.0 + (loud - typical) / 6.0).clamp(1.5, 4.0);
+        let threshold = (typical + trim_db).min(TARGET_DB);
+        Ok(Calibration {
+            trim_db: tenths(trim_db),
+            gate: GateConfig {
+                threshold_db: tenths((noise + GATE_MARGIN_DB).min(typical - GATE_MARGIN_DB) + trim_db),
+                ..Default::default()
+            },
+            compressor: CompressorConfig {
+                threshold_db: tenths(threshold),
+                ratio: tenths(ratio),
+                makeup_db: tenths((TARGET_DB - threshold) * (1.0 - 1.0 / ratio)),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// What the wizard sets.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calibration {
+    pub trim_db: f32,
+    pub gate: GateConfig,
+    pub compressor: CompressorConfig,
+}
+
+impl Calibration {
+    pub fn trim(&self) -> f32 {
+        10f32.powf(self.trim_db / 20.0)
+    }
+
+    /// Sets the first gate and compressor in `effects` to the calibrated levels, keeping their
+    /// timings, or adds a gate at the start and a compressor at the end where there's none.
+    pub fn apply(&self, effects: &mut Vec<EffectConfig>) {
+        match effects.iter_mut().find_map(|effect| match effect {
+            EffectConfig::Gate(gate) => Some(gate),
+            _ => None,
+        }) {
+            Some(gate) => gate.threshold_db = self.gate.threshold_db,
+            None => effects.insert(0, EffectConfig::Gate(self.gate.clone())),
+        }
+        match effects.iter_mut().find_map(|effect| match effect {
+            EffectConfig::Compressor(compressor) => Some(compressor),
+            _ => None,
+        }) {
+            Some(compressor) => {
+                compressor.threshold_db = self.compressor.threshold_db;
+                compressor.ratio = self.compressor.ratio;
+                compressor.makeup_db = self.compressor.makeup_db;
+            }
+            None => effects.push(EffectConfig::Compressor(self.compressor.clone())),
+        }
+    }
+}
+
+/// The value `share` of the way up `sorted`.
+fn percentile(sorted: &[f32], share: f32) -> f32 {
+    let last = sorted.len().saturating_sub(1);
+    sorted.get((last as f32 * share).round() as usize).copied().unwrap_or(f32::NEG_INFINITY)
+}
+
+/// Rounded to a tenth, so the config it's saved to reads cleanly.
+fn tenths(value: f32) -> f32 {
+    (value * 10.0).round() / 10.0
+}
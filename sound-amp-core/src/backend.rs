@@ -0,0 +1,207 @@
+use std::any::Any;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{BufferSize, InputCallbackInfo, OutputCallbackInfo, StreamConfig, SupportedBufferSize};
+
+use crate::error::Error;
+
+pub mod mock;
+
+/// Called with every block of samples an input delivers.
+pub type InputCallback = Box<dyn FnMut(&[f32]) + Send>;
+/// Called to fill every block of samples an output wants.
+pub type OutputCallback = Box<dyn FnMut(&mut [f32]) + Send>;
+/// Keeps a stream running until it's dropped.
+pub type StreamHandle = Box<dyn Any>;
+
+/// An input stream that's running, what it's running with, and how far ahead of its callback it
+/// captures.
+pub struct OpenInput {
+    pub name: String,
+    pub config: StreamConfig,
+    pub stream: StreamHandle,
+    pub latency: Arc<StreamLatency>,
+}
+
+/// An output stream that's running, and how far behind its callback it plays.
+pub struct OpenOutput {
+    pub stream: StreamHandle,
+    pub latency: Arc<StreamLatency>,
+}
+
+/// How far apart a stream's callback and the device are, as the device last said: how long after
+/// its callback an output plays the block it was given, or how long before its callback an input
+/// captured the block it's given.
+#[derive(Debug, Default)]
+pub struct StreamLatency(AtomicU64);
+
+impl StreamLatency {
+    pub fn set(&self, latency: Duration) {
+        self.0.store(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// `None` until the device has reported it.
+    pub fn get(&self) -> Option<Duration> {
+        match self.0.load(Ordering::Relaxed) {
+            0 => None,
+            micros => Some(Duration::from_micros(micros)),
+        }
+    }
+}
+
+/// The devices links are made of: cpal's default host, or [`mock::MockBackend`] in tests.
+pub trait AudioBackend {
+    /// Starts input device `index`, handing its samples to `callback`, in blocks of
+    /// `buffer_size` as near as the device allows.
+    fn open_input(
+        &self,
+        index: usize,
+        buffer_size: BufferSize,
+        callback: InputCallback,
+    ) -> Result<OpenInput, Error>;
+    /// Name and format of the output device, so the output callback can be set up for it.
+    fn output_format(&self) -> Result<(Option<String>, StreamConfig), Error>;
+    /// Starts output `device`, or the default output when it's `None`, with `config`, which came
+    /// from [`AudioBackend::output_format`]; its buffer size is brought within what the device
+    /// allows.
+    fn open_output(
+        &self,
+        device: Option<&str>,
+        config: &StreamConfig,
+        callback: OutputCallback,
+    ) -> Result<OpenOutput, Error>;
+}
+
+/// The system's audio devices, through cpal's default host.
+pub struct CpalBackend;
+
+impl AudioBackend for CpalBackend {
+    fn open_input(
+        &self,
+        index: usize,
+        buffer_size: BufferSize,
+        mut callback: InputCallback,
+    ) -> Result<OpenInput, Error> {
+        let device = cpal::default_host()
+            .input_devices()?
+            .nth(index)
+            .ok_or(Error::NoInputDevice(index))?;
+        let name = device.name().unwrap_or_default();
+        let supported = device
+            .default_input_config()
+            .map_err(|source| Error::NoDefaultConfig {
+                device: name.clone(),
+                source,
+            })?;
+        let config = StreamConfig {
+            buffer_size: fit_buffer(&buffer_size, supported.buffer_size()),
+            ..supported.into()
+        };
+        let latency = Arc::new(StreamLatency::default());
+        let measured = Arc::clone(&latency);
+        let stream = device
+            .build_input_stream(
+                &config,
+                move |data: &[f32], info: &InputCallbackInfo| {
+                    let timestamp = info.timestamp();
+                    if let Some(latency) = timestamp.callback.duration_since(&timestamp.capture) {
+                        measured.set(latency);
+                    }
+                    callback(data)
+                },
+                err_fn,
+            )
+            .map_err(|source| Error::BuildStream {
+                direction: "input",
+                source,
+            })?;
+        stream.play().map_err(|source| Error::PlayStream {
+            direction: "input",
+            source,
+        })?;
+        Ok(OpenInput {
+            name,
+            config,
+            stream: Box::new(stream),
+            latency,
+        })
+    }
+
+    fn output_format(&self) -> Result<(Option<String>, StreamConfig), Error> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or(Error::NoOutputDevice)?;
+        let name = device.name().ok();
+        let format = device
+            .default_output_config()
+            .map_err(|source| Error::NoDefaultConfig {
+                device: name.clone().unwrap_or_default(),
+                source,
+            })?;
+        eprintln!("Format: {:?}", format);
+        Ok((name, format.into()))
+    }
+
+    fn open_output(
+        &self,
+        device: Option<&str>,
+        config: &StreamConfig,
+        mut callback: OutputCallback,
+    ) -> Result<OpenOutput, Error> {
+        let host = cpal::default_host();
+        let device = match device {
+            Some(name) => host
+                .output_devices()?
+                .find(|d| d.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| Error::NoOutputDeviceNamed(name.to_string()))?,
+            None => host.default_output_device().ok_or(Error::NoOutputDevice)?,
+        };
+        let mut config = config.clone();
+        if let Ok(supported) = device.default_output_config() {
+            config.buffer_size = fit_buffer(&config.buffer_size, supported.buffer_size());
+        }
+        let latency = Arc::new(StreamLatency::default());
+        let measured = Arc::clone(&latency);
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], info: &OutputCallbackInfo| {
+                    let timestamp = info.timestamp();
+                    if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                        measured.set(latency);
+                    }
+                    callback(data)
+                },
+                err_fn,
+            )
+            .map_err(|source| Error::BuildStream {
+                direction: "output",
+                source,
+            })?;
+        stream.play().map_err(|source| Error::PlayStream {
+            direction: "output",
+            source,
+        })?;
+        Ok(OpenOutput {
+            stream: Box::new(stream),
+            latency,
+        })
+    }
+}
+
+/// `wanted`, brought within the buffer sizes the device supports, when it says what they are.
+fn fit_buffer(wanted: &BufferSize, supported: &SupportedBufferSize) -> BufferSize {
+    match (wanted, supported) {
+        (BufferSize::Fixed(frames), SupportedBufferSize::Range { min, max }) => {
+            BufferSize::Fixed((*frames).max(*min).min(*max))
+        }
+        _ => wanted.clone(),
+    }
+}
+
+fn err_fn(err: cpal::StreamError) {
+    eprintln!("an error occurred on stream: {:?}", err);
+}
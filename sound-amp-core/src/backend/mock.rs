@@ -0,0 +1,112 @@
+//! A backend with no hardware behind it, for tests: they push input blocks and pull output blocks by
+//! hand.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+
+use super::{AudioBackend, InputCallback, OpenInput, OpenOutput, OutputCallback};
+use crate::error::Error;
+
+/// Name of the default output.
+pub const DEFAULT_OUTPUT: &str = "mock output";
+
+/// One input device running at `config`, and any output device asked for at the same, or at
+/// `output_rate` if it's set.
+pub struct MockBackend {
+    pub config: StreamConfig,
+    pub output_rate: Option<u32>,
+    input: Mutex<Option<InputCallback>>,
+    outputs: Mutex<HashMap<String, OutputCallback>>,
+}
+
+impl MockBackend {
+    pub fn new(sample_rate: u32, channels: u16) -> MockBackend {
+        MockBackend {
+            config: StreamConfig {
+                channels,
+                sample_rate: SampleRate(sample_rate),
+                buffer_size: BufferSize::Default,
+            },
+            output_rate: None,
+            input: Mutex::new(None),
+            outputs: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The same, with the outputs at `rate` rather than the input's rate.
+    pub fn with_output_rate(self, rate: u32) -> MockBackend {
+        MockBackend {
+            output_rate: Some(rate),
+            ..self
+        }
+    }
+
+    /// Delivers `samples` to the input callback, as a device would in its own callback.
+    pub fn feed(&self, samples: &[f32]) {
+        let mut input = self.input.lock().unwrap();
+        (input.as_mut().expect("No input stream was opened"))(samples);
+    }
+
+    /// Asks the default output's callback for `len` samples, as a device would in its own callback.
+    pub fn pull(&self, len: usize) -> Vec<f32> {
+        self.pull_from(DEFAULT_OUTPUT, len)
+    }
+
+    /// Asks the callback of output `device` for `len` samples.
+    pub fn pull_from(&self, device: &str, len: usize) -> Vec<f32> {
+        let mut data = vec![f32::NAN; len];
+        let mut outputs = self.outputs.lock().unwrap();
+        let output = outputs
+            .get_mut(device)
+            .expect("No output stream was opened");
+        output(&mut data);
+        data
+    }
+}
+
+impl AudioBackend for MockBackend {
+    fn open_input(
+        &self,
+        index: usize,
+        _buffer_size: BufferSize,
+        callback: InputCallback,
+    ) -> Result<OpenInput, Error> {
+        if index != 0 {
+            return Err(Error::NoInputDevice(index));
+        }
+        *self.input.lock().unwrap() = Some(callback);
+        Ok(OpenInput {
+            name: "mock input".to_string(),
+            config: self.config.clone(),
+            stream: Box::new(()),
+            latency: Default::default(),
+        })
+    }
+
+    fn output_format(&self) -> Result<(Option<String>, StreamConfig), Error> {
+        let mut config = self.config.clone();
+        if let Some(rate) = self.output_rate {
+            config.sample_rate = SampleRate(rate);
+        }
+        Ok((Some(DEFAULT_OUTPUT.to_string()), config))
+    }
+
+    fn open_output(
+        &self,
+        device: Option<&str>,
+        _config: &StreamConfig,
+        callback: OutputCallback,
+    ) -> Result<OpenOutput, Error> {
+        let name = device.unwrap_or(DEFAULT_OUTPUT);
+        self.outputs
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), callback);
+        Ok(OpenOutput {
+            stream: Box::new(()),
+            latency: Default::default(),
+        })
+    }
+}
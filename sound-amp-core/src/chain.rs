@@ -0,0 +1,394 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use cpal::StreamConfig;
+
+use crate::ducking::Ducker;
+use crate::effects::{EffectConfig, PerChannel};
+use crate::workers::WorkerPool;
+
+/// How long new effects take to fade in over the ones they replace, and a link to fade out.
+pub const CROSSFADE: Duration = Duration::from_millis(100);
+/// Time constant of the ramp the trim, volume, mute and pan follow, so turning them doesn't click.
+pub const GAIN_SMOOTHING: Duration = Duration::from_millis(10);
+
+/// One effect in a link's chain, run in place on the link's interleaved input.
+pub trait Processor: Send {
+    fn process(&mut self, samples: &mut [f32]);
+
+    /// Whether it's holding the signal down as of the last block, like a shut gate; counted in
+    /// the [stats](crate::stats).
+    fn closed(&self) -> bool {
+        false
+    }
+
+    /// Frames it holds the signal back by, for the dry path of a bypassed chain to line up with.
+    fn latency(&self) -> usize {
+        0
+    }
+
+    /// Whether it takes long enough over a block, like a convolver, that a
+    /// [`PerChannel`] of them is worth spreading over the [workers](crate::workers).
+    fn heavy(&self) -> bool {
+        false
+    }
+
+    /// Hands it the DSP workers, for effects that have work to spread over them.
+    fn share_workers(&mut self, _workers: &WorkerPool) {}
+}
+
+/// The gain and effects of one link, applied in its input callback.
+pub struct EffectChain {
+    /// Gain ahead of the effects, bringing the input to the level they're set up for, whatever
+    /// the volume it's listened at.
+    pub trim: f32,
+    /// Gain after the effects, like a fader.
+    pub volume: f32,
+    /// Silences the link without losing the volume it comes back at.
+    pub muted: bool,
+    /// Leaves the effects out, for an A/B comparison: the input goes through held back as long as
+    /// the effects would hold it, so switching doesn't jump in time. The effects keep running, to
+    /// come back in without a gap.
+    pub bypassed: bool,
+    effects: Vec<Box<dyn Processor>>,
+    /// Ducks the link under another one's input, after the effects; it stays across loads.
+    ducker: Option<Ducker>,
+    /// The effects the last load replaced, still running while the new ones fade in over them.
+    outgoing: Option<Outgoing>,
+    /// The channels and rate the effects were loaded for.
+    format: Option<(u16, u32)>,
+    /// The trim and the gain after the effects as they're ramping, and how much of the way to
+    /// the set values they're still off by after a frame.
+    applied_trim: f32,
+    applied_gain: f32,
+    smoothing: f32,
+    /// How far the chain is faded in.
+    fade: Fade,
+    /// The input run through the outgoing effects.
+    scratch: Vec<f32>,
+    /// How far over to the bypassed input the output is, from 0 to 1, ramping like the gains.
+    applied_bypass: f32,
+    /// The trimmed input waiting out the effects' latency, and the block of it that's due.
+    dry: VecDeque<f32>,
+    delayed: Vec<f32>,
+    /// Shared with the effects loaded from now on.
+    workers: Option<WorkerPool>,
+}
+
+struct Outgoing {
+    effects: Vec<Box<dyn Processor>>,
+    /// Frames into the crossfade.
+    position: usize,
+    frames: usize,
+}
+
+/// A chain shared between the player, which edits it, and the link's input callback.
+pub type SharedChain = Arc<Mutex<EffectChain>>;
+
+impl Default for EffectChain {
+    fn default() -> Self {
+        EffectChain {
+            trim: 1.0,
+            volume: 1.0,
+            muted: false,
+            bypassed: false,
+            effects: Vec::new(),
+            ducker: None,
+            outgoing: None,
+            format: None,
+            applied_trim: 1.0,
+            applied_gain: 1.0,
+            smoothing: 0.0,
+            fade: Fade::default(),
+            scratch: Vec::new(),
+            applied_bypass: 0.0,
+            dry: VecDeque::new(),
+            delayed: Vec::new(),
+            workers: None,
+        }
+    }
+}
+
+impl EffectChain {
+    /// Appends `effect` after the ones already in the chain.
+    pub fn push(&mut self, mut effect: impl Processor + 'static) {
+        if let Some(workers) = &self.workers {
+            effect.share_workers(workers);
+        }
+        self.effects.push(Box::new(effect));
+    }
+
+    /// Puts `effect` ahead of the ones already in the chain.
+    pub fn push_front(&mut self, mut effect: impl Processor + 'static) {
+        if let Some(workers) = &self.workers {
+            effect.share_workers(workers);
+        }
+        self.effects.insert(0, Box::new(effect));
+    }
+
+    /// Lets the heavy effects run on `workers`, the ones loaded already and from now on.
+    pub fn set_workers(&mut self, workers: WorkerPool) {
+        for effect in &mut self.effects {
+            effect.share_workers(&workers);
+        }
+        self.workers = Some(workers);
+    }
+
+    /// Replaces the effects with the ones in `effects`, set up for a stream of `config`.
+    pub fn load(&mut self, effects: &[EffectConfig], config: &StreamConfig) {
+        let effects = effects.iter().map(|effect| effect.build(config)).collect();
+        self.replace(effects, config);
+    }
+
+    /// Replaces the effects with `left` on the first channel and `right` on the second, each run
+    /// on its own channel as if it were a mono stream; any further channels are left dry.
+    pub fn load_split(
+        &mut self,
+        left: &[EffectConfig],
+        right: &[EffectConfig],
+        config: &StreamConfig,
+    ) {
+        let mono = StreamConfig {
+            channels: 1,
+            ..config.clone()
+        };
+        let build =
+            |effects: &[EffectConfig]| effects.iter().map(|effect| effect.build(&mono)).collect();
+        let split = PerChannel::new(vec![build(left), build(right)], config);
+        self.replace(vec![Box::new(split)], config);
+    }
+
+    /// Fades `effects` in over the ones they replace, when those ran on a stream of the same
+    /// format; a link that just started has nothing worth fading from, nor gains worth ramping
+    /// from.
+    fn replace(&mut self, mut effects: Vec<Box<dyn Processor>>, config: &StreamConfig) {
+        if let Some(workers) = &self.workers {
+            for effect in &mut effects {
+                effect.share_workers(workers);
+            }
+        }
+        let format = (config.channels, config.sample_rate.0);
+        let frames = (CROSSFADE.as_secs_f32() * config.sample_rate.0 as f32) as usize;
+        let replaced = std::mem::replace(&mut self.effects, effects);
+        self.outgoing = (self.format == Some(format)).then_some(Outgoing {
+            effects: replaced,
+            position: 0,
+            frames: frames.max(1),
+        });
+        if self.format != Some(format) {
+            self.applied_trim = self.trim;
+            self.applied_gain = self.gain();
+        }
+        self.smoothing = (-1.0 / (GAIN_SMOOTHING.as_secs_f32() * config.sample_rate.0 as f32)).exp();
+        self.format = Some(format);
+    }
+
+    /// Ramps the output down to silence over `fade`, ahead of the link being torn down.
+    pub fn fade_out(&mut self, fade: Duration) {
+        self.fade.fade_out(fade);
+    }
+
+    /// Ramps the output back up from wherever a fade out has got to.
+    pub fn fade_back(&mut self, fade: Duration) {
+        self.fade.fade_back(fade);
+    }
+
+    /// Starts the output from silence and ramps it up over `fade`, for a link that's about to
+    /// start. It stays silent until the chain is loaded for the link's format.
+    pub fn fade_in(&mut self, fade: Duration) {
+        self.fade.fade_in(fade);
+    }
+
+    /// Ducks the link whenever `ducker`'s input is loud, or stops ducking it.
+    pub fn set_ducker(&mut self, ducker: Option<Ducker>) {
+        self.ducker = ducker;
+    }
+
+    pub fn ducker(&self) -> Option<&Ducker> {
+        self.ducker.as_ref()
+    }
+
+    pub fn ducker_mut(&mut self) -> Option<&mut Ducker> {
+        self.ducker.as_mut()
+    }
+
+    pub fn clear(&mut self) {
+        self.effects.clear();
+        self.outgoing = None;
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    /// Whether any of the effects is holding the signal down.
+    pub fn gated(&self) -> bool {
+        self.effects.iter().any(|effect| effect.closed())
+    }
+
+    /// Frames the effects hold the signal back by altogether.
+    pub fn latency(&self) -> usize {
+        self.effects.iter().map(|effect| effect.latency()).sum()
+    }
+
+    /// The gain after the effects that the volume and mute come to.
+    fn gain(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume
+        }
+    }
+
+    /// Applies the trim, runs the effects in the order they were pushed, or lets the trimmed
+    /// input past them while bypassed, and the ducker, then applies the volume like a fader; the
+    /// gains and the bypass ramp to new settings rather than jump.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.format.map_or(1, |(channels, _)| channels as usize);
+        if self.trim != 1.0 || self.applied_trim != self.trim {
+            for frame in samples.chunks_mut(channels) {
+                self.applied_trim = self.trim + (self.applied_trim - self.trim) * self.smoothing;
+                for sample in frame {
+                    *sample *= self.applied_trim;
+                }
+            }
+        }
+        // Kept up while the effects are in too, so bypassing them is lined up from the first frame.
+        let wanted = self.latency() * channels + samples.len();
+        self.dry.extend(samples.iter());
+        while self.dry.len() > wanted {
+            self.dry.pop_front();
+        }
+        while self.dry.len() < wanted {
+            self.dry.push_front(0.0);
+        }
+        self.delayed.clear();
+        self.delayed.extend(self.dry.drain(..samples.len()));
+        if let Some(outgoing) = &mut self.outgoing {
+            self.scratch.clear();
+            self.scratch.extend_from_slice(samples);
+            for effect in &mut outgoing.effects {
+                effect.process(&mut self.scratch);
+            }
+        }
+        for effect in &mut self.effects {
+            effect.process(samples);
+        }
+        if let Some(outgoing) = &mut self.outgoing {
+            for (frame, old) in samples
+                .chunks_mut(channels)
+                .zip(self.scratch.chunks(channels))
+            {
+                let new = (outgoing.position as f32 / outgoing.frames as f32).min(1.0);
+                for (sample, old) in frame.iter_mut().zip(old) {
+                    *sample = *sample * new + old * (1.0 - new);
+                }
+                outgoing.position += 1;
+            }
+            if outgoing.position >= outgoing.frames {
+                self.outgoing = None;
+            }
+        }
+        let bypass = if self.bypassed { 1.0 } else { 0.0 };
+        if self.applied_bypass != bypass {
+            for (frame, dry) in samples.chunks_mut(channels).zip(self.delayed.chunks(channels)) {
+                self.applied_bypass = bypass + (self.applied_bypass - bypass) * self.smoothing;
+                for (sample, dry) in frame.iter_mut().zip(dry) {
+                    *sample += (dry - *sample) * self.applied_bypass;
+                }
+            }
+            if (self.applied_bypass - bypass).abs() < 1e-4 {
+                self.applied_bypass = bypass;
+            }
+        } else if self.bypassed {
+            samples.copy_from_slice(&self.delayed);
+        }
+        if let Some(ducker) = &mut self.ducker {
+            ducker.process(samples);
+        }
+        let gain = self.gain();
+        let level_step = self.format.map_or(0.0, |(_, rate)| self.fade.step(rate));
+        for frame in samples.chunks_mut(channels) {
+            self.applied_gain = gain + (self.applied_gain - gain) * self.smoothing;
+            let level = self.fade.advance(level_step);
+            for sample in frame {
+                *sample *= self.applied_gain * level;
+            }
+        }
+    }
+}
+
+/// A level that ramps between silence and full, a frame at a time, to fade a signal in or out.
+#[derive(Debug, Clone)]
+pub struct Fade {
+    /// From 0 to 1.
+    level: f32,
+    /// How long a whole fade takes.
+    duration: Duration,
+    out: bool,
+}
+
+impl Default for Fade {
+    fn default() -> Self {
+        Fade {
+            level: 1.0,
+            duration: CROSSFADE,
+            out: false,
+        }
+    }
+}
+
+impl Fade {
+    /// Ramps down to silence over `fade`.
+    pub fn fade_out(&mut self, fade: Duration) {
+        self.out = true;
+        self.duration = fade;
+    }
+
+    /// Ramps back up from wherever a fade out has got to.
+    pub fn fade_back(&mut self, fade: Duration) {
+        self.out = false;
+        self.duration = fade;
+    }
+
+    /// Starts from silence and ramps up over `fade`.
+    pub fn fade_in(&mut self, fade: Duration) {
+        self.out = false;
+        self.level = 0.0;
+        self.duration = fade;
+    }
+
+    /// How far the level moves in a frame at `rate`.
+    pub fn step(&self, rate: u32) -> f32 {
+        1.0 / (self.duration.as_secs_f32() * rate as f32).max(1.0)
+    }
+
+    /// Moves the level `step` along the fade, returning it.
+    pub fn advance(&mut self, step: f32) -> f32 {
+        self.level = if self.out {
+            (self.level - step).max(0.0)
+        } else {
+            (self.level + step).min(1.0)
+        };
+        self.level
+    }
+
+    /// Fades interleaved `samples` of `channels` at `rate`.
+    pub fn process(&mut self, samples: &mut [f32], channels: usize, rate: u32) {
+        if self.level == 1.0 && !self.out {
+            return;
+        }
+        let step = self.step(rate);
+        for frame in samples.chunks_mut(channels) {
+            let level = self.advance(step);
+            for sample in frame {
+                *sample *= level;
+            }
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use std::path::{Path, PathBuf};
+use std::{error, fs};
+
+use chrono::{NaiveTime, Weekday};
+use serde::{Deserialize, Serialize};
+use toml_edit::{ArrayOfTables, DocumentMut, Item};
+
+#[cfg(feature = "network")]
+use crate::airplay::AirplayConfig;
+use crate::dose::DoseConfig;
+use crate::ducking::{DuckingConfig, SidechainConfig};
+use crate::effects::{CompressorConfig, EffectConfig, GateConfig, ShelfConfig};
+#[cfg(feature = "network")]
+use crate::icecast::IcecastConfig;
+#[cfg(feature = "midi")]
+use crate::midi::MidiConfig;
+#[cfg(feature = "network")]
+use crate::net::NetworkConfig;
+use crate::latency::LowLatencyConfig;
+use crate::looper::LooperConfig;
+use crate::metronome::MetronomeConfig;
+use crate::outputs::OutputsConfig;
+use crate::playback::PlayerConfig;
+use crate::recorder::{EncoderSettings, TagTemplates, TapPoint};
+use crate::remix::RemixConfig;
+use crate::replay::ReplayConfig;
+use crate::room::RoomCorrectionConfig;
+use crate::safety::{LoudnessAlertConfig, SafetyConfig};
+#[cfg(feature = "network")]
+use crate::rtp::RtpConfig;
+use crate::session::SessionConfig;
+#[cfg(feature = "network")]
+use crate::snapcast::SnapcastConfig;
+use crate::soundboard::SoundboardConfig;
+use crate::spectrogram::SpectrogramConfig;
+use crate::spl::SplConfig;
+use crate::standby::StandbyConfig;
+use crate::talk::{PushToTalkConfig, VoxConfig};
+#[cfg(feature = "transcribe")]
+use crate::transcribe::TranscriptionConfig;
+use crate::tuner::TunerConfig;
+use crate::workers::WorkersConfig;
+use crate::virtual_devices::VirtualDevicesConfig;
+
+pub const DEFAULT_CONFIG_PATH: &str = "sound-amp.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub profiles: Vec<Profile>,
+    pub schedule: Vec<ScheduleRule>,
+    pub recording: RecordingConfig,
+    pub replay: ReplayConfig,
+    pub player: PlayerConfig,
+    pub soundboard: SoundboardConfig,
+    pub ducking: DuckingConfig,
+    pub sidechain: SidechainConfig,
+    #[cfg(feature = "network")]
+    pub network: NetworkConfig,
+    #[cfg(feature = "network")]
+    pub rtp: RtpConfig,
+    #[cfg(feature = "midi")]
+    pub midi: MidiConfig,
+    #[cfg(feature = "network")]
+    pub icecast: IcecastConfig,
+    #[cfg(feature = "network")]
+    pub snapcast: SnapcastConfig,
+    pub virtual_devices: VirtualDevicesConfig,
+    #[cfg(feature = "network")]
+    pub airplay: AirplayConfig,
+    pub session: SessionConfig,
+    pub outputs: OutputsConfig,
+    pub remix: RemixConfig,
+    pub safety: SafetyConfig,
+    pub loudness_alert: LoudnessAlertConfig,
+    pub spl: SplConfig,
+    pub dose: DoseConfig,
+    pub push_to_talk: PushToTalkConfig,
+    pub vox: VoxConfig,
+    pub metronome: MetronomeConfig,
+    pub tuner: TunerConfig,
+    pub spectrogram: SpectrogramConfig,
+    pub room_correction: RoomCorrectionConfig,
+    pub looper: LooperConfig,
+    pub low_latency: LowLatencyConfig,
+    pub standby: StandbyConfig,
+    pub workers: WorkersConfig,
+    #[cfg(feature = "transcribe")]
+    pub transcription: TranscriptionConfig,
+}
+
+impl Config {
+    /// Reads the config from `path`, falling back to the defaults when the file doesn't exist.
+    /// The built-in profiles follow the ones in the file, unless it has one of the same name.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, Box<dyn error::Error>> {
+        let path = path.as_ref();
+        let mut config: Config = if path.exists() {
+            toml::from_str(&fs::read_to_string(path)?)?
+        } else {
+            Config::default()
+        };
+        for profile in Profile::builtin() {
+            if config.profile(&profile.name).is_none() {
+                config.profiles.push(profile);
+            }
+        }
+        config.safety.ceiling()?;
+        for rule in &config.schedule {
+            if config.profile(&rule.profile).is_none() {
+                return Err(format!("schedule refers to unknown profile '{}'", rule.profile).into());
+            }
+        }
+        Ok(config)
+    }
+
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profiles.iter().find(|p| p.name == name)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Directory recordings are written to, one timestamped file each.
+    pub directory: PathBuf,
+    /// Start a new file after this many minutes.
+    pub split_minutes: Option<u32>,
+    /// Start a new file once the current one reaches this size.
+    pub split_megabytes: Option<u64>,
+    /// Record the raw input, the processed signal or both.
+    pub tap: TapPoint,
+    /// Tags written into FLAC, MP3 and Opus recordings.
+    pub tags: TagTemplates,
+    #[serde(flatten)]
+    pub encoder: EncoderSettings,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        RecordingConfig {
+            directory: PathBuf::from("."),
+            split_minutes: None,
+            split_megabytes: None,
+            tap: TapPoint::default(),
+            tags: TagTemplates::default(),
+            encoder: EncoderSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    #[serde(default = "default_volume")]
+    pub volume: f32,
+    /// Gain ahead of the effects, so they see the level they're set up for at any volume.
+    #[serde(default = "default_volume")]
+    pub trim: f32,
+    /// Name of the input device to link, as reported by the host.
+    pub input_device: Option<String>,
+    /// Run in order on the main link's input, after the trim and before the volume.
+    #[serde(default)]
+    pub effects: Vec<EffectConfig>,
+    /// With the channels unlinked, `effects` only runs on the left channel and these on the right;
+    /// left out, the right gets a chain like the left's of its own.
+    pub right_effects: Option<Vec<EffectConfig>>,
+    /// Whether both channels go through one chain, which follows them together; off, each ear is
+    /// processed on its own.
+    #[serde(default = "default_link_channels")]
+    pub link_channels: bool,
+}
+
+impl Profile {
+    /// Presets for speech with mild or moderate hearing loss: a gate keeps room noise from being
+    /// amplified, a high shelf brings up the consonants, and a compressor lifts quiet voices more
+    /// than loud ones.
+    pub fn builtin() -> Vec<Profile> {
+        let hearing_assist = |name: &str, gate_db: f32, shelf_db: f32, ratio: f32, makeup_db: f32| Profile {
+            name: name.to_string(),
+            volume: 1.0,
+            trim: 1.0,
+            input_device: None,
+            effects: vec![
+                EffectConfig::Gate(GateConfig {
+                    threshold_db: gate_db,
+                    ..Default::default()
+                }),
+                EffectConfig::HighShelf(ShelfConfig {
+                    frequency: 2000.0,
+                    gain_db: shelf_db,
+                }),
+                EffectConfig::Compressor(CompressorConfig {
+                    threshold_db: -30.0,
+                    ratio,
+                    makeup_db,
+                    ..Default::default()
+                }),
+            ],
+            right_effects: None,
+            link_channels: true,
+        };
+        vec![
+            hearing_assist("Hearing assist (mild)", -55.0, 6.0, 2.0, 4.0),
+            hearing_assist("Hearing assist (moderate)", -50.0, 12.0, 3.0, 8.0),
+        ]
+    }
+
+    /// Writes the profile into the config file at `path`, over the `[[profiles]]` entry of the
+    /// same name if there's one, keeping the rest of the file as it was.
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn error::Error>> {
+        let mut document: DocumentMut = match fs::read_to_string(path) {
+            Ok(text) => text.parse()?,
+            Err(_) => DocumentMut::new(),
+        };
+        let table = toml::to_string(self)?.parse::<DocumentMut>()?.as_table().clone();
+        let profiles = document
+            .entry("profiles")
+            .or_insert_with(|| Item::ArrayOfTables(ArrayOfTables::new()))
+            .as_array_of_tables_mut()
+            .ok_or("`profiles` in the config isn't a list of tables")?;
+        let existing = profiles
+            .iter()
+            .position(|profile| profile.get("name").and_then(Item::as_str) == Some(self.name.as_str()));
+        match existing.and_then(|i| profiles.get_mut(i)) {
+            Some(profile) => *profile = table,
+            None => profiles.push(table),
+        }
+        fs::write(path, document.to_string())?;
+        Ok(())
+    }
+}
+
+fn default_volume() -> f32 {
+    1.0
+}
+
+fn default_link_channels() -> bool {
+    true
+}
+
+/// Activates `profile` on the given days between `start` and `end`.
+/// A rule whose `end` is before its `start` runs past midnight, and one whose `end` is its `start`
+/// runs for a whole day from then.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScheduleRule {
+    pub days: Days,
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+    pub profile: String,
+}
+
+impl ScheduleRule {
+    pub fn matches(&self, day: Weekday, time: NaiveTime) -> bool {
+        let (start, end) = (self.start.0, self.end.0);
+        if start < end {
+            self.days.contains(day) && start <= time && time < end
+        } else if time >= start {
+            self.days.contains(day)
+        } else {
+            // Early-morning tail of a rule that started the previous evening.
+            time < end && self.days.contains(day.pred())
+        }
+    }
+}
+
+/// A set of weekdays written as `daily`, `weekdays`, `weekends` or a list like `mon,wed,fri`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Days(Vec<Weekday>);
+
+impl Days {
+    pub fn contains(&self, day: Weekday) -> bool {
+        self.0.contains(&day)
+    }
+}
+
+impl TryFrom<String> for Days {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        use Weekday::*;
+        let days = match value.trim().to_lowercase().as_str() {
+            "daily" => vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun],
+            "weekdays" => vec![Mon, Tue, Wed, Thu, Fri],
+            "weekends" => vec![Sat, Sun],
+            list => list
+                .split(',')
+                .map(|day| day.trim().parse::<Weekday>())
+                .collect::<Result<_, _>>()
+                .map_err(|_| format!("invalid days '{}'", value))?,
+        };
+        Ok(Days(days))
+    }
+}
+
+/// Wall-clock time written as `HH:MM`.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TimeOfDay(pub NaiveTime);
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        NaiveTime::parse_from_str(value.trim(), "%H:%M")
+            .map(TimeOfDay)
+            .map_err(|_| format!("invalid time '{}', expected HH:MM", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(days: &str, start: &str, end: &str) -> ScheduleRule {
+        ScheduleRule {
+            days: Days::try_from(days.to_string()).unwrap(),
+            start: TimeOfDay::try_from(start.to_string()).unwrap(),
+            end: TimeOfDay::try_from(end.to_string()).unwrap(),
+            profile: "Night".to_string(),
+        }
+    }
+
+    fn at(time: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(time, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn a_rule_holds_from_its_start_up_to_its_end_on_its_days() {
+        let office = rule("weekdays", "09:00", "17:00");
+        assert!(office.matches(Weekday::Mon, at("09:00")));
+        assert!(office.matches(Weekday::Fri, at("16:59")));
+        assert!(!office.matches(Weekday::Wed, at("17:00")));
+        assert!(!office.matches(Weekday::Wed, at("08:59")));
+        assert!(!office.matches(Weekday::Sat, at("12:00")));
+    }
+
+    #[test]
+    fn a_rule_past_midnight_carries_on_into_the_next_day() {
+        let friday_night = rule("fri", "22:00", "02:00");
+        assert!(friday_night.matches(Weekday::Fri, at("22:00")));
+        assert!(friday_night.matches(Weekday::Sat, at("01:00")));
+        assert!(!friday_night.matches(Weekday::Sat, at("02:00")));
+        assert!(!friday_night.matches(Weekday::Sat, at("22:30")));
+        // Friday's early hours are Thursday night's, which it doesn't cover.
+        assert!(!friday_night.matches(Weekday::Fri, at("01:00")));
+    }
+
+    #[test]
+    fn a_rule_that_ends_where_it_starts_holds_for_a_whole_day() {
+        let all_day = rule("sun", "06:00", "06:00");
+        assert!(all_day.matches(Weekday::Sun, at("06:00")));
+        assert!(all_day.matches(Weekday::Sun, at("23:59")));
+        assert!(all_day.matches(Weekday::Mon, at("05:59")));
+        assert!(!all_day.matches(Weekday::Mon, at("06:00")));
+        assert!(!all_day.matches(Weekday::Sun, at("05:59")));
+    }
+
+    #[test]
+    fn days_are_read_by_name_or_as_a_list() {
+        use Weekday::*;
+        let days = |text: &str| Days::try_from(text.to_string()).map(|days| days.0);
+        assert_eq!(days("daily"), Ok(vec![Mon, Tue, Wed, Thu, Fri, Sat, Sun]));
+        assert_eq!(days(" Weekdays "), Ok(vec![Mon, Tue, Wed, Thu, Fri]));
+        assert_eq!(days("weekends"), Ok(vec![Sat, Sun]));
+        assert_eq!(days("mon, wed,Friday"), Ok(vec![Mon, Wed, Fri]));
+        assert_eq!(days("mon,funday"), Err("invalid days 'mon,funday'".to_string()));
+        assert!(days("").is_err());
+    }
+}
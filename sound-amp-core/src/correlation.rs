@@ -0,0 +1,53 @@
+//! A correlation meter for the output's first two channels: +1 when they're the same, 0 when they
+//! have nothing in common, and -1 when one is the other upside down, which cancels out when
+//! they're mixed down to mono. Staying under 0 is a phase problem, like two microphones at
+//! different distances from the source, or an effect that widens the sound by flipping one side.
+
+use std::time::Duration;
+
+use cpal::StreamConfig;
+
+/// How long the reading takes to settle, as on a hardware meter.
+const TIME_CONSTANT: Duration = Duration::from_millis(300);
+/// Below this mean square, about -80 dBFS, the output counts as silent and there's no reading,
+/// rather than one jumping about on the noise.
+const SILENCE: f64 = 1e-8;
+/// Readings under this are out of phase enough to be heard thinning out in mono.
+pub const OUT_OF_PHASE: f32 = -0.3;
+
+pub struct CorrelationMeter {
+    channels: usize,
+    smoothing: f64,
+    /// Smoothed products of the two channels.
+    left_right: f64,
+    left_left: f64,
+    right_right: f64,
+}
+
+impl CorrelationMeter {
+    /// A meter for the stream, or none if it's mono.
+    pub fn new(config: &StreamConfig) -> Option<CorrelationMeter> {
+        let rate = config.sample_rate.0 as f64;
+        (config.channels >= 2).then(|| CorrelationMeter {
+            channels: config.channels as usize,
+            smoothing: (-1.0 / (TIME_CONSTANT.as_secs_f64() * rate)).exp(),
+            left_right: 0.0,
+            left_left: 0.0,
+            right_right: 0.0,
+        })
+    }
+
+    /// Adds a block, and gives the correlation at the end of it, from -1 to +1, unless it's silent.
+    pub fn process(&mut self, samples: &[f32]) -> Option<f32> {
+        let smoothing = self.smoothing;
+        let follow = |average: &mut f64, value: f64| *average = value + (*average - value) * smoothing;
+        for frame in samples.chunks_exact(self.channels) {
+            let (left, right) = (frame[0] as f64, frame[1] as f64);
+            follow(&mut self.left_right, left * right);
+            follow(&mut self.left_left, left * left);
+            follow(&mut self.right_right, right * right);
+        }
+        let energy = (self.left_left * self.right_right).sqrt();
+        (energy > SILENCE).then(|| (self.left_right / energy).clamp(-1.0, 1.0) as f32)
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+
+pub type DiscoveryError = Box<dyn std::error::Error + Send + Sync>;
+
+/// How a receiver takes its stream, which decides the mDNS service type it's advertised under.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transport {
+    /// `--listen`, for `--send`.
+    Tcp,
+    /// `--rtp-listen`, for `--rtp-send`.
+    Rtp,
+}
+
+impl Transport {
+    fn service_type(self) -> &'static str {
+        match self {
+            Transport::Tcp => "_soundamp._tcp.local.",
+            Transport::Rtp => "_soundamp._udp.local.",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Tcp => "TCP",
+            Transport::Rtp => "RTP",
+        }
+    }
+}
+
+/// A receiving instance found on the local network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Peer {
+    pub name: String,
+    pub address: SocketAddr,
+    pub transport: Transport,
+}
+
+/// Receivers seen so far, kept up to date as they come and go.
+pub type Peers = Arc<Mutex<Vec<Peer>>>;
+
+/// Advertises this instance's receivers and browses for the others'.
+pub struct Discovery {
+    daemon: ServiceDaemon,
+    /// Full names of our own services, which are left out of `Peers`.
+    own: Arc<Mutex<Vec<String>>>,
+}
+
+impl Discovery {
+    pub fn start(peers: &Peers) -> Result<Discovery, DiscoveryError> {
+        let daemon = ServiceDaemon::new()?;
+        let own: Arc<Mutex<Vec<String>>> = Default::default();
+        for transport in [Transport::Tcp, Transport::Rtp] {
+            let events = daemon.browse(transport.service_type())?;
+            let peers = Arc::clone(peers);
+            let own = Arc::clone(&own);
+            thread::spawn(move || {
+                while let Ok(event) = events.recv() {
+                    match event {
+                        ServiceEvent::ServiceResolved(info) => {
+                            if own
+                                .lock()
+                                .unwrap()
+                                .iter()
+                                .any(|name| name == info.get_fullname())
+                            {
+                                continue;
+                            }
+                            // Prefer IPv4; link-local IPv6 addresses need a scope to be usable.
+                            let mut addresses: Vec<_> =
+                                info.get_addresses().iter().copied().collect();
+                            addresses.sort_by_key(|address| !address.is_ipv4());
+                            let Some(&ip) = addresses.first() else {
+                                continue;
+                            };
+                            let peer = Peer {
+                                name: instance_name(info.get_fullname(), transport),
+                                address: SocketAddr::new(ip, info.get_port()),
+                                transport,
+                            };
+                            let mut peers = peers.lock().unwrap();
+                            peers.retain(|p| p.name != peer.name || p.transport != transport);
+                            peers.push(peer);
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) => {
+                            let name = instance_name(&fullname, transport);
+                            peers
+                                .lock()
+                                .unwrap()
+                                .retain(|p| p.name != name || p.transport != transport);
+                        }
+                        _ => {}
+                    }
+                }
+            });
+        }
+        Ok(Discovery { daemon, own })
+    }
+
+    /// Announces a receiver listening on `port`.
+    pub fn advertise(&self, transport: Transport, port: u16) -> Result<(), DiscoveryError> {
+        let host = hostname();
+        let info = ServiceInfo::new(
+            transport.service_type(),
+            &format!("sound-amp on {}", host),
+            &format!("{}.local.", host),
+            "",
+            port,
+            None,
+        )?
+        .enable_addr_auto();
+        self.own
+            .lock()
+            .unwrap()
+            .push(info.get_fullname().to_string());
+        self.daemon.register(info)?;
+        Ok(())
+    }
+}
+
+impl Drop for Discovery {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+fn instance_name(fullname: &str, transport: Transport) -> String {
+    let suffix = format!(".{}", transport.service_type());
+    fullname
+        .strip_suffix(&suffix)
+        .unwrap_or(fullname)
+        .to_string()
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/etc/hostname")
+        .ok()
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "sound-amp".to_string())
+}
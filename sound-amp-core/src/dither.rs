@@ -0,0 +1,57 @@
+//! Dither for the outputs that carry 16-bit samples: the s16 pipe, RTP's L16 payload and AirPlay.
+//! Rounding to 16 bits leaves quiet passages with distortion that follows the signal, so
+//! triangular noise of up to a step either way is added first, turning it into a steady hiss far
+//! under anything heard. Noise shaping feeds each rounding error back into the next sample, which
+//! moves the hiss up towards the top of the band where the ear is least sensitive to it.
+
+/// Full scale in 16-bit steps, as the conversions without dither had it.
+const SCALE: f32 = i16::MAX as f32;
+
+pub struct Dither {
+    channels: usize,
+    noise_shaping: bool,
+    /// The channel of the next sample, so frames can be split across calls.
+    channel: usize,
+    /// Each channel's last rounding error, for the noise shaping.
+    error: Vec<f32>,
+    /// Xorshift state for the noise.
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(channels: u16, noise_shaping: bool) -> Dither {
+        let channels = channels.max(1) as usize;
+        Dither {
+            channels,
+            noise_shaping,
+            channel: 0,
+            error: vec![0.0; channels],
+            state: 0x9e37_79b9,
+        }
+    }
+
+    /// Rounds the next sample of an interleaved stream to 16 bits.
+    pub fn quantize(&mut self, sample: f32) -> i16 {
+        let channel = self.channel;
+        self.channel = (self.channel + 1) % self.channels;
+        let mut wanted = sample.clamp(-1.0, 1.0) * SCALE;
+        if self.noise_shaping {
+            wanted -= self.error[channel];
+        }
+        let noise = self.uniform() - self.uniform();
+        let rounded = (wanted + noise).round().clamp(i16::MIN as f32, i16::MAX as f32);
+        if self.noise_shaping {
+            // Bounded, so a stretch at full scale can't wind it up.
+            self.error[channel] = (rounded - wanted).clamp(-2.0, 2.0);
+        }
+        rounded as i16
+    }
+
+    /// Uniform from 0 to 1.
+    fn uniform(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+}
@@ -0,0 +1,353 @@
+//! The listening dose: how much of a day's safe exposure the output has used up, by the models
+//! noise at work is judged by. It needs the output calibrated, as for the
+//! [safety ceiling](crate::safety), since the dose depends on the level at the listener's ears.
+//!
+//! Both models allow a criterion level for a criterion time and halve the time for every 3 dB
+//! over it, which makes the dose the A-weighted sound energy heard, as a share of the day's.
+
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{error, fs};
+
+use chrono::Local;
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::lock;
+use crate::spl::{Response, SplConfig, SplMeter, Weighting};
+
+/// How fast the output is turned down and back up, so it's not a jump.
+const RAMP_SECONDS: f32 = 2.0;
+/// How much more of the dose has to build up before it's saved again.
+const SAVE_STEP: f64 = 0.01;
+
+pub type DoseError = Box<dyn error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DoseStandard {
+    /// 80 dB(A) for 40 hours a week, the WHO's safe listening level for adults, taken a day at a
+    /// time.
+    Who,
+    /// 85 dB(A) for 8 hours, NIOSH's recommended limit for noise at work.
+    Niosh,
+}
+
+impl DoseStandard {
+    fn criterion_db(self) -> f32 {
+        match self {
+            DoseStandard::Who => 80.0,
+            DoseStandard::Niosh => 85.0,
+        }
+    }
+
+    fn criterion_seconds(self) -> f64 {
+        match self {
+            DoseStandard::Who => 40.0 / 7.0 * 3600.0,
+            DoseStandard::Niosh => 8.0 * 3600.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DoseConfig {
+    pub standard: DoseStandard,
+    /// Share of the day's dose, in percent, at which to warn that it's getting close.
+    pub warn_percent: f32,
+    /// Turn the output down once the whole day's dose is used, rather than only saying so.
+    pub attenuate: bool,
+    /// How far to turn it down.
+    pub attenuation_db: f32,
+    /// Where the day's dose is kept, so it carries over when sound-amp is restarted.
+    pub path: PathBuf,
+}
+
+impl Default for DoseConfig {
+    fn default() -> Self {
+        DoseConfig {
+            standard: DoseStandard::Who,
+            warn_percent: 80.0,
+            attenuate: false,
+            attenuation_db: 10.0,
+            path: PathBuf::from("sound-amp-dose.toml"),
+        }
+    }
+}
+
+/// The dose so far on one day, as kept on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct DailyDose {
+    /// The local date, as `YYYY-MM-DD`.
+    day: String,
+    /// Share of the day's dose used, where 1 is all of it.
+    dose: f64,
+    /// Whether the user has been told the dose is close to the limit, and that it's reached it.
+    warned: bool,
+    reached: bool,
+}
+
+impl DailyDose {
+    fn new(day: String) -> DailyDose {
+        DailyDose {
+            day,
+            dose: 0.0,
+            warned: false,
+            reached: false,
+        }
+    }
+
+    /// Today's dose as kept at `path`, or a fresh one if there's none for today.
+    fn load(path: &Path, today: &str) -> Result<DailyDose, DoseError> {
+        let saved: DailyDose = match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(DailyDose::new(today.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(if saved.day == today {
+            saved
+        } else {
+            DailyDose::new(today.to_string())
+        })
+    }
+
+    /// Replaces the dose at `path` in one step, so a crash mid-write can't lose the day's.
+    fn save(&self, path: &Path) -> Result<(), DoseError> {
+        let partial = path.with_extension("partial");
+        fs::write(&partial, toml::to_string(self)?)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+}
+
+/// The day's dose, shared by the output callbacks that add to it and the player that reports and
+/// keeps it.
+#[derive(Clone)]
+pub struct DoseTracker {
+    config: DoseConfig,
+    full_scale_db_spl: f32,
+    today: Arc<Mutex<DailyDose>>,
+    /// The dose when it was last saved.
+    saved: Arc<Mutex<f64>>,
+}
+
+impl DoseTracker {
+    /// Picks up today's dose from the last run, given the output's calibration.
+    pub fn load(config: &DoseConfig, full_scale_db_spl: f32) -> Result<DoseTracker, DoseError> {
+        let today = DailyDose::load(&config.path, &today())?;
+        Ok(DoseTracker {
+            config: config.clone(),
+            full_scale_db_spl,
+            saved: Arc::new(Mutex::new(today.dose)),
+            today: Arc::new(Mutex::new(today)),
+        })
+    }
+
+    /// Measures what an output of `stream` plays into the dose.
+    pub fn meter(&self, stream: &StreamConfig) -> DoseMeter {
+        let spl = SplConfig {
+            weighting: Weighting::A,
+            response: Response::Fast,
+            full_scale_db_spl: self.full_scale_db_spl,
+        };
+        let rate = stream.sample_rate.0 as f32;
+        DoseMeter {
+            spl: SplMeter::new(&spl, stream),
+            criterion_db: self.config.standard.criterion_db(),
+            criterion_seconds: self.config.standard.criterion_seconds(),
+            attenuation: self
+                .config
+                .attenuate
+                .then(|| 10f32.powf(-self.config.attenuation_db.abs() / 20.0)),
+            ramp: (-1.0 / (RAMP_SECONDS / 3.0 * rate)).exp(),
+            gain: 1.0,
+            channels: stream.channels as usize,
+            rate,
+            today: Arc::clone(&self.today),
+        }
+    }
+
+    /// Share of the day's dose used so far.
+    pub fn dose(&self) -> f64 {
+        lock(&self.today).dose
+    }
+
+    /// Starts over at midnight, saves the dose now and then, and returns a notice the first time a
+    /// day's dose passes the warning level or the limit.
+    pub fn check(&self) -> Option<String> {
+        let mut today = lock(&self.today);
+        let date = self::today();
+        if today.day != date {
+            *today = DailyDose::new(date);
+            *lock(&self.saved) = 0.0;
+        }
+        let mut saved = lock(&self.saved);
+        if (today.dose - *saved).abs() >= SAVE_STEP {
+            match today.save(&self.config.path) {
+                Ok(()) => *saved = today.dose,
+                Err(e) => eprintln!(
+                    "Cannot keep the listening dose in {}: {}",
+                    self.config.path.display(),
+                    e
+                ),
+            }
+        }
+        let percent = (today.dose * 100.0).round();
+        if today.dose >= 1.0 && !today.reached {
+            today.reached = true;
+            today.warned = true;
+            Some(if self.config.attenuate {
+                format!(
+                    "Today's safe listening dose is used up, output turned down {} dB",
+                    self.config.attenuation_db.abs()
+                )
+            } else {
+                "Today's safe listening dose is used up, consider a break or a lower volume"
+                    .to_string()
+            })
+        } else if today.dose * 100.0 >= self.config.warn_percent as f64 && !today.warned {
+            today.warned = true;
+            Some(format!("{}% of today's safe listening dose used", percent))
+        } else {
+            None
+        }
+    }
+
+    /// Saves the dose as it is, as when the player exits.
+    pub fn save(&self) -> Result<(), DoseError> {
+        let today = lock(&self.today);
+        today.save(&self.config.path)?;
+        *lock(&self.saved) = today.dose;
+        Ok(())
+    }
+}
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// Adds what one output plays to the day's dose, and turns it down once the dose is used up if
+/// that's configured.
+pub struct DoseMeter {
+    spl: SplMeter,
+    criterion_db: f32,
+    criterion_seconds: f64,
+    /// Gain once the dose is used up, if the output is turned down at all.
+    attenuation: Option<f32>,
+    ramp: f32,
+    gain: f32,
+    channels: usize,
+    rate: f32,
+    today: Arc<Mutex<DailyDose>>,
+}
+
+impl DoseMeter {
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let mut today = lock(&self.today);
+        let target = match self.attenuation {
+            Some(attenuation) if today.dose >= 1.0 => attenuation,
+            _ => 1.0,
+        };
+        if target != 1.0 || self.gain != 1.0 {
+            for frame in samples.chunks_mut(self.channels) {
+                self.gain = target + (self.gain - target) * self.ramp;
+                for sample in frame {
+                    *sample *= self.gain;
+                }
+            }
+            if (self.gain - target).abs() < 1e-4 {
+                self.gain = target;
+            }
+        }
+        // What's played is measured after turning it down, since that's what's heard.
+        let level = self.spl.process(samples);
+        let seconds = (samples.len() / self.channels) as f64 / self.rate as f64;
+        today.dose += seconds * 10f64.powf((level - self.criterion_db) as f64 / 10.0)
+            / self.criterion_seconds;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use cpal::{BufferSize, SampleRate};
+
+    use super::*;
+
+    const RATE: u32 = 48000;
+    /// What a full-scale sine plays at.
+    const FULL_SCALE_DB_SPL: f32 = 100.0;
+    /// Long enough that the meter's first 125 ms rising to the level don't count.
+    const SECONDS: usize = 60;
+
+    fn tracker(name: &str, standard: DoseStandard) -> DoseTracker {
+        let name = format!("sound-amp-dose-{}-{}.toml", name, std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        let config = DoseConfig {
+            standard,
+            path,
+            ..Default::default()
+        };
+        DoseTracker::load(&config, FULL_SCALE_DB_SPL).unwrap()
+    }
+
+    /// Plays a 1 kHz tone at `db_spl` for `SECONDS` through a meter of `tracker`, and returns how
+    /// long the tone would take to use up the whole dose, in hours.
+    fn hours_to_use_up(tracker: &DoseTracker, db_spl: f32) -> f64 {
+        let stream = StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(RATE),
+            buffer_size: BufferSize::Default,
+        };
+        let mut meter = tracker.meter(&stream);
+        let amplitude = 10f32.powf((db_spl - FULL_SCALE_DB_SPL) / 20.0);
+        let mut tone: Vec<f32> = (0..RATE as usize * SECONDS)
+            .map(|i| amplitude * (TAU * 1000.0 * i as f32 / RATE as f32).sin())
+            .collect();
+        for block in tone.chunks_mut(480) {
+            meter.process(block);
+        }
+        SECONDS as f64 / tracker.dose() / 3600.0
+    }
+
+    #[test]
+    fn the_who_dose_is_80_db_a_for_40_hours_a_week_and_half_as_long_for_3_db_more() {
+        let hours = hours_to_use_up(&tracker("who", DoseStandard::Who), 80.0);
+        assert!((hours - 40.0 / 7.0).abs() < 0.01 * 40.0 / 7.0, "{} h", hours);
+        let hours = hours_to_use_up(&tracker("who-louder", DoseStandard::Who), 83.0);
+        assert!((hours - 20.0 / 7.0).abs() < 0.01 * 20.0 / 7.0, "{} h", hours);
+    }
+
+    #[test]
+    fn the_niosh_dose_is_85_db_a_for_8_hours_and_half_as_long_for_3_db_more() {
+        let hours = hours_to_use_up(&tracker("niosh", DoseStandard::Niosh), 85.0);
+        assert!((hours - 8.0).abs() < 0.01 * 8.0, "{} h", hours);
+        let hours = hours_to_use_up(&tracker("niosh-louder", DoseStandard::Niosh), 88.0);
+        assert!((hours - 4.0).abs() < 0.01 * 4.0, "{} h", hours);
+    }
+
+    #[test]
+    fn a_new_day_starts_the_dose_over() {
+        let tracker = tracker("midnight", DoseStandard::Who);
+        *lock(&tracker.today) = DailyDose {
+            day: "2000-01-01".to_string(),
+            dose: 1.5,
+            warned: true,
+            reached: true,
+        };
+        *lock(&tracker.saved) = 1.5;
+        assert_eq!(tracker.check(), None);
+        assert_eq!(*lock(&tracker.today), DailyDose::new(today()));
+        assert_eq!(*lock(&tracker.saved), 0.0);
+
+        // And the new day warns again once it gets there.
+        lock(&tracker.today).dose = 0.9;
+        assert!(tracker.check().is_some());
+        fs::remove_file(&tracker.config.path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+/// Peak level of the latest live input block, as `f32` bits.
+pub type LiveLevel = Arc<AtomicU32>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct DuckingConfig {
+    pub enabled: bool,
+    /// Live input peak above which file playback and the soundboard are ducked.
+    pub threshold_db: f32,
+    /// How far the ducked bus is attenuated.
+    pub amount_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for DuckingConfig {
+    fn default() -> Self {
+        DuckingConfig {
+            enabled: false,
+            threshold_db: -30.0,
+            amount_db: 12.0,
+            attack_ms: 10.0,
+            release_ms: 400.0,
+        }
+    }
+}
+
+/// Ducking one link under another's input, like desktop audio under a microphone; which link
+/// ducks under which is picked while they run.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SidechainConfig {
+    /// Peak of the keying link above which the ducked one is attenuated.
+    pub threshold_db: f32,
+    /// How far the ducked link is attenuated.
+    pub amount_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for SidechainConfig {
+    fn default() -> Self {
+        SidechainConfig {
+            threshold_db: -35.0,
+            amount_db: 15.0,
+            attack_ms: 10.0,
+            release_ms: 500.0,
+        }
+    }
+}
+
+impl SidechainConfig {
+    /// The same settings for a [`Ducker`], which is always on once a link is ducked.
+    pub fn ducking(&self) -> DuckingConfig {
+        DuckingConfig {
+            enabled: true,
+            threshold_db: self.threshold_db,
+            amount_db: self.amount_db,
+            attack_ms: self.attack_ms,
+            release_ms: self.release_ms,
+        }
+    }
+}
+
+/// Records the peak of a block of live input for the ducker to react to.
+pub fn store_level(level: &AtomicU32, samples: &[f32]) {
+    let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+    level.store(peak.to_bits(), Ordering::Relaxed);
+}
+
+/// Attenuates the file player and soundboard, or a sidechained link, while the live input it's
+/// keyed from is above the threshold.
+pub struct Ducker {
+    level: LiveLevel,
+    enabled: bool,
+    threshold: f32,
+    ducked_gain: f32,
+    attack: f32,
+    release: f32,
+    rate: f32,
+    channels: usize,
+    gain: f32,
+}
+
+impl Ducker {
+    pub fn new(config: &DuckingConfig, output_config: &StreamConfig, level: &LiveLevel) -> Ducker {
+        let mut ducker = Ducker {
+            level: Arc::clone(level),
+            enabled: false,
+            threshold: 0.0,
+            ducked_gain: 1.0,
+            attack: 0.0,
+            release: 0.0,
+            rate: output_config.sample_rate.0 as f32,
+            channels: output_config.channels as usize,
+            gain: 1.0,
+        };
+        ducker.set_config(config);
+        ducker
+    }
+
+    /// The live input the ducker reacts to.
+    pub fn level(&self) -> &LiveLevel {
+        &self.level
+    }
+
+    /// Takes new settings from the next block on, carrying on from the gain it's at so nothing
+    /// clicks; switched off, it releases as if the live input had gone quiet.
+    pub fn set_config(&mut self, config: &DuckingConfig) {
+        let coefficient = |ms: f32| (-1.0 / (ms.max(0.1) / 1000.0 * self.rate)).exp();
+        self.enabled = config.enabled;
+        self.threshold = db_to_gain(config.threshold_db);
+        self.ducked_gain = db_to_gain(-config.amount_db.abs());
+        self.attack = coefficient(config.attack_ms);
+        self.release = coefficient(config.release_ms);
+    }
+
+    /// Applies the smoothed ducking gain to interleaved `samples` in place.
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let live = f32::from_bits(self.level.load(Ordering::Relaxed));
+        let (target, coefficient) = if self.enabled && live > self.threshold {
+            (self.ducked_gain, self.attack)
+        } else {
+            (1.0, self.release)
+        };
+        for frame in samples.chunks_mut(self.channels) {
+            self.gain = target + (self.gain - target) * coefficient;
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
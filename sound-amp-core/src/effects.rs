@@ -0,0 +1,659 @@
+//! Effects a profile can put in a link's [`EffectChain`](crate::chain::EffectChain), and the
+//! config they're written in. Every effect runs on interleaved samples and keeps its own state per
+//! channel where it needs one.
+
+use std::f32::consts::PI;
+use std::mem;
+use std::panic;
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+use crate::workers::{Done, Lane, Panic, WorkerPool};
+
+pub use self::audiogram::{AudiogramConfig, FREQUENCIES};
+pub use self::convolution::ConvolutionConfig;
+pub use self::distortion::{Distortion, DistortionConfig, DistortionMode};
+pub use self::feedback::{FeedbackConfig, FeedbackSuppressor};
+pub use self::karaoke::{Karaoke, KaraokeConfig};
+pub use self::lowering::{LoweringConfig, LoweringMode};
+pub use self::vocoder::{Bins, PhaseVocoder};
+pub use self::voice::{VoiceChangerConfig, VoicePreset};
+pub use self::wind::{WindConfig, WindFilter};
+
+mod audiogram;
+mod convolution;
+mod distortion;
+mod feedback;
+mod karaoke;
+mod lowering;
+mod vocoder;
+mod voice;
+mod wind;
+
+/// One effect in a profile's `effects` list, told apart by its `type`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EffectConfig {
+    Gain(GainConfig),
+    HighShelf(ShelfConfig),
+    /// Cuts everything below a frequency, like rumble and handling noise.
+    HighPass(HighPassConfig),
+    /// Boosts or cuts a band, like the presence range that makes speech clearer.
+    Peaking(PeakingConfig),
+    Compressor(CompressorConfig),
+    Gate(GateConfig),
+    /// Takes out a narrow band, like the one around a tinnitus tone for notched listening.
+    Notch(NotchConfig),
+    /// Makes up for a hearing loss, each ear on its own channel.
+    Audiogram(AudiogramConfig),
+    /// Moves the content above a cutoff down to lower frequencies.
+    FrequencyLowering(LoweringConfig),
+    /// Notches out the tones that start to howl when a microphone is near a speaker.
+    FeedbackSuppression(FeedbackConfig),
+    /// Cuts the rumble of wind and plosives while they last.
+    WindReduction(WindConfig),
+    /// Takes out the lead vocal of a stereo mix, for singing along.
+    Karaoke(KaraokeConfig),
+    /// Moves the pitch and formants of a voice, and can make it a robot's.
+    VoiceChanger(VoiceChangerConfig),
+    /// Drives an instrument into clipping, with tone controls, for a practice amp's sound.
+    Distortion(DistortionConfig),
+    /// Convolves with an impulse response, like a guitar cabinet's, a microphone correction or a
+    /// room.
+    Convolution(ConvolutionConfig),
+}
+
+impl EffectConfig {
+    /// The effect, set up for a stream of `config`.
+    pub fn build(&self, config: &StreamConfig) -> Box<dyn Processor> {
+        match self {
+            EffectConfig::Gain(gain) => Box::new(Gain(db_to_gain(gain.gain_db))),
+            EffectConfig::HighShelf(shelf) => {
+                Box::new(Biquad::high_shelf(shelf.frequency, shelf.gain_db, config))
+            }
+            EffectConfig::HighPass(high_pass) => Box::new(Biquad::high_pass(
+                high_pass.frequency,
+                1.0 / 2f32.sqrt(),
+                config,
+            )),
+            EffectConfig::Peaking(peaking) => Box::new(Biquad::peaking(
+                peaking.frequency,
+                peaking.q,
+                peaking.gain_db,
+                config,
+            )),
+            EffectConfig::Compressor(compressor) => Box::new(Compressor::new(compressor, config)),
+            EffectConfig::Gate(gate) => Box::new(Gate::new(gate, config)),
+            EffectConfig::Notch(notch) => {
+                Box::new(Biquad::notch(notch.frequency, notch.width_octaves, config))
+            }
+            EffectConfig::Audiogram(audiogram) => Box::new(audiogram.build(config)),
+            EffectConfig::FrequencyLowering(lowering) => Box::new(lowering.build(config)),
+            EffectConfig::FeedbackSuppression(feedback) => Box::new(feedback.build(config)),
+            EffectConfig::WindReduction(wind) => Box::new(WindFilter::new(wind, config)),
+            EffectConfig::Karaoke(karaoke) => Box::new(Karaoke::new(karaoke, config)),
+            EffectConfig::VoiceChanger(voice) => Box::new(voice.build(config)),
+            EffectConfig::Distortion(distortion) => Box::new(Distortion::new(distortion, config)),
+            EffectConfig::Convolution(convolution) => Box::new(convolution.build(config)),
+        }
+    }
+
+    /// Whether the effect holds the signal back, like the phase vocoder's frames and the
+    /// convolver's partition, or takes long enough to run that it won't keep up with small
+    /// buffers, like the distortion's oversampling; [low latency mode](crate::latency) leaves
+    /// these out.
+    pub fn is_heavy(&self) -> bool {
+        matches!(
+            self,
+            EffectConfig::FrequencyLowering(_)
+                | EffectConfig::VoiceChanger(_)
+                | EffectConfig::Distortion(_)
+                | EffectConfig::Convolution(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GainConfig {
+    pub gain_db: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShelfConfig {
+    /// Where the shelf is halfway to its gain.
+    pub frequency: f32,
+    pub gain_db: f32,
+}
+
+impl Default for ShelfConfig {
+    fn default() -> Self {
+        ShelfConfig {
+            frequency: 2000.0,
+            gain_db: 6.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CompressorConfig {
+    /// Peak level above which the gain comes down.
+    pub threshold_db: f32,
+    /// How many dB over the threshold it takes to come out 1 dB louder.
+    pub ratio: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+    /// Gain applied after compressing, to bring the quiet parts up.
+    pub makeup_db: f32,
+}
+
+impl Default for CompressorConfig {
+    fn default() -> Self {
+        CompressorConfig {
+            threshold_db: -24.0,
+            ratio: 3.0,
+            attack_ms: 5.0,
+            release_ms: 120.0,
+            makeup_db: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GateConfig {
+    /// Peak level below which the input is taken for noise.
+    pub threshold_db: f32,
+    /// How far noise is turned down.
+    pub range_db: f32,
+    pub attack_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for GateConfig {
+    fn default() -> Self {
+        GateConfig {
+            threshold_db: -50.0,
+            range_db: 20.0,
+            attack_ms: 2.0,
+            release_ms: 150.0,
+        }
+    }
+}
+
+/// Scales every sample by a fixed gain.
+pub struct Gain(pub f32);
+
+impl Processor for Gain {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample *= self.0;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HighPassConfig {
+    /// Where the response is 3 dB down, rolling off at 12 dB an octave below it.
+    pub frequency: f32,
+}
+
+impl Default for HighPassConfig {
+    fn default() -> Self {
+        HighPassConfig { frequency: 100.0 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PeakingConfig {
+    pub frequency: f32,
+    /// How narrow the band is; 1 is about an octave and a third between the half-gain points.
+    pub q: f32,
+    pub gain_db: f32,
+}
+
+impl Default for PeakingConfig {
+    fn default() -> Self {
+        PeakingConfig {
+            frequency: 3000.0,
+            q: 1.0,
+            gain_db: 4.0,
+        }
+    }
+}
+
+/// The effects the speech boost adds after a profile's: a high-pass takes out the rumble under
+/// voices, a presence lift around 3 kHz brings out consonants, and a fast compressor evens out
+/// near and far talkers.
+pub fn speech_boost() -> Vec<EffectConfig> {
+    vec![
+        EffectConfig::HighPass(HighPassConfig { frequency: 120.0 }),
+        EffectConfig::Peaking(PeakingConfig {
+            frequency: 3000.0,
+            q: 1.0,
+            gain_db: 6.0,
+        }),
+        EffectConfig::Compressor(CompressorConfig {
+            threshold_db: -30.0,
+            ratio: 4.0,
+            attack_ms: 1.0,
+            release_ms: 60.0,
+            makeup_db: 6.0,
+        }),
+    ]
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NotchConfig {
+    /// Centre of the notch, where it takes everything out; the tinnitus pitch for notched
+    /// listening.
+    pub frequency: f32,
+    /// Width between the points 3 dB down.
+    pub width_octaves: f32,
+}
+
+impl Default for NotchConfig {
+    fn default() -> Self {
+        NotchConfig {
+            frequency: 4000.0,
+            width_octaves: 0.5,
+        }
+    }
+}
+
+/// Qualities of the two stages of a fourth-order Butterworth filter, low-pass or high-pass.
+pub const BUTTERWORTH_STAGE_Q: [f32; 2] = [0.541, 1.307];
+
+/// A second-order IIR filter, in transposed direct form II.
+pub struct Biquad {
+    b: [f32; 3],
+    /// `a1` and `a2`, with `a0` divided out.
+    a: [f32; 2],
+    channels: usize,
+    state: Vec<[f32; 2]>,
+}
+
+impl Biquad {
+    /// Boosts everything above `frequency` by `gain_db`, or cuts it if that's negative; the
+    /// shelf from the Audio EQ Cookbook, with a slope of 1.
+    pub fn high_shelf(frequency: f32, gain_db: f32, config: &StreamConfig) -> Biquad {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, alpha) = omega(frequency, 1.0 / 2f32.sqrt(), config);
+        let root = 2.0 * a.sqrt() * alpha;
+        Biquad::new(
+            [
+                a * ((a + 1.0) + (a - 1.0) * cos + root),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) - (a - 1.0) * cos + root,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - root,
+            ],
+            config,
+        )
+    }
+
+    /// Boosts everything below `frequency` by `gain_db`, or cuts it if that's negative; the
+    /// high shelf's mirror image.
+    pub fn low_shelf(frequency: f32, gain_db: f32, config: &StreamConfig) -> Biquad {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, alpha) = omega(frequency, 1.0 / 2f32.sqrt(), config);
+        let root = 2.0 * a.sqrt() * alpha;
+        Biquad::new(
+            [
+                a * ((a + 1.0) - (a - 1.0) * cos + root),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - root),
+            ],
+            [
+                (a + 1.0) + (a - 1.0) * cos + root,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - root,
+            ],
+            config,
+        )
+    }
+
+    /// Boosts or cuts by `gain_db` around `frequency`, narrower the higher `q` is.
+    pub fn peaking(frequency: f32, q: f32, gain_db: f32, config: &StreamConfig) -> Biquad {
+        let a = 10f32.powf(gain_db / 40.0);
+        let (cos, alpha) = omega(frequency, q, config);
+        Biquad::new(
+            [1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a],
+            [1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a],
+            config,
+        )
+    }
+
+    /// Passes everything above `frequency`, rolling off below it at 12 dB an octave.
+    pub fn high_pass(frequency: f32, q: f32, config: &StreamConfig) -> Biquad {
+        let (cos, alpha) = omega(frequency, q, config);
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            config,
+        )
+    }
+
+    /// Passes everything below `frequency`, rolling off above it at 12 dB an octave.
+    pub fn low_pass(frequency: f32, q: f32, config: &StreamConfig) -> Biquad {
+        let (cos, alpha) = omega(frequency, q, config);
+        Biquad::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            config,
+        )
+    }
+
+    /// Takes out `frequency` completely and the band `width_octaves` wide around it mostly.
+    pub fn notch(frequency: f32, width_octaves: f32, config: &StreamConfig) -> Biquad {
+        let rate = config.sample_rate.0 as f32;
+        let w0 = 2.0 * PI * frequency.clamp(1.0, rate * 0.49) / rate;
+        let bandwidth = width_octaves.max(0.01);
+        let alpha = w0.sin() * (2f32.ln() / 2.0 * bandwidth * w0 / w0.sin()).sinh();
+        let cos = w0.cos();
+        Biquad::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+            config,
+        )
+    }
+
+    /// How much the filter boosts a sine at `frequency`, in dB.
+    pub fn response_db(&self, frequency: f32, config: &StreamConfig) -> f32 {
+        let w = 2.0 * PI * frequency / config.sample_rate.0 as f32;
+        let magnitude = |[c0, c1, c2]: [f32; 3]| {
+            let real = c0 + c1 * w.cos() + c2 * (2.0 * w).cos();
+            let imaginary = c1 * w.sin() + c2 * (2.0 * w).sin();
+            real.hypot(imaginary)
+        };
+        let numerator = magnitude(self.b);
+        let denominator = magnitude([1.0, self.a[0], self.a[1]]);
+        gain_to_db(numerator / denominator)
+    }
+
+    pub(crate) fn new(b: [f32; 3], a: [f32; 3], config: &StreamConfig) -> Biquad {
+        let channels = config.channels as usize;
+        Biquad {
+            b: b.map(|b| b / a[0]),
+            a: [a[1] / a[0], a[2] / a[0]],
+            channels,
+            state: vec![[0.0; 2]; channels],
+        }
+    }
+}
+
+/// Cosine of the normalized `frequency` and the cookbook's alpha for quality `q`; the frequency is
+/// kept below Nyquist so a filter meant for a faster stream stays stable.
+fn omega(frequency: f32, q: f32, config: &StreamConfig) -> (f32, f32) {
+    let rate = config.sample_rate.0 as f32;
+    let w0 = 2.0 * PI * frequency.clamp(1.0, rate * 0.49) / rate;
+    (w0.cos(), w0.sin() / (2.0 * q))
+}
+
+impl Processor for Biquad {
+    fn process(&mut self, samples: &mut [f32]) {
+        let ([b0, b1, b2], [a1, a2]) = (self.b, self.a);
+        for frame in samples.chunks_mut(self.channels) {
+            for (sample, state) in frame.iter_mut().zip(&mut self.state) {
+                let x = *sample;
+                let y = b0 * x + state[0];
+                state[0] = b1 * x - a1 * y + state[1];
+                state[1] = b2 * x - a2 * y;
+                *sample = y;
+            }
+        }
+    }
+}
+
+/// Turns the level down by `ratio` above the threshold, following the loudest channel so the
+/// stereo image holds still.
+pub struct Compressor {
+    threshold_db: f32,
+    slope: f32,
+    makeup_db: f32,
+    attack: f32,
+    release: f32,
+    channels: usize,
+    envelope: f32,
+}
+
+impl Compressor {
+    pub fn new(config: &CompressorConfig, stream: &StreamConfig) -> Compressor {
+        let rate = stream.sample_rate.0 as f32;
+        Compressor {
+            threshold_db: config.threshold_db,
+            slope: 1.0 - 1.0 / config.ratio.max(1.0),
+            makeup_db: config.makeup_db,
+            attack: coefficient(config.attack_ms, rate),
+            release: coefficient(config.release_ms, rate),
+            channels: stream.channels as usize,
+            envelope: 0.0,
+        }
+    }
+}
+
+impl Processor for Compressor {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            let coefficient = if peak > self.envelope {
+                self.attack
+            } else {
+                self.release
+            };
+            self.envelope = peak + (self.envelope - peak) * coefficient;
+            let over = (gain_to_db(self.envelope) - self.threshold_db).max(0.0);
+            let gain = db_to_gain(self.makeup_db - over * self.slope);
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// Turns the input down by a fixed range while it's below the threshold, like the room noise
+/// between words.
+pub struct Gate {
+    threshold: f32,
+    floor: f32,
+    attack: f32,
+    release: f32,
+    channels: usize,
+    envelope: f32,
+    gain: f32,
+}
+
+impl Gate {
+    pub fn new(config: &GateConfig, stream: &StreamConfig) -> Gate {
+        let rate = stream.sample_rate.0 as f32;
+        Gate {
+            threshold: db_to_gain(config.threshold_db),
+            floor: db_to_gain(-config.range_db.abs()),
+            attack: coefficient(config.attack_ms, rate),
+            release: coefficient(config.release_ms, rate),
+            channels: stream.channels as usize,
+            envelope: 0.0,
+            gain: 1.0,
+        }
+    }
+}
+
+impl Processor for Gate {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            // The peak decays at the release rate so the gate doesn't chatter between cycles of a wave.
+            self.envelope = peak.max(self.envelope * self.release);
+            let (target, coefficient) = if self.envelope > self.threshold {
+                (1.0, self.attack)
+            } else {
+                (self.floor, self.release)
+            };
+            self.gain = target + (self.gain - target) * coefficient;
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.envelope <= self.threshold
+    }
+}
+
+/// How long a block waits for a worker's lane, long past when the callback should have returned,
+/// so it's only reached when a worker is stuck. The lane's channel goes through as it is until it's
+/// back.
+const WORKER_PATIENCE: Duration = Duration::from_millis(250);
+
+/// Runs a chain of its own on each channel, built for a mono stream. When the chains are heavy
+/// and it's been given [workers](crate::workers), they run side by side.
+pub struct PerChannel {
+    /// Each channel's chain, and that channel of the block taken out to run it on.
+    lanes: Vec<Lane>,
+    /// Which lanes are out on a worker.
+    out: Vec<bool>,
+    channels: usize,
+    workers: Option<WorkerPool>,
+    /// Where the workers send the lanes back, and where they're waited for.
+    done: Done,
+    finished: Receiver<(usize, Lane, Option<Panic>)>,
+}
+
+impl PerChannel {
+    /// `chains[i]` runs on channel `i` of a stream of `config`; channels past the last chain are
+    /// left as they are.
+    pub fn new(chains: Vec<Vec<Box<dyn Processor>>>, config: &StreamConfig) -> PerChannel {
+        let lanes: Vec<Lane> = chains
+            .into_iter()
+            .map(|effects| Lane {
+                effects,
+                samples: Vec::new(),
+            })
+            .collect();
+        let (done, finished) = mpsc::sync_channel(lanes.len());
+        PerChannel {
+            out: vec![false; lanes.len()],
+            lanes,
+            channels: config.channels as usize,
+            workers: None,
+            done,
+            finished,
+        }
+    }
+
+    /// Puts a lane back from a worker, raising its panic again here if it had one.
+    fn collect(&mut self, (i, lane, panic): (usize, Lane, Option<Panic>)) {
+        self.lanes[i] = lane;
+        self.out[i] = false;
+        if let Some(panic) = panic {
+            panic::resume_unwind(panic);
+        }
+    }
+
+    fn effects(&self) -> impl Iterator<Item = &Box<dyn Processor>> {
+        self.lanes.iter().flat_map(|lane| &lane.effects)
+    }
+}
+
+impl Processor for PerChannel {
+    fn process(&mut self, samples: &mut [f32]) {
+        // Lanes that came back too late for the last block.
+        while let Ok(returned) = self.finished.try_recv() {
+            self.collect(returned);
+        }
+        let mut running = 0;
+        for (i, (lane, out)) in self.lanes.iter_mut().zip(&mut self.out).enumerate() {
+            if *out {
+                continue;
+            }
+            lane.samples.clear();
+            lane.samples
+                .extend(samples.iter().skip(i).step_by(self.channels));
+            // The first lane runs here while the workers run the rest.
+            if let (Some(workers), true) = (&self.workers, i > 0) {
+                match workers.run(i, mem::take(lane), &self.done) {
+                    Ok(()) => {
+                        *out = true;
+                        running += 1;
+                        continue;
+                    }
+                    Err(busy) => *lane = busy,
+                }
+            }
+            lane.run();
+        }
+        for _ in 0..running {
+            match self.finished.recv_timeout(WORKER_PATIENCE) {
+                Ok(returned) => self.collect(returned),
+                Err(_) => break,
+            }
+        }
+        for (i, lane) in self.lanes.iter().enumerate() {
+            if self.out[i] {
+                continue;
+            }
+            let channel = samples.iter_mut().skip(i).step_by(self.channels);
+            for (sample, processed) in channel.zip(&lane.samples) {
+                *sample = *processed;
+            }
+        }
+    }
+
+    fn closed(&self) -> bool {
+        self.effects().any(|effect| effect.closed())
+    }
+
+    /// The slowest channel's; they're normally all alike.
+    fn latency(&self) -> usize {
+        self.lanes
+            .iter()
+            .map(|lane| lane.effects.iter().map(|effect| effect.latency()).sum())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn heavy(&self) -> bool {
+        self.effects().any(|effect| effect.heavy())
+    }
+
+    /// Only taken up when there's more than one chain and something heavy in them; the chains
+    /// pass it on to any of their own.
+    fn share_workers(&mut self, workers: &WorkerPool) {
+        if self.lanes.len() > 1 && self.heavy() {
+            self.workers = Some(workers.clone());
+        }
+        for lane in &mut self.lanes {
+            for effect in &mut lane.effects {
+                effect.share_workers(workers);
+            }
+        }
+    }
+}
+
+
+/// How much of the distance to its target a one-pole smoother keeps each frame, for a time
+/// constant of `ms`.
+fn coefficient(ms: f32, rate: f32) -> f32 {
+    (-1.0 / (ms.max(0.1) / 1000.0 * rate)).exp()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn gain_to_db(gain: f32) -> f32 {
+    20.0 * gain.max(1e-9).log10()
+}
@@ -0,0 +1,100 @@
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::{Biquad, Compressor, CompressorConfig, PerChannel};
+
+/// The frequencies of an audiogram, in Hz.
+pub const FREQUENCIES: [f32; 6] = [250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0];
+
+/// Most any one band is boosted by, however much is lost there.
+const MAX_GAIN_DB: f32 = 30.0;
+/// Bandwidth of each band's filter: an octave, like the spacing of the frequencies.
+const BAND_Q: f32 = 1.41;
+/// Times the band gains are corrected for the overlap of their neighbours.
+const FIT_PASSES: usize = 8;
+
+/// Hearing thresholds as an audiologist measures them, in dB HL at each of [`FREQUENCIES`].
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudiogramConfig {
+    /// The first channel.
+    pub left: [f32; 6],
+    /// The second channel.
+    pub right: [f32; 6],
+}
+
+impl AudiogramConfig {
+    /// Compensation for the loss of each ear on its own channel, by the half-gain rule: every band
+    /// is boosted by half the loss there, and the compressor's ratio grows with the average loss,
+    /// since soft sounds are lost more than loud ones. Any channel past the second gets the
+    /// average of both ears, and so does a mono stream.
+    pub fn build(&self, config: &StreamConfig) -> PerChannel {
+        let average: [f32; 6] = std::array::from_fn(|i| (self.left[i] + self.right[i]) / 2.0);
+        let mono = StreamConfig {
+            channels: 1,
+            ..config.clone()
+        };
+        let chains = (0..config.channels)
+            .map(|channel| match (config.channels, channel) {
+                (1, _) => compensation(&average, &mono),
+                (_, 0) => compensation(&self.left, &mono),
+                (_, 1) => compensation(&self.right, &mono),
+                _ => compensation(&average, &mono),
+            })
+            .collect();
+        PerChannel::new(chains, config)
+    }
+}
+
+fn compensation(thresholds: &[f32; 6], mono: &StreamConfig) -> Vec<Box<dyn Processor>> {
+    let mut effects: Vec<Box<dyn Processor>> = fit_bands(thresholds, mono)
+        .into_iter()
+        .map(|band| Box::new(band) as Box<dyn Processor>)
+        .collect();
+    let loss = thresholds.iter().sum::<f32>() / thresholds.len() as f32;
+    if loss > 0.0 {
+        // 1:1 with normal hearing, up to 3:1 for a severe loss.
+        let ratio = (100.0 / (100.0 - loss.min(66.7))).max(1.0);
+        effects.push(Box::new(Compressor::new(
+            &CompressorConfig {
+                threshold_db: -40.0,
+                ratio,
+                ..Default::default()
+            },
+            mono,
+        )));
+    }
+    effects
+}
+
+/// One peaking filter per frequency, with gains corrected so that together they come out at half
+/// the loss at every frequency, rather than the sum of a band and the skirts of its neighbours.
+fn fit_bands(thresholds: &[f32; 6], mono: &StreamConfig) -> Vec<Biquad> {
+    let targets = thresholds.map(|loss| (loss / 2.0).clamp(0.0, MAX_GAIN_DB));
+    let mut gains = targets;
+    let bands = |gains: &[f32; 6]| -> Vec<Biquad> {
+        FREQUENCIES
+            .iter()
+            .zip(gains)
+            .map(|(&frequency, &gain_db)| Biquad::peaking(frequency, BAND_Q, gain_db, mono))
+            .collect()
+    };
+    for _ in 0..FIT_PASSES {
+        let current = bands(&gains);
+        for (i, &frequency) in FREQUENCIES.iter().enumerate() {
+            let response: f32 = current
+                .iter()
+                .map(|band| band.response_db(frequency, mono))
+                .sum();
+            gains[i] += targets[i] - response;
+        }
+    }
+    bands(&gains)
+        .into_iter()
+        .zip(gains)
+        .filter(|(_, gain_db)| gain_db.abs() > 0.1)
+        .map(|(band, _)| band)
+        .collect()
+}
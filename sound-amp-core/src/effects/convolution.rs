@@ -0,0 +1,208 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cpal::StreamConfig;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+use crate::playback;
+
+use super::{db_to_gain, PerChannel};
+
+/// Samples in each partition of the impulse response, and so in each block the input is
+/// convolved in; the output lags the input by one, under 3 ms at 48 kHz.
+const PARTITION: usize = 128;
+/// Longest impulse response that's used, plenty for a cabinet or a room and short enough to run
+/// in real time; anything past it is cut off.
+const MAX_SECONDS: f32 = 4.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ConvolutionConfig {
+    /// The impulse response, a WAV or any other file the player can open. A mono one is used on
+    /// every channel; otherwise each channel has its own.
+    pub file: PathBuf,
+    /// How much of the output is the convolved signal rather than the input, from 0 to 1.
+    pub mix: f32,
+    /// Level of the convolved signal, after it's been normalised.
+    pub gain_db: f32,
+    /// Whether the impulse response is scaled to pass as much energy as it takes in, so responses
+    /// recorded at different levels come out about as loud.
+    pub normalize: bool,
+}
+
+impl Default for ConvolutionConfig {
+    fn default() -> Self {
+        ConvolutionConfig {
+            file: PathBuf::new(),
+            mix: 1.0,
+            gain_db: 0.0,
+            normalize: true,
+        }
+    }
+}
+
+impl ConvolutionConfig {
+    /// A convolver per channel with that channel of the impulse response, converted to the
+    /// stream's rate. An impulse response that can't be loaded is reported and the input passed
+    /// through as it is.
+    pub fn build(&self, config: &StreamConfig) -> PerChannel {
+        let channels = config.channels as usize;
+        let mut response = match playback::decode_all(&self.file, config) {
+            Ok(samples) => samples,
+            Err(e) => {
+                eprintln!(
+                    "Cannot load impulse response {}: {}",
+                    self.file.display(),
+                    e
+                );
+                return PerChannel::new(Vec::new(), config);
+            }
+        };
+        response.truncate((MAX_SECONDS * config.sample_rate.0 as f32) as usize * channels);
+        let mut gain = db_to_gain(self.gain_db);
+        if self.normalize {
+            let energy = response.iter().map(|s| s * s).sum::<f32>() / channels as f32;
+            if energy > 0.0 {
+                gain /= energy.sqrt();
+            }
+        }
+        let mix = self.mix.clamp(0.0, 1.0);
+        let chains = (0..channels)
+            .map(|channel| {
+                let response: Vec<f32> = response
+                    .iter()
+                    .skip(channel)
+                    .step_by(channels)
+                    .map(|s| s * gain)
+                    .collect();
+                let convolver: Box<dyn Processor> = Box::new(Convolver::new(&response, mix));
+                vec![convolver]
+            })
+            .collect();
+        PerChannel::new(chains, config)
+    }
+}
+
+/// Convolves a mono stream with an impulse response, by uniformly partitioned overlap-save: the
+/// response is cut into blocks of [`PARTITION`] samples, each held as a spectrum, and every new
+/// block of input is multiplied with the first of them, the block before it with the second, and
+/// so on, so a long response costs no more latency than a short one.
+struct Convolver {
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    /// Spectra of the response's partitions, each zero-padded to twice its length; only the bins
+    /// up to Nyquist, since the rest mirror them.
+    partitions: Vec<Vec<Complex<f32>>>,
+    /// Spectra of the latest input blocks, as many as there are partitions; `newest` is the last
+    /// one written.
+    history: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    mix: f32,
+    /// The last block of input, then the one being filled.
+    input: Vec<f32>,
+    /// The convolved block being played out while the next one fills.
+    output: Vec<f32>,
+    /// Where the next sample goes in the block being filled.
+    rover: usize,
+    spectrum: Vec<Complex<f32>>,
+    sum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Convolver {
+    fn new(response: &[f32], mix: f32) -> Convolver {
+        let size = 2 * PARTITION;
+        let bins = PARTITION + 1;
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+        let scratch_len = forward
+            .get_inplace_scratch_len()
+            .max(inverse.get_inplace_scratch_len());
+        let mut scratch = vec![Complex::default(); scratch_len];
+        let partitions: Vec<Vec<Complex<f32>>> = response
+            .chunks(PARTITION)
+            .map(|chunk| {
+                let mut spectrum = vec![Complex::default(); size];
+                for (bin, sample) in spectrum.iter_mut().zip(chunk) {
+                    *bin = Complex::new(*sample, 0.0);
+                }
+                forward.process_with_scratch(&mut spectrum, &mut scratch);
+                spectrum.truncate(bins);
+                spectrum
+            })
+            .collect();
+        Convolver {
+            history: vec![vec![Complex::default(); bins]; partitions.len().max(1)],
+            partitions,
+            newest: 0,
+            forward,
+            inverse,
+            mix,
+            input: vec![0.0; size],
+            output: vec![0.0; PARTITION],
+            rover: 0,
+            spectrum: vec![Complex::default(); size],
+            sum: vec![Complex::default(); bins],
+            scratch,
+        }
+    }
+
+    fn block(&mut self) {
+        let size = 2 * PARTITION;
+        for (bin, sample) in self.spectrum.iter_mut().zip(&self.input) {
+            *bin = Complex::new(*sample, 0.0);
+        }
+        self.forward
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        self.newest = (self.newest + 1) % self.history.len();
+        self.history[self.newest].copy_from_slice(&self.spectrum[..=PARTITION]);
+
+        self.sum.fill(Complex::default());
+        for (age, partition) in self.partitions.iter().enumerate() {
+            let block =
+                &self.history[(self.newest + self.history.len() - age) % self.history.len()];
+            for ((sum, x), h) in self.sum.iter_mut().zip(block).zip(partition) {
+                *sum += x * h;
+            }
+        }
+        self.spectrum[..=PARTITION].copy_from_slice(&self.sum);
+        for k in 1..PARTITION {
+            self.spectrum[size - k] = self.sum[k].conj();
+        }
+        self.inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        // The first half wraps around the circular convolution; the second is the new block.
+        for (out, bin) in self.output.iter_mut().zip(&self.spectrum[PARTITION..]) {
+            *out = bin.re / size as f32;
+        }
+        self.input.copy_within(PARTITION.., 0);
+    }
+}
+
+impl Processor for Convolver {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            // The input from a block ago, to line up with the convolved output.
+            let dry = self.input[self.rover];
+            self.input[PARTITION + self.rover] = *sample;
+            *sample = dry + (self.output[self.rover] - dry) * self.mix;
+            self.rover += 1;
+            if self.rover == PARTITION {
+                self.rover = 0;
+                self.block();
+            }
+        }
+    }
+
+    fn latency(&self) -> usize {
+        PARTITION
+    }
+
+    fn heavy(&self) -> bool {
+        true
+    }
+}
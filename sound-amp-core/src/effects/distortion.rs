@@ -0,0 +1,152 @@
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::{db_to_gain, Biquad, BUTTERWORTH_STAGE_Q};
+
+/// How many times faster than the stream the clipping runs, so the harmonics it makes above
+/// Nyquist are filtered out rather than folding back down as inharmonic fizz.
+const OVERSAMPLE: usize = 4;
+/// Below this the input is thinned out before it's driven, so low notes don't turn to mud.
+const TIGHTEN_HZ: f32 = 100.0;
+/// Above this the output rolls off like a guitar speaker's, taking the edge off the fizz.
+const CABINET_HZ: f32 = 5000.0;
+/// Where the tone controls' shelves and the middle of their mid band sit.
+const BASS_HZ: f32 = 120.0;
+const MID_HZ: f32 = 700.0;
+const MID_Q: f32 = 0.7;
+const TREBLE_HZ: f32 = 3000.0;
+/// Offset the overdrive's curve is shifted by, which clips one side of the wave sooner than the
+/// other and adds the even harmonics of a valve amp.
+const BIAS: f32 = 0.2;
+/// The clipping is uneven and leaves a DC offset, taken out below this.
+const DC_HZ: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistortionMode {
+    /// Rounds the peaks off gently and unevenly, like a valve amp pushed hard.
+    #[default]
+    Overdrive,
+    /// Squares the peaks off, like a fuzz or distortion pedal.
+    Distortion,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DistortionConfig {
+    pub mode: DistortionMode,
+    /// How hard the input is pushed into the clipping.
+    pub drive_db: f32,
+    /// The tone controls, boosting or cutting the low shelf, the mids and the high shelf.
+    pub bass_db: f32,
+    pub mid_db: f32,
+    pub treble_db: f32,
+    /// Level of the output, which is about as loud whatever the drive.
+    pub level_db: f32,
+}
+
+impl Default for DistortionConfig {
+    fn default() -> Self {
+        DistortionConfig {
+            mode: DistortionMode::default(),
+            drive_db: 24.0,
+            bass_db: 0.0,
+            mid_db: 0.0,
+            treble_db: 0.0,
+            level_db: -6.0,
+        }
+    }
+}
+
+/// A practice amp: the input thinned out in the lows and driven into clipping, then the cabinet's
+/// roll-off and the tone controls.
+pub struct Distortion {
+    mode: DistortionMode,
+    drive: f32,
+    level: f32,
+    channels: usize,
+    tighten: Biquad,
+    /// Smooth the stream stuffed with zeros up to the faster rate, and the clipped stream before
+    /// it's brought back down.
+    upsample: [Biquad; 2],
+    downsample: [Biquad; 2],
+    tone: Vec<Biquad>,
+    oversampled: Vec<f32>,
+}
+
+impl Distortion {
+    pub fn new(config: &DistortionConfig, stream: &StreamConfig) -> Distortion {
+        let rate = stream.sample_rate.0 as f32;
+        let fast = StreamConfig {
+            sample_rate: cpal::SampleRate(stream.sample_rate.0 * OVERSAMPLE as u32),
+            ..stream.clone()
+        };
+        let anti_alias = || BUTTERWORTH_STAGE_Q.map(|q| Biquad::low_pass(rate * 0.45, q, &fast));
+        let butterworth = 1.0 / 2f32.sqrt();
+        Distortion {
+            mode: config.mode,
+            drive: db_to_gain(config.drive_db),
+            level: db_to_gain(config.level_db),
+            channels: stream.channels as usize,
+            tighten: Biquad::high_pass(TIGHTEN_HZ, butterworth, stream),
+            upsample: anti_alias(),
+            downsample: anti_alias(),
+            tone: vec![
+                Biquad::high_pass(DC_HZ, butterworth, stream),
+                Biquad::low_pass(CABINET_HZ, butterworth, stream),
+                Biquad::low_shelf(BASS_HZ, config.bass_db, stream),
+                Biquad::peaking(MID_HZ, MID_Q, config.mid_db, stream),
+                Biquad::high_shelf(TREBLE_HZ, config.treble_db, stream),
+            ],
+            oversampled: Vec::new(),
+        }
+    }
+}
+
+impl DistortionMode {
+    fn clip(self, x: f32) -> f32 {
+        match self {
+            DistortionMode::Overdrive => (x + BIAS).tanh() - BIAS.tanh(),
+            // A curve that's flat past about ±1 but still has no corner to alias from.
+            DistortionMode::Distortion => x / (1.0 + x.powi(8)).powf(1.0 / 8.0),
+        }
+    }
+}
+
+impl Processor for Distortion {
+    fn process(&mut self, samples: &mut [f32]) {
+        self.tighten.process(samples);
+        let channels = self.channels;
+        // Each frame followed by zeroed ones, scaled up to keep the level once they're smoothed.
+        self.oversampled.clear();
+        for frame in samples.chunks(channels) {
+            self.oversampled
+                .extend(frame.iter().map(|s| s * self.drive * OVERSAMPLE as f32));
+            self.oversampled
+                .extend(std::iter::repeat_n(0.0, channels * (OVERSAMPLE - 1)));
+        }
+        for filter in &mut self.upsample {
+            filter.process(&mut self.oversampled);
+        }
+        for sample in &mut self.oversampled {
+            *sample = self.mode.clip(*sample);
+        }
+        for filter in &mut self.downsample {
+            filter.process(&mut self.oversampled);
+        }
+        for (frame, fast) in samples
+            .chunks_mut(channels)
+            .zip(self.oversampled.chunks(channels * OVERSAMPLE))
+        {
+            frame.copy_from_slice(&fast[..channels]);
+        }
+        for filter in &mut self.tone {
+            filter.process(samples);
+        }
+        for sample in samples {
+            *sample *= self.level;
+        }
+    }
+}
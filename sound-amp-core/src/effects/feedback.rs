@@ -0,0 +1,207 @@
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+use cpal::StreamConfig;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::{gain_to_db, Biquad};
+
+/// Samples in each analysis frame; about 43 ms at 48 kHz, with bins narrow enough to tell a howl
+/// from the harmonics around it.
+const FRAME_SIZE: usize = 2048;
+const HOP: usize = FRAME_SIZE / 2;
+/// Frames in a row a peak has to keep growing over before it counts as feedback.
+const GROWTH_FRAMES: usize = 8;
+/// Bins either side of a peak it's compared against.
+const NEIGHBOURS: usize = 8;
+/// Quietest a peak can be and still be taken for feedback, in dBFS.
+const FLOOR_DB: f32 = -60.0;
+/// Quality of each notch; about a fifth of a semitone wide, so speech around it is left alone.
+const NOTCH_Q: f32 = 30.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FeedbackConfig {
+    /// How far above the bins around it a peak stands when it's a single ringing tone.
+    pub prominence_db: f32,
+    /// How much a peak has to grow over the last few frames, about a sixth of a second, to be a
+    /// howl building up rather than a held note.
+    pub growth_db: f32,
+    /// How far each notch cuts.
+    pub depth_db: f32,
+    /// Most notches in at once; a new howl takes over the oldest.
+    pub notches: usize,
+    /// How long a notch stays in after the howl it was put in for.
+    pub hold_s: f32,
+}
+
+impl Default for FeedbackConfig {
+    fn default() -> Self {
+        FeedbackConfig {
+            prominence_db: 15.0,
+            growth_db: 3.0,
+            depth_db: 18.0,
+            notches: 6,
+            hold_s: 10.0,
+        }
+    }
+}
+
+/// Puts temporary notches on the tones that start to howl when a microphone hears its own
+/// speaker: narrow peaks that keep getting louder.
+pub struct FeedbackSuppressor {
+    config: FeedbackConfig,
+    stream: StreamConfig,
+    channels: usize,
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// The last frame of input, mixed down to mono.
+    input: Vec<f32>,
+    filled: usize,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// Level of every bin over the last few frames, oldest first.
+    history: Vec<Vec<f32>>,
+    notches: Vec<Notch>,
+    /// Frames processed so far, to time the notches by.
+    frame: u64,
+}
+
+struct Notch {
+    frequency: f32,
+    filter: Biquad,
+    /// Frame the notch comes out at.
+    expires: u64,
+}
+
+impl FeedbackConfig {
+    pub fn build(&self, config: &StreamConfig) -> FeedbackSuppressor {
+        let fft = FftPlanner::new().plan_fft_forward(FRAME_SIZE);
+        let scratch = vec![Complex::default(); fft.get_inplace_scratch_len()];
+        FeedbackSuppressor {
+            config: self.clone(),
+            stream: config.clone(),
+            channels: config.channels as usize,
+            fft,
+            window: (0..FRAME_SIZE)
+                .map(|i| 0.5 - 0.5 * (TAU * i as f32 / FRAME_SIZE as f32).cos())
+                .collect(),
+            input: vec![0.0; FRAME_SIZE],
+            filled: FRAME_SIZE - HOP,
+            spectrum: vec![Complex::default(); FRAME_SIZE],
+            scratch,
+            history: vec![vec![FLOOR_DB; FRAME_SIZE / 2]; GROWTH_FRAMES + 1],
+            notches: Vec::new(),
+            frame: 0,
+        }
+    }
+}
+
+impl FeedbackSuppressor {
+    fn analyse(&mut self) {
+        self.frame += 1;
+        for ((bin, sample), window) in self.spectrum.iter_mut().zip(&self.input).zip(&self.window) {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.fft
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        // A full-scale sine comes out at 0 dB.
+        let scale = 4.0 / FRAME_SIZE as f32;
+        self.history.rotate_left(1);
+        let levels = self.history.last_mut().expect("there's a frame of history");
+        for (level, bin) in levels.iter_mut().zip(&self.spectrum) {
+            *level = gain_to_db(bin.norm() * scale);
+        }
+        let rate = self.stream.sample_rate.0 as f32;
+        let howls: Vec<f32> = (NEIGHBOURS..FRAME_SIZE / 2 - NEIGHBOURS)
+            .filter(|&k| self.is_howling(k))
+            .map(|k| {
+                // Where the peak really is, between its bin and the neighbours, by fitting a parabola.
+                let levels = &self.history[GROWTH_FRAMES];
+                let (left, centre, right) = (levels[k - 1], levels[k], levels[k + 1]);
+                let curvature = left - 2.0 * centre + right;
+                let offset = if curvature < 0.0 {
+                    (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+                } else {
+                    0.0
+                };
+                (k as f32 + offset) * rate / FRAME_SIZE as f32
+            })
+            .collect();
+        let hold = (self.config.hold_s * rate / HOP as f32) as u64;
+        for frequency in howls {
+            self.notch(frequency, self.frame + hold);
+        }
+        let frame = self.frame;
+        self.notches.retain(|notch| notch.expires > frame);
+    }
+
+    /// Whether bin `k` has been a narrow peak that grew every frame lately, by enough in all.
+    fn is_howling(&self, k: usize) -> bool {
+        let level = self.history[GROWTH_FRAMES][k];
+        // A peak in noise stands out for a frame or two; a howl does all along.
+        let prominent = self.history.iter().all(|levels| {
+            let around = levels[k - NEIGHBOURS..k - 1]
+                .iter()
+                .chain(&levels[k + 2..=k + NEIGHBOURS])
+                .sum::<f32>()
+                / (2 * NEIGHBOURS - 2) as f32;
+            levels[k] >= levels[k - 1]
+                && levels[k] >= levels[k + 1]
+                && levels[k] - around >= self.config.prominence_db
+        });
+        let growing = self.history.windows(2).all(|pair| pair[1][k] > pair[0][k]);
+        level >= FLOOR_DB
+            && prominent
+            && growing
+            && level - self.history[0][k] >= self.config.growth_db
+    }
+
+    /// Notches `frequency` until frame `expires`, keeping a notch already close to it rather than
+    /// starting another.
+    fn notch(&mut self, frequency: f32, expires: u64) {
+        let close = frequency / NOTCH_Q;
+        if let Some(notch) = self
+            .notches
+            .iter_mut()
+            .find(|notch| (notch.frequency - frequency).abs() < close)
+        {
+            notch.expires = expires;
+            return;
+        }
+        if self.notches.len() >= self.config.notches.max(1) {
+            self.notches.remove(0);
+        }
+        self.notches.push(Notch {
+            frequency,
+            filter: Biquad::peaking(
+                frequency,
+                NOTCH_Q,
+                -self.config.depth_db.abs(),
+                &self.stream,
+            ),
+            expires,
+        });
+    }
+}
+
+impl Processor for FeedbackSuppressor {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks(self.channels) {
+            self.input[self.filled] = frame.iter().sum::<f32>() / self.channels as f32;
+            self.filled += 1;
+            if self.filled == FRAME_SIZE {
+                self.analyse();
+                self.input.copy_within(HOP.., 0);
+                self.filled = FRAME_SIZE - HOP;
+            }
+        }
+        for notch in &mut self.notches {
+            notch.filter.process(samples);
+        }
+    }
+}
@@ -0,0 +1,83 @@
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::{Biquad, BUTTERWORTH_STAGE_Q};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KaraokeConfig {
+    /// The centre is only taken out above this, so the bass and kick drum, mixed in the middle
+    /// too, stay.
+    pub low_cut: f32,
+    /// How much of the centre is taken out, from 0 to 1.
+    pub depth: f32,
+}
+
+impl Default for KaraokeConfig {
+    fn default() -> Self {
+        KaraokeConfig {
+            low_cut: 150.0,
+            depth: 1.0,
+        }
+    }
+}
+
+/// Takes out what's the same in the left and right channels above the low cut, which in most
+/// mixes is the lead vocal. It needs the first two channels of a stereo stream and leaves a mono
+/// one, or a channel run through a chain of its own, as it is.
+pub struct Karaoke {
+    depth: f32,
+    channels: usize,
+    low_pass: [Biquad; 2],
+    mid: Vec<f32>,
+    low: Vec<f32>,
+}
+
+impl Karaoke {
+    pub fn new(config: &KaraokeConfig, stream: &StreamConfig) -> Karaoke {
+        let mono = StreamConfig {
+            channels: 1,
+            ..stream.clone()
+        };
+        Karaoke {
+            depth: config.depth.clamp(0.0, 1.0),
+            channels: stream.channels as usize,
+            low_pass: BUTTERWORTH_STAGE_Q.map(|q| Biquad::low_pass(config.low_cut, q, &mono)),
+            mid: Vec::new(),
+            low: Vec::new(),
+        }
+    }
+}
+
+impl Processor for Karaoke {
+    fn process(&mut self, samples: &mut [f32]) {
+        if self.channels < 2 {
+            return;
+        }
+        self.mid.clear();
+        self.mid.extend(
+            samples
+                .chunks(self.channels)
+                .map(|frame| (frame[0] + frame[1]) / 2.0),
+        );
+        self.low.clear();
+        self.low.extend_from_slice(&self.mid);
+        for filter in &mut self.low_pass {
+            filter.process(&mut self.low);
+        }
+        // The centre's low band and what's left of the rest go back on both sides; the rest minus
+        // the low band is exactly the high band, so nothing is lost at a depth of 0.
+        for ((frame, mid), low) in samples
+            .chunks_mut(self.channels)
+            .zip(&self.mid)
+            .zip(&self.low)
+        {
+            let side = (frame[0] - frame[1]) / 2.0;
+            let centre = low + (mid - low) * (1.0 - self.depth);
+            frame[0] = centre + side;
+            frame[1] = centre - side;
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::vocoder::{Bins, PhaseVocoder};
+use super::{db_to_gain, PerChannel};
+
+/// Samples in each analysis frame; about 21 ms at 48 kHz, fine enough to keep speech harmonics apart.
+const FRAME_SIZE: usize = 1024;
+const OVERLAP: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoweringMode {
+    /// Copies the band above the cutoff down by `ratio`, mixed in with what's already there.
+    #[default]
+    Transposition,
+    /// Squeezes everything above the cutoff into a narrower band just above it.
+    Compression,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LoweringConfig {
+    pub mode: LoweringMode,
+    /// Where lowering starts; everything below it is left as it is.
+    pub cutoff: f32,
+    /// For transposition, what frequencies above the cutoff are divided by (2 moves them an octave
+    /// down); for compression, how many Hz above the cutoff end up in each Hz of the new band.
+    pub ratio: f32,
+    /// Level of the transposed copy.
+    pub gain_db: f32,
+}
+
+impl Default for LoweringConfig {
+    fn default() -> Self {
+        LoweringConfig {
+            mode: LoweringMode::default(),
+            cutoff: 4000.0,
+            ratio: 2.0,
+            gain_db: 0.0,
+        }
+    }
+}
+
+impl LoweringConfig {
+    /// Moves high-frequency content, like the consonants of speech, down to where someone with
+    /// a high-frequency loss can hear it.
+    pub fn build(&self, config: &StreamConfig) -> PerChannel {
+        let rate = config.sample_rate.0 as f32;
+        let chains = (0..config.channels)
+            .map(|_| {
+                let LoweringConfig {
+                    mode,
+                    cutoff,
+                    ratio,
+                    gain_db,
+                } = *self;
+                let ratio = ratio.max(1.0);
+                let gain = db_to_gain(gain_db);
+                let remap = move |analysis: &Bins, synthesis: &mut Bins| {
+                    for k in 0..analysis.len() {
+                        let centre = analysis.centre(k);
+                        match mode {
+                            _ if centre <= cutoff => synthesis.shift(analysis, k, 1.0, 1.0),
+                            LoweringMode::Transposition => {
+                                synthesis.shift(analysis, k, 1.0, 1.0);
+                                synthesis.shift(analysis, k, 1.0 / ratio, gain);
+                            }
+                            LoweringMode::Compression => {
+                                let lowered = cutoff + (centre - cutoff) / ratio;
+                                synthesis.shift(analysis, k, lowered / centre, 1.0);
+                            }
+                        }
+                    }
+                };
+                let vocoder = PhaseVocoder::new(FRAME_SIZE, OVERLAP, rate, remap);
+                vec![Box::new(vocoder) as Box<dyn Processor>]
+            })
+            .collect();
+        PerChannel::new(chains, config)
+    }
+}
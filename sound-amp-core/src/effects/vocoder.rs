@@ -0,0 +1,191 @@
+use std::f32::consts::TAU;
+use std::sync::Arc;
+
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+
+use crate::chain::Processor;
+
+/// The bins of one frame: how strong each is, and the frequency in Hz it was measured at.
+pub struct Bins {
+    pub magnitude: Vec<f32>,
+    pub frequency: Vec<f32>,
+    bin_width: f32,
+}
+
+impl Bins {
+    fn new(len: usize, bin_width: f32) -> Bins {
+        let mut bins = Bins {
+            magnitude: vec![0.0; len],
+            frequency: vec![0.0; len],
+            bin_width,
+        };
+        bins.clear();
+        bins
+    }
+
+    /// Silences every bin, leaving each at its centre frequency.
+    fn clear(&mut self) {
+        self.magnitude.fill(0.0);
+        for (k, frequency) in self.frequency.iter_mut().enumerate() {
+            *frequency = k as f32 * self.bin_width;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.magnitude.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.magnitude.is_empty()
+    }
+
+    /// The frequency bin `k` is centred on.
+    pub fn centre(&self, k: usize) -> f32 {
+        k as f32 * self.bin_width
+    }
+
+    /// Adds bin `k` of `analysis` with its frequency multiplied by `factor` and its level by
+    /// `gain`; moved past Nyquist, it's dropped. Where several land on one bin, it rings at the
+    /// frequency of the strongest, so leakage can't pull a loud partial out of tune.
+    pub fn shift(&mut self, analysis: &Bins, k: usize, factor: f32, gain: f32) {
+        let bin = (k as f32 * factor).round() as usize;
+        if bin < self.len() {
+            let magnitude = analysis.magnitude[k] * gain;
+            if magnitude > self.magnitude[bin] {
+                self.frequency[bin] = analysis.frequency[k] * factor;
+            }
+            self.magnitude[bin] += magnitude;
+        }
+    }
+}
+
+/// Moves the partials of a mono stream around in frequency, frame by overlapping frame, while
+/// keeping each one's phase running smoothly. `remap` gets every frame's analysis and fills in
+/// what's resynthesised; the output lags the input by a frame.
+pub struct PhaseVocoder<F> {
+    size: usize,
+    hop: usize,
+    forward: Arc<dyn Fft<f32>>,
+    inverse: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    input: Vec<f32>,
+    output: Vec<f32>,
+    accumulator: Vec<f32>,
+    /// Where the next sample goes in `input`.
+    rover: usize,
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    last_phase: Vec<f32>,
+    phase_sum: Vec<f32>,
+    analysis: Bins,
+    synthesis: Bins,
+    remap: F,
+}
+
+impl<F: FnMut(&Bins, &mut Bins)> PhaseVocoder<F> {
+    /// Frames of `size` samples, `overlap` of them covering each sample, at `rate` Hz.
+    pub fn new(size: usize, overlap: usize, rate: f32, remap: F) -> PhaseVocoder<F> {
+        let mut planner = FftPlanner::new();
+        let forward = planner.plan_fft_forward(size);
+        let inverse = planner.plan_fft_inverse(size);
+        let scratch_len = forward
+            .get_inplace_scratch_len()
+            .max(inverse.get_inplace_scratch_len());
+        let bins = size / 2 + 1;
+        let bin_width = rate / size as f32;
+        let hop = size / overlap;
+        PhaseVocoder {
+            size,
+            hop,
+            forward,
+            inverse,
+            window: (0..size)
+                .map(|i| 0.5 - 0.5 * (TAU * i as f32 / size as f32).cos())
+                .collect(),
+            input: vec![0.0; size],
+            output: vec![0.0; size],
+            accumulator: vec![0.0; size],
+            rover: size - hop,
+            spectrum: vec![Complex::default(); size],
+            scratch: vec![Complex::default(); scratch_len],
+            last_phase: vec![0.0; bins],
+            phase_sum: vec![0.0; bins],
+            analysis: Bins::new(bins, bin_width),
+            synthesis: Bins::new(bins, bin_width),
+            remap,
+        }
+    }
+
+    fn frame(&mut self) {
+        let size = self.size;
+        let bins = size / 2 + 1;
+        let bin_width = self.analysis.bin_width;
+        let overlap = (size / self.hop) as f32;
+        // How far the phase of a partial right on bin k turns in a hop.
+        let expected = TAU * self.hop as f32 / size as f32;
+        for ((bin, sample), window) in self.spectrum.iter_mut().zip(&self.input).zip(&self.window) {
+            *bin = Complex::new(sample * window, 0.0);
+        }
+        self.forward
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        for k in 0..bins {
+            let (magnitude, phase) = self.spectrum[k].to_polar();
+            let mut delta = phase - self.last_phase[k] - k as f32 * expected;
+            self.last_phase[k] = phase;
+            delta -= TAU * (delta / TAU).round();
+            self.analysis.magnitude[k] = magnitude;
+            self.analysis.frequency[k] = (k as f32 + delta * overlap / TAU) * bin_width;
+        }
+        self.synthesis.clear();
+        (self.remap)(&self.analysis, &mut self.synthesis);
+        for k in 0..bins {
+            let deviation = self.synthesis.frequency[k] / bin_width - k as f32;
+            self.phase_sum[k] =
+                (self.phase_sum[k] + deviation * TAU / overlap + k as f32 * expected) % TAU;
+            self.spectrum[k] = Complex::from_polar(self.synthesis.magnitude[k], self.phase_sum[k]);
+        }
+        for k in bins..size {
+            self.spectrum[k] = self.spectrum[size - k].conj();
+        }
+        self.inverse
+            .process_with_scratch(&mut self.spectrum, &mut self.scratch);
+        // Undoes the unscaled transforms and the overlap of the squared Hann windows.
+        let scale = 8.0 / (3.0 * size as f32 * overlap);
+        for ((sum, bin), window) in self
+            .accumulator
+            .iter_mut()
+            .zip(&self.spectrum)
+            .zip(&self.window)
+        {
+            *sum += bin.re * window * scale;
+        }
+        self.output[..self.hop].copy_from_slice(&self.accumulator[..self.hop]);
+        self.accumulator.copy_within(self.hop.., 0);
+        self.accumulator[size - self.hop..].fill(0.0);
+        self.input.copy_within(self.hop.., 0);
+    }
+}
+
+impl<F: FnMut(&Bins, &mut Bins) + Send> Processor for PhaseVocoder<F> {
+    fn process(&mut self, samples: &mut [f32]) {
+        let latency = self.size - self.hop;
+        for sample in samples {
+            self.input[self.rover] = *sample;
+            *sample = self.output[self.rover - latency];
+            self.rover += 1;
+            if self.rover == self.size {
+                self.rover = latency;
+                self.frame();
+            }
+        }
+    }
+
+    fn latency(&self) -> usize {
+        self.size - self.hop
+    }
+
+    fn heavy(&self) -> bool {
+        true
+    }
+}
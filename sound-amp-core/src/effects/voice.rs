@@ -0,0 +1,157 @@
+use std::f32::consts::TAU;
+
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::vocoder::{Bins, PhaseVocoder};
+use super::PerChannel;
+
+/// Samples in each analysis frame; about 21 ms at 48 kHz, fine enough to keep voice harmonics
+/// apart.
+const FRAME_SIZE: usize = 1024;
+const OVERLAP: usize = 4;
+/// Width of the band the spectral envelope is averaged over, wider than the spacing of a voice's
+/// harmonics so it follows the formants rather than them.
+const ENVELOPE_HZ: f32 = 400.0;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VoiceChangerConfig {
+    /// How far the pitch moves, in semitones.
+    pub pitch_semitones: f32,
+    /// How far the formants move, in semitones; moved with the pitch, they make a bigger or smaller
+    /// speaker rather than the same one singing higher or lower.
+    pub formant_semitones: f32,
+    /// Frequency the voice is ring-modulated with afterwards, for a robot; 0 for none.
+    pub ring_mod_hz: f32,
+}
+
+impl VoiceChangerConfig {
+    /// Each channel on its own: the pitch and formants moved by a phase vocoder, which lags by a
+    /// frame, then the ring modulator.
+    pub fn build(&self, config: &StreamConfig) -> PerChannel {
+        let rate = config.sample_rate.0 as f32;
+        let mono = StreamConfig {
+            channels: 1,
+            ..config.clone()
+        };
+        let chains = (0..config.channels)
+            .map(|_| {
+                let mut chain: Vec<Box<dyn Processor>> = Vec::new();
+                if self.pitch_semitones != 0.0 || self.formant_semitones != 0.0 {
+                    chain.push(Box::new(shifter(
+                        semitones(self.pitch_semitones),
+                        semitones(self.formant_semitones),
+                        rate,
+                    )));
+                }
+                if self.ring_mod_hz > 0.0 {
+                    chain.push(Box::new(RingModulator::new(self.ring_mod_hz, &mono)));
+                }
+                chain
+            })
+            .collect();
+        PerChannel::new(chains, config)
+    }
+}
+
+/// The voice changer's ready-made voices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VoicePreset {
+    Deep,
+    Chipmunk,
+    Robot,
+}
+
+impl VoicePreset {
+    pub const ALL: [VoicePreset; 3] =
+        [VoicePreset::Deep, VoicePreset::Chipmunk, VoicePreset::Robot];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VoicePreset::Deep => "deep",
+            VoicePreset::Chipmunk => "chipmunk",
+            VoicePreset::Robot => "robot",
+        }
+    }
+
+    pub fn config(self) -> VoiceChangerConfig {
+        match self {
+            VoicePreset::Deep => VoiceChangerConfig {
+                pitch_semitones: -5.0,
+                formant_semitones: -3.0,
+                ring_mod_hz: 0.0,
+            },
+            VoicePreset::Chipmunk => VoiceChangerConfig {
+                pitch_semitones: 7.0,
+                formant_semitones: 5.0,
+                ring_mod_hz: 0.0,
+            },
+            VoicePreset::Robot => VoiceChangerConfig {
+                pitch_semitones: 0.0,
+                formant_semitones: 0.0,
+                ring_mod_hz: 60.0,
+            },
+        }
+    }
+}
+
+fn semitones(semitones: f32) -> f32 {
+    2f32.powf(semitones / 12.0)
+}
+
+/// Moves every partial by `pitch` while keeping the spectral envelope where it was, then moves the
+/// envelope by `formant`: each bin is divided by the envelope where it came from and multiplied by
+/// the moved envelope where it lands.
+fn shifter(
+    pitch: f32,
+    formant: f32,
+    rate: f32,
+) -> PhaseVocoder<impl FnMut(&Bins, &mut Bins) + Send> {
+    let bin_width = rate / FRAME_SIZE as f32;
+    let half_width = ((ENVELOPE_HZ / bin_width / 2.0) as usize).max(1);
+    let mut envelope = Vec::new();
+    let remap = move |analysis: &Bins, synthesis: &mut Bins| {
+        let len = analysis.len();
+        envelope.clear();
+        envelope.extend((0..len).map(|k| {
+            let band =
+                &analysis.magnitude[k.saturating_sub(half_width)..(k + half_width + 1).min(len)];
+            band.iter().sum::<f32>() / band.len() as f32 + 1e-9
+        }));
+        for k in 0..len {
+            let source = (k as f32 * pitch / formant).round() as usize;
+            let moved = envelope.get(source).copied().unwrap_or(0.0);
+            synthesis.shift(analysis, k, pitch, moved / envelope[k]);
+        }
+    };
+    PhaseVocoder::new(FRAME_SIZE, OVERLAP, rate, remap)
+}
+
+/// Multiplies a mono stream by a sine, which turns every partial into a pair around it and makes
+/// a voice metallic.
+struct RingModulator {
+    step: f32,
+    phase: f32,
+}
+
+impl RingModulator {
+    fn new(frequency: f32, config: &StreamConfig) -> RingModulator {
+        RingModulator {
+            step: TAU * frequency / config.sample_rate.0 as f32,
+            phase: 0.0,
+        }
+    }
+}
+
+impl Processor for RingModulator {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample *= self.phase.sin();
+            self.phase = (self.phase + self.step) % TAU;
+        }
+    }
+}
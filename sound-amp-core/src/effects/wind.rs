@@ -0,0 +1,115 @@
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+
+use super::{coefficient, db_to_gain, Biquad, BUTTERWORTH_STAGE_Q};
+
+/// Top of the band the detector listens to; wind and plosives have most of their energy below it,
+/// speech very little.
+const DETECT_HZ: f32 = 100.0;
+/// How fast the high-pass comes in once a burst starts, so the first thump is already cut.
+const ATTACK_MS: f32 = 2.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindConfig {
+    /// Where the high-pass cuts in while a burst lasts, rolling off at 24 dB an octave below it.
+    pub cutoff: f32,
+    /// Level the low band has to reach to be taken for a burst rather than room rumble.
+    pub threshold_db: f32,
+    /// How much of the signal's peak the low band has to carry; voices have little down there.
+    pub dominance: f32,
+    /// How long the high-pass takes to fade back out after a burst.
+    pub release_ms: f32,
+}
+
+impl Default for WindConfig {
+    fn default() -> Self {
+        WindConfig {
+            cutoff: 150.0,
+            threshold_db: -30.0,
+            dominance: 0.5,
+            release_ms: 150.0,
+        }
+    }
+}
+
+/// Brings in a steep high-pass for as long as a burst of low-frequency energy, like wind buffeting
+/// the microphone or a plosive hitting it, stands out from the rest of the signal.
+pub struct WindFilter {
+    threshold: f32,
+    dominance: f32,
+    attack: f32,
+    release: f32,
+    channels: usize,
+    high_pass: [Biquad; 2],
+    detector: Biquad,
+    low_envelope: f32,
+    envelope: f32,
+    /// How far in the high-pass is, from 0 to 1.
+    mix: f32,
+    filtered: Vec<f32>,
+    low: Vec<f32>,
+}
+
+impl WindFilter {
+    pub fn new(config: &WindConfig, stream: &StreamConfig) -> WindFilter {
+        let rate = stream.sample_rate.0 as f32;
+        let mono = StreamConfig {
+            channels: 1,
+            ..stream.clone()
+        };
+        WindFilter {
+            threshold: db_to_gain(config.threshold_db),
+            dominance: config.dominance,
+            attack: coefficient(ATTACK_MS, rate),
+            release: coefficient(config.release_ms, rate),
+            channels: stream.channels as usize,
+            high_pass: BUTTERWORTH_STAGE_Q.map(|q| Biquad::high_pass(config.cutoff, q, stream)),
+            detector: Biquad::low_pass(DETECT_HZ, 1.0 / 2f32.sqrt(), &mono),
+            low_envelope: 0.0,
+            envelope: 0.0,
+            mix: 0.0,
+            filtered: Vec::new(),
+            low: Vec::new(),
+        }
+    }
+}
+
+impl Processor for WindFilter {
+    fn process(&mut self, samples: &mut [f32]) {
+        // Both paths run all the time, so the filters are settled whenever the high-pass comes in.
+        self.filtered.clear();
+        self.filtered.extend_from_slice(samples);
+        for stage in &mut self.high_pass {
+            stage.process(&mut self.filtered);
+        }
+        self.low.clear();
+        self.low.extend(
+            samples
+                .chunks(self.channels)
+                .map(|frame| frame.iter().sum::<f32>() / self.channels as f32),
+        );
+        self.detector.process(&mut self.low);
+        let frames = samples
+            .chunks_mut(self.channels)
+            .zip(self.filtered.chunks(self.channels));
+        for ((frame, filtered), low) in frames.zip(&self.low) {
+            let peak = frame.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+            self.envelope = peak.max(self.envelope * self.release);
+            self.low_envelope = low.abs().max(self.low_envelope * self.release);
+            let burst = self.low_envelope > self.threshold
+                && self.low_envelope > self.envelope * self.dominance;
+            let (target, coefficient) = if burst {
+                (1.0, self.attack)
+            } else {
+                (0.0, self.release)
+            };
+            self.mix = target + (self.mix - target) * coefficient;
+            for (sample, filtered) in frame.iter_mut().zip(filtered) {
+                *sample += (filtered - *sample) * self.mix;
+            }
+        }
+    }
+}
@@ -0,0 +1,2812 @@
+//! The player thread and the links it runs between an input and the output device.
+
+use std::any::Any;
+use std::iter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::{BufferSize, StreamConfig};
+use ringbuf::{Producer, RingBuffer};
+use serde::Serialize;
+
+#[cfg(windows)]
+use crate::app_capture::{AppCapture, AppCaptureReader};
+use crate::autogain::GainMeasurement;
+use crate::impulse::ImpulseCapture;
+use crate::loopback::LoopbackTest;
+use crate::backend::{AudioBackend, OpenOutput, StreamHandle, StreamLatency};
+use crate::chain::{self, EffectChain, Fade, Processor, SharedChain};
+use crate::config::{Profile, RecordingConfig};
+use crate::correlation::CorrelationMeter;
+#[cfg(feature = "network")]
+use crate::discovery::Peer;
+use crate::dose::DoseTracker;
+use crate::ducking::{self, Ducker, DuckingConfig, LiveLevel, SidechainConfig};
+use crate::effects::{self, EffectConfig, Karaoke, KaraokeConfig, VoicePreset};
+use crate::error::Error;
+use crate::graph::{Delay, Effect, Graph, Mix, RingSink, RingSource, Source};
+use crate::latency::LowLatencyConfig;
+use crate::meter::Meters;
+use crate::lock;
+use crate::looper::{Looper, LooperAction, LooperConfig, LooperState};
+use crate::loudness::{Loudness, LoudnessMeter};
+use crate::metronome::{self, Metronome, MetronomeBus, MetronomeConfig};
+#[cfg(feature = "network")]
+use crate::net::{NetworkInput, NetworkReader};
+use crate::outputs::{MonitorConfig, MonitorInput, OutputsConfig};
+use crate::pipe::{InputPipe, OutputPipe, PcmPipe, StdinReader};
+use crate::playback::{FilePlayer, LoopEdit, PlaybackBus, PlaybackState, PlayerConfig};
+use crate::recorder::{Recorder, RecordingTap, TapPoint, Track, TrackSync};
+use crate::remix::{Remix, RemixConfig};
+use crate::resampler::LinearResampler;
+use crate::replay::{ReplayBuffer, ReplayConfig};
+use crate::room::{RoomCorrection, RoomCorrectionConfig, RoomFilter};
+#[cfg(feature = "network")]
+use crate::rtp::{RtpInput, RtpReceiver};
+use crate::safety::{LoudnessAlertConfig, LoudnessGuard, SafetyLimiter, TruePeak};
+use crate::session::Session;
+use crate::sleep::{Sleep, SleepTimer};
+use crate::soundboard::{Soundboard, SoundboardBus, SoundboardConfig};
+use crate::spatial::Position;
+use crate::spectrogram::{Spectrogram, SpectrogramConfig, SpectrogramTap, SpectrumAnalysis};
+use crate::spl::{SplConfig, SplMeter};
+use crate::standby::{SilenceWatch, Standby, StandbyConfig};
+use crate::stats;
+use crate::talk::{PushToTalk, PushToTalkConfig, Vox, VoxConfig};
+#[cfg(feature = "transcribe")]
+use crate::transcribe::{Captions, Transcriber, TranscriptionConfig};
+use crate::tuner::{Tuner, TunerConfig};
+use crate::vad::VoiceDetector;
+use crate::virtual_devices::VirtualSinkFeed;
+use crate::workers::{WorkerPool, WorkersConfig};
+
+#[cfg(feature = "network")]
+use self::streams::Streams;
+use self::watchdog::Heartbeat;
+
+pub use self::state::{EngineState, Transition};
+#[cfg(feature = "network")]
+pub use self::streams::StreamSettings;
+
+mod state;
+#[cfg(feature = "network")]
+mod streams;
+mod watchdog;
+
+/// Samples the ring between the input and output callbacks holds.
+const RING_CAPACITY: usize = 48000;
+
+/// Where a link takes its input from.
+pub enum InputSource {
+    Device(usize),
+    Stdin(InputPipe),
+    /// A sender that connected to `--listen`.
+    #[cfg(feature = "network")]
+    Network(NetworkInput),
+    /// The socket bound for `--rtp-listen`.
+    #[cfg(feature = "network")]
+    Rtp(RtpInput),
+    /// One application's audio, rather than a device's.
+    #[cfg(windows)]
+    App(AppCapture),
+}
+
+/// What the player thread can be asked to do, sent down the channel [`setup_stream`] returns.
+pub enum PlayerCommand {
+    /// Links this input to the default output device, replacing the main link.
+    Start(InputSource),
+    /// Tears the main link down, and every link added alongside it.
+    Stop,
+    /// Links another input to the output alongside the main link, with a gain and effects of its
+    /// own, and selects it.
+    AddLink(InputSource),
+    /// Tears down link `i` of [`LinkStatus::links`]; the main link is 0, so that's a [`Stop`](Self::Stop).
+    RemoveLink(usize),
+    /// Points the volume and mute commands at link `i` of [`LinkStatus::links`].
+    SelectLink(usize),
+    /// Ducks link `link` of [`LinkStatus::links`] whenever link `key`'s input is loud, or stops
+    /// ducking it when `key` is `None`.
+    DuckLink { link: usize, key: Option<usize> },
+    /// Places the selected link's mono input around the listener, for headphones on a stereo
+    /// output, or puts it back in the middle; the main link's place carries over to the next one.
+    SetPosition(Option<Position>),
+    /// Pans the selected link's mono input between the left and right speakers, from -1 to 1.
+    SetPan(f32),
+    /// Volume and mute go to the selected link; the main link's carry over to the next one.
+    /// Volumes below 0 are taken as 0, and ones that aren't finite are ignored.
+    IncreaseVolume(f32),
+    SetVolume(f32),
+    /// The selected link's gain ahead of its effects; the volume comes after them.
+    SetTrim(f32),
+    /// Sets the gain in dB of the main link's `band`th EQ band, counting from 0 through the
+    /// profile's peaking and high-shelf effects in order; in both ears when they're unlinked.
+    SetEqGain { band: usize, gain_db: f32 },
+    /// Silences the input without losing the volume it comes back at.
+    SetMuted(bool),
+    ToggleMute,
+    /// Leaves the selected link's effects out, lined up in time with them, to compare the two.
+    SetBypassed(bool),
+    ToggleBypass,
+    ApplyProfile(Profile),
+    /// Runs both channels of the main link through one chain, or each through its own.
+    LinkChannels(bool),
+    ToggleLinkChannels,
+    /// Adds the [speech boost](crate::effects::speech_boost) after the main link's effects, or
+    /// takes it out.
+    SetSpeechBoost(bool),
+    ToggleSpeechBoost,
+    /// Takes the [lead vocal](crate::effects::Karaoke) out of the main link's input, or puts it
+    /// back.
+    SetKaraoke(bool),
+    ToggleKaraoke,
+    /// Runs the main link through one of the [voice changer](crate::effects::VoicePreset)'s voices,
+    /// or none.
+    SetVoice(Option<VoicePreset>),
+    /// Moves on to the next voice, and back to none after the last.
+    CycleVoice,
+    /// Starts or stops the [metronome](crate::metronome) in the main link's output.
+    SetMetronome(bool),
+    ToggleMetronome,
+    /// Sets the metronome's tempo, in beats per minute, whether or not it's clicking.
+    SetTempo(f32),
+    /// Starts or stops the [tuner](crate::tuner) on the main link's raw input.
+    SetTuner(bool),
+    ToggleTuner,
+    /// Starts or stops the [spectrogram](crate::spectrogram) of the main link.
+    SetSpectrogram(bool),
+    ToggleSpectrogram,
+    /// Has the spectrogram show the raw input or the output, starting it over if it's on.
+    SetSpectrogramTap(SpectrogramTap),
+    /// Changes the spectrogram's FFT size, window and averaging, starting it over if it's on.
+    SetSpectrumAnalysis(SpectrumAnalysis),
+    /// Opens the [push-to-talk](crate::talk) gate while the talk key is held; ignored when
+    /// push-to-talk is off.
+    SetTalking(bool),
+    /// Works the [looper](crate::looper) on the main link.
+    Looper(LooperAction),
+    /// Goes in or out of [low latency mode](crate::latency), relinking a device input to change
+    /// its buffers.
+    SetLowLatency(bool),
+    ToggleLowLatency,
+    /// Listens to the main link's raw input for [`autogain::DURATION`](crate::autogain::DURATION)
+    /// while the user speaks normally, then sets the trim, gate and compressor from what it heard
+    /// and saves them as the input device's profile.
+    CalibrateGain,
+    /// Plays a chirp out of the main link in place of its input and times how long it takes to
+    /// come back in, for the [round trip](crate::loopback) through the air or a cable.
+    MeasureLatency,
+    /// Plays a sweep out of the main link in place of its input, and saves the
+    /// [impulse response](crate::impulse) it comes back in with to this directory.
+    CaptureImpulse(PathBuf),
+    /// Corrects the output for the [room](crate::room) an impulse response was captured in, or
+    /// stops correcting it.
+    SetRoomCorrection(Option<PathBuf>),
+    /// Fades out and stops the links after this long, or calls off the [sleep timer](crate::sleep).
+    SleepAfter(Option<Duration>),
+    /// Starts recording once a link is running (or right away if one already is).
+    StartRecording(RecordingConfig),
+    StopRecording,
+    /// Dumps the replay buffer to a file.
+    SaveReplay(RecordingConfig),
+    /// Plays a file into the output mix of the running link, replacing any file already playing.
+    PlayFile(PathBuf),
+    TogglePlayback,
+    /// Seeks the playing file by this many seconds.
+    SeekFile(f64),
+    EditLoop(LoopEdit),
+    StopFile,
+    /// Mixes soundboard sample `i` into the output.
+    TriggerSample(usize),
+    /// Streams to this receiver instead of the `--send`/`--rtp-send` targets, or stops sending.
+    #[cfg(feature = "network")]
+    SendTo(Option<Peer>),
+    /// Applies new settings to the running links without relinking them.
+    Reconfigure(LiveSettings),
+}
+
+impl PlayerCommand {
+    /// Whether this only means something to a running link; it's ignored in any other state.
+    pub fn needs_link(&self) -> bool {
+        matches!(
+            self,
+            PlayerCommand::AddLink(_)
+                | PlayerCommand::CalibrateGain
+                | PlayerCommand::MeasureLatency
+                | PlayerCommand::CaptureImpulse(_)
+                | PlayerCommand::SaveReplay(_)
+                | PlayerCommand::PlayFile(_)
+                | PlayerCommand::TogglePlayback
+                | PlayerCommand::SeekFile(_)
+                | PlayerCommand::EditLoop(_)
+                | PlayerCommand::StopFile
+                | PlayerCommand::TriggerSample(_)
+        )
+    }
+}
+
+/// Where the input callback hands its samples, before and after processing.
+struct LinkTaps {
+    raw: Vec<RecordingTap>,
+    /// The processed input's recording, kept in line with the raw input's by `sync`.
+    recording: RecordingTap,
+    /// The rest of what the processed input goes to, which drops what it can't keep up with.
+    processed: Vec<RecordingTap>,
+    /// The final output mix, lined up with the raw input by `sync`.
+    output: RecordingTap,
+    /// The raw input again, for the tuner; it's not in `raw`, since those mark where a recording
+    /// starts.
+    tuner: RecordingTap,
+    /// The raw input and the final output, for the spectrogram of whichever it shows.
+    spectrogram_input: RecordingTap,
+    spectrogram_output: RecordingTap,
+    sync: Arc<TrackSync>,
+    /// Levels of the processed input and the output, and dropouts of either.
+    meters: Arc<Meters>,
+    /// How the sound level of the raw input is read for `meters`.
+    spl: SplConfig,
+    /// What the output adds to the day's listening dose, if it's calibrated.
+    dose: Option<DoseTracker>,
+    /// When the output counts as loud for too long, for `meters`.
+    loudness_alert: LoudnessAlertConfig,
+    /// The [auto-gain](crate::autogain) measurement of the raw input, while it's listening.
+    calibration: Update<GainMeasurement>,
+    /// The [round-trip test](crate::loopback), while it's playing and listening.
+    loopback: Update<LoopbackTest>,
+    /// The [impulse response capture](crate::impulse), while it's playing and listening.
+    impulse: Update<ImpulseCapture>,
+    /// The room correction the output runs through, set up for its format.
+    room_correction: Arc<Mutex<Option<RoomFilter>>>,
+    /// The sleep timer's fade of the file, soundboard and metronome in the output; the links fade
+    /// in their chains.
+    bus_fade: Arc<Mutex<Fade>>,
+    fault: LinkFault,
+}
+
+/// A new value for something a callback holds, picked up at the start of its next block.
+type Update<T> = Arc<Mutex<Option<T>>>;
+
+/// Where a callback that panicked leaves its message before asking the player to stop the link.
+#[derive(Clone)]
+struct LinkFault {
+    message: Arc<Mutex<Option<String>>>,
+    player: Sender<PlayerCommand>,
+}
+
+impl LinkFault {
+    /// Takes the message of a panic since the last call, if there was one.
+    fn take(&self) -> Option<String> {
+        lock(&self.message).take()
+    }
+}
+
+impl LinkTaps {
+    /// Taps for a link added alongside the main one, which only reaches the output and has meters
+    /// of its own.
+    fn detached(&self) -> LinkTaps {
+        LinkTaps {
+            raw: Vec::new(),
+            recording: Default::default(),
+            processed: Vec::new(),
+            output: Default::default(),
+            tuner: Default::default(),
+            spectrogram_input: Default::default(),
+            spectrogram_output: Default::default(),
+            sync: Default::default(),
+            meters: Default::default(),
+            spl: self.spl.clone(),
+            dose: self.dose.clone(),
+            loudness_alert: self.loudness_alert.clone(),
+            calibration: Default::default(),
+            loopback: Default::default(),
+            impulse: Default::default(),
+            // Only the main link's output is corrected.
+            room_correction: Default::default(),
+            bus_fade: Arc::clone(&self.bus_fade),
+            fault: self.fault.clone(),
+        }
+    }
+}
+
+/// Runs a callback body so that a panic in it tears the link down instead of the process.
+struct CallbackGuard {
+    fault: LinkFault,
+    failed: bool,
+}
+
+impl CallbackGuard {
+    fn new(fault: &LinkFault) -> CallbackGuard {
+        CallbackGuard {
+            fault: fault.clone(),
+            failed: false,
+        }
+    }
+
+    /// Runs `body` unless an earlier run panicked; returns whether it ran to the end.
+    fn run(&mut self, body: impl FnOnce()) -> bool {
+        if self.failed {
+            return false;
+        }
+        match panic::catch_unwind(AssertUnwindSafe(body)) {
+            Ok(()) => true,
+            Err(payload) => {
+                // The stream stays silent from here until the player drops it.
+                self.failed = true;
+                *lock(&self.fault.message) = Some(panic_message(payload.as_ref()));
+                let _ = self.fault.player.send(PlayerCommand::Stop);
+                false
+            }
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    let message = payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic");
+    format!("Audio callback panicked: {}", message)
+}
+
+/// The settings that can change under running links, without a relink or a gap in the audio.
+#[derive(Debug, Clone)]
+pub struct LiveSettings {
+    pub ducking: DuckingConfig,
+    pub sidechain: SidechainConfig,
+    /// Offsets and alignment apply at once; extra devices that come or go wait for the next link.
+    pub outputs: OutputsConfig,
+    /// Used from the next file played.
+    pub player: PlayerConfig,
+}
+
+/// What the player thread sets up around every link; the parts in [`LiveSettings`] can change
+/// while it runs. The default has nothing beyond the link itself switched on.
+#[derive(Default)]
+pub struct LinkSettings {
+    pub replay: ReplayConfig,
+    pub player: PlayerConfig,
+    pub soundboard: SoundboardConfig,
+    pub ducking: DuckingConfig,
+    /// How a link ducks under another one's input, once it's picked which.
+    pub sidechain: SidechainConfig,
+    /// How an input is fitted to an output with other channels.
+    pub remix: RemixConfig,
+    pub output_pipe: Option<OutputPipe>,
+    /// Network senders and streaming targets.
+    #[cfg(feature = "network")]
+    pub streams: StreamSettings,
+    /// Name of the virtual sink to play into, once it's been created.
+    pub virtual_sink: Option<String>,
+    /// Extra output devices and how they're lined up in time.
+    pub outputs: OutputsConfig,
+    /// Where to keep the running device link so the next launch can restore it after a crash.
+    pub session: Option<PathBuf>,
+    /// The config file the auto-gain wizard saves device profiles to; without one, they're only
+    /// applied.
+    pub config: Option<PathBuf>,
+    /// Peak level the output devices are limited to, from [`SafetyConfig::ceiling`](crate::safety::SafetyConfig::ceiling).
+    pub ceiling: Option<f32>,
+    /// How long every link takes to come up from silence when it starts.
+    pub fade_in: Duration,
+    /// Weighting, response and calibration of the input's sound level meter.
+    pub spl: SplConfig,
+    /// The day's listening dose, tracked when the output is calibrated.
+    pub dose: Option<DoseTracker>,
+    /// When to warn that the output has been loud for too long, and whether to turn it down.
+    pub loudness_alert: LoudnessAlertConfig,
+    /// Whether the main link's input only opens while the talk key is held.
+    pub push_to_talk: PushToTalkConfig,
+    /// Whether the main link's input only opens while it's loud enough.
+    pub vox: VoxConfig,
+    /// The click track, and whether it starts out clicking.
+    pub metronome: MetronomeConfig,
+    /// Whether the tuner starts out on.
+    pub tuner: TunerConfig,
+    /// Whether the spectrogram starts out on, and what it shows.
+    pub spectrogram: SpectrogramConfig,
+    /// The room the output is corrected for from the start, and how far it may be.
+    pub room_correction: RoomCorrectionConfig,
+    /// How long a loop can be and how loud it plays.
+    pub looper: LooperConfig,
+    /// Whether low latency mode starts out on, and the buffer sizes it tries.
+    pub low_latency: LowLatencyConfig,
+    /// When a quiet device input is let go of until it's loud again.
+    pub standby: StandbyConfig,
+    /// Threads for the heavy effects of each link's chain.
+    pub workers: WorkersConfig,
+    /// Where to send the processed signal to be transcribed; nothing is unless a server is set.
+    #[cfg(feature = "transcribe")]
+    pub transcription: TranscriptionConfig,
+    /// Where the captions end up, for the frontend to show.
+    #[cfg(feature = "transcribe")]
+    pub captions: Captions,
+}
+
+/// One of the links in [`LinkStatus::links`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LinkInfo {
+    pub input: String,
+    pub volume: f32,
+    pub trim: f32,
+    pub muted: bool,
+    pub bypassed: bool,
+    /// The link whose input ducks this one.
+    pub ducked_by: Option<usize>,
+    /// Where the link's input is heard from in headphones, once it's been placed.
+    pub position: Option<Position>,
+    pub pan: f32,
+}
+
+/// One of the devices in [`LinkStatus::outputs`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OutputInfo {
+    pub name: String,
+    /// How far behind its callback the device plays, once it has said.
+    pub latency_ms: Option<f32>,
+    /// How far it's held back to line up with the other outputs.
+    pub delay_ms: f32,
+}
+
+/// What the player thread is running, for anything outside it that wants to show it.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct LinkStatus {
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub volume: f32,
+    pub trim: f32,
+    pub muted: bool,
+    /// Whether the selected link's effects are bypassed for an A/B comparison.
+    pub bypassed: bool,
+    pub preset: Option<String>,
+    /// Whether the main link's channels go through one chain rather than one each.
+    pub channels_linked: bool,
+    pub speech_boost: bool,
+    pub karaoke: bool,
+    pub voice: Option<VoicePreset>,
+    /// The metronome's tempo, while it's clicking.
+    pub metronome: Option<f32>,
+    /// The tuner's pitch for the A above middle C, while it's on.
+    pub tuner: Option<f32>,
+    /// What the spectrogram shows, while it's on.
+    pub spectrogram: Option<SpectrogramTap>,
+    /// Whether the talk key is held, when push-to-talk is on.
+    pub talking: Option<bool>,
+    /// Whether the VOX gate is open, when VOX is on.
+    pub vox_open: Option<bool>,
+    pub looper: LooperState,
+    /// The buffer size in frames the devices are opened with, while low latency mode is on.
+    pub low_latency: Option<u32>,
+    /// How long until the sleep timer stops the links, in seconds, while it's set.
+    pub sleep_seconds: Option<u64>,
+    /// How many seconds the auto-gain wizard has left to listen, while it is.
+    pub calibrating: Option<u64>,
+    /// Whether the link is in [standby](crate::standby), waiting for its input to be loud again.
+    pub standby: bool,
+    pub recording: bool,
+    pub send_to: Option<String>,
+    pub rtp_send_to: Option<String>,
+    /// The devices the main link plays to, the default output first.
+    pub outputs: Vec<OutputInfo>,
+    /// How long the main link's input takes to come out of the default output, once both devices
+    /// have said how far they are from their callbacks.
+    pub round_trip_ms: Option<f32>,
+    /// Whether the round-trip test is playing its chirp and listening for it.
+    pub measuring_latency: bool,
+    /// The round trip the last test heard, for the devices and buffers the link has now.
+    pub measured_round_trip_ms: Option<f32>,
+    /// Whether an impulse response capture is playing its sweep and listening for it.
+    pub capturing_impulse: bool,
+    /// Where the last impulse response captured was saved.
+    pub impulse_response: Option<PathBuf>,
+    /// The impulse response the output is corrected for, while it is.
+    pub room_correction: Option<PathBuf>,
+    /// The main link first, then the ones added alongside it.
+    pub links: Vec<LinkInfo>,
+    /// The link `volume`, `trim` and `muted` are of, and that volume and mute commands go to.
+    pub selected: usize,
+    pub state: EngineState,
+    /// What the player last did on its own, like relinking a stalled device.
+    pub notice: Option<String>,
+    /// Share of the day's safe listening dose used so far, where 1 is all of it, when it's tracked.
+    pub dose: Option<f32>,
+    /// Why the player is in [`EngineState::Error`].
+    pub error: Option<String>,
+}
+
+/// The effects of the applied profile, for the main link's chain.
+struct MainEffects {
+    effects: Vec<EffectConfig>,
+    right_effects: Option<Vec<EffectConfig>>,
+    linked: bool,
+    /// Whether the [speech boost](effects::speech_boost) follows the profile's effects.
+    speech_boost: bool,
+    /// Whether the [vocal removal](effects::Karaoke) goes ahead of the profile's effects.
+    karaoke: bool,
+    /// The voice changer's voice, after the profile's effects and before the speech boost.
+    voice: Option<VoicePreset>,
+    /// The gate that closes the input while the talk key isn't held, when push-to-talk is on.
+    push_to_talk: Option<PushToTalk>,
+    /// The gate that closes the input while it's quiet, when VOX is on.
+    vox: Option<Vox>,
+    looper: Looper,
+    /// The buffer size in frames low latency mode opens the devices with, while it's on; the
+    /// heavy effects are left out then.
+    low_latency: Option<u32>,
+}
+
+impl Default for MainEffects {
+    fn default() -> Self {
+        MainEffects {
+            effects: Vec::new(),
+            right_effects: None,
+            linked: true,
+            speech_boost: false,
+            karaoke: false,
+            voice: None,
+            push_to_talk: None,
+            vox: None,
+            looper: Looper::new(&LooperConfig::default()),
+            low_latency: None,
+        }
+    }
+}
+
+impl MainEffects {
+    /// Sets the gain of the `band`th peaking or high-shelf effect, returning whether there was
+    /// one to set.
+    fn set_eq_gain(&mut self, band: usize, gain_db: f32) -> bool {
+        if !gain_db.is_finite() {
+            return false;
+        }
+        let set = |effects: &mut [EffectConfig]| {
+            let gain = effects
+                .iter_mut()
+                .filter_map(|effect| match effect {
+                    EffectConfig::Peaking(peaking) => Some(&mut peaking.gain_db),
+                    EffectConfig::HighShelf(shelf) => Some(&mut shelf.gain_db),
+                    _ => None,
+                })
+                .nth(band);
+            gain.map(|gain| *gain = gain_db).is_some()
+        };
+        let left = set(&mut self.effects);
+        let right = self.right_effects.as_deref_mut().is_some_and(set);
+        left || right
+    }
+
+    /// Sets `chain` up for a stream of `config`, with one chain per ear unless they're linked.
+    fn load(&self, chain: &mut EffectChain, config: &StreamConfig) {
+        let boosted = |effects: &[EffectConfig]| {
+            let mut effects = effects.to_vec();
+            if let Some(voice) = self.voice {
+                effects.push(EffectConfig::VoiceChanger(voice.config()));
+            }
+            if self.speech_boost {
+                effects.extend(effects::speech_boost());
+            }
+            if self.low_latency.is_some() {
+                effects.retain(|effect| !effect.is_heavy());
+            }
+            effects
+        };
+        let left = boosted(&self.effects);
+        if self.linked {
+            chain.load(&left, config);
+        } else {
+            let right = boosted(self.right_effects.as_deref().unwrap_or(&self.effects));
+            chain.load_split(&left, &right, config);
+        }
+        // First, since it needs both channels even when they're unlinked.
+        if self.karaoke {
+            chain.push_front(Karaoke::new(&KaraokeConfig::default(), config));
+        }
+        // Last, so a reverb's tail doesn't carry on after the gates close.
+        if let Some(vox) = &self.vox {
+            chain.push(vox.gate(config));
+        }
+        if let Some(push_to_talk) = &self.push_to_talk {
+            chain.push(push_to_talk.gate(config));
+        }
+        // After the gates, so the loop goes on playing while they're closed.
+        chain.push(self.looper.stage(config));
+    }
+}
+
+struct Link {
+    _streams: Vec<StreamHandle>,
+    /// What reads a pipe or socket input, stopped when it's dropped.
+    _reader: Option<Box<dyn Any>>,
+    input_name: String,
+    /// `None` when only the pipe is written.
+    output_name: Option<String>,
+    input_config: StreamConfig,
+    output_config: StreamConfig,
+    /// How far ahead of its callback the input device captures; never known for other inputs.
+    input_latency: Arc<StreamLatency>,
+    /// How the input is fitted to the output, its remix retargeted when the link is placed or
+    /// panned.
+    conversion: Update<Conversion>,
+    /// What that was set up from, the link's place and pan included.
+    remix_config: RemixConfig,
+    /// The input device, to relink if the link stalls.
+    device: Option<usize>,
+    heartbeat: Arc<Heartbeat>,
+    chain: SharedChain,
+    /// Peak of the processed input, which ducks the file player and soundboard, and any link
+    /// sidechained to it.
+    level: LiveLevel,
+    /// The default output first, then the extra ones that opened; empty when only the pipe is written.
+    outputs: Vec<LinkOutput>,
+    ducking: Update<DuckingConfig>,
+}
+
+/// One of the devices a link plays to, and how far it's held back to line up with the others.
+struct LinkOutput {
+    name: Option<String>,
+    latency: Arc<StreamLatency>,
+    /// Samples the graph holds this output back by.
+    delay: Arc<AtomicUsize>,
+    /// The offset from the config, in ms.
+    offset_ms: f32,
+}
+
+impl Link {
+    /// Moves to `config`'s pan and position from the next block on.
+    fn remix(&mut self, config: RemixConfig) {
+        if let Some(remix) = lock(&self.conversion).as_mut().and_then(|c| c.remix.as_mut()) {
+            remix.retarget(&config);
+        }
+        self.remix_config = config;
+    }
+
+    /// Whether a device callback stopped firing, as when a device is suspended or its backend wedged.
+    fn stalled(&self) -> bool {
+        self.heartbeat
+            .stalled(self.device.is_some(), self.output_name.is_some())
+    }
+
+    /// Takes the offsets in `config` for the outputs that are open; devices it adds or drops wait
+    /// for the next link.
+    fn set_offsets(&mut self, config: &OutputsConfig) {
+        let mut outputs = self.outputs.iter_mut();
+        if let Some(default) = outputs.next() {
+            default.offset_ms = config.delay_ms;
+        }
+        for output in outputs {
+            let extra = config.extra.iter().find(|extra| output.name.as_ref() == Some(&extra.device));
+            if let Some(extra) = extra {
+                output.offset_ms = extra.delay_ms;
+            }
+        }
+    }
+
+    /// Sets each output's delay to its offset plus, with `align`, how much sooner it plays than
+    /// the slowest output.
+    fn align_outputs(&self, align: bool) {
+        let slowest = self.outputs.iter().filter_map(|o| o.latency.get()).max().unwrap_or_default();
+        let frames_per_ms = self.output_config.sample_rate.0 as f32 / 1000.0;
+        for output in &self.outputs {
+            let lead = match (align, output.latency.get()) {
+                (true, Some(latency)) => slowest - latency,
+                _ => Duration::ZERO,
+            };
+            let ms = (lead.as_secs_f32() * 1000.0 + output.offset_ms).max(0.0);
+            let frames = (ms * frames_per_ms).round() as usize;
+            output.delay.store(frames * self.output_config.channels as usize, Ordering::Relaxed);
+        }
+    }
+
+    /// The input device's latency, then what's waiting in the ring and the default output's
+    /// delay, then that device's latency; `None` until both devices have said.
+    fn round_trip(&self, meters: &Meters) -> Option<Duration> {
+        let output = self.outputs.first()?;
+        let (captured, played) = (self.input_latency.get()?, output.latency.get()?);
+        let queued = meters.buffer_fill() * RING_CAPACITY as f32 + output.delay.load(Ordering::Relaxed) as f32;
+        let samples_per_second = self.output_config.sample_rate.0 as f32 * self.output_config.channels as f32;
+        Some(captured + Duration::from_secs_f32(queued / samples_per_second) + played)
+    }
+
+    fn output_info(&self) -> Vec<OutputInfo> {
+        let samples_per_ms = self.output_config.sample_rate.0 as f32 / 1000.0 * self.output_config.channels as f32;
+        self.outputs
+            .iter()
+            .map(|output| OutputInfo {
+                name: output.name.clone().unwrap_or_default(),
+                latency_ms: output.latency.get().map(|latency| latency.as_secs_f32() * 1000.0),
+                delay_ms: output.delay.load(Ordering::Relaxed) as f32 / samples_per_ms,
+            })
+            .collect()
+    }
+}
+
+/// What the player thread reacts to: a command, or its own periodic check on the link.
+enum Event {
+    Command(PlayerCommand),
+    Tick,
+}
+
+/// Spawns the player thread, linking devices from `backend`, and returns the channel that drives it.
+///
+/// Nothing is linked until a [`PlayerCommand::Start`] or a profile with an input device arrives.
+/// `recording` and `status` are kept up to date after every command, and `meters` while a link
+/// runs; `playback` follows whatever file [`PlayerCommand::PlayFile`] started.
+pub fn setup_stream(
+    backend: Arc<dyn AudioBackend + Send + Sync>,
+    recording: Arc<AtomicBool>,
+    playback: Arc<PlaybackState>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    settings: LinkSettings,
+) -> mpsc::Sender<PlayerCommand> {
+    // Outlives the main link, so its volume and effects carry over when it's replaced.
+    let main_chain = SharedChain::default();
+    start_player(backend, recording, playback, status, meters, settings, main_chain)
+}
+
+/// [`setup_stream`] around a main chain that's already set up, as the tests put effects of their
+/// own in.
+fn start_player(
+    backend: Arc<dyn AudioBackend + Send + Sync>,
+    recording: Arc<AtomicBool>,
+    playback: Arc<PlaybackState>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    mut settings: LinkSettings,
+    main_chain: SharedChain,
+) -> mpsc::Sender<PlayerCommand> {
+    let (tx, rx) = mpsc::channel();
+    let fault = LinkFault {
+        message: Default::default(),
+        player: tx.clone(),
+    };
+    thread::spawn(move || {
+        let mut link: Option<Link> = None;
+        // Shared by every link's chain.
+        let workers = WorkerPool::start(&settings.workers);
+        if let Some(workers) = &workers {
+            lock(&main_chain).set_workers(workers.clone());
+        }
+        // And its level, so links ducked under it stay ducked when it's replaced.
+        let main_level: LiveLevel = Arc::new(Default::default());
+        // The main link's place and pan, which carry over to the next one.
+        let mut main_remix = settings.remix.clone();
+        // The preset's effects, set up again for every main link since they depend on its format.
+        let mut main_effects = MainEffects {
+            push_to_talk: settings
+                .push_to_talk
+                .enabled
+                .then(|| PushToTalk::new(&settings.push_to_talk)),
+            vox: settings.vox.enabled.then(|| Vox::new(&settings.vox)),
+            looper: Looper::new(&settings.looper),
+            low_latency: settings
+                .low_latency
+                .enabled
+                .then_some(settings.low_latency.buffer_frames),
+            ..Default::default()
+        };
+        // Dropouts as of the last check, to tell whether low latency mode's buffers keep up;
+        // `None` until a new link has had a check to settle in.
+        let mut xruns_seen: Option<u64> = None;
+        let mut sleep: Option<SleepTimer> = None;
+        let mut silence = SilenceWatch::new(&settings.standby);
+        let mut standby: Option<Standby> = None;
+        // When the output's level last went into the history.
+        let mut history_at = Instant::now();
+        // What the last round-trip test heard, until the link changes.
+        let mut measured_round_trip: Option<Duration> = None;
+        // Where the last impulse response went, kept across links since it's a file.
+        let mut impulse_response: Option<PathBuf> = None;
+        let mut added_links: Vec<Link> = Vec::new();
+        let mut selected = 0;
+        let recording_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let raw_recording_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let output_recording_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let track_sync = Arc::new(TrackSync::default());
+        let replay_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let pipe_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let virtual_sink_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let tuner_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let spectrogram_input_tap: RecordingTap = Arc::new(Mutex::new(None));
+        let spectrogram_output_tap: RecordingTap = Arc::new(Mutex::new(None));
+        #[cfg(feature = "transcribe")]
+        let transcription_tap: RecordingTap = Arc::new(Mutex::new(None));
+        #[cfg_attr(not(any(feature = "network", feature = "transcribe")), allow(unused_mut))]
+        let mut processed_taps = vec![
+            Arc::clone(&replay_tap),
+            Arc::clone(&pipe_tap),
+            Arc::clone(&virtual_sink_tap),
+        ];
+        #[cfg(feature = "network")]
+        let mut streams = Streams::new(&settings.streams);
+        #[cfg(feature = "network")]
+        processed_taps.extend(streams.taps());
+        #[cfg(feature = "transcribe")]
+        processed_taps.push(Arc::clone(&transcription_tap));
+        let taps = LinkTaps {
+            raw: vec![Arc::clone(&raw_recording_tap)],
+            recording: Arc::clone(&recording_tap),
+            processed: processed_taps,
+            output: Arc::clone(&output_recording_tap),
+            tuner: Arc::clone(&tuner_tap),
+            spectrogram_input: Arc::clone(&spectrogram_input_tap),
+            spectrogram_output: Arc::clone(&spectrogram_output_tap),
+            sync: Arc::clone(&track_sync),
+            meters,
+            spl: settings.spl.clone(),
+            dose: settings.dose.clone(),
+            loudness_alert: settings.loudness_alert.clone(),
+            calibration: Default::default(),
+            loopback: Default::default(),
+            impulse: Default::default(),
+            room_correction: Default::default(),
+            bus_fade: Default::default(),
+            fault,
+        };
+        let mut recorders: Vec<Recorder> = Vec::new();
+        let mut replay: Option<ReplayBuffer> = None;
+        let mut pipe: Option<PcmPipe> = None;
+        let mut virtual_sink_feed: Option<VirtualSinkFeed> = None;
+        #[cfg(feature = "transcribe")]
+        let mut transcriber: Option<Transcriber> = None;
+        let device_output = settings.output_pipe.is_none_or(|p| !p.exclusive);
+        let mut pending_recording: Option<RecordingConfig> = None;
+        let mut preset: Option<String> = None;
+        let mut state = EngineState::Idle;
+        let mut error: Option<String> = None;
+        let mut notice: Option<String> = None;
+        let mut saved_session: Option<Session> = None;
+        let file_bus: PlaybackBus = Arc::new(Mutex::new(None));
+        let mut file_player: Option<FilePlayer> = None;
+        let soundboard_bus: SoundboardBus = Arc::new(Mutex::new(Default::default()));
+        let mut soundboard: Option<Soundboard> = None;
+        let mut tuner: Option<Tuner> = None;
+        let mut tuner_on = settings.tuner.enabled;
+        let restart_tuner = |tuner: &mut Option<Tuner>, on: bool, link: Option<&Link>| {
+            if let Some(tuner) = tuner.take() {
+                tuner.stop();
+            }
+            *tuner = link
+                .filter(|_| on)
+                .map(|link| Tuner::start(&link.input_config, &tuner_tap, &taps.meters));
+        };
+        let mut spectrogram: Option<Spectrogram> = None;
+        let mut spectrogram_settings = settings.spectrogram.clone();
+        let restart_spectrogram =
+            |spectrogram: &mut Option<Spectrogram>, settings: &SpectrogramConfig, link: Option<&Link>| {
+                if let Some(spectrogram) = spectrogram.take() {
+                    spectrogram.stop();
+                }
+                *spectrogram = link.filter(|_| settings.enabled).map(|link| {
+                    let (stream, tap) = match settings.tap {
+                        SpectrogramTap::Input => (&link.input_config, &spectrogram_input_tap),
+                        SpectrogramTap::Output => (&link.output_config, &spectrogram_output_tap),
+                    };
+                    Spectrogram::start(stream, &settings.analysis, tap, &taps.meters)
+                });
+            };
+        let mut room_correction = settings.room_correction.impulse_response.clone().and_then(|path| {
+            RoomCorrection::measure(&path, &settings.room_correction)
+                .map_err(|e| eprintln!("{}", e))
+                .ok()
+                .map(|correction| (path, correction))
+        });
+        let restart_room_correction = |correction: Option<&RoomCorrection>, link: Option<&Link>| {
+            *lock(&taps.room_correction) =
+                correction.zip(link).map(|(correction, link)| correction.filter(&link.output_config));
+        };
+        let metronome_bus: MetronomeBus = Default::default();
+        let mut metronome = settings.metronome.clone();
+        let restart_metronome = |metronome: &MetronomeConfig, link: Option<&Link>| {
+            *lock(&metronome_bus) = link
+                .filter(|_| metronome.enabled)
+                .map(|link| Metronome::new(metronome, &link.output_config));
+        };
+        let stop_recording = |recorders: &mut Vec<Recorder>| {
+            for r in recorders.drain(..) {
+                if let Err(e) = r.stop() {
+                    eprintln!("Cannot finalize recording: {}", e);
+                }
+            }
+            recording.store(false, Ordering::Relaxed);
+        };
+        let stop_file = |file_player: &mut Option<FilePlayer>| {
+            if let Some(player) = file_player.take() {
+                player.stop();
+            }
+        };
+        let mut event_handler = |event: Event| {
+            let automatic = matches!(event, Event::Tick);
+            // The device a quiet link is to listen to once it's torn down.
+            let mut pause: Option<usize> = None;
+            let command = match event {
+                Event::Command(command) => command,
+                Event::Tick => {
+                    if history_at.elapsed() >= Duration::from_secs(1) {
+                        history_at = Instant::now();
+                        taps.meters.history.push(taps.meters.output.take_history());
+                    }
+                    if let Some(dose) = &taps.dose {
+                        if let Some(message) = dose.check() {
+                            notice = Some(message);
+                        }
+                        let mut status = lock(&status);
+                        status.dose = Some(dose.dose() as f32);
+                        status.notice = notice.clone();
+                    }
+                    // Latencies are only known once the outputs have played, and can drift as they run.
+                    if let Some(link) = &link {
+                        link.align_outputs(settings.outputs.align);
+                        let mut status = lock(&status);
+                        status.outputs = link.output_info();
+                        status.round_trip_ms = link.round_trip(&taps.meters).map(|t| t.as_secs_f32() * 1000.0);
+                    }
+                    let asleep = match sleep.as_mut().map(SleepTimer::check) {
+                        Some(Sleep::FadeOut(fade)) => {
+                            for chain in iter::once(&main_chain).chain(added_links.iter().map(|link| &link.chain)) {
+                                lock(chain).fade_out(fade);
+                            }
+                            lock(&taps.bus_fade).fade_out(fade);
+                            false
+                        }
+                        Some(Sleep::Stop) => true,
+                        _ => false,
+                    };
+                    lock(&status).sleep_seconds = sleep.as_ref().map(|timer| timer.remaining().as_secs());
+                    let measured = lock(&taps.calibration).take_if(|measurement| measurement.done());
+                    if let Some((measurement, link)) = measured.zip(link.as_ref()) {
+                        notice = Some(match measurement.finish() {
+                            Ok(calibration) => {
+                                let mut chain = lock(&main_chain);
+                                chain.trim = calibration.trim();
+                                calibration.apply(&mut main_effects.effects);
+                                if let Some(right_effects) = &mut main_effects.right_effects {
+                                    calibration.apply(right_effects);
+                                }
+                                main_effects.load(&mut chain, &link.input_config);
+                                // Named for the device and linking it, so applying it brings the
+                                // device back at the level it was calibrated for.
+                                let profile = Profile {
+                                    name: link.input_name.clone(),
+                                    volume: chain.volume,
+                                    trim: chain.trim,
+                                    input_device: Some(link.input_name.clone()),
+                                    effects: main_effects.effects.clone(),
+                                    right_effects: main_effects.right_effects.clone(),
+                                    link_channels: main_effects.linked,
+                                };
+                                preset = Some(profile.name.clone());
+                                let saved = match &settings.config {
+                                    Some(path) => match profile.save(path) {
+                                        Ok(()) => format!(", saved to {}", path.display()),
+                                        Err(e) => format!(", cannot save it to {}: {}", path.display(), e),
+                                    },
+                                    None => String::new(),
+                                };
+                                format!(
+                                    "Calibrated {}: trim {:+.1} dB, gate at {:.1} dB, compressing above {:.1} dB at {:.1}:1{}",
+                                    profile.name,
+                                    calibration.trim_db,
+                                    calibration.gate.threshold_db,
+                                    calibration.compressor.threshold_db,
+                                    calibration.compressor.ratio,
+                                    saved
+                                )
+                            }
+                            Err(e) => e,
+                        });
+                        let trim = lock(&main_chain).trim;
+                        let mut status = lock(&status);
+                        if selected == 0 {
+                            status.trim = trim;
+                        }
+                        status.preset = preset.clone();
+                        status.notice = notice.clone();
+                    }
+                    lock(&status).calibrating = lock(&taps.calibration).as_ref().map(calibration_seconds);
+                    let tested = lock(&taps.loopback).take_if(|test| test.done());
+                    if let Some((test, link)) = tested.zip(link.as_ref()) {
+                        let buffers = match main_effects.low_latency {
+                            Some(frames) => format!("{} frame buffers", frames),
+                            None => "default buffers".to_string(),
+                        };
+                        let result = test.finish();
+                        measured_round_trip = result.as_ref().ok().copied();
+                        notice = Some(match result {
+                            Ok(round_trip) => format!(
+                                "Measured {:.1} ms round trip from {} to {} at {}",
+                                round_trip.as_secs_f32() * 1000.0,
+                                link.input_name,
+                                link.output_name.as_deref().unwrap_or("the output"),
+                                buffers
+                            ),
+                            Err(e) => e,
+                        });
+                        let mut status = lock(&status);
+                        status.measured_round_trip_ms = measured_round_trip.map(|t| t.as_secs_f32() * 1000.0);
+                        status.notice = notice.clone();
+                    }
+                    lock(&status).measuring_latency = lock(&taps.loopback).is_some();
+                    let captured = lock(&taps.impulse).take_if(|capture| capture.done());
+                    if let Some((capture, link)) = captured.zip(link.as_ref()) {
+                        notice = Some(match capture.finish() {
+                            Ok(path) => {
+                                let saved = format!(
+                                    "Captured the impulse response from {} to {} as {}",
+                                    link.output_name.as_deref().unwrap_or("the output"),
+                                    link.input_name,
+                                    path.display()
+                                );
+                                impulse_response = Some(path);
+                                saved
+                            }
+                            Err(e) => e,
+                        });
+                        let mut status = lock(&status);
+                        status.impulse_response = impulse_response.clone();
+                        status.notice = notice.clone();
+                    }
+                    lock(&status).capturing_impulse = lock(&taps.impulse).is_some();
+                    let peak = taps.meters.raw_input.take().peak;
+                    let quiet = match link.as_ref().and_then(|link| link.device) {
+                        Some(device) => silence.quiet_for_long(peak).then_some(device),
+                        None => {
+                            silence.reset();
+                            None
+                        }
+                    };
+                    let woken = standby.as_ref().filter(|_| silence.heard(peak)).map(|standby| standby.device);
+                    if asleep {
+                        sleep = None;
+                        standby = None;
+                        notice = Some("The sleep timer ran out, stopped".to_string());
+                        PlayerCommand::Stop
+                    } else if let Some(device) = woken {
+                        standby = None;
+                        notice = Some("The input came back, relinked it".to_string());
+                        PlayerCommand::Start(InputSource::Device(device))
+                    } else if let Some(device) = quiet {
+                        pause = Some(device);
+                        notice = Some(format!("The input was quiet for {} min, went into standby", settings.standby.minutes));
+                        PlayerCommand::Stop
+                    } else {
+                        match link.as_ref().filter(|link| link.stalled()) {
+                            Some(stalled) => match stalled.device {
+                                Some(device) => {
+                                    notice = Some(format!("{} stopped responding, relinked it", stalled.input_name));
+                                    PlayerCommand::Start(InputSource::Device(device))
+                                }
+                                // Only a device input can be opened again; a pipe or socket is gone with the link.
+                                None => {
+                                    *lock(&taps.fault.message) = Some(format!(
+                                        "{} stopped responding",
+                                        stalled.output_name.as_deref().unwrap_or_default()
+                                    ));
+                                    PlayerCommand::Stop
+                                }
+                            },
+                            None => {
+                                let xruns = taps.meters.xruns.load(Ordering::Relaxed);
+                                let dropped = xruns_seen.replace(xruns).is_some_and(|seen| xruns > seen);
+                                // Dropouts at low latency mode's buffer size mean the machine can't keep
+                                // up with it, so the device is opened again with bigger buffers.
+                                let bigger = main_effects
+                                    .low_latency
+                                    .filter(|_| dropped)
+                                    .and_then(|frames| settings.low_latency.step_up(frames));
+                                match (bigger, link.as_ref().and_then(|link| link.device)) {
+                                    (Some(frames), Some(device)) => {
+                                        main_effects.low_latency = Some(frames);
+                                        notice = Some(format!("Dropouts in low latency mode, went up to {} frames", frames));
+                                        PlayerCommand::Start(InputSource::Device(device))
+                                    }
+                                    _ => return,
+                                }
+                            }
+                        }
+                    }
+                }
+            };
+            if command.needs_link() && state != EngineState::Running {
+                eprintln!("Nothing is linked yet");
+                return;
+            }
+            let mut relink: Option<InputSource> = None;
+            let mut unlink = false;
+            let selected_chain = match selected {
+                0 => Arc::clone(&main_chain),
+                i => Arc::clone(&added_links[i - 1].chain),
+            };
+            match command {
+                PlayerCommand::Start(source) => {
+                    relink = Some(source);
+                }
+                PlayerCommand::Stop | PlayerCommand::RemoveLink(0) => {
+                    unlink = true;
+                }
+                PlayerCommand::AddLink(source) => {
+                    let chain = SharedChain::default();
+                    lock(&chain).fade_in(settings.fade_in);
+                    if let Some(workers) = &workers {
+                        lock(&chain).set_workers(workers.clone());
+                    }
+                    match create_link(
+                        backend.as_ref(),
+                        source,
+                        &chain,
+                        &Arc::new(Default::default()),
+                        &taps.detached(),
+                        &Default::default(),
+                        &Default::default(),
+                        &Default::default(),
+                        &DuckingConfig::default(),
+                        // Added links only play to the default output.
+                        &OutputsConfig {
+                            extra: Vec::new(),
+                            monitor: MonitorConfig::default(),
+                            ..settings.outputs.clone()
+                        },
+                        &settings.remix,
+                        settings.ceiling,
+                        device_output,
+                        main_effects.low_latency,
+                    ) {
+                        Ok(added) => {
+                            // No effects, but the gains ramp at the link's rate.
+                            lock(&chain).load(&[], &added.input_config);
+                            added_links.push(added);
+                            selected = added_links.len();
+                        }
+                        Err(e) => eprintln!("Cannot add link: {}", e),
+                    }
+                }
+                PlayerCommand::RemoveLink(i) => {
+                    if i <= added_links.len() {
+                        let removed = added_links.remove(i - 1);
+                        // Nothing keys the links it ducked any more.
+                        for chain in iter::once(&main_chain).chain(added_links.iter().map(|link| &link.chain)) {
+                            let mut chain = lock(chain);
+                            if chain.ducker().is_some_and(|ducker| Arc::ptr_eq(ducker.level(), &removed.level)) {
+                                chain.set_ducker(None);
+                            }
+                        }
+                        if selected == i {
+                            selected = 0;
+                        } else if selected > i {
+                            selected -= 1;
+                        }
+                    }
+                }
+                PlayerCommand::SelectLink(i) => {
+                    if i <= added_links.len() {
+                        selected = i;
+                    }
+                }
+                PlayerCommand::DuckLink { link: ducked, key } => {
+                    let links: Vec<&Link> = link.iter().chain(&added_links).collect();
+                    match (links.get(ducked), key.map(|key| links.get(key))) {
+                        (Some(ducked), None) => lock(&ducked.chain).set_ducker(None),
+                        (Some(ducked), Some(Some(key))) if !ptr::eq(*ducked, *key) => {
+                            let ducker = Ducker::new(&settings.sidechain.ducking(), &ducked.input_config, &key.level);
+                            lock(&ducked.chain).set_ducker(Some(ducker));
+                        }
+                        _ => eprintln!("No such links to duck one under the other"),
+                    }
+                }
+                command @ (PlayerCommand::SetPosition(_) | PlayerCommand::SetPan(_)) => {
+                    let mut config = match selected {
+                        0 => main_remix.clone(),
+                        i => added_links[i - 1].remix_config.clone(),
+                    };
+                    match command {
+                        PlayerCommand::SetPosition(position) => config.position = position,
+                        PlayerCommand::SetPan(pan) => config.pan = pan.clamp(-1.0, 1.0),
+                        _ => {}
+                    }
+                    let remixed = match selected {
+                        0 => link.as_mut(),
+                        i => added_links.get_mut(i - 1),
+                    };
+                    if selected == 0 {
+                        main_remix = config.clone();
+                    }
+                    if let Some(remixed) = remixed {
+                        remixed.remix(config);
+                    }
+                }
+                PlayerCommand::IncreaseVolume(amount) if amount.is_finite() => {
+                    let mut chain = lock(&selected_chain);
+                    chain.volume = (chain.volume + amount).max(0.0);
+                }
+                PlayerCommand::SetVolume(volume) if volume.is_finite() => {
+                    lock(&selected_chain).volume = volume.max(0.0);
+                }
+                PlayerCommand::SetTrim(trim) if trim.is_finite() => {
+                    lock(&selected_chain).trim = trim.max(0.0);
+                }
+                PlayerCommand::IncreaseVolume(_)
+                | PlayerCommand::SetVolume(_)
+                | PlayerCommand::SetTrim(_) => {}
+                PlayerCommand::SetEqGain { band, gain_db } => {
+                    if main_effects.set_eq_gain(band, gain_db) {
+                        if let Some(link) = &link {
+                            main_effects.load(&mut lock(&main_chain), &link.input_config);
+                        }
+                    }
+                }
+                PlayerCommand::SetMuted(muted) => {
+                    lock(&selected_chain).muted = muted;
+                }
+                PlayerCommand::ToggleMute => {
+                    let mut chain = lock(&selected_chain);
+                    chain.muted = !chain.muted;
+                }
+                PlayerCommand::SetBypassed(bypassed) => {
+                    lock(&selected_chain).bypassed = bypassed;
+                }
+                PlayerCommand::ToggleBypass => {
+                    let mut chain = lock(&selected_chain);
+                    chain.bypassed = !chain.bypassed;
+                }
+                PlayerCommand::ApplyProfile(profile) => {
+                    preset = Some(profile.name.clone());
+                    main_effects = MainEffects {
+                        effects: profile.effects.clone(),
+                        right_effects: profile.right_effects.clone(),
+                        linked: profile.link_channels,
+                        // The boost, vocal removal, voice, loop and low latency mode are the
+                        // user's, not the profile's, so they stay on across them.
+                        speech_boost: main_effects.speech_boost,
+                        karaoke: main_effects.karaoke,
+                        voice: main_effects.voice,
+                        push_to_talk: main_effects.push_to_talk.take(),
+                        vox: main_effects.vox.take(),
+                        looper: main_effects.looper.clone(),
+                        low_latency: main_effects.low_latency,
+                    };
+                    relink = profile
+                        .input_device
+                        .as_deref()
+                        .and_then(find_input_device)
+                        .map(InputSource::Device);
+                    let mut chain = lock(&main_chain);
+                    chain.volume = profile.volume;
+                    chain.trim = profile.trim;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut chain, &link.input_config);
+                    }
+                }
+                PlayerCommand::LinkChannels(linked) => {
+                    main_effects.linked = linked;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::ToggleLinkChannels => {
+                    main_effects.linked = !main_effects.linked;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::SetSpeechBoost(on) => {
+                    main_effects.speech_boost = on;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::ToggleSpeechBoost => {
+                    main_effects.speech_boost = !main_effects.speech_boost;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::SetKaraoke(on) => {
+                    main_effects.karaoke = on;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::ToggleKaraoke => {
+                    main_effects.karaoke = !main_effects.karaoke;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::SetVoice(voice) => {
+                    main_effects.voice = voice;
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::CycleVoice => {
+                    main_effects.voice = match main_effects.voice {
+                        None => Some(VoicePreset::ALL[0]),
+                        Some(voice) => {
+                            let i = VoicePreset::ALL.iter().position(|&v| v == voice).unwrap_or(0);
+                            VoicePreset::ALL.get(i + 1).copied()
+                        }
+                    };
+                    if let Some(link) = &link {
+                        main_effects.load(&mut lock(&main_chain), &link.input_config);
+                    }
+                }
+                PlayerCommand::SetMetronome(on) => {
+                    metronome.enabled = on;
+                    restart_metronome(&metronome, link.as_ref());
+                }
+                PlayerCommand::ToggleMetronome => {
+                    metronome.enabled = !metronome.enabled;
+                    restart_metronome(&metronome, link.as_ref());
+                }
+                PlayerCommand::SetTempo(bpm) => {
+                    metronome.bpm = bpm.clamp(metronome::BPM_RANGE.0, metronome::BPM_RANGE.1);
+                    if let Some(clicking) = lock(&metronome_bus).as_mut() {
+                        clicking.set_bpm(metronome.bpm);
+                    }
+                }
+                PlayerCommand::SetTuner(on) => {
+                    tuner_on = on;
+                    restart_tuner(&mut tuner, tuner_on, link.as_ref());
+                }
+                PlayerCommand::ToggleTuner => {
+                    tuner_on = !tuner_on;
+                    restart_tuner(&mut tuner, tuner_on, link.as_ref());
+                }
+                PlayerCommand::SetSpectrogram(on) => {
+                    spectrogram_settings.enabled = on;
+                    restart_spectrogram(&mut spectrogram, &spectrogram_settings, link.as_ref());
+                }
+                PlayerCommand::ToggleSpectrogram => {
+                    spectrogram_settings.enabled = !spectrogram_settings.enabled;
+                    restart_spectrogram(&mut spectrogram, &spectrogram_settings, link.as_ref());
+                }
+                PlayerCommand::SetSpectrogramTap(shown) => {
+                    spectrogram_settings.tap = shown;
+                    restart_spectrogram(&mut spectrogram, &spectrogram_settings, link.as_ref());
+                }
+                PlayerCommand::SetSpectrumAnalysis(analysis) => {
+                    spectrogram_settings.analysis = analysis;
+                    restart_spectrogram(&mut spectrogram, &spectrogram_settings, link.as_ref());
+                }
+                PlayerCommand::SetTalking(talking) => {
+                    if let Some(push_to_talk) = &main_effects.push_to_talk {
+                        push_to_talk.set_talking(talking);
+                    }
+                }
+                command @ (PlayerCommand::SetLowLatency(_) | PlayerCommand::ToggleLowLatency) => {
+                    let on = match command {
+                        PlayerCommand::SetLowLatency(on) => on,
+                        _ => main_effects.low_latency.is_none(),
+                    };
+                    if on != main_effects.low_latency.is_some() {
+                        main_effects.low_latency = on.then_some(settings.low_latency.buffer_frames);
+                        // The buffer sizes only take when the devices are opened.
+                        relink = link.as_ref().and_then(|link| link.device).map(InputSource::Device);
+                        if let (None, Some(link)) = (&relink, &link) {
+                            main_effects.load(&mut lock(&main_chain), &link.input_config);
+                        }
+                    }
+                }
+                PlayerCommand::Looper(action) => {
+                    main_effects.looper.act(action);
+                }
+                PlayerCommand::CalibrateGain => {
+                    // Only a device has a profile of its own to keep what's heard in.
+                    match link.as_ref().filter(|link| link.device.is_some()) {
+                        Some(link) => {
+                            *lock(&taps.calibration) = Some(GainMeasurement::new(&link.input_config));
+                        }
+                        None => notice = Some("Only a device input can be calibrated".to_string()),
+                    }
+                }
+                PlayerCommand::MeasureLatency => {
+                    if let Some(link) = &link {
+                        *lock(&taps.loopback) = Some(LoopbackTest::new(&link.input_config));
+                        notice = Some("Measuring the round trip, keep quiet for a second".to_string());
+                    }
+                }
+                PlayerCommand::CaptureImpulse(directory) => {
+                    if let Some(link) = &link {
+                        *lock(&taps.impulse) = Some(ImpulseCapture::new(&link.input_config, &directory));
+                        notice = Some("Capturing the impulse response, keep quiet for a few seconds".to_string());
+                    }
+                }
+                PlayerCommand::SetRoomCorrection(path) => {
+                    let measured = path.map(|path| {
+                        RoomCorrection::measure(&path, &settings.room_correction).map(|correction| (path, correction))
+                    });
+                    match measured {
+                        Some(Ok((path, correction))) => {
+                            let (boost, cut) = correction.extremes();
+                            notice = Some(format!(
+                                "Correcting the room for {}, {:+.1} to {:+.1} dB",
+                                path.display(),
+                                cut,
+                                boost
+                            ));
+                            room_correction = Some((path, correction));
+                        }
+                        Some(Err(e)) => notice = Some(e),
+                        None => room_correction = None,
+                    }
+                    restart_room_correction(room_correction.as_ref().map(|(_, c)| c), link.as_ref());
+                }
+                PlayerCommand::SleepAfter(after) => {
+                    // Called off partway through the fade, the links come back up.
+                    if sleep.as_ref().is_some_and(SleepTimer::fading) {
+                        for chain in iter::once(&main_chain).chain(added_links.iter().map(|link| &link.chain)) {
+                            lock(chain).fade_back(chain::CROSSFADE);
+                        }
+                        lock(&taps.bus_fade).fade_back(chain::CROSSFADE);
+                    }
+                    sleep = after.map(SleepTimer::new);
+                }
+                PlayerCommand::StartRecording(recording_config) => {
+                    pending_recording = Some(recording_config);
+                }
+                PlayerCommand::StopRecording => {
+                    pending_recording = None;
+                    stop_recording(&mut recorders);
+                }
+                PlayerCommand::SaveReplay(recording_config) => {
+                    if let Some(replay) = &replay {
+                        replay.save(&recording_config, preset.as_deref());
+                    }
+                }
+                PlayerCommand::PlayFile(path) => {
+                    stop_file(&mut file_player);
+                    if let Some(link) = &link {
+                        match FilePlayer::open(
+                            &path,
+                            settings.player.volume,
+                            settings.player.looping,
+                            &link.output_config,
+                            &file_bus,
+                            &playback,
+                        ) {
+                            Ok(player) => file_player = Some(player),
+                            Err(e) => eprintln!("Cannot play {}: {}", path.display(), e),
+                        }
+                    }
+                }
+                PlayerCommand::TogglePlayback => {
+                    if let Some(player) = &file_player {
+                        player.toggle_pause();
+                    }
+                }
+                PlayerCommand::SeekFile(offset) => {
+                    if let Some(player) = &file_player {
+                        player.seek_by(offset);
+                    }
+                }
+                PlayerCommand::EditLoop(edit) => {
+                    if let Some(player) = &file_player {
+                        player.edit_loop(edit);
+                    }
+                }
+                PlayerCommand::StopFile => {
+                    stop_file(&mut file_player);
+                }
+                PlayerCommand::TriggerSample(i) => {
+                    if let Some(soundboard) = &soundboard {
+                        soundboard.trigger(i);
+                    }
+                }
+                PlayerCommand::Reconfigure(live) => {
+                    if let Some(link) = &mut link {
+                        *lock(&link.ducking) = Some(live.ducking.clone());
+                        link.set_offsets(&live.outputs);
+                        link.align_outputs(live.outputs.align);
+                    }
+                    for added in &mut added_links {
+                        added.set_offsets(&live.outputs);
+                        added.align_outputs(live.outputs.align);
+                    }
+                    for chain in iter::once(&main_chain).chain(added_links.iter().map(|link| &link.chain)) {
+                        if let Some(ducker) = lock(chain).ducker_mut() {
+                            ducker.set_config(&live.sidechain.ducking());
+                        }
+                    }
+                    settings.ducking = live.ducking;
+                    settings.sidechain = live.sidechain;
+                    settings.outputs = live.outputs;
+                    settings.player = live.player;
+                }
+                #[cfg(feature = "network")]
+                PlayerCommand::SendTo(peer) => {
+                    streams.send_to(peer, link.as_ref().map(|link| &link.input_config), &settings.streams);
+                }
+            }
+            let relinked = unlink || relink.is_some();
+            if relinked && !automatic {
+                notice = None;
+                standby = None;
+            }
+            if unlink {
+                added_links.clear();
+                selected = 0;
+                // Whatever ducked the main link went with them.
+                lock(&main_chain).set_ducker(None);
+            }
+            if relinked {
+                xruns_seen = None;
+                lock(&taps.calibration).take();
+                lock(&taps.loopback).take();
+                lock(&taps.impulse).take();
+                measured_round_trip = None;
+                stop_recording(&mut recorders);
+                stop_file(&mut file_player);
+                // A link that panicked asked for this stop; anything else replaces the link anyway.
+                let fault = taps.fault.take();
+                // Switching inputs fades the old one out rather than cutting it off, before the
+                // new one fades in like any link that starts; both can't run through the main
+                // chain at once. A link that stalled or failed has nothing left to fade.
+                let fade = link.is_some() && relink.is_some() && !automatic && fault.is_none();
+                if link.is_some() {
+                    if fade {
+                        lock(&main_chain).fade_out(chain::CROSSFADE);
+                        // Twice over, so the faded end has made it through the ring and out.
+                        thread::sleep(chain::CROSSFADE * 2);
+                    }
+                    state = advance(state, Transition::Stop, &status);
+                    link = None;
+                    // Nothing is listening any more to say it stopped hearing speech.
+                    taps.meters.voice.store(false, Ordering::Relaxed);
+                    taps.meters.set_spl(None);
+                    taps.meters.set_loudness(Loudness::default());
+                    taps.meters.set_correlation(None);
+                    taps.meters.scope.clear();
+                    taps.meters.loud.store(false, Ordering::Relaxed);
+                    if let Some(fault) = fault {
+                        error = Some(fault);
+                        state = advance(state, Transition::Fail, &status);
+                    } else if relink.is_none() {
+                        state = advance(state, Transition::Stopped, &status);
+                    }
+                } else if unlink && state == EngineState::Error {
+                    state = advance(state, Transition::Stop, &status);
+                }
+                // Listened to with a bare stream, once the link's own is gone, so nothing but the
+                // level is worked out until the input is loud again.
+                if let Some(device) = pause {
+                    let meters = Arc::clone(&taps.meters);
+                    standby = backend
+                        .open_input(device, BufferSize::Default, Box::new(move |data| meters.raw_input.update(data)))
+                        .map(|input| Standby { device, stream: input.stream })
+                        .map_err(|e| eprintln!("Cannot listen for the input in standby: {}", e))
+                        .ok();
+                }
+                if let Some(source) = relink {
+                    state = advance(state, Transition::Link, &status);
+                    lock(&main_chain).fade_in(settings.fade_in);
+                    // Back up from however far the sleep timer got with it.
+                    *lock(&taps.bus_fade) = Fade::default();
+                    link = create_link(
+                        backend.as_ref(),
+                        source,
+                        &main_chain,
+                        &main_level,
+                        &taps,
+                        &file_bus,
+                        &soundboard_bus,
+                        &metronome_bus,
+                        &settings.ducking,
+                        &settings.outputs,
+                        &main_remix,
+                        settings.ceiling,
+                        device_output,
+                        main_effects.low_latency,
+                    )
+                    .map_err(|e| error = Some(format!("Cannot start link: {}", e)))
+                    .ok();
+                    if let Some(link) = &link {
+                        let mut chain = lock(&main_chain);
+                        main_effects.load(&mut chain, &link.input_config);
+                        // A ducker is set up for the format of the link it ducks.
+                        if let Some(key) = chain.ducker().map(|ducker| Arc::clone(ducker.level())) {
+                            let ducker = Ducker::new(&settings.sidechain.ducking(), &link.input_config, &key);
+                            chain.set_ducker(Some(ducker));
+                        }
+                    }
+                    let outcome = if link.is_some() { Transition::Linked } else { Transition::Fail };
+                    state = advance(state, outcome, &status);
+                }
+                if state != EngineState::Error {
+                    error = None;
+                }
+                if let Some(e) = &error {
+                    eprintln!("{}", e);
+                }
+                replay = start_replay(replay.take(), link.as_ref(), &settings.replay, &replay_tap);
+                if let Some(p) = pipe.take() {
+                    p.stop();
+                }
+                pipe = link.as_ref().zip(settings.output_pipe).map(|(link, output_pipe)| {
+                    PcmPipe::start(&output_pipe, &link.input_config, &pipe_tap)
+                });
+                soundboard = link
+                    .as_ref()
+                    .map(|link| Soundboard::load(&settings.soundboard, &link.output_config, &soundboard_bus));
+                restart_metronome(&metronome, link.as_ref());
+                restart_tuner(&mut tuner, tuner_on, link.as_ref());
+                restart_spectrogram(&mut spectrogram, &spectrogram_settings, link.as_ref());
+                restart_room_correction(room_correction.as_ref().map(|(_, c)| c), link.as_ref());
+                if let Some(feed) = virtual_sink_feed.take() {
+                    feed.stop();
+                }
+                virtual_sink_feed = link
+                    .as_ref()
+                    .zip(settings.virtual_sink.as_deref())
+                    .and_then(|(link, sink_name)| {
+                        VirtualSinkFeed::start(sink_name, &link.input_config, &virtual_sink_tap)
+                            .map_err(|e| eprintln!("Cannot feed the virtual sink: {}", e))
+                            .ok()
+                    });
+                #[cfg(feature = "network")]
+                streams.restart(link.as_ref().map(|link| &link.input_config), &settings.streams);
+                #[cfg(feature = "transcribe")]
+                {
+                    if let Some(transcriber) = transcriber.take() {
+                        transcriber.stop();
+                    }
+                    transcriber = link
+                        .as_ref()
+                        .filter(|_| settings.transcription.server.is_some())
+                        .and_then(|link| {
+                            Transcriber::start(&settings.transcription, &link.input_config, &transcription_tap, &settings.captions)
+                                .map_err(|e| eprintln!("Cannot transcribe: {}", e))
+                                .ok()
+                        });
+                }
+            }
+            #[cfg(feature = "network")]
+            let (send_to, rtp_send_to) = (streams.send_to.clone(), streams.rtp_send_to.clone());
+            #[cfg(not(feature = "network"))]
+            let (send_to, rtp_send_to) = (None, None);
+            if let Some(link) = &link {
+                if let Some(recording_config) = pending_recording.take() {
+                    let input = &link.input_config;
+                    let raw = (&raw_recording_tap, input, "sound-amp-raw");
+                    let processed = (&recording_tap, input, "sound-amp");
+                    // The output track goes first so it's waiting when the raw track marks the start.
+                    let output = (&output_recording_tap, &link.output_config, "sound-amp-output");
+                    let points = match recording_config.tap {
+                        TapPoint::Raw => vec![raw],
+                        TapPoint::Processed => vec![processed],
+                        TapPoint::Both => vec![raw, processed],
+                        TapPoint::Multitrack => vec![output, raw],
+                    };
+                    track_sync.reset();
+                    for (tap, config, prefix) in points {
+                        match Recorder::start(&recording_config, config, tap, prefix, preset.as_deref()) {
+                            Ok(r) => recorders.push(r),
+                            Err(e) => {
+                                eprintln!("Cannot start recording: {}", e);
+                                stop_recording(&mut recorders);
+                                break;
+                            }
+                        }
+                    }
+                    recording.store(!recorders.is_empty(), Ordering::Relaxed);
+                }
+            }
+            let selected_chain = match selected {
+                0 => &main_chain,
+                i => &added_links[i - 1].chain,
+            };
+            let (volume, trim, muted, bypassed) = {
+                let chain = lock(selected_chain);
+                (chain.volume, chain.trim, chain.muted, chain.bypassed)
+            };
+            let levels: Vec<&LiveLevel> = link.iter().chain(&added_links).map(|link| &link.level).collect();
+            let links = link
+                .iter()
+                .chain(&added_links)
+                .map(|link| {
+                    let chain = lock(&link.chain);
+                    LinkInfo {
+                        input: link.input_name.clone(),
+                        volume: chain.volume,
+                        trim: chain.trim,
+                        muted: chain.muted,
+                        bypassed: chain.bypassed,
+                        ducked_by: chain
+                            .ducker()
+                            .and_then(|ducker| levels.iter().position(|&level| Arc::ptr_eq(level, ducker.level()))),
+                        position: link.remix_config.position,
+                        pan: link.remix_config.pan,
+                    }
+                })
+                .collect();
+            *lock(&status) = LinkStatus {
+                input: link.as_ref().map(|link| link.input_name.clone()),
+                output: link.as_ref().and_then(|link| link.output_name.clone()),
+                sample_rate: link.as_ref().map(|link| link.input_config.sample_rate.0),
+                channels: link.as_ref().map(|link| link.input_config.channels),
+                volume,
+                trim,
+                muted,
+                bypassed,
+                preset: preset.clone(),
+                channels_linked: main_effects.linked,
+                speech_boost: main_effects.speech_boost,
+                karaoke: main_effects.karaoke,
+                voice: main_effects.voice,
+                metronome: metronome.enabled.then_some(metronome.bpm),
+                tuner: tuner_on.then_some(settings.tuner.reference_hz),
+                spectrogram: spectrogram_settings.enabled.then_some(spectrogram_settings.tap),
+                talking: main_effects.push_to_talk.as_ref().map(PushToTalk::is_talking),
+                vox_open: main_effects.vox.as_ref().map(Vox::is_open),
+                looper: main_effects.looper.state(),
+                low_latency: main_effects.low_latency,
+                sleep_seconds: sleep.as_ref().map(|timer| timer.remaining().as_secs()),
+                standby: standby.is_some(),
+                calibrating: lock(&taps.calibration).as_ref().map(calibration_seconds),
+                recording: !recorders.is_empty(),
+                send_to: send_to.clone(),
+                rtp_send_to: rtp_send_to.clone(),
+                outputs: link.as_ref().map(Link::output_info).unwrap_or_default(),
+                round_trip_ms: link
+                    .as_ref()
+                    .and_then(|link| link.round_trip(&taps.meters))
+                    .map(|t| t.as_secs_f32() * 1000.0),
+                measuring_latency: lock(&taps.loopback).is_some(),
+                measured_round_trip_ms: measured_round_trip.map(|t| t.as_secs_f32() * 1000.0),
+                capturing_impulse: lock(&taps.impulse).is_some(),
+                impulse_response: impulse_response.clone(),
+                room_correction: room_correction.as_ref().map(|(path, _)| path.clone()),
+                links,
+                selected,
+                state,
+                notice: notice.clone(),
+                dose: taps.dose.as_ref().map(|dose| dose.dose() as f32),
+                error: error.clone(),
+            };
+            if let Some(path) = &settings.session {
+                // Pipes and sockets can't be reopened by a later run, so only device links are kept.
+                let main = lock(&main_chain);
+                let session = link.as_ref().filter(|link| link.device.is_some()).map(|link| Session {
+                    input: link.input_name.clone(),
+                    volume: main.volume,
+                    trim: main.trim,
+                    muted: main.muted,
+                    preset: preset.clone(),
+                    recording: !recorders.is_empty(),
+                    send_to,
+                    rtp_send_to,
+                });
+                if session != saved_session {
+                    let saved = match &session {
+                        Some(session) => session.save(path),
+                        None => Session::clear(path).map_err(Into::into),
+                    };
+                    if let Err(e) = saved {
+                        eprintln!("Cannot keep the session in {}: {}", path.display(), e);
+                    }
+                    saved_session = session;
+                }
+            }
+        };
+        loop {
+            match rx.recv_timeout(watchdog::CHECK_INTERVAL) {
+                Ok(command) => event_handler(Event::Command(command)),
+                Err(RecvTimeoutError::Timeout) => event_handler(Event::Tick),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        if let Some(dose) = &taps.dose {
+            if let Err(e) = dose.save() {
+                eprintln!("Cannot keep the listening dose: {}", e);
+            }
+        }
+    });
+    tx
+}
+
+/// Seconds left of an auto-gain measurement, rounded up so it doesn't show 0 while listening.
+fn calibration_seconds(measurement: &GainMeasurement) -> u64 {
+    measurement.remaining().as_secs_f32().ceil() as u64
+}
+
+/// Moves to the state `transition` leads to, showing it right away since linking can take a while.
+fn advance(state: EngineState, transition: Transition, status: &Mutex<LinkStatus>) -> EngineState {
+    let next = state.next(transition);
+    debug_assert!(next.is_some(), "{:?} while {:?}", transition, state);
+    let next = next.unwrap_or(state);
+    lock(status).state = next;
+    next
+}
+
+/// (Re)starts the replay buffer for a new link; it can't outlive the stream format it was made for.
+fn start_replay(
+    previous: Option<ReplayBuffer>,
+    link: Option<&Link>,
+    config: &ReplayConfig,
+    tap: &RecordingTap,
+) -> Option<ReplayBuffer> {
+    if let Some(replay) = previous {
+        replay.stop();
+    }
+    link.filter(|_| config.enabled)
+        .map(|link| ReplayBuffer::start(config, &link.input_config, tap))
+}
+
+/// Position of the input device called `name`, as [`InputSource::Device`] takes it.
+pub fn find_input_device(name: &str) -> Option<usize> {
+    cpal::default_host()
+        .input_devices()
+        .ok()?
+        .position(|dev| dev.name().is_ok_and(|n| n == name))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_link(
+    backend: &dyn AudioBackend,
+    source: InputSource,
+    chain: &SharedChain,
+    live_level: &LiveLevel,
+    taps: &LinkTaps,
+    file_bus: &PlaybackBus,
+    soundboard_bus: &SoundboardBus,
+    metronome_bus: &MetronomeBus,
+    ducking_config: &DuckingConfig,
+    outputs_config: &OutputsConfig,
+    remix_config: &RemixConfig,
+    ceiling: Option<f32>,
+    device_output: bool,
+    buffer_frames: Option<u32>,
+) -> Result<Link, Error> {
+    let buffer_size = buffer_frames.map_or(BufferSize::Default, BufferSize::Fixed);
+    let ring: RingBuffer<f32> = RingBuffer::new(RING_CAPACITY);
+    let (mut producer, consumer) = ring.split();
+    // The monitor's dry input comes the same way as the processed one, in a ring of its own.
+    let monitor = &outputs_config.monitor;
+    let dry_monitor = monitor.device.is_some() && monitor.input == MonitorInput::Dry && device_output;
+    let (mut dry_producer, dry_consumer) = match dry_monitor {
+        true => {
+            let (producer, consumer) = RingBuffer::new(RING_CAPACITY).split();
+            (Some(producer), Some(consumer))
+        }
+        false => (None, None),
+    };
+    let live_level = Arc::clone(live_level);
+    let heartbeat = Arc::new(Heartbeat::new());
+    // Set up once the input's format is known, which is only after it has started.
+    let voice: Update<VoiceDetector> = Default::default();
+    let spl: Update<SplMeter> = Default::default();
+    // And once the output's is; until then there's no telling how to fill the ring.
+    let conversion: Update<Conversion> = Default::default();
+    let process_input = {
+        let voice = Arc::clone(&voice);
+        let spl = Arc::clone(&spl);
+        let conversion = Arc::clone(&conversion);
+        let heartbeat = Arc::clone(&heartbeat);
+        let chain = Arc::clone(chain);
+        let live_level = Arc::clone(&live_level);
+        let raw_taps = taps.raw.clone();
+        let tuner_tap = Arc::clone(&taps.tuner);
+        let spectrogram_tap = Arc::clone(&taps.spectrogram_input);
+        let calibration = Arc::clone(&taps.calibration);
+        let loopback = Arc::clone(&taps.loopback);
+        let impulse = Arc::clone(&taps.impulse);
+        let sync = Arc::clone(&taps.sync);
+        let meters = Arc::clone(&taps.meters);
+        let recording_tap = Arc::clone(&taps.recording);
+        let taps = taps.processed.clone();
+        let mut processed: Vec<f32> = Vec::new();
+        // Samples handed to the output callback so far.
+        let mut pushed = 0u64;
+        move |data: &[f32]| {
+            heartbeat.beat_input();
+            let started = Instant::now();
+            if let Some(dry) = &mut dry_producer {
+                dry.push_slice(data);
+            }
+            meters.raw_input.update(data);
+            if let Some(measurement) = lock(&calibration).as_mut() {
+                measurement.push(data);
+            }
+            if let Some(tuner) = lock(&tuner_tap).as_mut() {
+                tuner.push_slice(data);
+            }
+            if let Some(spectrogram) = lock(&spectrogram_tap).as_mut() {
+                spectrogram.push_slice(data);
+            }
+            if let Some(detector) = lock(&voice).as_mut() {
+                meters.voice.store(detector.process(data), Ordering::Relaxed);
+            }
+            if let Some(spl) = lock(&spl).as_mut() {
+                meters.set_spl(Some(spl.process(data)));
+            }
+            processed.clear();
+            processed.extend_from_slice(data);
+            let gated = {
+                let mut chain = lock(&chain);
+                chain.process(&mut processed);
+                chain.gated()
+            };
+            meters.stats.count_input(data, gated);
+            if let Some(test) = lock(&loopback).as_mut() {
+                test.listen(data);
+                test.play(&mut processed);
+            }
+            if let Some(capture) = lock(&impulse).as_mut() {
+                capture.listen(data);
+                capture.play(&mut processed);
+            }
+            // The input that went into what the ring took, too.
+            let (offered, accepted, taken) = match lock(&conversion).as_mut() {
+                // Held back, so the ring can't start out partway through a frame.
+                None => (0, 0, 0),
+                Some(conversion) => {
+                    let converted = conversion.process(&processed);
+                    let accepted = producer.push_slice(converted);
+                    let offered = converted.len();
+                    (offered, accepted, conversion.input_len(accepted, offered, processed.len()))
+                }
+            };
+            if accepted < offered {
+                meters.xrun();
+            }
+            // Only the input that made it into the ring is recorded raw, so what an overflow drops
+            // is missing from the output track too and the two stay lined up.
+            for tap in &raw_taps {
+                if let Some(recording) = lock(tap).as_mut() {
+                    sync.mark_start(pushed);
+                    if !sync.push(Track::Raw, recording, &data[..taken]) {
+                        meters.xrun();
+                    }
+                }
+            }
+            pushed += accepted as u64;
+            meters.input.update(&processed);
+            // Kept up whether or not ducking is on, so it can be switched on while the link runs.
+            ducking::store_level(&live_level, &processed);
+            if let Some(recording) = lock(&recording_tap).as_mut() {
+                if !sync.push(Track::Processed, recording, &processed) {
+                    meters.xrun();
+                }
+            }
+            for tap in &taps {
+                if let Some(recording) = lock(tap).as_mut() {
+                    recording.push_slice(&processed);
+                }
+            }
+            meters.input_callbacks.record(started.elapsed());
+        }
+    };
+    let process_input = {
+        let mut guard = CallbackGuard::new(&taps.fault);
+        let mut process_input = process_input;
+        move |data: &[f32]| {
+            guard.run(|| process_input(data));
+        }
+    };
+    let mut streams = Vec::new();
+    let mut reader: Option<Box<dyn Any>> = None;
+    let mut input_latency: Arc<StreamLatency> = Default::default();
+    let device = match source {
+        InputSource::Device(i) => Some(i),
+        _ => None,
+    };
+    let (input_config, input_name) = match source {
+        InputSource::Device(input_device_id) => {
+            let input = backend.open_input(input_device_id, buffer_size.clone(), Box::new(process_input))?;
+            streams.push(input.stream);
+            input_latency = input.latency;
+            (input.config, input.name)
+        }
+        InputSource::Stdin(input) => {
+            reader = Some(Box::new(StdinReader::spawn(input, process_input)));
+            (input.stream_config(), "stdin".to_string())
+        }
+        #[cfg(feature = "network")]
+        InputSource::Network(input) => {
+            let config = input.config.clone();
+            reader = Some(Box::new(NetworkReader::spawn(input, process_input)));
+            (config, "network".to_string())
+        }
+        #[cfg(feature = "network")]
+        InputSource::Rtp(input) => {
+            let config = input.config.stream_config();
+            reader = Some(Box::new(RtpReceiver::spawn(input, process_input)));
+            (config, "rtp".to_string())
+        }
+        #[cfg(windows)]
+        InputSource::App(capture) => {
+            let (config, name) = (capture.stream_config(), capture.name.clone());
+            reader = Some(Box::new(AppCaptureReader::spawn(capture, process_input)?));
+            (config, name)
+        }
+    };
+    *lock(&voice) = Some(VoiceDetector::new(&input_config));
+    *lock(&spl) = Some(SplMeter::new(&taps.spl, &input_config));
+    if !device_output {
+        *lock(&conversion) = Some(Conversion::new(remix_config, &input_config, &input_config));
+        return Ok(Link {
+            _streams: streams,
+            _reader: reader,
+            input_name,
+            output_name: None,
+            output_config: input_config.clone(),
+            input_config,
+            input_latency,
+            conversion,
+            remix_config: remix_config.clone(),
+            device,
+            heartbeat,
+            chain: Arc::clone(chain),
+            level: live_level,
+            outputs: Vec::new(),
+            ducking: Default::default(),
+        });
+    }
+
+    let (output_name, mut output_config) = backend.output_format()?;
+    output_config.buffer_size = buffer_size;
+    *lock(&conversion) = Some(Conversion::new(remix_config, &input_config, &output_config));
+    eprintln!("Sound device: {}", output_name.as_deref().unwrap_or_default());
+    let main_delay = Arc::new(AtomicUsize::new(0));
+    let mut outputs = Vec::new();
+    let ducking_update: Update<DuckingConfig> = Default::default();
+    let output_stream = {
+        let file_bus = Arc::clone(file_bus);
+        let soundboard_bus = Arc::clone(soundboard_bus);
+        let metronome_bus = Arc::clone(metronome_bus);
+        let mut ducker = Ducker::new(ducking_config, &output_config, &live_level);
+        let ducking_update = Arc::clone(&ducking_update);
+        let mut graph = Graph::new();
+        let source = RingSource::new(consumer, &taps.meters);
+        // Two buffers' worth: the block being played and one to spare.
+        let source = match buffer_frames {
+            Some(frames) => {
+                let channels = output_config.channels as usize;
+                source.trimmed(2 * frames as usize * channels, channels)
+            }
+            None => source,
+        };
+        let link_input = graph.add(source);
+        let file = graph.add(Source(move |block: &mut [f32]| {
+            if let Some(file) = lock(&file_bus).as_mut() {
+                file.mix_into(block);
+            }
+        }));
+        let samples = graph.add(Source(move |block: &mut [f32]| {
+            lock(&soundboard_bus).mix_into(block);
+        }));
+        // File playback and the soundboard are mixed first so the ducker can attenuate them together.
+        let bus = graph.add(Effect(move |block: &mut [f32]| {
+            if let Some(config) = lock(&ducking_update).take() {
+                ducker.set_config(&config);
+            }
+            ducker.process(block);
+        }));
+        // The click isn't ducked, since it's what the user keeps time by.
+        let click = graph.add(Source(move |block: &mut [f32]| {
+            if let Some(metronome) = lock(&metronome_bus).as_mut() {
+                metronome.mix_into(block);
+            }
+        }));
+        // The links fade in their chains, so only the buses are faded here.
+        let bus_fade = Arc::clone(&taps.bus_fade);
+        let (channels, rate) = (output_config.channels as usize, output_config.sample_rate.0);
+        let faded = graph.add(Effect(move |block: &mut [f32]| {
+            lock(&bus_fade).process(block, channels, rate);
+        }));
+        let mix = graph.add(Mix);
+        graph.connect(click, faded)?;
+        graph.connect(file, bus)?;
+        graph.connect(samples, bus)?;
+        graph.connect(bus, faded)?;
+        graph.connect(link_input, mix)?;
+        graph.connect(faded, mix)?;
+        // Everything a device plays goes through the ceiling, whatever got mixed in before it.
+        let mut limiter = ceiling.map(|ceiling| SafetyLimiter::new(ceiling, &output_config));
+        let mut dose = taps.dose.as_ref().map(|dose| dose.meter(&output_config));
+        let mut guard = LoudnessGuard::new(&taps.loudness_alert, &output_config);
+        let mut true_peak = TruePeak::new(&output_config);
+        let mut loudness = LoudnessMeter::new(&output_config);
+        let mut correlation = CorrelationMeter::new(&output_config);
+        let output_channels = output_config.channels as usize;
+        let output_meters = Arc::clone(&taps.meters);
+        let limited = graph.add(Effect(move |block: &mut [f32]| {
+            if let Some(guard) = &mut guard {
+                output_meters.loud.store(guard.process(block), Ordering::Relaxed);
+            }
+            let clipped = stats::clipped(block);
+            if let Some(limiter) = &mut limiter {
+                limiter.process(block);
+            }
+            let limiting = limiter.as_ref().is_some_and(SafetyLimiter::limiting);
+            output_meters.stats.count_output(block.len(), clipped, limiting);
+            output_meters.update_true_peak(true_peak.process(block));
+            if output_meters.take_loudness_restart() {
+                loudness.restart();
+            }
+            if let Some(reading) = loudness.process(block) {
+                output_meters.set_loudness(reading);
+            }
+            if let Some(correlation) = &mut correlation {
+                output_meters.set_correlation(correlation.process(block));
+            }
+            output_meters.scope.update(block, output_channels);
+            if let Some(dose) = &mut dose {
+                dose.process(block);
+            }
+        }));
+        // Ahead of the ceiling, so what it boosts is still held under it.
+        let room_correction = Arc::clone(&taps.room_correction);
+        let corrected = graph.add(Effect(move |block: &mut [f32]| {
+            if let Some(filter) = lock(&room_correction).as_mut() {
+                filter.process(block);
+            }
+        }));
+        graph.connect(mix, corrected)?;
+        graph.connect(corrected, limited)?;
+        let main_output = graph.add(Delay::new(&main_delay));
+        graph.connect(limited, main_output)?;
+        for extra in &outputs_config.extra {
+            match open_extra_output(backend, &extra.device, &output_config, taps) {
+                Ok((output, producer)) => {
+                    let delay = Arc::new(AtomicUsize::new(0));
+                    let delayed = graph.add(Delay::new(&delay));
+                    let sink = graph.add(RingSink::new(producer, &taps.meters));
+                    graph.connect(limited, delayed)?;
+                    graph.connect(delayed, sink)?;
+                    streams.push(output.stream);
+                    outputs.push(LinkOutput {
+                        name: Some(extra.device.clone()),
+                        latency: output.latency,
+                        delay,
+                        offset_ms: extra.delay_ms,
+                    });
+                }
+                // One speaker that's missing shouldn't keep the others quiet.
+                Err(e) => eprintln!("Cannot open output {}: {}", extra.device, e),
+            }
+        }
+        if let Some(device) = &monitor.device {
+            match open_extra_output(backend, device, &output_config, taps) {
+                Ok((output, producer)) => {
+                    let input = match (monitor.input, dry_consumer) {
+                        (MonitorInput::Processed, _) => Some(link_input),
+                        // Its dropouts are the main ring's too, so they're only counted there.
+                        (MonitorInput::Dry, Some(consumer)) => {
+                            Some(graph.add(RingSource::new(consumer, &Default::default())))
+                        }
+                        _ => None,
+                    };
+                    let monitor_mix = graph.add(Mix);
+                    if let Some(input) = input {
+                        let level = monitor.input_level;
+                        let input_level = graph.add(Effect(move |block: &mut [f32]| scale(block, level)));
+                        graph.connect(input, input_level)?;
+                        graph.connect(input_level, monitor_mix)?;
+                    }
+                    let level = monitor.playback_level;
+                    let playback_level = graph.add(Effect(move |block: &mut [f32]| scale(block, level)));
+                    graph.connect(bus, playback_level)?;
+                    graph.connect(playback_level, monitor_mix)?;
+                    let level = monitor.level;
+                    let mut limiter = ceiling.map(|ceiling| SafetyLimiter::new(ceiling, &output_config));
+                    let monitor_limited = graph.add(Effect(move |block: &mut [f32]| {
+                        scale(block, level);
+                        if let Some(limiter) = &mut limiter {
+                            limiter.process(block);
+                        }
+                    }));
+                    let sink = graph.add(RingSink::new(producer, &taps.meters));
+                    graph.connect(monitor_mix, monitor_limited)?;
+                    graph.connect(monitor_limited, sink)?;
+                    streams.push(output.stream);
+                }
+                Err(e) => eprintln!("Cannot open the monitor output {}: {}", device, e),
+            }
+        }
+        let output_tap = Arc::clone(&taps.output);
+        let spectrogram_tap = Arc::clone(&taps.spectrogram_output);
+        let sync = Arc::clone(&taps.sync);
+        let meters = Arc::clone(&taps.meters);
+        let heartbeat = Arc::clone(&heartbeat);
+        let mut fill_output = move |data: &mut [f32]| {
+            heartbeat.beat_output();
+            let started = Instant::now();
+            graph.render(data.len());
+            data.copy_from_slice(graph.block(main_output));
+            meters.output.update(data);
+            if let Some(spectrogram) = lock(&spectrogram_tap).as_mut() {
+                spectrogram.push_slice(data);
+            }
+            let ring = graph.node::<RingSource>(link_input).expect("the link input is a ring");
+            // Only what came through the stream is recorded, so dropouts don't shift the tracks apart.
+            // It's taken before the alignment delay, which would shift them too.
+            if let Some(offset) = sync.output_offset(ring.position, ring.received) {
+                if let Some(recording) = lock(&output_tap).as_mut() {
+                    if !sync.push(Track::Output, recording, &graph.block(mix)[offset..ring.received]) {
+                        meters.xrun();
+                    }
+                }
+            }
+            meters.output_callbacks.record(started.elapsed());
+        };
+        let mut guard = CallbackGuard::new(&taps.fault);
+        let data_callback = move |data: &mut [f32]| {
+            if !guard.run(|| fill_output(data)) {
+                data.fill(0.0);
+            }
+        };
+        backend.open_output(None, &output_config, Box::new(data_callback))?
+    };
+    streams.push(output_stream.stream);
+    outputs.insert(
+        0,
+        LinkOutput {
+            name: output_name.clone(),
+            latency: output_stream.latency,
+            delay: main_delay,
+            offset_ms: outputs_config.delay_ms,
+        },
+    );
+    let link = Link {
+        _streams: streams,
+        _reader: reader,
+        input_name,
+        output_name,
+        input_config,
+        output_config,
+        input_latency,
+        conversion,
+        remix_config: remix_config.clone(),
+        device,
+        heartbeat,
+        chain: Arc::clone(chain),
+        level: live_level,
+        outputs,
+        ducking: ducking_update,
+    };
+    // The offsets hold from the start; the measured part follows once the outputs have played.
+    link.align_outputs(outputs_config.align);
+    Ok(link)
+}
+
+/// Fits the processed input to the output: resampled to the output's rate, then remixed onto its
+/// channels, so the remix runs at the rate it's heard at.
+struct Conversion {
+    resampler: Option<LinearResampler>,
+    remix: Option<Remix>,
+    /// Input frames to an output frame.
+    ratio: f64,
+    channels: usize,
+    resampled: Vec<f32>,
+    remixed: Vec<f32>,
+}
+
+impl Conversion {
+    fn new(config: &RemixConfig, input: &StreamConfig, output: &StreamConfig) -> Conversion {
+        let (from, to) = (input.sample_rate.0, output.sample_rate.0);
+        let channels = input.channels as usize;
+        Conversion {
+            resampler: (from != to).then(|| LinearResampler::new(from, to, channels)),
+            remix: Remix::new(config, input, output),
+            ratio: from as f64 / to as f64,
+            channels,
+            resampled: Vec::new(),
+            remixed: Vec::new(),
+        }
+    }
+
+    /// `input` in the output's format.
+    fn process<'a>(&'a mut self, input: &'a [f32]) -> &'a [f32] {
+        let mut block = input;
+        if let Some(resampler) = &mut self.resampler {
+            self.resampled.clear();
+            resampler.process(block, &mut self.resampled);
+            block = &self.resampled;
+        }
+        if let Some(remix) = &mut self.remix {
+            remix.process(block, &mut self.remixed);
+            block = &self.remixed;
+        }
+        block
+    }
+
+    /// How much of an input block of `len` went into the first `accepted` of the `offered`
+    /// samples it was converted to, in whole frames.
+    fn input_len(&self, accepted: usize, offered: usize, len: usize) -> usize {
+        if accepted == offered {
+            return len;
+        }
+        let resampled = self.remix.as_ref().map_or(accepted, |remix| remix.input_len(accepted));
+        let frames = (resampled / self.channels) as f64 * self.ratio;
+        (frames as usize * self.channels).min(len)
+    }
+}
+
+fn scale(block: &mut [f32], gain: f32) {
+    for sample in block {
+        *sample *= gain;
+    }
+}
+
+/// Opens `device` to play what the graph's sink for it pushes into the returned ring.
+fn open_extra_output(
+    backend: &dyn AudioBackend,
+    device: &str,
+    config: &StreamConfig,
+    taps: &LinkTaps,
+) -> Result<(OpenOutput, Producer<f32>), Error> {
+    let (producer, mut consumer) = RingBuffer::new(RING_CAPACITY).split();
+    let meters = Arc::clone(&taps.meters);
+    let mut started = false;
+    let mut guard = CallbackGuard::new(&taps.fault);
+    let data_callback = move |data: &mut [f32]| {
+        let ran = guard.run(|| {
+            let received = consumer.pop_slice(data);
+            data[received..].fill(0.0);
+            // As on the main output, running dry only counts once the graph has started feeding it.
+            if received < data.len() && started {
+                meters.xrun();
+            }
+            started |= received > 0;
+        });
+        if !ran {
+            data.fill(0.0);
+        }
+    };
+    let output = backend.open_output(Some(device), config, Box::new(data_callback))?;
+    Ok((output, producer))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::mpsc::Receiver;
+
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use crate::soundboard::SoundboardMix;
+
+    struct Harness {
+        backend: MockBackend,
+        chain: SharedChain,
+        taps: LinkTaps,
+        file_bus: PlaybackBus,
+        soundboard_bus: SoundboardBus,
+        metronome_bus: MetronomeBus,
+        _player: Receiver<PlayerCommand>,
+    }
+
+    impl Harness {
+        fn new() -> Harness {
+            let (player, rx) = mpsc::channel();
+            Harness {
+                backend: MockBackend::new(48000, 2),
+                chain: Default::default(),
+                taps: LinkTaps {
+                    raw: Vec::new(),
+                    recording: Arc::new(Mutex::new(None)),
+                    processed: Vec::new(),
+                    output: Arc::new(Mutex::new(None)),
+                    tuner: Arc::new(Mutex::new(None)),
+                    spectrogram_input: Default::default(),
+                    spectrogram_output: Default::default(),
+                    sync: Default::default(),
+                    meters: Default::default(),
+                    spl: Default::default(),
+                    dose: None,
+                    loudness_alert: Default::default(),
+                    calibration: Default::default(),
+                    loopback: Default::default(),
+                    impulse: Default::default(),
+                    room_correction: Default::default(),
+                    bus_fade: Default::default(),
+                    fault: LinkFault {
+                        message: Default::default(),
+                        player,
+                    },
+                },
+                file_bus: Arc::new(Mutex::new(None)),
+                soundboard_bus: Default::default(),
+                metronome_bus: Default::default(),
+                _player: rx,
+            }
+        }
+
+        fn link(&self, source: InputSource) -> Result<Link, Error> {
+            create_link(
+                &self.backend,
+                source,
+                &self.chain,
+                &Default::default(),
+                &self.taps,
+                &self.file_bus,
+                &self.soundboard_bus,
+                &self.metronome_bus,
+                &DuckingConfig::default(),
+                &OutputsConfig::default(),
+                &RemixConfig::default(),
+                None,
+                true,
+                None,
+            )
+        }
+
+        fn xruns(&self) -> u64 {
+            self.taps.meters.xruns.load(Ordering::Relaxed)
+        }
+    }
+
+    fn ramp(len: usize) -> Vec<f32> {
+        (0..len).map(|i| i as f32 / len as f32).collect()
+    }
+
+    #[test]
+    fn links_the_input_device_to_the_output() {
+        let harness = Harness::new();
+        let link = harness.link(InputSource::Device(0)).unwrap();
+        assert_eq!(link.input_name, "mock input");
+        assert_eq!(link.output_name.as_deref(), Some("mock output"));
+        assert_eq!(link.input_config.sample_rate.0, 48000);
+        assert_eq!(link.output_config.channels, 2);
+    }
+
+    #[test]
+    fn a_missing_input_device_is_an_error() {
+        let harness = Harness::new();
+        assert!(matches!(
+            harness.link(InputSource::Device(3)),
+            Err(Error::NoInputDevice(3))
+        ));
+    }
+
+    #[test]
+    fn applies_the_volume_to_the_input() {
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        harness.chain.lock().unwrap().volume = 0.5;
+        harness.backend.feed(&[1.0, -1.0, 0.25, 0.0]);
+        assert_eq!(harness.backend.pull(4), [0.5, -0.5, 0.125, 0.0]);
+    }
+
+    #[test]
+    fn ramps_to_a_new_volume_rather_than_jumping() {
+        let harness = Harness::new();
+        let link = harness.link(InputSource::Device(0)).unwrap();
+        {
+            let mut chain = harness.chain.lock().unwrap();
+            chain.load(&[], &link.input_config);
+            chain.volume = 0.5;
+        }
+        // 50 ms, five time constants of the ramp.
+        harness.backend.feed(&[1.0; 4800]);
+        let output = harness.backend.pull(4800);
+        assert!(output[0] > 0.95, "starts at {}", output[0]);
+        assert!(output.windows(2).all(|w| w[1] <= w[0]));
+        assert!((output[4799] - 0.5).abs() < 0.01, "ends at {}", output[4799]);
+    }
+
+    #[test]
+    fn ramps_the_trim_and_the_mute_rather_than_jumping() {
+        let harness = Harness::new();
+        let link = harness.link(InputSource::Device(0)).unwrap();
+        harness.chain.lock().unwrap().load(&[], &link.input_config);
+        // 50 ms after each change, five time constants of the ramp.
+        let after = |change: fn(&mut EffectChain)| {
+            change(&mut harness.chain.lock().unwrap());
+            harness.backend.feed(&[1.0; 4800]);
+            harness.backend.pull(4800)
+        };
+
+        let trimmed = after(|chain| chain.trim = 0.5);
+        assert!(trimmed[0] > 0.95, "starts at {}", trimmed[0]);
+        assert!(trimmed.windows(2).all(|w| w[1] <= w[0]));
+        assert!((trimmed[4799] - 0.5).abs() < 0.01, "ends at {}", trimmed[4799]);
+
+        let muted = after(|chain| chain.muted = true);
+        assert!(muted[0] > 0.45, "starts at {}", muted[0]);
+        assert!(muted.windows(2).all(|w| w[1] <= w[0]));
+        assert!(muted[4799] < 0.01, "ends at {}", muted[4799]);
+
+        let unmuted = after(|chain| chain.muted = false);
+        assert!(unmuted[0] < 0.05, "starts at {}", unmuted[0]);
+        assert!(unmuted.windows(2).all(|w| w[1] >= w[0]));
+        assert!((unmuted[4799] - 0.5).abs() < 0.01, "ends at {}", unmuted[4799]);
+    }
+
+    #[test]
+    fn trims_the_input_ahead_of_the_effects_and_turns_it_down_after_them() {
+        struct Clip;
+        impl chain::Processor for Clip {
+            fn process(&mut self, samples: &mut [f32]) {
+                for sample in samples {
+                    *sample = sample.clamp(-0.5, 0.5);
+                }
+            }
+        }
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        {
+            let mut chain = harness.chain.lock().unwrap();
+            chain.push(Clip);
+            chain.trim = 2.0;
+            chain.volume = 0.5;
+        }
+        harness.backend.feed(&[0.2, -0.2, 0.4, -0.4]);
+        assert_eq!(harness.backend.pull(4), [0.2, -0.2, 0.25, -0.25]);
+    }
+
+    #[test]
+    fn a_bypassed_chain_lets_the_input_through_as_late_as_the_effects_would() {
+        /// Turns the signal upside down two samples late.
+        struct Invert(VecDeque<f32>);
+        impl chain::Processor for Invert {
+            fn process(&mut self, samples: &mut [f32]) {
+                for sample in samples {
+                    self.0.push_back(-*sample);
+                    *sample = self.0.pop_front().unwrap();
+                }
+            }
+
+            fn latency(&self) -> usize {
+                2
+            }
+        }
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        {
+            let mut chain = harness.chain.lock().unwrap();
+            chain.push(Invert(VecDeque::from([0.0; 2])));
+            chain.bypassed = true;
+        }
+        harness.backend.feed(&[1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(harness.backend.pull(4), [0.0, 0.0, 1.0, 2.0]);
+        harness.chain.lock().unwrap().bypassed = false;
+        harness.backend.feed(&[5.0, 6.0, 7.0, 8.0]);
+        assert_eq!(harness.backend.pull(4), [-3.0, -4.0, -5.0, -6.0]);
+    }
+
+    #[test]
+    fn counts_clipped_input_and_the_gate_closing() {
+        struct Shut(bool);
+        impl chain::Processor for Shut {
+            fn process(&mut self, samples: &mut [f32]) {
+                self.0 = samples.iter().all(|&s| s == 0.0);
+            }
+
+            fn closed(&self) -> bool {
+                self.0
+            }
+        }
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        harness.chain.lock().unwrap().push(Shut(false));
+        for block in [[0.0; 4], [1.0, -1.0, 0.5, 0.0], [0.0; 4], [0.0; 4]] {
+            harness.backend.feed(&block);
+        }
+        let stats = harness.taps.meters.stats.read();
+        assert_eq!(stats.input_clipped, 2);
+        assert_eq!(stats.gate_closings, 2);
+        assert_eq!(stats.gated_share, 0.75);
+    }
+
+    #[test]
+    fn an_effect_that_panics_fails_the_link_and_the_next_one_starts() {
+        struct PanicsOnce(bool);
+        impl chain::Processor for PanicsOnce {
+            fn process(&mut self, _: &mut [f32]) {
+                if !self.0 {
+                    self.0 = true;
+                    panic!("an effect fell over");
+                }
+            }
+        }
+        let backend = Arc::new(MockBackend::new(48000, 2));
+        let status: Arc<Mutex<LinkStatus>> = Default::default();
+        let chain = SharedChain::default();
+        let player = start_player(
+            backend.clone(),
+            Default::default(),
+            Default::default(),
+            Arc::clone(&status),
+            Default::default(),
+            LinkSettings::default(),
+            Arc::clone(&chain),
+        );
+        let wait_for = |state: EngineState| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while status.lock().unwrap().state != state {
+                assert!(Instant::now() < deadline, "The player never got to {:?}", state);
+                thread::sleep(Duration::from_millis(10));
+            }
+        };
+
+        player.send(PlayerCommand::Start(InputSource::Device(0))).unwrap();
+        wait_for(EngineState::Running);
+        // Once the link has loaded the main link's effects in, so they don't replace it.
+        chain.lock().unwrap().push(PanicsOnce(false));
+        backend.feed(&[0.5; 4]);
+        wait_for(EngineState::Error);
+        let error = status.lock().unwrap().error.clone().unwrap();
+        assert!(error.contains("an effect fell over"), "{}", error);
+
+        player.send(PlayerCommand::Start(InputSource::Device(0))).unwrap();
+        wait_for(EngineState::Running);
+        backend.feed(&[0.5; 4]);
+        backend.pull(4);
+        player.send(PlayerCommand::Stop).unwrap();
+        wait_for(EngineState::Idle);
+    }
+
+    #[test]
+    fn a_bus_that_panics_leaves_the_next_link_its_buses() {
+        let harness = Harness::new();
+        let link = harness.link(InputSource::Device(0)).unwrap();
+        *harness.soundboard_bus.lock().unwrap() = SoundboardMix::overrun();
+        harness.backend.feed(&[0.0; 4]);
+        assert!(harness.backend.pull(4).iter().all(|&s| s == 0.0));
+        assert!(harness.taps.fault.take().is_some());
+        assert!(harness.soundboard_bus.is_poisoned());
+        drop(link);
+
+        // Relinked as the player does, with the soundboard set up again for the new output.
+        let link = harness.link(InputSource::Device(0)).unwrap();
+        let _soundboard = Soundboard::load(&Default::default(), &link.output_config, &harness.soundboard_bus);
+        let path = std::env::temp_dir().join(format!("sound-amp-bus-{}.wav", std::process::id()));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 48000,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..48000 {
+            writer.write_sample(0.5f32).unwrap();
+        }
+        writer.finalize().unwrap();
+        let file = FilePlayer::open(&path, 1.0, false, &link.output_config, &harness.file_bus, &Default::default()).unwrap();
+
+        // The decoder fills the bus in the background.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            harness.backend.feed(&[0.0; 256]);
+            if harness.backend.pull(256).contains(&0.5) {
+                break;
+            }
+            assert!(Instant::now() < deadline, "The file never played");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(harness.taps.fault.take().is_none());
+        file.stop();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn passes_samples_through_the_ring_in_order() {
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        let input = ramp(1024);
+        for block in input.chunks(96) {
+            harness.backend.feed(block);
+        }
+        let output: Vec<f32> = (0..8).flat_map(|_| harness.backend.pull(128)).collect();
+        assert_eq!(output, input);
+        assert_eq!(harness.xruns(), 0);
+    }
+
+    #[test]
+    fn resamples_an_input_at_another_rate_to_the_output() {
+        let mut harness = Harness::new();
+        harness.backend = MockBackend::new(44100, 2).with_output_rate(48000);
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        // A tenth of a second at 44.1 kHz, played as one at 48 kHz.
+        for _ in 0..10 {
+            harness.backend.feed(&[0.5; 2 * 441]);
+        }
+        let output = harness.backend.pull(2 * 4790);
+        // After the first two frames, which rise from the silence before it.
+        assert!(output[4..].iter().all(|&s| (s - 0.5).abs() < 1e-6));
+        assert_eq!(harness.xruns(), 0);
+    }
+
+    #[test]
+    fn is_silent_without_an_xrun_before_the_input_starts() {
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        assert!(harness.backend.pull(256).iter().all(|&s| s == 0.0));
+        assert_eq!(harness.xruns(), 0);
+    }
+
+    #[test]
+    fn running_dry_after_the_input_started_is_an_xrun() {
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        harness.backend.feed(&[0.5; 64]);
+        harness.backend.pull(64);
+        let output = harness.backend.pull(64);
+        assert!(output.iter().all(|&s| s == 0.0));
+        assert_eq!(harness.xruns(), 1);
+    }
+
+    #[test]
+    fn input_that_overflows_the_ring_is_dropped_as_an_xrun() {
+        let harness = Harness::new();
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        let input = ramp(RING_CAPACITY + 100);
+        harness.backend.feed(&input);
+        assert_eq!(harness.xruns(), 1);
+        // What fit is played; the rest never made it in.
+        assert_eq!(harness.backend.pull(RING_CAPACITY), input[..RING_CAPACITY]);
+        harness.backend.pull(1);
+        assert_eq!(harness.xruns(), 2);
+    }
+
+    #[test]
+    fn the_raw_and_output_tracks_lose_the_same_input_to_an_overflow() {
+        let mut harness = Harness::new();
+        let (raw, mut raw_track) = RingBuffer::new(2 * RING_CAPACITY).split();
+        let (output, mut output_track) = RingBuffer::new(2 * RING_CAPACITY).split();
+        harness.taps.raw = vec![Arc::new(Mutex::new(Some(raw)))];
+        *harness.taps.output.lock().unwrap() = Some(output);
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+
+        let input = ramp(RING_CAPACITY + 100);
+        harness.backend.feed(&input);
+        harness.backend.pull(RING_CAPACITY);
+        harness.backend.feed(&input[..100]);
+        harness.backend.pull(100);
+
+        let drain = |track: &mut ringbuf::Consumer<f32>| {
+            let mut samples = vec![0.0; 2 * RING_CAPACITY];
+            let len = track.pop_slice(&mut samples);
+            samples.truncate(len);
+            samples
+        };
+        let (raw, output) = (drain(&mut raw_track), drain(&mut output_track));
+        assert_eq!(raw.len(), RING_CAPACITY + 100);
+        assert_eq!(raw, output);
+    }
+
+    #[test]
+    fn a_stalled_track_writer_leaves_a_gap_rather_than_pulling_the_tracks_apart() {
+        let mut harness = Harness::new();
+        // The raw track's writer stalls partway through the second block; the output's keeps up.
+        let (raw, mut raw_track) = RingBuffer::new(150).split();
+        let (output, mut output_track) = RingBuffer::new(1000).split();
+        harness.taps.raw = vec![Arc::new(Mutex::new(Some(raw)))];
+        *harness.taps.output.lock().unwrap() = Some(output);
+        let _link = harness.link(InputSource::Device(0)).unwrap();
+        let drain = |track: &mut ringbuf::Consumer<f32>, samples: &mut Vec<f32>| {
+            let mut block = vec![0.0; 1000];
+            let len = track.pop_slice(&mut block);
+            samples.extend_from_slice(&block[..len]);
+        };
+
+        let input = ramp(3 * 96);
+        let (mut raw, mut output) = (Vec::new(), Vec::new());
+        for (i, block) in input.chunks(96).enumerate() {
+            harness.backend.feed(block);
+            harness.backend.pull(96);
+            // The writer catches up after the second block.
+            if i == 1 {
+                drain(&mut raw_track, &mut raw);
+            }
+        }
+        drain(&mut raw_track, &mut raw);
+        drain(&mut output_track, &mut output);
+
+        assert_eq!(harness.xruns(), 1);
+        assert_eq!(raw.len(), output.len());
+        assert_eq!(raw[..150], output[..150]);
+        assert!(raw[150..192].iter().all(|&s| s == 0.0));
+        assert_eq!(raw[192..], output[192..]);
+    }
+}
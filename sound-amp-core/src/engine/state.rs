@@ -0,0 +1,67 @@
+use serde::Serialize;
+
+/// Where the player is in the life of a link.
+///
+/// ```text
+/// Idle ──Link──▶ Linking ──Linked──▶ Running ──Stop──▶ Stopping ──Stopped──▶ Idle
+///                   │                                      │ │
+///                   └──Fail──▶ Error ◀────────Fail─────────┘ └──Link──▶ Linking
+/// ```
+///
+/// `Error` goes back to `Linking` on the next start, or to `Idle` once stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EngineState {
+    /// Nothing is linked.
+    #[default]
+    Idle,
+    /// Opening the devices or stream for a new link.
+    Linking,
+    Running,
+    /// The last link couldn't be started or failed while running; nothing is linked.
+    Error,
+    /// Tearing the running link down, before going idle or linking something else.
+    Stopping,
+}
+
+/// What moves the player from one [`EngineState`] to the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Starts setting up a link.
+    Link,
+    /// The link is up.
+    Linked,
+    /// The link couldn't be set up, or it was torn down because it failed.
+    Fail,
+    /// Starts tearing the link down.
+    Stop,
+    /// The link is gone.
+    Stopped,
+}
+
+impl EngineState {
+    /// The state `transition` leads to from this one, or `None` when it can't happen here.
+    pub fn next(self, transition: Transition) -> Option<EngineState> {
+        use EngineState::*;
+        match (self, transition) {
+            (Idle | Error | Stopping, Transition::Link) => Some(Linking),
+            (Linking, Transition::Linked) => Some(Running),
+            (Linking | Stopping, Transition::Fail) => Some(Error),
+            (Running, Transition::Stop) => Some(Stopping),
+            // There's nothing to tear down after a failure; stopping just clears it.
+            (Error, Transition::Stop) => Some(Idle),
+            (Stopping, Transition::Stopped) => Some(Idle),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EngineState::Idle => "idle",
+            EngineState::Linking => "linking",
+            EngineState::Running => "running",
+            EngineState::Error => "error",
+            EngineState::Stopping => "stopping",
+        }
+    }
+}
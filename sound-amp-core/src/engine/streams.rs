@@ -0,0 +1,156 @@
+//! The network streams the processed input is fanned out to, started again with every link.
+
+use std::sync::{Arc, Mutex};
+
+use cpal::StreamConfig;
+
+use crate::airplay::{AirplayConfig, AirplaySender};
+use crate::discovery::{Peer, Transport};
+use crate::icecast::{IcecastConfig, IcecastSource};
+use crate::net::{NetworkConfig, NetworkSender};
+use crate::recorder::RecordingTap;
+use crate::rtp::{RtpConfig, RtpSender};
+use crate::snapcast::{SnapcastConfig, SnapcastSink};
+
+/// The part of [`LinkSettings`](super::LinkSettings) that needs the `network` feature.
+#[derive(Default)]
+pub struct StreamSettings {
+    /// `--send` address.
+    pub send_to: Option<String>,
+    pub network: NetworkConfig,
+    /// `--rtp-send` address.
+    pub rtp_send_to: Option<String>,
+    pub rtp: RtpConfig,
+    /// Broadcasting is on whenever `icecast.url` is set.
+    pub icecast: IcecastConfig,
+    /// Feeding Snapcast is on whenever `snapcast.target` is set.
+    pub snapcast: SnapcastConfig,
+    /// Streaming to a speaker is on whenever `airplay.target` is set.
+    pub airplay: AirplayConfig,
+}
+
+pub(super) struct Streams {
+    /// Where the senders stream to; the `--send`/`--rtp-send` targets until a
+    /// [`PlayerCommand::SendTo`](super::PlayerCommand::SendTo) changes them.
+    pub send_to: Option<String>,
+    pub rtp_send_to: Option<String>,
+    send_tap: RecordingTap,
+    rtp_tap: RecordingTap,
+    icecast_tap: RecordingTap,
+    snapcast_tap: RecordingTap,
+    airplay_tap: RecordingTap,
+    sender: Option<NetworkSender>,
+    rtp_sender: Option<RtpSender>,
+    icecast: Option<IcecastSource>,
+    snapcast: Option<SnapcastSink>,
+    airplay: Option<AirplaySender>,
+}
+
+impl Streams {
+    pub(super) fn new(settings: &StreamSettings) -> Streams {
+        Streams {
+            send_to: settings.send_to.clone(),
+            rtp_send_to: settings.rtp_send_to.clone(),
+            send_tap: Arc::new(Mutex::new(None)),
+            rtp_tap: Arc::new(Mutex::new(None)),
+            icecast_tap: Arc::new(Mutex::new(None)),
+            snapcast_tap: Arc::new(Mutex::new(None)),
+            airplay_tap: Arc::new(Mutex::new(None)),
+            sender: None,
+            rtp_sender: None,
+            icecast: None,
+            snapcast: None,
+            airplay: None,
+        }
+    }
+
+    /// Where the input callback has to push the processed signal for the streams to get it.
+    pub(super) fn taps(&self) -> Vec<RecordingTap> {
+        [
+            &self.send_tap,
+            &self.rtp_tap,
+            &self.icecast_tap,
+            &self.snapcast_tap,
+            &self.airplay_tap,
+        ]
+        .into_iter()
+        .map(Arc::clone)
+        .collect()
+    }
+
+    /// Stops every stream and, if there's a link producing `config`, starts the ones `settings`
+    /// turn on.
+    pub(super) fn restart(&mut self, config: Option<&StreamConfig>, settings: &StreamSettings) {
+        if let Some(source) = self.icecast.take() {
+            source.stop();
+        }
+        self.icecast = config
+            .filter(|_| settings.icecast.url.is_some())
+            .and_then(|config| {
+                IcecastSource::start(&settings.icecast, config, &self.icecast_tap)
+                    .map_err(|e| eprintln!("Cannot start Icecast broadcast: {}", e))
+                    .ok()
+            });
+        if let Some(sink) = self.snapcast.take() {
+            sink.stop();
+        }
+        self.snapcast = config
+            .filter(|_| settings.snapcast.target.is_some())
+            .and_then(|config| {
+                SnapcastSink::start(&settings.snapcast, config, &self.snapcast_tap)
+                    .map_err(|e| eprintln!("Cannot feed Snapcast: {}", e))
+                    .ok()
+            });
+        if let Some(sender) = self.airplay.take() {
+            sender.stop();
+        }
+        self.airplay = config
+            .filter(|_| settings.airplay.target.is_some())
+            .and_then(|config| {
+                AirplaySender::start(&settings.airplay, config, &self.airplay_tap)
+                    .map_err(|e| eprintln!("Cannot start AirPlay: {}", e))
+                    .ok()
+            });
+        self.restart_senders(config, settings);
+    }
+
+    /// Streams to `peer` instead of the current targets, or stops sending.
+    pub(super) fn send_to(
+        &mut self,
+        peer: Option<Peer>,
+        config: Option<&StreamConfig>,
+        settings: &StreamSettings,
+    ) {
+        let address = |transport| {
+            peer.as_ref()
+                .filter(|peer| peer.transport == transport)
+                .map(|peer| peer.address.to_string())
+        };
+        self.send_to = address(Transport::Tcp);
+        self.rtp_send_to = address(Transport::Rtp);
+        self.restart_senders(config, settings);
+    }
+
+    fn restart_senders(&mut self, config: Option<&StreamConfig>, settings: &StreamSettings) {
+        if let Some(s) = self.sender.take() {
+            s.stop();
+        }
+        self.sender = config
+            .zip(self.send_to.as_deref())
+            .and_then(|(config, address)| {
+                NetworkSender::start(address, &settings.network, config, &self.send_tap)
+                    .map_err(|e| eprintln!("Cannot start network sender: {}", e))
+                    .ok()
+            });
+        if let Some(s) = self.rtp_sender.take() {
+            s.stop();
+        }
+        self.rtp_sender = config
+            .zip(self.rtp_send_to.as_deref())
+            .and_then(|(config, address)| {
+                RtpSender::start(address, &settings.rtp, config, &self.rtp_tap)
+                    .map_err(|e| eprintln!("Cannot start RTP sender: {}", e))
+                    .ok()
+            });
+    }
+}
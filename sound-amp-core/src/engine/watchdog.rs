@@ -0,0 +1,46 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often the player thread looks for a stalled link.
+pub const CHECK_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a device callback can go without firing before its link is rebuilt.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// When the device callbacks of a link last fired.
+pub struct Heartbeat {
+    start: Instant,
+    /// Milliseconds since `start`.
+    input: AtomicU64,
+    output: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn new() -> Heartbeat {
+        Heartbeat {
+            start: Instant::now(),
+            input: AtomicU64::new(0),
+            output: AtomicU64::new(0),
+        }
+    }
+
+    pub fn beat_input(&self) {
+        self.input.store(self.now(), Ordering::Relaxed);
+    }
+
+    pub fn beat_output(&self) {
+        self.output.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// Whether a watched callback has been quiet for longer than [`STALL_TIMEOUT`]; a link gets
+    /// that long to start up as well.
+    pub fn stalled(&self, watch_input: bool, watch_output: bool) -> bool {
+        let quiet = |last: &AtomicU64| {
+            self.now().saturating_sub(last.load(Ordering::Relaxed)) > STALL_TIMEOUT.as_millis() as u64
+        };
+        (watch_input && quiet(&self.input)) || (watch_output && quiet(&self.output))
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+}
@@ -0,0 +1,44 @@
+use thiserror::Error;
+
+/// Why a link couldn't be set up, or why the player can't be reached.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cannot list audio devices: {0}")]
+    Devices(#[from] cpal::DevicesError),
+    #[error("No input device #{0}")]
+    NoInputDevice(usize),
+    #[error("No default output device")]
+    NoOutputDevice,
+    #[error("No output device named {0}")]
+    NoOutputDeviceNamed(String),
+    #[error("{device} has no default stream format: {source}")]
+    NoDefaultConfig {
+        device: String,
+        source: cpal::DefaultStreamConfigError,
+    },
+    #[error("Cannot create the {direction} stream: {source}")]
+    BuildStream {
+        direction: &'static str,
+        source: cpal::BuildStreamError,
+    },
+    #[error("Cannot start the {direction} stream: {source}")]
+    PlayStream {
+        direction: &'static str,
+        source: cpal::PlayStreamError,
+    },
+    #[error("Connecting node {from} to node {to} would make a cycle")]
+    GraphCycle { from: usize, to: usize },
+    /// `--capture-app` names no running process.
+    #[cfg(windows)]
+    #[error("No running application named {0}")]
+    NoApp(String),
+    #[cfg(windows)]
+    #[error("Cannot capture {name}: {source}")]
+    AppCapture {
+        name: String,
+        source: windows::core::Error,
+    },
+    /// The player thread is gone, so commands have nowhere to go.
+    #[error("The player has stopped")]
+    PlayerStopped,
+}
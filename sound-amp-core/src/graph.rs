@@ -0,0 +1,311 @@
+//! A small audio graph: source nodes produce a block, effect and mix nodes combine the blocks of
+//! the nodes feeding them, and sink nodes hand the result on. One [`Graph::render`] call runs every
+//! node once, in an order where each node's inputs are ready before it, so any routing of several
+//! inputs through shared effects to several outputs is rendered from a single callback.
+//!
+//! Every block in a graph has the same length and channel layout, interleaved.
+
+use std::any::Any;
+use std::collections::VecDeque;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use ringbuf::{Consumer, Producer};
+
+use crate::error::Error;
+use crate::meter::Meters;
+
+/// Callbacks a [`RingSource`] watches its ring over before trimming it.
+const TRIM_CALLBACKS: u32 = 50;
+
+/// Position of a node in its [`Graph`], as returned by [`Graph::add`].
+pub type NodeId = usize;
+
+/// One step of a graph.
+pub trait Node: Any + Send {
+    /// Writes this node's block into `output`, which starts out silent, from the blocks of the
+    /// nodes connected to it.
+    fn process(&mut self, inputs: Inputs<'_>, output: &mut [f32]);
+}
+
+/// The blocks of the nodes feeding a node, in the order they were connected.
+#[derive(Clone, Copy)]
+pub struct Inputs<'a> {
+    blocks: &'a [Vec<f32>],
+    ids: &'a [NodeId],
+}
+
+impl<'a> Inputs<'a> {
+    pub fn iter(&self) -> impl Iterator<Item = &'a [f32]> + 'a {
+        let blocks = self.blocks;
+        self.ids.iter().map(move |&id| blocks[id].as_slice())
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ids.is_empty()
+    }
+
+    /// Adds every input into `output`.
+    pub fn mix_into(&self, output: &mut [f32]) {
+        for input in self.iter() {
+            for (sample, s) in output.iter_mut().zip(input) {
+                *sample += s;
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Graph {
+    nodes: Vec<Box<dyn Node>>,
+    /// The nodes feeding each node.
+    inputs: Vec<Vec<NodeId>>,
+    /// Every node, after all of the nodes feeding it.
+    order: Vec<NodeId>,
+    blocks: Vec<Vec<f32>>,
+}
+
+impl Graph {
+    pub fn new() -> Graph {
+        Graph::default()
+    }
+
+    pub fn add(&mut self, node: impl Node) -> NodeId {
+        let id = self.nodes.len();
+        self.nodes.push(Box::new(node));
+        self.inputs.push(Vec::new());
+        self.blocks.push(Vec::new());
+        self.order.push(id);
+        id
+    }
+
+    /// Feeds the block of `from` into `to`. Panics if either isn't a node of this graph.
+    pub fn connect(&mut self, from: NodeId, to: NodeId) -> Result<(), Error> {
+        assert!(
+            from < self.nodes.len() && to < self.nodes.len(),
+            "No node {} or {}",
+            from,
+            to
+        );
+        self.inputs[to].push(from);
+        match self.sorted() {
+            Some(order) => {
+                self.order = order;
+                Ok(())
+            }
+            None => {
+                self.inputs[to].pop();
+                Err(Error::GraphCycle { from, to })
+            }
+        }
+    }
+
+    /// Runs every node once over a block of `len` samples.
+    pub fn render(&mut self, len: usize) {
+        for &id in &self.order {
+            // Taken out so the node can write it while reading the others; a node never feeds itself.
+            let mut block = mem::take(&mut self.blocks[id]);
+            block.clear();
+            block.resize(len, 0.0);
+            let inputs = Inputs {
+                blocks: &self.blocks,
+                ids: &self.inputs[id],
+            };
+            self.nodes[id].process(inputs, &mut block);
+            self.blocks[id] = block;
+        }
+    }
+
+    /// What node `id` wrote in the last [`render`](Graph::render).
+    pub fn block(&self, id: NodeId) -> &[f32] {
+        &self.blocks[id]
+    }
+
+    /// Node `id`, if it's a `T`.
+    pub fn node<T: Node>(&self, id: NodeId) -> Option<&T> {
+        let node: &dyn Any = self.nodes.get(id)?.as_ref();
+        node.downcast_ref()
+    }
+
+    pub fn node_mut<T: Node>(&mut self, id: NodeId) -> Option<&mut T> {
+        let node: &mut dyn Any = self.nodes.get_mut(id)?.as_mut();
+        node.downcast_mut()
+    }
+
+    /// The nodes in an order that renders every input before the node it feeds, or `None` if the
+    /// connections go round in a cycle.
+    fn sorted(&self) -> Option<Vec<NodeId>> {
+        let mut waiting: Vec<usize> = self.inputs.iter().map(Vec::len).collect();
+        let mut order: Vec<NodeId> = (0..self.nodes.len())
+            .filter(|&id| waiting[id] == 0)
+            .collect();
+        let mut next = 0;
+        while next < order.len() {
+            let done = order[next];
+            next += 1;
+            for (id, inputs) in self.inputs.iter().enumerate() {
+                for _ in inputs.iter().filter(|&&input| input == done) {
+                    waiting[id] -= 1;
+                    if waiting[id] == 0 {
+                        order.push(id);
+                    }
+                }
+            }
+        }
+        (order.len() == self.nodes.len()).then_some(order)
+    }
+}
+
+/// Sums its inputs.
+pub struct Mix;
+
+impl Node for Mix {
+    fn process(&mut self, inputs: Inputs<'_>, output: &mut [f32]) {
+        inputs.mix_into(output);
+    }
+}
+
+/// Fills its block from something outside the graph, like a playing file.
+pub struct Source<F>(pub F);
+
+impl<F: FnMut(&mut [f32]) + Send + 'static> Node for Source<F> {
+    fn process(&mut self, _: Inputs<'_>, output: &mut [f32]) {
+        (self.0)(output);
+    }
+}
+
+/// Sums its inputs and processes the sum in place.
+pub struct Effect<F>(pub F);
+
+impl<F: FnMut(&mut [f32]) + Send + 'static> Node for Effect<F> {
+    fn process(&mut self, inputs: Inputs<'_>, output: &mut [f32]) {
+        inputs.mix_into(output);
+        (self.0)(output);
+    }
+}
+
+/// Sums its inputs and hands them on a number of samples later, which can be changed while it runs.
+pub struct Delay {
+    line: VecDeque<f32>,
+    delay: Arc<AtomicUsize>,
+}
+
+impl Delay {
+    pub fn new(delay: &Arc<AtomicUsize>) -> Delay {
+        Delay {
+            line: VecDeque::new(),
+            delay: Arc::clone(delay),
+        }
+    }
+}
+
+impl Node for Delay {
+    fn process(&mut self, inputs: Inputs<'_>, output: &mut [f32]) {
+        inputs.mix_into(output);
+        // A new delay takes hold at once, by adding silence or dropping the oldest samples.
+        let delay = self.delay.load(Ordering::Relaxed);
+        self.line.resize(delay.max(self.line.len()), 0.0);
+        self.line.drain(..self.line.len() - delay);
+        for sample in output {
+            self.line.push_back(*sample);
+            *sample = self.line.pop_front().unwrap_or_default();
+        }
+    }
+}
+
+/// Plays what another callback pushed into a ring, the way a graph takes in an input running on
+/// its own clock.
+pub struct RingSource {
+    consumer: Consumer<f32>,
+    meters: Arc<Meters>,
+    popped: u64,
+    /// Samples taken from the ring before the last block.
+    pub position: u64,
+    /// How much of the last block came from the ring; the rest is silence.
+    pub received: usize,
+    /// Samples the ring is trimmed down to, and the frame size it's trimmed by.
+    trim: Option<(usize, usize)>,
+    /// The least the ring has held at a callback since it was last trimmed.
+    lowest: usize,
+    callbacks: u32,
+}
+
+impl RingSource {
+    pub fn new(consumer: Consumer<f32>, meters: &Arc<Meters>) -> RingSource {
+        RingSource {
+            consumer,
+            meters: Arc::clone(meters),
+            popped: 0,
+            position: 0,
+            received: 0,
+            trim: None,
+            lowest: usize::MAX,
+            callbacks: 0,
+        }
+    }
+
+    /// Keeps the ring from holding more than `samples` for long. What it never runs below over
+    /// [`TRIM_CALLBACKS`] callbacks is latency that isn't needed, so it's dropped, whole frames of
+    /// `channels` at a time; that's a skip in the sound, but only once the input has got ahead.
+    pub fn trimmed(self, samples: usize, channels: usize) -> RingSource {
+        RingSource {
+            trim: Some((samples, channels.max(1))),
+            ..self
+        }
+    }
+}
+
+impl Node for RingSource {
+    fn process(&mut self, _: Inputs<'_>, output: &mut [f32]) {
+        self.meters
+            .set_buffer_fill(self.consumer.len() as f32 / self.consumer.capacity() as f32);
+        if let Some((samples, channels)) = self.trim {
+            self.lowest = self.lowest.min(self.consumer.len());
+            self.callbacks += 1;
+            if self.callbacks == TRIM_CALLBACKS {
+                let excess = self.lowest.saturating_sub(samples) / channels * channels;
+                // Counted as played, so the recorded tracks stay lined up.
+                self.popped += self.consumer.discard(excess) as u64;
+                self.lowest = usize::MAX;
+                self.callbacks = 0;
+            }
+        }
+        self.position = self.popped;
+        self.received = self.consumer.pop_slice(output);
+        // Running dry before the input ever delivered is just startup, not a dropout.
+        if self.received < output.len() && self.position > 0 {
+            self.meters.xrun();
+        }
+        self.popped += self.received as u64;
+    }
+}
+
+/// Sums its inputs and pushes the sum into a ring, the way a graph feeds an output running on its
+/// own clock.
+pub struct RingSink {
+    producer: Producer<f32>,
+    meters: Arc<Meters>,
+}
+
+impl RingSink {
+    pub fn new(producer: Producer<f32>, meters: &Arc<Meters>) -> RingSink {
+        RingSink {
+            producer,
+            meters: Arc::clone(meters),
+        }
+    }
+}
+
+impl Node for RingSink {
+    fn process(&mut self, inputs: Inputs<'_>, output: &mut [f32]) {
+        inputs.mix_into(output);
+        if self.producer.push_slice(output) < output.len() {
+            self.meters.xrun();
+        }
+    }
+}
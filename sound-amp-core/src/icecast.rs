@@ -0,0 +1,296 @@
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::Local;
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::net;
+use crate::recorder::{EncoderSettings, RecordingFormat, RecordingTap, Tags};
+use crate::resampler;
+
+const SEND_INTERVAL: Duration = Duration::from_millis(20);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long the server gets to accept or refuse the source.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_PORT: u16 = 8000;
+const DEFAULT_USER: &str = "source";
+
+pub type IcecastError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IcecastFormat {
+    /// Opus in Ogg; needs the `opus` feature.
+    #[default]
+    Opus,
+    /// Needs the `mp3` feature.
+    Mp3,
+}
+
+impl IcecastFormat {
+    fn recording_format(self) -> RecordingFormat {
+        match self {
+            IcecastFormat::Opus => RecordingFormat::Opus,
+            IcecastFormat::Mp3 => RecordingFormat::Mp3,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            IcecastFormat::Opus => "audio/ogg",
+            IcecastFormat::Mp3 => "audio/mpeg",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct IcecastConfig {
+    /// Mountpoint to broadcast to, as `http://[user:password@]host[:port]/mount`.
+    pub url: Option<String>,
+    pub format: IcecastFormat,
+    pub bitrate_kbps: u32,
+    /// Stream name shown to listeners and in directories.
+    pub name: String,
+    pub description: String,
+    pub genre: String,
+    /// List the stream in the server's public directories.
+    pub public: bool,
+}
+
+impl Default for IcecastConfig {
+    fn default() -> Self {
+        IcecastConfig {
+            url: None,
+            format: IcecastFormat::default(),
+            bitrate_kbps: 128,
+            name: "sound-amp".to_string(),
+            description: String::new(),
+            genre: String::new(),
+            public: false,
+        }
+    }
+}
+
+/// Where and as whom to connect, taken apart from the configured URL.
+#[derive(Debug, Clone, PartialEq)]
+struct Mountpoint {
+    host: String,
+    port: u16,
+    mount: String,
+    user: String,
+    password: String,
+}
+
+impl Mountpoint {
+    fn parse(url: &str) -> Result<Mountpoint, IcecastError> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or("Icecast URLs must start with http://")?;
+        let (authority, mount) = rest
+            .split_once('/')
+            .ok_or("The Icecast URL has no mountpoint")?;
+        let (credentials, address) = match authority.rsplit_once('@') {
+            Some((credentials, address)) => (Some(credentials), address),
+            None => (None, authority),
+        };
+        let (user, password) = match credentials {
+            Some(credentials) => credentials
+                .split_once(':')
+                .unwrap_or((DEFAULT_USER, credentials)),
+            None => (DEFAULT_USER, ""),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| "Invalid port in the Icecast URL")?,
+            ),
+            None => (address, DEFAULT_PORT),
+        };
+        if host.is_empty() || mount.is_empty() {
+            return Err(format!("Invalid Icecast URL '{}'", url).into());
+        }
+        Ok(Mountpoint {
+            host: host.to_string(),
+            port,
+            mount: format!("/{}", mount),
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    /// Where the stream can be heard, without the credentials.
+    fn listen_url(&self) -> String {
+        format!("http://{}:{}{}", self.host, self.port, self.mount)
+    }
+}
+
+/// Broadcasts samples arriving on the tap to an Icecast mountpoint, reconnecting whenever
+/// the server drops the source. Each connection starts a fresh stream with its own headers.
+pub struct IcecastSource {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl IcecastSource {
+    pub fn start(
+        icecast: &IcecastConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<IcecastSource, IcecastError> {
+        let url = icecast.url.as_deref().ok_or("No Icecast URL is set")?;
+        let mountpoint = Mountpoint::parse(url)?;
+        let input_channels = config.channels as usize;
+        // Both formats carry mono or stereo; anything wider is folded onto two channels.
+        let stream_config = StreamConfig {
+            channels: config.channels.min(2),
+            ..config.clone()
+        };
+        let settings = EncoderSettings {
+            format: icecast.format.recording_format(),
+            bitrate_kbps: icecast.bitrate_kbps,
+            ..EncoderSettings::default()
+        };
+        // Fail now on a missing feature or an unsupported bitrate rather than on every reconnect.
+        settings.create_stream_encoder(Box::new(io::sink()), &stream_config, &Tags::default())?;
+
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *tap.lock().unwrap() = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let icecast = icecast.clone();
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut remapped: Vec<f32> = Vec::new();
+                let mut reported = false;
+                while !stop.load(Ordering::Acquire) {
+                    let tags = Tags {
+                        title: icecast.name.clone(),
+                        artist: "sound-amp".to_string(),
+                        date: Local::now().format("%Y-%m-%d").to_string(),
+                        comment: icecast.description.clone(),
+                    };
+                    let encoder = connect(&mountpoint, &icecast).and_then(|stream| {
+                        settings.create_stream_encoder(Box::new(stream), &stream_config, &tags)
+                    });
+                    let mut encoder = match encoder {
+                        Ok(encoder) => {
+                            eprintln!("Broadcasting to {}", mountpoint.listen_url());
+                            reported = false;
+                            encoder
+                        }
+                        Err(e) => {
+                            if !reported {
+                                eprintln!("Cannot connect to Icecast, retrying: {}", e);
+                                reported = true;
+                            }
+                            net::wait_to_reconnect(RECONNECT_INTERVAL, &mut consumer);
+                            continue;
+                        }
+                    };
+                    while !stop.load(Ordering::Acquire) {
+                        let n = consumer.pop_slice(&mut buffer);
+                        remapped.clear();
+                        resampler::remap_channels(
+                            &buffer[..n],
+                            input_channels,
+                            stream_config.channels as usize,
+                            &mut remapped,
+                        );
+                        if let Err(e) = encoder.write(&remapped) {
+                            eprintln!("Lost connection to Icecast: {}", e);
+                            break;
+                        }
+                        thread::sleep(SEND_INTERVAL);
+                    }
+                    if stop.load(Ordering::Acquire) {
+                        // Ends the Ogg stream properly; the server may already be gone.
+                        let _ = encoder.finalize();
+                    }
+                }
+            });
+        }
+        Ok(IcecastSource {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *self.tap.lock().unwrap() = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Logs in as a source with an HTTP PUT, which Icecast 2.4 and later understand.
+fn connect(mountpoint: &Mountpoint, icecast: &IcecastConfig) -> Result<TcpStream, IcecastError> {
+    let mut stream = TcpStream::connect((mountpoint.host.as_str(), mountpoint.port))?;
+    let credentials = format!("{}:{}", mountpoint.user, mountpoint.password);
+    let mut request = format!(
+        "PUT {} HTTP/1.1\r\n\
+         Host: {}:{}\r\n\
+         Authorization: Basic {}\r\n\
+         User-Agent: sound-amp/{}\r\n\
+         Content-Type: {}\r\n\
+         Ice-Name: {}\r\n\
+         Ice-Public: {}\r\n\
+         Ice-Bitrate: {}\r\n\
+         Expect: 100-continue\r\n",
+        mountpoint.mount,
+        mountpoint.host,
+        mountpoint.port,
+        base64(credentials.as_bytes()),
+        env!("CARGO_PKG_VERSION"),
+        icecast.format.content_type(),
+        icecast.name,
+        icecast.public as u8,
+        icecast.bitrate_kbps,
+    );
+    if !icecast.description.is_empty() {
+        request.push_str(&format!("Ice-Description: {}\r\n", icecast.description));
+    }
+    if !icecast.genre.is_empty() {
+        request.push_str(&format!("Ice-Genre: {}\r\n", icecast.genre));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let response = net::read_response_head(&mut stream)?;
+    let status = response.lines().next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    if code != "100" && code != "200" {
+        return Err(format!("Icecast refused the source: {}", status).into());
+    }
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
@@ -0,0 +1,193 @@
+//! Captures an impulse response of whatever the main link's output reaches its input through: a
+//! sweep is played out in place of the input, and what the input picks up divided by it, leaving
+//! how the speaker, the room and the microphone colour and stretch out a click. Saved as a WAV, it's
+//! a response the [convolution](crate::effects::ConvolutionConfig) can run other sounds through,
+//! and a measurement of the room to correct for.
+//!
+//! Like the [round-trip test](crate::loopback), the input is counted from the block the sweep went
+//! out in. The response starts just ahead of the loudest part of it, the direct sound, rather than
+//! with the round trip's worth of silence before it.
+
+use std::f64::consts::PI;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::recorder::{self, EncoderSettings, RecordingFormat, Tags};
+
+const SWEEP: Duration = Duration::from_secs(5);
+const LOW_HZ: f32 = 20.0;
+const HIGH_HZ: f32 = 20000.0;
+const AMPLITUDE: f32 = 0.5;
+/// Fade at each end of the sweep, so it doesn't click.
+const TAPER: Duration = Duration::from_millis(20);
+/// The longest round trip looked for.
+const MAX_LATENCY: Duration = Duration::from_secs(1);
+/// Length of the response saved, long enough for most rooms to have died away.
+const LENGTH: Duration = Duration::from_secs(1);
+/// Kept ahead of the direct sound, for the rise to it.
+const PRE_ROLL: Duration = Duration::from_millis(2);
+/// How much of the sweep's loudest the division is held back by, within its range and outside it:
+/// hardly at all where it played, even at the top, which it plays least of, and so much outside
+/// it that the noise there isn't brought up to full scale.
+const REGULARISATION: f32 = 1e-5;
+const OUT_OF_RANGE: f32 = 1.0;
+/// How far the direct sound has to stand out over the rest, or it's taken for noise.
+const MIN_PEAK_TO_RMS: f32 = 10.0;
+
+pub struct ImpulseCapture {
+    rate: u32,
+    channels: usize,
+    sweep: Vec<f32>,
+    /// Frames of the sweep played so far.
+    played: usize,
+    /// Each channel of the input from the block the sweep started in.
+    heard: Vec<Vec<f32>>,
+    wanted: usize,
+    /// Where the response is saved.
+    path: PathBuf,
+}
+
+impl ImpulseCapture {
+    /// A capture from an input of `config`, saved in `directory` once it's done.
+    pub fn new(config: &StreamConfig, directory: &Path) -> ImpulseCapture {
+        let rate = config.sample_rate.0;
+        let sweep = sweep(rate);
+        let channels = config.channels.max(1) as usize;
+        ImpulseCapture {
+            rate,
+            channels,
+            wanted: sweep.len() + frames(MAX_LATENCY + LENGTH, rate),
+            sweep,
+            played: 0,
+            heard: vec![Vec::new(); channels],
+            path: recorder::timestamped_path(directory, "sound-amp-ir", RecordingFormat::Wav),
+        }
+    }
+
+    /// Adds a block of the raw input.
+    pub fn listen(&mut self, samples: &[f32]) {
+        for frame in samples.chunks(self.channels) {
+            if self.done() {
+                break;
+            }
+            for (channel, &sample) in self.heard.iter_mut().zip(frame) {
+                channel.push(sample);
+            }
+        }
+    }
+
+    /// Puts the next stretch of the sweep, or silence once it's out, in place of a block going to
+    /// the output, so what comes back is only the sweep and can't feed back.
+    pub fn play(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_mut(self.channels) {
+            frame.fill(self.sweep.get(self.played).copied().unwrap_or(0.0));
+            self.played += 1;
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.heard[0].len() >= self.wanted
+    }
+
+    /// Works the response out from what was heard and saves it, a channel for each of the input's,
+    /// giving where it went, or why there's nothing worth saving.
+    pub fn finish(self) -> Result<PathBuf, String> {
+        let responses = self.deconvolve();
+        let (start, peak) = responses
+            .iter()
+            .flat_map(|response| response[..frames(MAX_LATENCY, self.rate)].iter().enumerate())
+            .fold((0, 0f32), |(at, peak), (i, s)| if s.abs() > peak { (i, s.abs()) } else { (at, peak) });
+        let start = start.saturating_sub(frames(PRE_ROLL, self.rate));
+        let length = frames(LENGTH, self.rate);
+        let kept: Vec<&[f32]> = responses.iter().map(|response| &response[start..start + length]).collect();
+        let energy = kept.iter().flat_map(|response| response.iter()).map(|s| s * s).sum::<f32>();
+        let rms = (energy / (length * self.channels) as f32).sqrt();
+        if peak < rms * MIN_PEAK_TO_RMS || peak == 0.0 {
+            return Err(
+                "Didn't hear the sweep come back; point the output at the input, or turn it up".to_string(),
+            );
+        }
+
+        let samples: Vec<f32> = (0..length)
+            .flat_map(|i| kept.iter().map(move |response| response[i]))
+            .collect();
+        let config = StreamConfig {
+            channels: self.channels as u16,
+            sample_rate: SampleRate(self.rate),
+            buffer_size: BufferSize::Default,
+        };
+        let save = || {
+            let mut encoder = EncoderSettings::default().create_encoder(&self.path, &config, &Tags::default())?;
+            encoder.write(&samples)?;
+            encoder.finalize()
+        };
+        save().map_err(|e| format!("Cannot save the impulse response to {}: {}", self.path.display(), e))?;
+        Ok(self.path)
+    }
+
+    /// Each channel of what was heard divided by the sweep, as long as what was heard.
+    fn deconvolve(&self) -> Vec<Vec<f32>> {
+        let size = (self.heard[0].len() + self.sweep.len()).next_power_of_two();
+        let mut planner = FftPlanner::new();
+        let (forward, inverse) = (planner.plan_fft_forward(size), planner.plan_fft_inverse(size));
+        let spectrum = |signal: &[f32]| {
+            let mut buffer: Vec<Complex<f32>> = signal.iter().map(|&s| Complex::new(s, 0.0)).collect();
+            buffer.resize(size, Complex::default());
+            forward.process(&mut buffer);
+            buffer
+        };
+        let sweep = spectrum(&self.sweep);
+        let loudest = sweep.iter().map(|bin| bin.norm_sqr()).fold(0.0, f32::max);
+        let range = LOW_HZ..=high_hz(self.rate);
+        let floors: Vec<f32> = (0..size)
+            .map(|bin| {
+                // The bins past Nyquist mirror the ones under it.
+                let hz = bin.min(size - bin) as f32 * self.rate as f32 / size as f32;
+                loudest * if range.contains(&hz) { REGULARISATION } else { OUT_OF_RANGE }
+            })
+            .collect();
+        self.heard
+            .iter()
+            .map(|heard| {
+                let mut response: Vec<Complex<f32>> = spectrum(heard)
+                    .iter()
+                    .zip(&sweep)
+                    .zip(&floors)
+                    .map(|((h, s), floor)| h * s.conj() / (s.norm_sqr() + floor))
+                    .collect();
+                inverse.process(&mut response);
+                response[..heard.len()].iter().map(|bin| bin.re / size as f32).collect()
+            })
+            .collect()
+    }
+}
+
+/// A sweep from [`LOW_HZ`] to [`HIGH_HZ`], or as near it as the rate goes, rising exponentially
+/// so each octave gets as long and any distortion ends up ahead of the response, where it's cut
+/// off. Worked out in f64, since the phase runs to tens of thousands of radians.
+fn sweep(rate: u32) -> Vec<f32> {
+    let high = high_hz(rate) as f64;
+    let (len, taper) = (frames(SWEEP, rate), frames(TAPER, rate));
+    let (duration, octaves) = (SWEEP.as_secs_f64(), (high / LOW_HZ as f64).ln());
+    (0..len)
+        .map(|i| {
+            let t = i as f64 / rate as f64;
+            let phase = 2.0 * PI * LOW_HZ as f64 * duration / octaves * ((t / duration * octaves).exp() - 1.0);
+            let edge = i.min(len - 1 - i);
+            let fade = if edge < taper { 0.5 - 0.5 * (PI * edge as f64 / taper as f64).cos() } else { 1.0 };
+            (AMPLITUDE as f64 * fade * phase.sin()) as f32
+        })
+        .collect()
+}
+
+fn high_hz(rate: u32) -> f32 {
+    HIGH_HZ.min(0.45 * rate as f32)
+}
+
+fn frames(duration: Duration, rate: u32) -> usize {
+    (duration.as_secs_f64() * rate as f64) as usize
+}
@@ -0,0 +1,34 @@
+//! Low latency mode, for hearing one's own voice or instrument through the amp without an echo:
+//! the devices run with the smallest buffers they can keep up with, the ring between the input and
+//! the output is kept close to empty, and the effects that add latency or take long to run are
+//! left out of the main link's chain.
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LowLatencyConfig {
+    /// Whether the player starts out in low latency mode.
+    pub enabled: bool,
+    /// The buffer size tried first, in frames. Each time the link drops out at a size, it's
+    /// linked again at twice that, up to `max_buffer_frames`.
+    pub buffer_frames: u32,
+    pub max_buffer_frames: u32,
+}
+
+impl Default for LowLatencyConfig {
+    fn default() -> Self {
+        LowLatencyConfig {
+            enabled: false,
+            buffer_frames: 64,
+            max_buffer_frames: 1024,
+        }
+    }
+}
+
+impl LowLatencyConfig {
+    /// The buffer size to try after dropouts at `frames`, or `None` when that's as big as it goes.
+    pub fn step_up(&self, frames: u32) -> Option<u32> {
+        (frames < self.max_buffer_frames).then(|| (frames * 2).min(self.max_buffer_frames))
+    }
+}
@@ -0,0 +1,97 @@
+//! The sound-amp engine: links an input (a device, stdin, a network sender, or on Windows one
+//! application's audio) to the default output device through a volume stage and a ducking
+//! [`graph`], and fans the processed signal out to recorders, pipes, network senders and streaming
+//! targets.
+//!
+//! A frontend spawns the player thread with [`setup_stream`] and drives it with
+//! [`PlayerCommand`]s; [`LinkStatus`], [`Meters`](meter::Meters) and
+//! [`PlaybackState`](playback::PlaybackState) are how it reads back what's running. Settings come
+//! from a [`Config`](config::Config), usually loaded from the user's config file and overridden by
+//! command-line flags, and are fixed per run in [`LinkSettings`]. The [`schedule`], `osc` and
+//! `midi` modules are further ways of sending the same commands, and [`offline`] runs a profile's
+//! effects over a file instead.
+//!
+//! Only the link itself is built by default. The `network` feature adds the network inputs,
+//! senders and streaming targets, `flac`, `mp3` and `opus` the recording formats beyond WAV, and
+//! `midi` and `osc` the modules of the same name, and `transcribe` captions from a whisper.cpp
+//! server.
+
+#[cfg(feature = "network")]
+pub mod airplay;
+#[cfg(windows)]
+pub mod app_capture;
+pub mod autogain;
+pub mod backend;
+pub mod chain;
+pub mod config;
+pub mod correlation;
+#[cfg(feature = "network")]
+pub mod discovery;
+pub mod dither;
+pub mod dose;
+pub mod ducking;
+pub mod effects;
+mod engine;
+mod error;
+pub mod graph;
+pub mod impulse;
+#[cfg(feature = "network")]
+pub mod icecast;
+pub mod latency;
+pub mod looper;
+pub mod loudness;
+pub mod loopback;
+pub mod meter;
+pub mod metronome;
+#[cfg(feature = "midi")]
+pub mod midi;
+#[cfg(feature = "network")]
+pub mod net;
+pub mod offline;
+#[cfg(feature = "osc")]
+pub mod osc;
+pub mod outputs;
+pub mod pipe;
+pub mod playback;
+pub mod recorder;
+pub mod remix;
+pub mod replay;
+pub mod resampler;
+pub mod room;
+pub mod safety;
+#[cfg(feature = "network")]
+pub mod rtp;
+pub mod schedule;
+pub mod session;
+pub mod sleep;
+#[cfg(feature = "network")]
+pub mod snapcast;
+pub mod soundboard;
+pub mod spatial;
+pub mod spectrogram;
+pub mod spl;
+pub mod standby;
+pub mod stats;
+pub mod talk;
+#[cfg(feature = "transcribe")]
+pub mod transcribe;
+pub mod tuner;
+pub mod vad;
+pub mod virtual_devices;
+pub mod workers;
+
+pub use error::Error;
+pub use engine::{
+    find_input_device, setup_stream, EngineState, InputSource, LinkInfo, LinkSettings,
+    LinkStatus, LiveSettings, OutputInfo, PlayerCommand, Transition,
+};
+#[cfg(feature = "network")]
+pub use engine::StreamSettings;
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Locks something an audio callback shares, even if a panic left it poisoned: the callback that
+/// panicked is torn down with its link, and what it held carries over to the next one as it is.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,122 @@
+//! The round-trip latency test: a chirp is played out of the link in place of its input, and
+//! picked up again by the input, through the air from a speaker to a microphone or over a cable
+//! from the output to the input. How far into the input it's found, by cross-correlation, is the
+//! true delay of the path for the devices and buffer sizes in use, rather than what they report.
+//!
+//! Both ends are counted in input frames from the block the chirp went out in, since the link
+//! hands each input block to the output in the callback it came in.
+
+use std::f32::consts::{PI, TAU};
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+const CHIRP: Duration = Duration::from_millis(100);
+const LOW_HZ: f32 = 300.0;
+const HIGH_HZ: f32 = 6000.0;
+const AMPLITUDE: f32 = 0.5;
+/// Fade at each end of the chirp, so it doesn't click.
+const TAPER: Duration = Duration::from_millis(5);
+/// The longest round trip looked for.
+const MAX_LATENCY: Duration = Duration::from_secs(1);
+/// How alike the input has to be to the chirp where it's found, from 0 to 1.
+const MIN_MATCH: f32 = 0.3;
+
+pub struct LoopbackTest {
+    rate: u32,
+    channels: usize,
+    chirp: Vec<f32>,
+    /// Frames of the chirp played so far.
+    played: usize,
+    /// The input's first channel from the block the chirp started in.
+    heard: Vec<f32>,
+    wanted: usize,
+}
+
+impl LoopbackTest {
+    pub fn new(config: &StreamConfig) -> LoopbackTest {
+        let rate = config.sample_rate.0;
+        let chirp = chirp(rate);
+        LoopbackTest {
+            rate,
+            channels: config.channels as usize,
+            wanted: chirp.len() + frames(MAX_LATENCY, rate),
+            chirp,
+            played: 0,
+            heard: Vec::new(),
+        }
+    }
+
+    /// Adds a block of the raw input.
+    pub fn listen(&mut self, samples: &[f32]) {
+        let frames = samples.chunks(self.channels).map(|frame| frame[0]);
+        let room = self.wanted.saturating_sub(self.heard.len());
+        self.heard.extend(frames.take(room));
+    }
+
+    /// Puts the next stretch of the chirp, or silence once it's out, in place of a block going to
+    /// the output, so what comes back is only the chirp and can't feed back.
+    pub fn play(&mut self, block: &mut [f32]) {
+        for frame in block.chunks_mut(self.channels) {
+            frame.fill(self.chirp.get(self.played).copied().unwrap_or(0.0));
+            self.played += 1;
+        }
+    }
+
+    pub fn done(&self) -> bool {
+        self.heard.len() >= self.wanted
+    }
+
+    /// The round trip, or why it wasn't found.
+    pub fn finish(self) -> Result<Duration, String> {
+        let size = (self.heard.len() + self.chirp.len()).next_power_of_two();
+        let mut planner = FftPlanner::new();
+        let (forward, inverse) = (planner.plan_fft_forward(size), planner.plan_fft_inverse(size));
+        let spectrum = |signal: &[f32]| {
+            let mut buffer: Vec<Complex<f32>> = signal.iter().map(|&s| Complex::new(s, 0.0)).collect();
+            buffer.resize(size, Complex::default());
+            forward.process(&mut buffer);
+            buffer
+        };
+        let (heard, chirp) = (spectrum(&self.heard), spectrum(&self.chirp));
+        let mut correlation: Vec<Complex<f32>> = heard.iter().zip(&chirp).map(|(h, c)| h * c.conj()).collect();
+        inverse.process(&mut correlation);
+
+        let lags = self.heard.len() - self.chirp.len() + 1;
+        let lag = (0..lags)
+            .max_by(|&a, &b| correlation[a].re.total_cmp(&correlation[b].re))
+            .unwrap_or_default();
+        // How alike they are there, whatever the level it came back at.
+        let energy = |signal: &[f32]| signal.iter().map(|s| s * s).sum::<f32>();
+        let heard = &self.heard[lag..lag + self.chirp.len()];
+        let matched = correlation[lag].re / size as f32 / (energy(&self.chirp) * energy(heard)).sqrt().max(1e-9);
+        if matched < MIN_MATCH {
+            return Err(format!(
+                "Didn't hear the chirp come back within {} ms; point the output at the input, or turn it up",
+                MAX_LATENCY.as_millis()
+            ));
+        }
+        Ok(Duration::from_secs_f64(lag as f64 / self.rate as f64))
+    }
+}
+
+/// A sweep from [`LOW_HZ`] to [`HIGH_HZ`], rising exponentially so each octave gets as long.
+fn chirp(rate: u32) -> Vec<f32> {
+    let (len, taper) = (frames(CHIRP, rate), frames(TAPER, rate));
+    let (duration, octaves) = (CHIRP.as_secs_f32(), (HIGH_HZ / LOW_HZ).ln());
+    (0..len)
+        .map(|i| {
+            let t = i as f32 / rate as f32;
+            let phase = TAU * LOW_HZ * duration / octaves * ((t / duration * octaves).exp() - 1.0);
+            let edge = i.min(len - 1 - i);
+            let fade = if edge < taper { 0.5 - 0.5 * (PI * edge as f32 / taper as f32).cos() } else { 1.0 };
+            AMPLITUDE * fade * phase.sin()
+        })
+        .collect()
+}
+
+fn frames(duration: Duration, rate: u32) -> usize {
+    (duration.as_secs_f64() * rate as f64) as usize
+}
@@ -0,0 +1,213 @@
+//! A looper on the main link, like a looper pedal's: a phrase recorded from the input plays round
+//! and round under the live signal, more layers can be played over it and kept, and it can be
+//! cleared to start again. The loop lives outside the chain, so it carries on when the chain is
+//! set up again for a new preset or toggle.
+
+use std::sync::{Arc, Mutex};
+
+use cpal::StreamConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::chain::Processor;
+use crate::lock;
+use crate::soundboard::Hotkey;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LooperConfig {
+    /// Longest loop that can be recorded; recording stops and the loop starts playing once it's
+    /// reached.
+    pub max_seconds: f32,
+    /// Level of the loop under the live signal.
+    pub volume: f32,
+    /// The key that starts recording, closes the loop, and goes in and out of overdubbing.
+    pub record_key: Hotkey,
+    /// The key that throws the loop away.
+    pub clear_key: Hotkey,
+}
+
+impl Default for LooperConfig {
+    fn default() -> Self {
+        LooperConfig {
+            max_seconds: 60.0,
+            volume: 1.0,
+            record_key: Hotkey::Char('g'),
+            clear_key: Hotkey::Char('G'),
+        }
+    }
+}
+
+/// What the looper is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LooperState {
+    /// Nothing recorded.
+    #[default]
+    Empty,
+    /// Recording the first layer, which sets the loop's length.
+    Recording,
+    Playing,
+    /// Playing, and adding the input to the loop as it goes round.
+    Overdubbing,
+}
+
+/// The looper's two controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LooperAction {
+    /// Like a looper pedal's footswitch: starts recording, then closes the loop and plays it,
+    /// then goes in and out of overdubbing.
+    Record,
+    /// Stops and throws away everything recorded.
+    Clear,
+}
+
+/// The recorded loop, in the format of the chain it was recorded in.
+#[derive(Default)]
+struct Loop {
+    state: LooperState,
+    samples: Vec<f32>,
+    /// Where the next sample is played from.
+    position: usize,
+    /// Samples the loop can hold while it's recording.
+    capacity: usize,
+    channels: u16,
+    rate: u32,
+    /// Counts the stages made for the loop. Only the latest records and moves it on; one it
+    /// replaced, still fading out of the chain, just plays along.
+    generation: u64,
+}
+
+/// The loop, shared between the player, which works the controls, and the stage at the end of the
+/// main link's chain.
+#[derive(Clone)]
+pub struct Looper {
+    shared: Arc<Mutex<Loop>>,
+    max_seconds: f32,
+    volume: f32,
+}
+
+impl Looper {
+    pub fn new(config: &LooperConfig) -> Looper {
+        Looper {
+            shared: Default::default(),
+            max_seconds: config.max_seconds,
+            volume: config.volume,
+        }
+    }
+
+    pub fn act(&self, action: LooperAction) {
+        let mut shared = lock(&self.shared);
+        match (action, shared.state) {
+            (LooperAction::Clear, _) => {
+                *shared = Loop {
+                    channels: shared.channels,
+                    rate: shared.rate,
+                    generation: shared.generation,
+                    ..Default::default()
+                }
+            }
+            // Nothing's known about the format to record in until a link has run.
+            (LooperAction::Record, LooperState::Empty) if shared.rate == 0 => {}
+            (LooperAction::Record, LooperState::Empty) => {
+                // Set aside here rather than in the audio callback.
+                let frames = (self.max_seconds.max(0.0) * shared.rate as f32) as usize;
+                shared.capacity = frames * shared.channels as usize;
+                let capacity = shared.capacity;
+                shared.samples.reserve_exact(capacity);
+                shared.state = LooperState::Recording;
+            }
+            (LooperAction::Record, LooperState::Recording) => shared.close(),
+            (LooperAction::Record, LooperState::Playing) => shared.state = LooperState::Overdubbing,
+            (LooperAction::Record, LooperState::Overdubbing) => shared.state = LooperState::Playing,
+        }
+    }
+
+    pub fn state(&self) -> LooperState {
+        lock(&self.shared).state
+    }
+
+    /// The stage for a chain running on a stream of `config`. A loop recorded in another format
+    /// can't be played in this one, so it's cleared.
+    pub fn stage(&self, config: &StreamConfig) -> LoopStage {
+        let mut shared = lock(&self.shared);
+        if (shared.channels, shared.rate) != (config.channels, config.sample_rate.0) {
+            *shared = Loop {
+                channels: config.channels,
+                rate: config.sample_rate.0,
+                generation: shared.generation,
+                ..Default::default()
+            };
+        }
+        shared.generation += 1;
+        LoopStage {
+            shared: Arc::clone(&self.shared),
+            volume: self.volume,
+            generation: shared.generation,
+        }
+    }
+}
+
+impl Loop {
+    /// Ends the first layer where it is and starts playing it from the top.
+    fn close(&mut self) {
+        self.position = 0;
+        self.state = if self.samples.is_empty() {
+            LooperState::Empty
+        } else {
+            LooperState::Playing
+        };
+    }
+}
+
+/// Records the input into the loop and plays the loop under it, as the looper's controls say.
+pub struct LoopStage {
+    shared: Arc<Mutex<Loop>>,
+    volume: f32,
+    generation: u64,
+}
+
+impl Processor for LoopStage {
+    fn process(&mut self, samples: &mut [f32]) {
+        let mut shared = lock(&self.shared);
+        let shared = &mut *shared;
+        if shared.generation != self.generation {
+            if matches!(
+                shared.state,
+                LooperState::Playing | LooperState::Overdubbing
+            ) {
+                let looped = shared.samples[shared.position..]
+                    .iter()
+                    .chain(shared.samples.iter().cycle());
+                for (sample, played) in samples.iter_mut().zip(looped) {
+                    *sample += played * self.volume;
+                }
+            }
+            return;
+        }
+        match shared.state {
+            LooperState::Empty => {}
+            LooperState::Recording => {
+                let room = shared.capacity - shared.samples.len();
+                shared
+                    .samples
+                    .extend_from_slice(&samples[..samples.len().min(room)]);
+                if samples.len() >= room {
+                    shared.close();
+                }
+            }
+            LooperState::Playing | LooperState::Overdubbing => {
+                let overdubbing = shared.state == LooperState::Overdubbing;
+                for sample in samples {
+                    let looped = &mut shared.samples[shared.position];
+                    let played = *looped;
+                    if overdubbing {
+                        *looped += *sample;
+                    }
+                    *sample += played * self.volume;
+                    shared.position = (shared.position + 1) % shared.samples.len();
+                }
+            }
+        }
+    }
+}
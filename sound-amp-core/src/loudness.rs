@@ -0,0 +1,170 @@
+//! A loudness meter for the output, by ITU-R BS.1770 and EBU R 128, which is how the streaming
+//! platforms measure what they turn a programme down to: momentary over the last 400 ms, short-term
+//! over the last 3 s, and integrated over everything since the link started or the stats were
+//! last cleared, in LUFS.
+//!
+//! The signal is K-weighted first, a shelf that lifts the top the way the head does and a
+//! high-pass that leaves out the deep bass, and the channels' energy added up. The integrated
+//! reading leaves out silence, and then everything 10 LU under what's left, so the pauses between
+//! the talking don't drag it down.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use std::mem;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use serde::Serialize;
+
+use crate::chain::Processor;
+use crate::effects::Biquad;
+
+/// The K-weighting's shelf and high-pass, in Hz, dB and Q, as BS.1770 gives them at 48 kHz
+/// worked back to analogue, so they can be made again for any rate.
+const SHELF_HZ: f64 = 1681.974450955533;
+const SHELF_DB: f64 = 3.999843853973347;
+const SHELF_Q: f64 = 0.7071752369554196;
+const HIGH_PASS_HZ: f64 = 38.13547087602444;
+const HIGH_PASS_Q: f64 = 0.5003270373238773;
+/// Takes out the K-weighting's lift at 1 kHz, so a full-scale sine there reads -3.01 LUFS.
+const OFFSET_DB: f32 = -0.691;
+/// How often the readings move, and the hop between the gating blocks.
+const STEP: Duration = Duration::from_millis(100);
+const MOMENTARY_STEPS: usize = 4;
+const SHORT_TERM_STEPS: usize = 30;
+/// Blocks under this are silence, and left out of the integrated reading.
+const ABSOLUTE_GATE_LUFS: f32 = -70.0;
+/// Blocks this far under the loudness of the rest are left out too.
+const RELATIVE_GATE_LU: f32 = -10.0;
+/// The blocks for the integrated reading are counted into bins of this width from the absolute
+/// gate up to [`TOP_LUFS`], so it doesn't grow with the session.
+const BIN_LU: f32 = 0.1;
+const TOP_LUFS: f32 = 10.0;
+
+/// Readings in LUFS, each missing until there's enough of the output for it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Loudness {
+    pub momentary: Option<f32>,
+    pub short_term: Option<f32>,
+    pub integrated: Option<f32>,
+}
+
+pub struct LoudnessMeter {
+    weighting: [Biquad; 2],
+    channels: usize,
+    step_frames: usize,
+    /// The block being weighted, kept so the callbacks don't allocate.
+    weighted: Vec<f32>,
+    /// Sum of squares over the channels and frames of the step so far.
+    energy: f64,
+    frames: usize,
+    /// Mean square of each of the last steps, oldest first.
+    steps: VecDeque<f64>,
+    /// Number and summed mean square of the gating blocks in each bin.
+    bins: Vec<(u64, f64)>,
+}
+
+impl LoudnessMeter {
+    pub fn new(config: &StreamConfig) -> LoudnessMeter {
+        let bins = ((TOP_LUFS - ABSOLUTE_GATE_LUFS) / BIN_LU) as usize;
+        LoudnessMeter {
+            weighting: k_weighting(config),
+            channels: config.channels.max(1) as usize,
+            step_frames: (STEP.as_secs_f64() * config.sample_rate.0 as f64) as usize,
+            weighted: Vec::new(),
+            energy: 0.0,
+            frames: 0,
+            steps: VecDeque::with_capacity(SHORT_TERM_STEPS + 1),
+            bins: vec![(0, 0.0); bins],
+        }
+    }
+
+    /// Adds a block of the output, and gives the readings if a step ended in it.
+    pub fn process(&mut self, samples: &[f32]) -> Option<Loudness> {
+        let mut weighted = mem::take(&mut self.weighted);
+        weighted.clear();
+        weighted.extend_from_slice(samples);
+        for filter in &mut self.weighting {
+            filter.process(&mut weighted);
+        }
+        let mut reading = None;
+        for frame in weighted.chunks(self.channels) {
+            self.energy += frame.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+            self.frames += 1;
+            if self.frames == self.step_frames {
+                let mean_square = self.energy / self.frames as f64;
+                (self.energy, self.frames) = (0.0, 0);
+                reading = Some(self.step(mean_square));
+            }
+        }
+        self.weighted = weighted;
+        reading
+    }
+
+    /// Leaves the integrated reading to start over.
+    pub fn restart(&mut self) {
+        self.bins.fill((0, 0.0));
+    }
+
+    fn step(&mut self, mean_square: f64) -> Loudness {
+        if self.steps.len() == SHORT_TERM_STEPS {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(mean_square);
+        let over = |steps: usize| {
+            (self.steps.len() >= steps).then(|| self.steps.iter().rev().take(steps).sum::<f64>() / steps as f64)
+        };
+        let momentary = over(MOMENTARY_STEPS);
+        // Each momentary block is a gating block, overlapping the last by three quarters.
+        if let Some(block) = momentary.filter(|&block| lufs(block) >= ABSOLUTE_GATE_LUFS) {
+            let bin = &mut self.bins[bin(lufs(block))];
+            bin.0 += 1;
+            bin.1 += block;
+        }
+        Loudness {
+            momentary: momentary.map(lufs),
+            short_term: over(SHORT_TERM_STEPS).map(lufs),
+            integrated: self.integrated(),
+        }
+    }
+
+    fn integrated(&self) -> Option<f32> {
+        let mean_over = |bins: &[(u64, f64)]| {
+            let (count, energy) = bins
+                .iter()
+                .fold((0, 0.0), |(count, energy), bin| (count + bin.0, energy + bin.1));
+            (count > 0).then(|| energy / count as f64)
+        };
+        let ungated = mean_over(&self.bins)?;
+        let threshold = bin((lufs(ungated) + RELATIVE_GATE_LU).max(ABSOLUTE_GATE_LUFS));
+        mean_over(&self.bins[threshold..]).map(lufs)
+    }
+}
+
+fn k_weighting(config: &StreamConfig) -> [Biquad; 2] {
+    let prewarp = |frequency: f64| (PI * frequency / config.sample_rate.0 as f64).tan();
+    let biquad = |b: [f64; 3], a: [f64; 3]| Biquad::new(b.map(|b| b as f32), a.map(|a| a as f32), config);
+    let (k, shelf) = (prewarp(SHELF_HZ), 10f64.powf(SHELF_DB / 20.0));
+    let band = shelf.powf(0.4996667741545416);
+    let shelf = biquad(
+        [shelf + band * k / SHELF_Q + k * k, 2.0 * (k * k - shelf), shelf - band * k / SHELF_Q + k * k],
+        [1.0 + k / SHELF_Q + k * k, 2.0 * (k * k - 1.0), 1.0 - k / SHELF_Q + k * k],
+    );
+    let k = prewarp(HIGH_PASS_HZ);
+    let a0 = 1.0 + k / HIGH_PASS_Q + k * k;
+    // Unlike the shelf's, its numerator isn't divided by `a0`.
+    let high_pass = biquad(
+        [a0, -2.0 * a0, a0],
+        [a0, 2.0 * (k * k - 1.0), 1.0 - k / HIGH_PASS_Q + k * k],
+    );
+    [shelf, high_pass]
+}
+
+fn lufs(mean_square: f64) -> f32 {
+    OFFSET_DB + 10.0 * mean_square.max(1e-20).log10() as f32
+}
+
+fn bin(lufs: f32) -> usize {
+    let top = ((TOP_LUFS - ABSOLUTE_GATE_LUFS) / BIN_LU) as usize - 1;
+    (((lufs - ABSOLUTE_GATE_LUFS) / BIN_LU) as usize).min(top)
+}
@@ -0,0 +1,355 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::loudness::Loudness;
+use crate::spectrogram::SpectrogramHistory;
+use crate::stats::Stats;
+
+/// Levels below this read as silence.
+const FLOOR_DB: f32 = -120.0;
+/// Seconds of levels kept by a [`LevelHistory`].
+pub const HISTORY_SECONDS: usize = 60;
+/// Frames kept by a [`StereoScope`], about 40 ms at 48 kHz.
+pub const SCOPE_FRAMES: usize = 2048;
+
+/// Peak and RMS of a signal since the meter was last read.
+#[derive(Default)]
+pub struct LevelMeter {
+    live: Accumulator,
+    /// Read once a second into the [`LevelHistory`], so it and the live meters don't reset each other.
+    history: Accumulator,
+    /// Like `live`'s peak, but read by the metrics endpoint, for the same reason.
+    metrics_peak: AtomicU32,
+    /// Like `live`, but read by the WebSocket API, which streams levels while others are polled.
+    websocket: Accumulator,
+    /// And by the gRPC API, which streams them too.
+    grpc: Accumulator,
+}
+
+#[derive(Default)]
+struct Accumulator {
+    /// Non-negative `f32` bits, which order the same way as the values.
+    peak: AtomicU32,
+    /// Sum of squares as `f64` bits.
+    energy: AtomicU64,
+    samples: AtomicU64,
+}
+
+/// A meter reading in dBFS.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Level {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+impl LevelMeter {
+    /// Adds a block of samples; called from the audio callbacks.
+    pub fn update(&self, samples: &[f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        let energy: f64 = samples.iter().map(|&s| s as f64 * s as f64).sum();
+        self.live.add(peak, energy, samples.len());
+        self.history.add(peak, energy, samples.len());
+        self.websocket.add(peak, energy, samples.len());
+        self.grpc.add(peak, energy, samples.len());
+        self.metrics_peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Reads the levels since the last call and starts over.
+    pub fn take(&self) -> Level {
+        self.live.take()
+    }
+
+    /// Like [`take`](Self::take), for the [`LevelHistory`].
+    pub fn take_history(&self) -> Level {
+        self.history.take()
+    }
+
+    /// Like [`take`](Self::take), for the WebSocket API.
+    pub fn take_websocket(&self) -> Level {
+        self.websocket.take()
+    }
+
+    /// Like [`take`](Self::take), for the gRPC API.
+    pub fn take_grpc(&self) -> Level {
+        self.grpc.take()
+    }
+
+    /// The peak in dBFS since the last call.
+    pub fn take_metrics_peak(&self) -> f32 {
+        to_db(f32::from_bits(self.metrics_peak.swap(0, Ordering::Relaxed)))
+    }
+}
+
+impl Accumulator {
+    fn add(&self, peak: f32, energy: f64, samples: usize) {
+        self.peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        let _ = self
+            .energy
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + energy).to_bits())
+            });
+        self.samples.fetch_add(samples as u64, Ordering::Relaxed);
+    }
+
+    fn take(&self) -> Level {
+        let peak = f32::from_bits(self.peak.swap(0, Ordering::Relaxed));
+        let energy = f64::from_bits(self.energy.swap(0, Ordering::Relaxed));
+        let samples = self.samples.swap(0, Ordering::Relaxed);
+        let rms = if samples == 0 {
+            0.0
+        } else {
+            (energy / samples as f64).sqrt() as f32
+        };
+        Level {
+            peak: to_db(peak),
+            rms: to_db(rms),
+        }
+    }
+}
+
+/// The output's level over the last [`HISTORY_SECONDS`], a second to an entry, oldest first, to
+/// see whether it's been too quiet or too loud for a while.
+#[derive(Default)]
+pub struct LevelHistory {
+    levels: Mutex<VecDeque<Level>>,
+}
+
+impl LevelHistory {
+    pub fn push(&self, level: Level) {
+        let mut levels = self.levels.lock().unwrap();
+        if levels.len() == HISTORY_SECONDS {
+            levels.pop_front();
+        }
+        levels.push_back(level);
+    }
+
+    pub fn levels(&self) -> Vec<Level> {
+        self.levels.lock().unwrap().iter().copied().collect()
+    }
+}
+
+/// The output's latest frames, its first two channels, for drawing the stereo field.
+#[derive(Default)]
+pub struct StereoScope {
+    frames: Mutex<VecDeque<(f32, f32)>>,
+}
+
+impl StereoScope {
+    /// Adds a block of interleaved frames; called from the output graph, which skips the block
+    /// rather than wait for whoever is drawing.
+    pub fn update(&self, samples: &[f32], channels: usize) {
+        if channels < 2 {
+            return;
+        }
+        let Ok(mut frames) = self.frames.try_lock() else {
+            return;
+        };
+        for frame in samples.chunks_exact(channels) {
+            if frames.len() == SCOPE_FRAMES {
+                frames.pop_front();
+            }
+            frames.push_back((frame[0], frame[1]));
+        }
+    }
+
+    /// The latest frames as left and right, oldest first.
+    pub fn frames(&self) -> Vec<(f32, f32)> {
+        self.frames.lock().unwrap().iter().copied().collect()
+    }
+
+    pub fn clear(&self) {
+        self.frames.lock().unwrap().clear();
+    }
+}
+
+fn to_db(gain: f32) -> f32 {
+    (20.0 * gain.log10()).max(FLOOR_DB)
+}
+
+/// Levels and dropouts of the running link, shared between the callbacks and whoever displays them.
+pub struct Meters {
+    /// The processed input.
+    pub input: LevelMeter,
+    /// The raw input, read by the player to tell when it's gone quiet for
+    /// [standby](crate::standby), or come back.
+    pub raw_input: LevelMeter,
+    /// The final output mix.
+    pub output: LevelMeter,
+    /// Input blocks that didn't fit the ring and output blocks it couldn't fill.
+    pub xruns: AtomicU64,
+    /// How full the ring between the input and the output was at the last output callback,
+    /// from 0 to 1, as `f32` bits.
+    buffer_fill: AtomicU32,
+    pub input_callbacks: CallbackTimer,
+    pub output_callbacks: CallbackTimer,
+    /// Whether the [`VoiceDetector`](crate::vad::VoiceDetector) hears speech in the raw input.
+    pub voice: AtomicBool,
+    /// Whether the [`LoudnessGuard`](crate::safety::LoudnessGuard) says the output has been loud
+    /// for too long.
+    pub loud: AtomicBool,
+    /// Sound level of the raw input in dB SPL, as `f32` bits, or NaN while there's no link.
+    spl: AtomicU32,
+    /// Pitch of the raw input in Hz, as `f32` bits, or NaN while the tuner's off or hears none.
+    pitch: AtomicU32,
+    /// The output's [true peak](crate::safety::TruePeak) since it was last read, as `f32` bits.
+    true_peak: AtomicU32,
+    /// The output's [loudness](crate::loudness) in LUFS, momentary, short-term and integrated, as
+    /// `f32` bits, or NaN while there isn't one.
+    loudness: [AtomicU32; 3],
+    /// How alike the output's first two channels are, from -1 to +1, as `f32` bits, or NaN while
+    /// it's silent or mono.
+    correlation: AtomicU32,
+    /// Set for the output graph to start the integrated loudness over.
+    restart_loudness: AtomicBool,
+    /// Clipping, limiting and gating over the session.
+    pub stats: Stats,
+    pub history: LevelHistory,
+    pub scope: StereoScope,
+    pub spectrogram: SpectrogramHistory,
+}
+
+impl Default for Meters {
+    fn default() -> Self {
+        Meters {
+            input: Default::default(),
+            raw_input: Default::default(),
+            output: Default::default(),
+            xruns: Default::default(),
+            buffer_fill: Default::default(),
+            input_callbacks: Default::default(),
+            output_callbacks: Default::default(),
+            voice: Default::default(),
+            loud: Default::default(),
+            spl: AtomicU32::new(f32::NAN.to_bits()),
+            pitch: AtomicU32::new(f32::NAN.to_bits()),
+            true_peak: Default::default(),
+            loudness: [(); 3].map(|_| AtomicU32::new(f32::NAN.to_bits())),
+            restart_loudness: Default::default(),
+            correlation: AtomicU32::new(f32::NAN.to_bits()),
+            stats: Default::default(),
+            history: Default::default(),
+            scope: Default::default(),
+            spectrogram: Default::default(),
+        }
+    }
+}
+
+impl Meters {
+    pub fn xrun(&self) {
+        self.xruns.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_buffer_fill(&self, fill: f32) {
+        self.buffer_fill.store(fill.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn buffer_fill(&self) -> f32 {
+        f32::from_bits(self.buffer_fill.load(Ordering::Relaxed))
+    }
+
+    pub fn voice(&self) -> bool {
+        self.voice.load(Ordering::Relaxed)
+    }
+
+    pub fn loud(&self) -> bool {
+        self.loud.load(Ordering::Relaxed)
+    }
+
+    pub fn set_spl(&self, db_spl: Option<f32>) {
+        self.spl
+            .store(db_spl.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+    }
+
+    /// The input's sound level from the [`SplMeter`](crate::spl::SplMeter), if there's a link.
+    pub fn spl(&self) -> Option<f32> {
+        Some(f32::from_bits(self.spl.load(Ordering::Relaxed))).filter(|db| !db.is_nan())
+    }
+
+    pub fn set_pitch(&self, hz: Option<f32>) {
+        self.pitch
+            .store(hz.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+    }
+
+    /// The input's pitch from the [`Tuner`](crate::tuner::Tuner), if it's on and hears one.
+    pub fn pitch(&self) -> Option<f32> {
+        Some(f32::from_bits(self.pitch.load(Ordering::Relaxed))).filter(|hz| !hz.is_nan())
+    }
+
+    /// Adds the output's true peak over a block; called from the output graph.
+    pub fn update_true_peak(&self, peak: f32) {
+        self.true_peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+        self.stats.count_true_peak(peak);
+    }
+
+    /// The output's true peak in dBTP since the last call.
+    pub fn take_true_peak(&self) -> f32 {
+        to_db(f32::from_bits(self.true_peak.swap(0, Ordering::Relaxed)))
+    }
+
+    pub fn set_loudness(&self, loudness: Loudness) {
+        let readings = [loudness.momentary, loudness.short_term, loudness.integrated];
+        for (stored, reading) in self.loudness.iter().zip(readings) {
+            stored.store(reading.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        let [momentary, short_term, integrated] = self
+            .loudness
+            .each_ref()
+            .map(|stored| Some(f32::from_bits(stored.load(Ordering::Relaxed))).filter(|lufs| !lufs.is_nan()));
+        Loudness {
+            momentary,
+            short_term,
+            integrated,
+        }
+    }
+
+    pub fn set_correlation(&self, correlation: Option<f32>) {
+        self.correlation
+            .store(correlation.unwrap_or(f32::NAN).to_bits(), Ordering::Relaxed);
+    }
+
+    /// The output's [correlation](crate::correlation), if it's stereo and not silent.
+    pub fn correlation(&self) -> Option<f32> {
+        Some(f32::from_bits(self.correlation.load(Ordering::Relaxed))).filter(|c| !c.is_nan())
+    }
+
+    /// Starts the [stats](Stats) over, and the integrated loudness with them, since both are
+    /// readings of the whole session.
+    pub fn clear_stats(&self) {
+        self.stats.clear();
+        self.restart_loudness.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether the integrated loudness is to start over; called from the output graph.
+    pub fn take_loudness_restart(&self) -> bool {
+        self.restart_loudness.swap(false, Ordering::Relaxed)
+    }
+}
+
+/// How many times an audio callback ran and how long it took altogether.
+#[derive(Default)]
+pub struct CallbackTimer {
+    pub count: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl CallbackTimer {
+    pub fn record(&self, duration: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,116 @@
+//! A click track mixed into the main link's output, for playing along to while monitoring through
+//! the amp. The first beat of every bar is accented, higher and louder than the rest.
+
+use std::f32::consts::TAU;
+use std::sync::{Arc, Mutex};
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+/// The metronome of the running link, mixed into its output when there is one.
+pub type MetronomeBus = Arc<Mutex<Option<Metronome>>>;
+
+/// Length of a click, and how fast it dies away within it.
+const CLICK_SECONDS: f32 = 0.03;
+const DECAY_SECONDS: f32 = 0.006;
+/// Pitch of the clicks, an accented one a fifth above the others.
+const CLICK_HZ: f32 = 1000.0;
+const ACCENT_HZ: f32 = 1500.0;
+/// Slowest and fastest tempo it can be set to.
+pub const BPM_RANGE: (f32, f32) = (20.0, 400.0);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MetronomeConfig {
+    /// Whether it's clicking when sound-amp starts.
+    pub enabled: bool,
+    pub bpm: f32,
+    /// Beats in a bar, the upper number of the time signature; the tempo counts these beats.
+    pub beats_per_bar: u32,
+    /// How much louder the first beat of a bar is than the others.
+    pub accent_db: f32,
+    /// Level of the other beats in the output mix.
+    pub volume: f32,
+}
+
+impl Default for MetronomeConfig {
+    fn default() -> Self {
+        MetronomeConfig {
+            enabled: false,
+            bpm: 120.0,
+            beats_per_bar: 4,
+            accent_db: 6.0,
+            volume: 0.3,
+        }
+    }
+}
+
+/// Clicks on every beat, the first of each bar accented.
+pub struct Metronome {
+    click: Vec<f32>,
+    accent: Vec<f32>,
+    rate: f32,
+    channels: usize,
+    beats_per_bar: u32,
+    frames_per_beat: f64,
+    /// Frames since the current beat started.
+    position: f64,
+    /// The current beat of the bar, from 0.
+    beat: u32,
+}
+
+impl Metronome {
+    /// Starts at the top of a bar, for an output of `output`.
+    pub fn new(config: &MetronomeConfig, output: &StreamConfig) -> Metronome {
+        let rate = output.sample_rate.0 as f32;
+        let click = |frequency: f32, level: f32| -> Vec<f32> {
+            (0..(CLICK_SECONDS * rate) as usize)
+                .map(|i| {
+                    let t = i as f32 / rate;
+                    level * (-t / DECAY_SECONDS).exp() * (TAU * frequency * t).sin()
+                })
+                .collect()
+        };
+        let mut metronome = Metronome {
+            click: click(CLICK_HZ, config.volume),
+            accent: click(
+                ACCENT_HZ,
+                config.volume * 10f32.powf(config.accent_db / 20.0),
+            ),
+            rate,
+            channels: output.channels as usize,
+            beats_per_bar: config.beats_per_bar.max(1),
+            frames_per_beat: 0.0,
+            position: 0.0,
+            beat: 0,
+        };
+        metronome.set_bpm(config.bpm);
+        metronome
+    }
+
+    /// Changes the tempo from the next beat on, without losing the place in the bar.
+    pub fn set_bpm(&mut self, bpm: f32) {
+        let bpm = bpm.clamp(BPM_RANGE.0, BPM_RANGE.1);
+        self.frames_per_beat = 60.0 * self.rate as f64 / bpm as f64;
+    }
+
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        for frame in output.chunks_mut(self.channels) {
+            if self.position >= self.frames_per_beat {
+                self.position -= self.frames_per_beat;
+                self.beat = (self.beat + 1) % self.beats_per_bar;
+            }
+            let click = if self.beat == 0 {
+                &self.accent
+            } else {
+                &self.click
+            };
+            if let Some(s) = click.get(self.position as usize) {
+                for sample in frame {
+                    *sample += s;
+                }
+            }
+            self.position += 1.0;
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use serde::Deserialize;
+use toml_edit::{value, ArrayOfTables, DocumentMut, Item, Table};
+
+use crate::PlayerCommand;
+
+pub type MidiError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct MidiConfig {
+    pub enabled: bool,
+    /// Part of the name of the input port to open; the first port when unset.
+    pub port: Option<String>,
+    pub mappings: Vec<CcMapping>,
+}
+
+/// Binds a control change to a parameter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CcMapping {
+    pub cc: u8,
+    /// MIDI channel 1-16; any channel when unset.
+    #[serde(default)]
+    pub channel: Option<u8>,
+    pub action: MidiAction,
+    /// What CC value 0 maps to, for continuous actions; the action's own range when unset.
+    #[serde(default)]
+    pub min: Option<f32>,
+    /// What CC value 127 maps to, for continuous actions.
+    #[serde(default)]
+    pub max: Option<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MidiAction {
+    /// Master gain, scaled from `min` to `max`, 0 to 2 unless they're set.
+    Volume,
+    /// Gain in dB of the first EQ band of the profile, its first peaking or high-shelf effect,
+    /// scaled from `min` to `max`, -12 to 12 unless they're set.
+    #[serde(rename = "eq-1")]
+    Eq1,
+    /// The second EQ band's gain, like `eq-1`'s.
+    #[serde(rename = "eq-2")]
+    Eq2,
+    #[serde(rename = "eq-3")]
+    Eq3,
+    #[serde(rename = "eq-4")]
+    Eq4,
+    /// Muted while the value is 64 or more, for latching buttons and switches.
+    Mute,
+    /// Toggles mute on every non-zero value, for momentary buttons.
+    MuteToggle,
+    /// Opens the push-to-talk gate while the value is 64 or more, for a talk button or pedal.
+    Talk,
+}
+
+impl MidiAction {
+    pub const ALL: [MidiAction; 8] = [
+        MidiAction::Volume,
+        MidiAction::Eq1,
+        MidiAction::Eq2,
+        MidiAction::Eq3,
+        MidiAction::Eq4,
+        MidiAction::Mute,
+        MidiAction::MuteToggle,
+        MidiAction::Talk,
+    ];
+
+    /// The name used in the config.
+    pub fn name(self) -> &'static str {
+        match self {
+            MidiAction::Volume => "volume",
+            MidiAction::Eq1 => "eq-1",
+            MidiAction::Eq2 => "eq-2",
+            MidiAction::Eq3 => "eq-3",
+            MidiAction::Eq4 => "eq-4",
+            MidiAction::Mute => "mute",
+            MidiAction::MuteToggle => "mute-toggle",
+            MidiAction::Talk => "talk",
+        }
+    }
+
+    /// What CC values 0 and 127 map to when the mapping doesn't say, for continuous actions.
+    fn default_range(self) -> Option<(f32, f32)> {
+        match self {
+            MidiAction::Volume => Some((0.0, 2.0)),
+            MidiAction::Eq1 | MidiAction::Eq2 | MidiAction::Eq3 | MidiAction::Eq4 => {
+                Some((-12.0, 12.0))
+            }
+            MidiAction::Mute | MidiAction::MuteToggle | MidiAction::Talk => None,
+        }
+    }
+}
+
+/// The mappings in use, shared with the UI so it can show them and start a MIDI learn.
+#[derive(Debug, Default)]
+pub struct MidiState {
+    pub mappings: Vec<CcMapping>,
+    /// The action bound to the next control that moves.
+    pub learning: Option<MidiAction>,
+}
+
+pub type SharedMidi = Arc<Mutex<MidiState>>;
+
+impl MidiState {
+    /// Binds `action` to the control that was just moved, replacing its previous binding
+    /// and whatever that control was bound to before.
+    fn learn(&mut self, action: MidiAction, channel: u8, cc: u8) {
+        let previous = self.mappings.iter().find(|m| m.action == action);
+        let mapping = CcMapping {
+            cc,
+            channel: Some(channel),
+            action,
+            min: previous.and_then(|m| m.min),
+            max: previous.and_then(|m| m.max),
+        };
+        self.mappings
+            .retain(|m| m.action != action && !m.matches(channel, cc));
+        self.mappings.push(mapping);
+    }
+}
+
+impl CcMapping {
+    fn matches(&self, channel: u8, cc: u8) -> bool {
+        self.cc == cc && self.channel.is_none_or(|c| c == channel)
+    }
+
+    /// Where `value` puts a continuous action between `min` and `max`.
+    fn scale(&self, value: u8) -> f32 {
+        let (min, max) = self.action.default_range().unwrap_or((0.0, 1.0));
+        let (min, max) = (self.min.unwrap_or(min), self.max.unwrap_or(max));
+        min + (max - min) * value as f32 / 127.0
+    }
+
+    fn command(&self, value: u8) -> Option<PlayerCommand> {
+        let eq = |band| {
+            Some(PlayerCommand::SetEqGain {
+                band,
+                gain_db: self.scale(value),
+            })
+        };
+        match self.action {
+            MidiAction::Volume => Some(PlayerCommand::SetVolume(self.scale(value))),
+            MidiAction::Eq1 => eq(0),
+            MidiAction::Eq2 => eq(1),
+            MidiAction::Eq3 => eq(2),
+            MidiAction::Eq4 => eq(3),
+            MidiAction::Mute => Some(PlayerCommand::SetMuted(value >= 64)),
+            MidiAction::MuteToggle => (value > 0).then_some(PlayerCommand::ToggleMute),
+            MidiAction::Talk => Some(PlayerCommand::SetTalking(value >= 64)),
+        }
+    }
+}
+
+/// Opens the configured input port and turns mapped control changes into player commands
+/// for as long as the connection is kept. Mappings learned along the way are written back
+/// to the config at `config_path`.
+pub fn connect(
+    config: &MidiConfig,
+    config_path: PathBuf,
+    state: SharedMidi,
+    player_channel: Sender<PlayerCommand>,
+) -> Result<MidiInputConnection<()>, MidiError> {
+    let mut input = MidiInput::new("sound-amp")?;
+    input.ignore(Ignore::All);
+    let ports = input.ports();
+    let port = ports
+        .iter()
+        .find(|port| {
+            let name = input.port_name(port).unwrap_or_default();
+            config
+                .port
+                .as_deref()
+                .is_none_or(|wanted| name.contains(wanted))
+        })
+        .ok_or("No matching MIDI input port")?;
+    eprintln!("MIDI input: {}", input.port_name(port)?);
+    state.lock().unwrap().mappings = config.mappings.clone();
+    let connection = input
+        .connect(
+            port,
+            "sound-amp-control",
+            move |_, message, _| {
+                let Some((channel, cc, value)) = control_change(message) else {
+                    return;
+                };
+                let mut state = state.lock().unwrap();
+                if let Some(action) = state.learning.take() {
+                    state.learn(action, channel, cc);
+                    if let Err(e) = save_mappings(&config_path, &state.mappings) {
+                        eprintln!("Cannot save MIDI mappings: {}", e);
+                    }
+                    return;
+                }
+                for mapping in state.mappings.iter().filter(|m| m.matches(channel, cc)) {
+                    if let Some(command) = mapping.command(value) {
+                        let _ = player_channel.send(command);
+                    }
+                }
+            },
+            (),
+        )
+        .map_err(|e| e.to_string())?;
+    Ok(connection)
+}
+
+/// Channel (1-16), controller and value of a control change message.
+fn control_change(message: &[u8]) -> Option<(u8, u8, u8)> {
+    match *message {
+        [status, cc, value] if status & 0xf0 == 0xb0 => Some(((status & 0x0f) + 1, cc, value)),
+        _ => None,
+    }
+}
+
+/// Rewrites the `[[midi.mappings]]` of the config file, keeping the rest of it as it was.
+fn save_mappings(path: &Path, mappings: &[CcMapping]) -> Result<(), MidiError> {
+    let mut document: DocumentMut = match fs::read_to_string(path) {
+        Ok(text) => text.parse()?,
+        Err(_) => DocumentMut::new(),
+    };
+    let midi = document
+        .entry("midi")
+        .or_insert_with(toml_edit::table)
+        .as_table_mut()
+        .ok_or("`midi` in the config isn't a table")?;
+    let mut tables = ArrayOfTables::new();
+    for mapping in mappings {
+        let mut table = Table::new();
+        table["cc"] = value(mapping.cc as i64);
+        if let Some(channel) = mapping.channel {
+            table["channel"] = value(channel as i64);
+        }
+        table["action"] = value(mapping.action.name());
+        if let Some(min) = mapping.min {
+            table["min"] = value(shortest(min));
+        }
+        if let Some(max) = mapping.max {
+            table["max"] = value(shortest(max));
+        }
+        tables.push(table);
+    }
+    midi["mappings"] = Item::ArrayOfTables(tables);
+    fs::write(path, document.to_string())?;
+    Ok(())
+}
+
+/// Widens without picking up float noise, so 0.1 is written as 0.1.
+fn shortest(n: f32) -> f64 {
+    n.to_string().parse().unwrap_or(n as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(toml: &str) -> CcMapping {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn an_eq_band_goes_from_minus_to_plus_12_db_unless_its_range_is_set() {
+        let band = mapping("cc = 20\naction = \"eq-2\"");
+        assert!(matches!(
+            band.command(0),
+            Some(PlayerCommand::SetEqGain { band: 1, gain_db }) if gain_db == -12.0
+        ));
+        assert!(matches!(
+            band.command(127),
+            Some(PlayerCommand::SetEqGain { band: 1, gain_db }) if gain_db == 12.0
+        ));
+
+        let band = mapping("cc = 20\naction = \"eq-1\"\nmin = -6.0\nmax = 0.0");
+        assert!(matches!(
+            band.command(127),
+            Some(PlayerCommand::SetEqGain { band: 0, gain_db }) if gain_db == 0.0
+        ));
+    }
+
+    #[test]
+    fn the_volume_goes_from_0_to_2_unless_its_range_is_set() {
+        let volume = mapping("cc = 7\naction = \"volume\"");
+        assert!(matches!(volume.command(127), Some(PlayerCommand::SetVolume(v)) if v == 2.0));
+        let volume = mapping("cc = 7\naction = \"volume\"\nmax = 1.0");
+        assert!(matches!(volume.command(127), Some(PlayerCommand::SetVolume(v)) if v == 1.0));
+        assert!(matches!(volume.command(0), Some(PlayerCommand::SetVolume(v)) if v == 0.0));
+    }
+
+    #[test]
+    fn learning_a_control_keeps_the_range_of_the_action_it_binds() {
+        let mut state = MidiState {
+            mappings: vec![mapping("cc = 20\naction = \"eq-1\"\nmin = -6.0\nmax = 6.0")],
+            learning: None,
+        };
+        state.learn(MidiAction::Eq1, 1, 21);
+        assert_eq!(state.mappings.len(), 1);
+        assert_eq!(state.mappings[0].cc, 21);
+        assert_eq!((state.mappings[0].min, state.mappings[0].max), (Some(-6.0), Some(6.0)));
+    }
+}
@@ -0,0 +1,506 @@
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::{Consumer, RingBuffer};
+use serde::Deserialize;
+
+use crate::lock;
+use crate::pipe::{self, PcmFormat};
+use crate::recorder::RecordingTap;
+#[cfg(feature = "opus")]
+use crate::resampler::{self, LinearResampler};
+use crate::{InputSource, PlayerCommand};
+
+// Stream layout: a header of MAGIC, version (u16), channels (u16), sample rate (u32) and codec (u16),
+// then packets of a byte length (u32) followed by that many bytes of f32 samples or one Opus frame.
+// Everything is little-endian.
+const MAGIC: &[u8; 4] = b"SAMP";
+const VERSION: u16 = 2;
+const HEADER_LEN: usize = 14;
+/// Anything bigger than a second of 8-channel 192 kHz audio is a corrupt stream.
+const MAX_PACKET_LEN: usize = 8 * 192_000 * 4;
+
+const SEND_INTERVAL: Duration = Duration::from_millis(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a new connection has to announce its format before the next one gets a turn.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(2);
+/// Opus runs at 48 kHz here whatever the input rate, so both ends agree without negotiating.
+#[cfg(feature = "opus")]
+const OPUS_RATE: u32 = 48000;
+/// The longest frame Opus produces, 120 ms at 48 kHz, per channel.
+#[cfg(feature = "opus")]
+const MAX_OPUS_FRAME: usize = 5760;
+
+pub type NetworkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// What `--send` puts on the wire; receivers follow whatever the sender announces.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum NetworkCodec {
+    /// Raw f32 samples at the input format.
+    #[default]
+    Pcm,
+    /// 48 kHz mono or stereo Opus, for links that can't carry raw PCM. Needs the `opus` feature.
+    Opus,
+}
+
+impl NetworkCodec {
+    fn id(self) -> u16 {
+        match self {
+            NetworkCodec::Pcm => 0,
+            NetworkCodec::Opus => 1,
+        }
+    }
+
+    fn from_id(id: u16) -> Option<NetworkCodec> {
+        match id {
+            0 => Some(NetworkCodec::Pcm),
+            1 => Some(NetworkCodec::Opus),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub codec: NetworkCodec,
+    /// Opus bitrate.
+    pub bitrate_kbps: u32,
+    /// Audio per Opus packet; Opus accepts 2.5, 5, 10, 20, 40 or 60 ms. Shorter frames cut latency.
+    pub frame_ms: f32,
+    /// Advertise `--listen` and `--rtp-listen` over mDNS and list other instances' receivers.
+    pub discovery: bool,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            codec: NetworkCodec::default(),
+            bitrate_kbps: 96,
+            frame_ms: 10.0,
+            discovery: true,
+        }
+    }
+}
+
+/// A sender that connected to `--listen`, and the format it announced.
+pub struct NetworkInput {
+    stream: TcpStream,
+    decoder: PacketDecoder,
+    pub config: StreamConfig,
+}
+
+/// Accepts senders on `port`; each new connection becomes the input of a fresh link,
+/// replacing whatever the previous one was, and is resampled to the output like any other input.
+pub fn listen(port: u16, player_channel: Sender<PlayerCommand>) -> io::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", port))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let input = stream.map_err(NetworkError::from).and_then(|mut stream| {
+                // A connection that never says anything, like a port scan, gives up its turn.
+                stream.set_read_timeout(Some(HEADER_TIMEOUT))?;
+                let (config, codec) = read_header(&mut stream)?;
+                let decoder = PacketDecoder::new(codec, &config)?;
+                Ok(NetworkInput {
+                    stream,
+                    decoder,
+                    config,
+                })
+            });
+            match input {
+                Ok(input) => {
+                    let command = PlayerCommand::Start(InputSource::Network(input));
+                    if player_channel.send(command).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => eprintln!("Rejected network sender: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn read_header(stream: &mut TcpStream) -> io::Result<(StreamConfig, NetworkCodec)> {
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let invalid = |message: &str| io::Error::new(ErrorKind::InvalidData, message.to_string());
+    if &header[..4] != MAGIC {
+        return Err(invalid("not a sound-amp stream"));
+    }
+    if u16::from_le_bytes([header[4], header[5]]) != VERSION {
+        return Err(invalid("unsupported stream version"));
+    }
+    let channels = u16::from_le_bytes([header[6], header[7]]);
+    let sample_rate = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if channels == 0 || sample_rate == 0 {
+        return Err(invalid("empty stream format"));
+    }
+    let codec = NetworkCodec::from_id(u16::from_le_bytes([header[12], header[13]]))
+        .ok_or_else(|| invalid("unknown codec"))?;
+    let config = StreamConfig {
+        channels,
+        sample_rate: SampleRate(sample_rate),
+        buffer_size: BufferSize::Default,
+    };
+    Ok((config, codec))
+}
+
+fn write_header(
+    stream: &mut impl Write,
+    config: &StreamConfig,
+    codec: NetworkCodec,
+) -> io::Result<()> {
+    let mut header = Vec::with_capacity(HEADER_LEN);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&VERSION.to_le_bytes());
+    header.extend_from_slice(&config.channels.to_le_bytes());
+    header.extend_from_slice(&config.sample_rate.0.to_le_bytes());
+    header.extend_from_slice(&codec.id().to_le_bytes());
+    stream.write_all(&header)
+}
+
+/// Turns samples of the input format into packets for the wire.
+enum PacketEncoder {
+    Pcm,
+    #[cfg(feature = "opus")]
+    Opus {
+        encoder: opus::Encoder,
+        input_channels: usize,
+        channels: usize,
+        resampler: Option<LinearResampler>,
+        /// Samples waiting for a whole frame.
+        pending: Vec<f32>,
+        remapped: Vec<f32>,
+        frame_len: usize,
+    },
+}
+
+impl PacketEncoder {
+    #[cfg_attr(not(feature = "opus"), allow(unused_variables))]
+    fn new(network: &NetworkConfig, config: &StreamConfig) -> Result<PacketEncoder, NetworkError> {
+        match network.codec {
+            NetworkCodec::Pcm => Ok(PacketEncoder::Pcm),
+            #[cfg(feature = "opus")]
+            NetworkCodec::Opus => {
+                let channels = config.channels.min(2) as usize;
+                let opus_channels = if channels == 1 {
+                    opus::Channels::Mono
+                } else {
+                    opus::Channels::Stereo
+                };
+                let frame_len = (OPUS_RATE as f32 * network.frame_ms / 1000.0) as usize;
+                if ![120, 240, 480, 960, 1920, 2880].contains(&frame_len) {
+                    return Err(format!("Opus can't use {} ms frames", network.frame_ms).into());
+                }
+                let mut encoder =
+                    opus::Encoder::new(OPUS_RATE, opus_channels, opus::Application::LowDelay)?;
+                encoder.set_bitrate(opus::Bitrate::Bits(network.bitrate_kbps as i32 * 1000))?;
+                Ok(PacketEncoder::Opus {
+                    encoder,
+                    input_channels: config.channels as usize,
+                    channels,
+                    resampler: (config.sample_rate.0 != OPUS_RATE)
+                        .then(|| LinearResampler::new(config.sample_rate.0, OPUS_RATE, channels)),
+                    pending: Vec::new(),
+                    remapped: Vec::new(),
+                    frame_len: frame_len * channels,
+                })
+            }
+            #[cfg(not(feature = "opus"))]
+            NetworkCodec::Opus => Err("sound-amp was built without the `opus` feature".into()),
+        }
+    }
+
+    /// The format the receiver ends up with.
+    fn wire_config(&self, config: &StreamConfig) -> StreamConfig {
+        match self {
+            PacketEncoder::Pcm => config.clone(),
+            #[cfg(feature = "opus")]
+            PacketEncoder::Opus { channels, .. } => StreamConfig {
+                channels: *channels as u16,
+                sample_rate: SampleRate(OPUS_RATE),
+                buffer_size: BufferSize::Default,
+            },
+        }
+    }
+
+    /// Appends packets for `samples` to `out`, holding back whatever doesn't fill an Opus frame yet.
+    fn encode(&mut self, samples: &[f32], out: &mut Vec<u8>) -> Result<(), NetworkError> {
+        match self {
+            PacketEncoder::Pcm => {
+                out.extend_from_slice(&((samples.len() * 4) as u32).to_le_bytes());
+                pipe::encode_f32(samples, out);
+            }
+            #[cfg(feature = "opus")]
+            PacketEncoder::Opus {
+                encoder,
+                input_channels,
+                channels,
+                resampler,
+                pending,
+                remapped,
+                frame_len,
+            } => {
+                remapped.clear();
+                resampler::remap_channels(samples, *input_channels, *channels, remapped);
+                match resampler {
+                    Some(resampler) => resampler.process(remapped, pending),
+                    None => pending.extend_from_slice(remapped),
+                }
+                while pending.len() >= *frame_len {
+                    let packet = encoder.encode_vec_float(&pending[..*frame_len], 4000)?;
+                    out.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+                    out.extend_from_slice(&packet);
+                    pending.drain(..*frame_len);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns packets from the wire back into samples.
+enum PacketDecoder {
+    Pcm,
+    #[cfg(feature = "opus")]
+    Opus(opus::Decoder, Vec<f32>),
+}
+
+impl PacketDecoder {
+    #[cfg_attr(not(feature = "opus"), allow(unused_variables))]
+    fn new(codec: NetworkCodec, config: &StreamConfig) -> Result<PacketDecoder, NetworkError> {
+        match codec {
+            NetworkCodec::Pcm => Ok(PacketDecoder::Pcm),
+            #[cfg(feature = "opus")]
+            NetworkCodec::Opus => {
+                let channels = match config.channels {
+                    1 => opus::Channels::Mono,
+                    2 => opus::Channels::Stereo,
+                    n => return Err(format!("Opus can't carry {} channels", n).into()),
+                };
+                let decoder = opus::Decoder::new(config.sample_rate.0, channels)?;
+                Ok(PacketDecoder::Opus(
+                    decoder,
+                    vec![0.0; MAX_OPUS_FRAME * config.channels as usize],
+                ))
+            }
+            #[cfg(not(feature = "opus"))]
+            NetworkCodec::Opus => Err("sound-amp was built without the `opus` feature".into()),
+        }
+    }
+
+    fn decode(&mut self, packet: &[u8], out: &mut Vec<f32>) -> Result<(), NetworkError> {
+        match self {
+            PacketDecoder::Pcm => PcmFormat::F32.decode(packet, out),
+            #[cfg(feature = "opus")]
+            PacketDecoder::Opus(decoder, frame) => {
+                let channels = frame.len() / MAX_OPUS_FRAME;
+                let frames = decoder.decode_float(packet, frame, false)?;
+                out.extend_from_slice(&frame[..frames * channels]);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Feeds packets from a connected sender to `process`, like an input stream callback would.
+/// The reader stops once this is dropped or the sender disconnects.
+pub struct NetworkReader {
+    stop: Arc<AtomicBool>,
+}
+
+impl NetworkReader {
+    pub fn spawn<F>(input: NetworkInput, mut process: F) -> NetworkReader
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let NetworkInput {
+            stream,
+            mut decoder,
+            ..
+        } = input;
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                // The timeout lets the reader notice it's been dropped while the sender is quiet.
+                if let Err(e) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+                    eprintln!("Cannot receive from network: {}", e);
+                    return;
+                }
+                let mut stream = BufReader::new(stream);
+                let mut bytes: Vec<u8> = Vec::new();
+                let mut samples: Vec<f32> = Vec::new();
+                loop {
+                    let mut len = [0u8; 4];
+                    if read_fully(&mut stream, &mut len, &stop).is_err() {
+                        break;
+                    }
+                    let len = u32::from_le_bytes(len) as usize;
+                    if len > MAX_PACKET_LEN {
+                        eprintln!("Network sender sent a {} byte packet, disconnecting", len);
+                        break;
+                    }
+                    bytes.resize(len, 0);
+                    if read_fully(&mut stream, &mut bytes, &stop).is_err() {
+                        break;
+                    }
+                    samples.clear();
+                    if let Err(e) = decoder.decode(&bytes, &mut samples) {
+                        eprintln!("Cannot decode network packet, disconnecting: {}", e);
+                        break;
+                    }
+                    process(&samples);
+                }
+                if !stop.load(Ordering::Acquire) {
+                    eprintln!("Network sender disconnected");
+                }
+            });
+        }
+        NetworkReader { stop }
+    }
+}
+
+impl Drop for NetworkReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// `read_exact` that keeps waiting through read timeouts until the reader is stopped.
+fn read_fully(stream: &mut impl Read, mut buffer: &mut [u8], stop: &AtomicBool) -> io::Result<()> {
+    while !buffer.is_empty() {
+        match stream.read(buffer) {
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(n) => buffer = &mut buffer[n..],
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                if stop.load(Ordering::Acquire) {
+                    return Err(e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Reads up to the blank line that ends an HTTP or RTSP response's headers, and no further.
+pub fn read_response_head(stream: &mut TcpStream) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut byte = [0u8];
+    while !head.ends_with(b"\r\n\r\n") {
+        if stream.read(&mut byte)? == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        head.push(byte[0]);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+/// Waits `interval` before a sender tries its connection again, then drops what its tap queued up
+/// meanwhile, which is stale by now.
+pub fn wait_to_reconnect(interval: Duration, consumer: &mut Consumer<f32>) {
+    thread::sleep(interval);
+    let stale = consumer.len();
+    consumer.discard(stale);
+}
+
+/// Sends samples arriving on the tap to a `--listen`ing instance, reconnecting whenever the link drops.
+pub struct NetworkSender {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl NetworkSender {
+    pub fn start(
+        address: &str,
+        network: &NetworkConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<NetworkSender, NetworkError> {
+        let mut encoder = PacketEncoder::new(network, config)?;
+        let wire_config = encoder.wire_config(config);
+        // Opus frames go out as soon as they're full; raw PCM can be batched.
+        let interval = match network.codec {
+            NetworkCodec::Pcm => SEND_INTERVAL,
+            NetworkCodec::Opus => {
+                SEND_INTERVAL.min(Duration::from_secs_f32(network.frame_ms / 2000.0))
+            }
+        };
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let network_codec = network.codec;
+            let address = address.to_string();
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut bytes: Vec<u8> = Vec::new();
+                let mut reported = false;
+                while !stop.load(Ordering::Acquire) {
+                    let stream = match TcpStream::connect(&address) {
+                        Ok(stream) => {
+                            reported = false;
+                            stream
+                        }
+                        Err(e) => {
+                            if !reported {
+                                eprintln!("Cannot connect to {}, retrying: {}", address, e);
+                                reported = true;
+                            }
+                            wait_to_reconnect(RECONNECT_INTERVAL, &mut consumer);
+                            continue;
+                        }
+                    };
+                    let _ = stream.set_nodelay(true);
+                    let mut stream = BufWriter::new(stream);
+                    if write_header(&mut stream, &wire_config, network_codec)
+                        .and_then(|_| stream.flush())
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    while !stop.load(Ordering::Acquire) {
+                        let n = consumer.pop_slice(&mut buffer);
+                        if n > 0 {
+                            bytes.clear();
+                            if let Err(e) = encoder.encode(&buffer[..n], &mut bytes) {
+                                eprintln!("Cannot encode network packet: {}", e);
+                            }
+                            if stream
+                                .write_all(&bytes)
+                                .and_then(|_| stream.flush())
+                                .is_err()
+                            {
+                                eprintln!("Lost connection to {}", address);
+                                break;
+                            }
+                        }
+                        thread::sleep(interval);
+                    }
+                }
+            });
+        }
+        Ok(NetworkSender {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
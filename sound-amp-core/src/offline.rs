@@ -0,0 +1,62 @@
+//! Runs a profile's effects over a file rather than a live input, as fast as they go, to prepare
+//! a recording with them or hear what they do to a known one. It's the same
+//! [`EffectChain`] a link runs in its input callback, fed the file a callback's block at a time,
+//! at the file's own rate and channels.
+
+use std::error;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::chain::EffectChain;
+use crate::config::Profile;
+use crate::playback;
+use crate::recorder::{EncoderSettings, Tags};
+use crate::workers::WorkerPool;
+
+/// Frames handed to the chain at a time, about what a device callback gets.
+const BLOCK_FRAMES: usize = 512;
+
+/// Runs `input` through `profile`'s trim, effects and volume into `output`, encoded by `encoder`,
+/// and gives how long the file plays for. The effects' latency is taken back out, so the output
+/// lines up with the input and is as long.
+pub fn process_file(
+    input: &Path,
+    output: &Path,
+    profile: &Profile,
+    encoder: &EncoderSettings,
+    tags: &Tags,
+    workers: Option<WorkerPool>,
+) -> Result<Duration, Box<dyn error::Error + Send + Sync>> {
+    let config = playback::file_config(input)?;
+    let mut samples = playback::decode_all(input, &config)?;
+    let channels = config.channels as usize;
+    let frames = samples.len() / channels;
+
+    let mut chain = EffectChain::default();
+    if let Some(workers) = workers {
+        chain.set_workers(workers);
+    }
+    chain.trim = profile.trim;
+    chain.volume = profile.volume;
+    // As the main link loads a profile, one chain per ear unless they're linked.
+    if profile.link_channels {
+        chain.load(&profile.effects, &config);
+    } else {
+        let right = profile.right_effects.as_deref().unwrap_or(&profile.effects);
+        chain.load_split(&profile.effects, right, &config);
+    }
+
+    // Silence after the end, to flush out what the effects are holding back.
+    let latency = chain.latency() * channels;
+    samples.resize(samples.len() + latency, 0.0);
+    let mut encoder = encoder.create_encoder(output, &config, tags)?;
+    let mut skip = latency;
+    for block in samples.chunks_mut(BLOCK_FRAMES * channels) {
+        chain.process(block);
+        let skipped = skip.min(block.len());
+        skip -= skipped;
+        encoder.write(&block[skipped..])?;
+    }
+    encoder.finalize()?;
+    Ok(Duration::from_secs_f64(frames as f64 / config.sample_rate.0 as f64))
+}
@@ -0,0 +1,134 @@
+use std::io;
+use std::net::UdpSocket;
+use std::sync::mpsc::Sender;
+use std::thread;
+
+use rosc::{OscMessage, OscPacket, OscType};
+
+use crate::config::{Profile, RecordingConfig};
+use crate::{find_input_device, InputSource, PlayerCommand};
+
+const MAX_DATAGRAM: usize = 65536;
+
+/// Takes OSC messages on UDP `port` and turns them into player commands:
+///
+/// - `/soundamp/volume <gain>` sets the gain, `/soundamp/volume/step <amount>` nudges it
+/// - `/soundamp/eq/<band>/gain <dB>` sets an EQ band's gain, numbering the profile's peaking and
+///   high-shelf effects from 1
+/// - `/soundamp/start <index or name>` links that input device, `/soundamp/stop` tears the link down
+/// - `/soundamp/preset <name>` applies a profile
+/// - `/soundamp/record <on>` starts or stops recording
+/// - `/soundamp/sample/<index>` triggers a soundboard sample
+/// - `/soundamp/talk <held>` opens the push-to-talk gate while the talk button is held
+///
+/// Controllers send buttons as 1 on press and 0 on release, so argument-less actions
+/// only fire on a non-zero (or missing) argument.
+pub fn listen(
+    port: u16,
+    profiles: Vec<Profile>,
+    recording_config: RecordingConfig,
+    player_channel: Sender<PlayerCommand>,
+) -> io::Result<()> {
+    let socket = UdpSocket::bind(("0.0.0.0", port))?;
+    thread::spawn(move || {
+        let mut datagram = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let len = match socket.recv(&mut datagram) {
+                Ok(len) => len,
+                Err(e) => {
+                    eprintln!("Cannot receive OSC: {}", e);
+                    break;
+                }
+            };
+            let packet = match rosc::decoder::decode_udp(&datagram[..len]) {
+                Ok((_, packet)) => packet,
+                Err(e) => {
+                    eprintln!("Ignoring malformed OSC packet: {:?}", e);
+                    continue;
+                }
+            };
+            let mut messages = Vec::new();
+            flatten(packet, &mut messages);
+            for message in messages {
+                let Some(command) = to_command(&message, &profiles, &recording_config) else {
+                    continue;
+                };
+                if player_channel.send(command).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Unpacks bundles; their time tags are ignored and everything applies right away.
+fn flatten(packet: OscPacket, messages: &mut Vec<OscMessage>) {
+    match packet {
+        OscPacket::Message(message) => messages.push(message),
+        OscPacket::Bundle(bundle) => {
+            for packet in bundle.content {
+                flatten(packet, messages);
+            }
+        }
+    }
+}
+
+fn to_command(
+    message: &OscMessage,
+    profiles: &[Profile],
+    recording_config: &RecordingConfig,
+) -> Option<PlayerCommand> {
+    let arg = message.args.first();
+    let pressed = arg.is_none_or(|arg| number(arg).is_some_and(|n| n != 0.0));
+    match message.addr.as_str() {
+        "/soundamp/volume" => Some(PlayerCommand::SetVolume(number(arg?)? as f32)),
+        "/soundamp/volume/step" => Some(PlayerCommand::IncreaseVolume(number(arg?)? as f32)),
+        "/soundamp/start" => {
+            let device = match arg? {
+                OscType::String(name) => find_input_device(name)?,
+                arg => number(arg)? as usize,
+            };
+            Some(PlayerCommand::Start(InputSource::Device(device)))
+        }
+        "/soundamp/stop" if pressed => Some(PlayerCommand::Stop),
+        "/soundamp/preset" => match arg? {
+            OscType::String(name) => profiles
+                .iter()
+                .find(|p| &p.name == name)
+                .map(|profile| PlayerCommand::ApplyProfile(profile.clone())),
+            _ => None,
+        },
+        "/soundamp/record" if pressed => {
+            Some(PlayerCommand::StartRecording(recording_config.clone()))
+        }
+        "/soundamp/record" => Some(PlayerCommand::StopRecording),
+        // Unlike the buttons above, this needs the release too, so it takes no missing argument.
+        "/soundamp/talk" => Some(PlayerCommand::SetTalking(number(arg?)? != 0.0)),
+        addr if addr.starts_with("/soundamp/eq/") && addr.ends_with("/gain") => {
+            let band: usize = addr["/soundamp/eq/".len()..addr.len() - "/gain".len()]
+                .parse()
+                .ok()?;
+            Some(PlayerCommand::SetEqGain {
+                band: band.checked_sub(1)?,
+                gain_db: number(arg?)? as f32,
+            })
+        }
+        addr if pressed && addr.starts_with("/soundamp/sample/") => {
+            let i = addr["/soundamp/sample/".len()..].parse().ok()?;
+            Some(PlayerCommand::TriggerSample(i))
+        }
+        _ => None,
+    }
+}
+
+fn number(arg: &OscType) -> Option<f64> {
+    match *arg {
+        OscType::Int(n) => Some(n as f64),
+        OscType::Long(n) => Some(n as f64),
+        OscType::Float(n) => Some(n as f64),
+        OscType::Double(n) => Some(n),
+        OscType::Bool(b) => Some(if b { 1.0 } else { 0.0 }),
+        _ => None,
+    }
+}
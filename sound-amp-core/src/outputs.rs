@@ -0,0 +1,78 @@
+use serde::Deserialize;
+
+/// The devices the main link plays to, and how they're lined up in time.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OutputsConfig {
+    /// Holds every output back by how much sooner it plays than the slowest one, as the devices
+    /// report their latency, so speakers in one room don't echo against each other.
+    pub align: bool,
+    /// Delay of the default output on top of the alignment; an offset below zero can only take
+    /// back delay the alignment added.
+    pub delay_ms: f32,
+    /// Devices played to alongside the default output, in its format.
+    pub extra: Vec<ExtraOutput>,
+    /// A device that plays a mix of its own rather than the main output's.
+    pub monitor: MonitorConfig,
+}
+
+impl Default for OutputsConfig {
+    fn default() -> Self {
+        OutputsConfig {
+            align: true,
+            delay_ms: 0.0,
+            extra: Vec::new(),
+            monitor: MonitorConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtraOutput {
+    /// Name of the output device, as listed on the devices tab.
+    pub device: String,
+    /// Delay on top of the alignment, like [`OutputsConfig::delay_ms`].
+    #[serde(default)]
+    pub delay_ms: f32,
+}
+
+/// A second mix on a device of its own, like the headphones of a streamer who wants to hear their
+/// voice dry, or only the desktop audio while the main output carries everything. Its levels are
+/// its own, so it can be louder or quieter than the main output. It isn't delayed to line up with
+/// the other outputs, so it stays as quick as the device allows.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MonitorConfig {
+    /// Name of the output device, as listed on the devices tab; there's no monitor without one.
+    pub device: Option<String>,
+    /// What of the main link's input goes into the monitor.
+    pub input: MonitorInput,
+    pub input_level: f32,
+    /// Level of the file player and the soundboard in the monitor.
+    pub playback_level: f32,
+    /// Level of the whole monitor mix.
+    pub level: f32,
+}
+
+impl Default for MonitorConfig {
+    fn default() -> Self {
+        MonitorConfig {
+            device: None,
+            input: MonitorInput::Processed,
+            input_level: 1.0,
+            playback_level: 1.0,
+            level: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MonitorInput {
+    /// The input as the main output has it, after the effects and volume.
+    Processed,
+    /// The input as it comes in, without effects or volume.
+    Dry,
+    /// None of it, for a mix-minus of only the playback.
+    Off,
+}
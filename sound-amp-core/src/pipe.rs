@@ -0,0 +1,212 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::RingBuffer;
+
+use crate::dither::Dither;
+use crate::lock;
+use crate::recorder::RecordingTap;
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Little-endian raw sample formats for piping audio in and out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum)]
+pub enum PcmFormat {
+    #[default]
+    F32,
+    S16,
+}
+
+impl PcmFormat {
+    /// Appends `samples`, rounded to 16 bits through `dither` for s16.
+    pub fn encode(self, samples: &[f32], dither: &mut Dither, out: &mut Vec<u8>) {
+        match self {
+            PcmFormat::F32 => encode_f32(samples, out),
+            PcmFormat::S16 => {
+                for &s in samples {
+                    out.extend_from_slice(&dither.quantize(s).to_le_bytes());
+                }
+            }
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8], out: &mut Vec<f32>) {
+        match self {
+            PcmFormat::F32 => out.extend(
+                bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            ),
+            PcmFormat::S16 => out.extend(
+                bytes
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / i16::MAX as f32),
+            ),
+        }
+    }
+
+    fn sample_size(self) -> usize {
+        match self {
+            PcmFormat::F32 => 4,
+            PcmFormat::S16 => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            PcmFormat::F32 => "f32le",
+            PcmFormat::S16 => "s16le",
+        }
+    }
+}
+
+/// Appends `samples` as f32le, for the streams that are always in it.
+pub fn encode_f32(samples: &[f32], out: &mut Vec<u8>) {
+    for s in samples {
+        out.extend_from_slice(&s.to_le_bytes());
+    }
+}
+
+/// How the processed signal is piped to stdout.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputPipe {
+    pub format: PcmFormat,
+    /// Skip the hardware output and only write to the pipe.
+    pub exclusive: bool,
+    /// Shape the [dither](crate::dither) of s16 towards the top of the band.
+    pub noise_shaping: bool,
+}
+
+/// Writes samples arriving on the tap to stdout on a background thread.
+pub struct PcmPipe {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl PcmPipe {
+    pub fn start(pipe: &OutputPipe, config: &StreamConfig, tap: &RecordingTap) -> PcmPipe {
+        let format = pipe.format;
+        eprintln!(
+            "Piping {} {} Hz {} ch to stdout",
+            format.name(),
+            config.sample_rate.0,
+            config.channels
+        );
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+        let mut dither = Dither::new(config.channels, pipe.noise_shaping);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let tap = Arc::clone(tap);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut bytes: Vec<u8> = Vec::new();
+                let mut stdout = io::stdout().lock();
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    if n > 0 {
+                        bytes.clear();
+                        format.encode(&buffer[..n], &mut dither, &mut bytes);
+                        // The reader went away; there's nobody left to write for.
+                        if stdout.write_all(&bytes).and_then(|_| stdout.flush()).is_err() {
+                            *lock(&tap) = None;
+                            break;
+                        }
+                    }
+                    thread::sleep(DRAIN_INTERVAL);
+                }
+            });
+        }
+        PcmPipe {
+            tap: Arc::clone(tap),
+            stop,
+        }
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Raw PCM read from stdin in place of an input device.
+#[derive(Debug, Clone, Copy)]
+pub struct InputPipe {
+    pub format: PcmFormat,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl InputPipe {
+    pub fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: BufferSize::Default,
+        }
+    }
+}
+
+/// Feeds stdin to `process` in real time, like an input stream callback would.
+/// The reader stops once this is dropped (after the next read returns) or stdin ends.
+pub struct StdinReader {
+    stop: Arc<AtomicBool>,
+}
+
+impl StdinReader {
+    pub fn spawn<F>(input: InputPipe, mut process: F) -> StdinReader
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let frame_size = input.format.sample_size() * input.channels as usize;
+                // 10 ms at a time, about what an input callback gets.
+                let mut bytes = vec![0u8; frame_size * (input.sample_rate as usize / 100).max(1)];
+                let mut filled = 0;
+                let mut samples: Vec<f32> = Vec::new();
+                let mut frames_read = 0u64;
+                let started = Instant::now();
+                let mut stdin = io::stdin().lock();
+                while !stop.load(Ordering::Acquire) {
+                    match stdin.read(&mut bytes[filled..]) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => filled += n,
+                    }
+                    let whole = filled / frame_size * frame_size;
+                    if whole == 0 {
+                        continue;
+                    }
+                    samples.clear();
+                    input.format.decode(&bytes[..whole], &mut samples);
+                    process(&samples);
+                    bytes.copy_within(whole..filled, 0);
+                    filled -= whole;
+
+                    // Don't run ahead of real time when stdin is a file rather than a live source.
+                    frames_read += (whole / frame_size) as u64;
+                    let due = Duration::from_secs_f64(frames_read as f64 / input.sample_rate as f64);
+                    if let Some(ahead) = due.checked_sub(started.elapsed()) {
+                        thread::sleep(ahead);
+                    }
+                }
+            });
+        }
+        StdinReader { stop }
+    }
+}
+
+impl Drop for StdinReader {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
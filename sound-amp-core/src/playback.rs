@@ -0,0 +1,541 @@
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::{Consumer, Producer, RingBuffer};
+use serde::Deserialize;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+use crate::lock;
+use crate::resampler::{self, LinearResampler};
+
+pub type PlaybackError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Where the output callback picks up decoded file audio to mix in.
+pub type PlaybackBus = Arc<Mutex<Option<PlaybackOutput>>>;
+
+pub const SUPPORTED_EXTENSIONS: [&str; 4] = ["wav", "flac", "mp3", "ogg"];
+
+const RING_SECONDS: usize = 1;
+const IDLE_INTERVAL: Duration = Duration::from_millis(10);
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PlayerConfig {
+    /// Directory listed in the player tab.
+    pub directory: PathBuf,
+    /// Level of the file player in the output mix.
+    pub volume: f32,
+    /// Start files with looping on.
+    #[serde(rename = "loop")]
+    pub looping: bool,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            directory: PathBuf::from("."),
+            volume: 1.0,
+            looping: false,
+        }
+    }
+}
+
+/// Playback status shared between the decoder, the output callback and the UI.
+#[derive(Default)]
+pub struct PlaybackState {
+    pub paused: AtomicBool,
+    pub active: AtomicBool,
+    flush: AtomicBool,
+    played_frames: AtomicU64,
+    sample_rate: AtomicU32,
+    duration_millis: AtomicU64,
+    pub title: Mutex<String>,
+    pub looping: AtomicBool,
+    /// Loop region in output frames; an end of 0 stands for the end of the file.
+    loop_start: AtomicU64,
+    loop_end: AtomicU64,
+    /// Length of the file in output frames, once known.
+    file_frames: AtomicU64,
+}
+
+impl PlaybackState {
+    pub fn position(&self) -> Duration {
+        let mut played = self.played_frames.load(Ordering::Relaxed);
+        // The played count keeps running while the decoder wraps around, so fold it back into the loop.
+        if let Some((start, end)) = self.loop_region() {
+            if played >= end {
+                played = start + (played - start) % (end - start);
+            }
+        }
+        self.frames_to_duration(played)
+    }
+
+    /// The A–B points: where a loop starts, and where it ends if that isn't the end of the file.
+    pub fn loop_points(&self) -> (Duration, Option<Duration>) {
+        let end = match self.loop_end.load(Ordering::Relaxed) {
+            0 => None,
+            end => Some(self.frames_to_duration(end)),
+        };
+        (self.frames_to_duration(self.loop_start.load(Ordering::Relaxed)), end)
+    }
+
+    /// The looped region in output frames, while looping is on and its end is known.
+    fn loop_region(&self) -> Option<(u64, u64)> {
+        let start = self.loop_start.load(Ordering::Relaxed);
+        let end = match self.loop_end.load(Ordering::Relaxed) {
+            0 => self.file_frames.load(Ordering::Relaxed),
+            end => end,
+        };
+        (self.looping.load(Ordering::Relaxed) && end > start).then_some((start, end))
+    }
+
+    fn frames_to_duration(&self, frames: u64) -> Duration {
+        let rate = self.sample_rate.load(Ordering::Relaxed).max(1) as f64;
+        Duration::from_secs_f64(frames as f64 / rate)
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        match self.duration_millis.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(Duration::from_millis(millis)),
+        }
+    }
+}
+
+/// The output side of a playing file: the callback mixes it on top of the live signal.
+pub struct PlaybackOutput {
+    consumer: Consumer<f32>,
+    state: Arc<PlaybackState>,
+    channels: usize,
+    volume: f32,
+}
+
+impl PlaybackOutput {
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        if self.state.flush.load(Ordering::Acquire) {
+            let len = self.consumer.len();
+            self.consumer.discard(len);
+            self.state.flush.store(false, Ordering::Release);
+        }
+        if self.state.paused.load(Ordering::Relaxed) {
+            return;
+        }
+        let mut mixed = 0;
+        for sample in output.iter_mut() {
+            match self.consumer.pop() {
+                Some(s) => *sample += s * self.volume,
+                None => break,
+            }
+            mixed += 1;
+        }
+        self.state
+            .played_frames
+            .fetch_add((mixed / self.channels) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Changes to the loop of the playing file.
+#[derive(Debug, Clone, Copy)]
+pub enum LoopEdit {
+    Toggle,
+    /// Sets point A at the current position.
+    MarkStart,
+    /// Sets point B at the current position.
+    MarkEnd,
+    /// Goes back to looping the whole file.
+    Clear,
+}
+
+enum DecoderCommand {
+    Seek(f64),
+    Stop,
+}
+
+/// Decodes a file on a background thread, converted to the output stream's format.
+pub struct FilePlayer {
+    bus: PlaybackBus,
+    state: Arc<PlaybackState>,
+    commands: Sender<DecoderCommand>,
+}
+
+impl FilePlayer {
+    pub fn open(
+        path: &Path,
+        volume: f32,
+        looping: bool,
+        output_config: &StreamConfig,
+        bus: &PlaybackBus,
+        state: &Arc<PlaybackState>,
+    ) -> Result<FilePlayer, PlaybackError> {
+        let file = DecodedFile::open(path)?;
+        let output_rate = output_config.sample_rate.0;
+        let output_channels = output_config.channels as usize;
+
+        state.paused.store(false, Ordering::Relaxed);
+        state.flush.store(false, Ordering::Relaxed);
+        state.played_frames.store(0, Ordering::Relaxed);
+        state.sample_rate.store(output_rate, Ordering::Relaxed);
+        let duration_millis = file.duration.map_or(0, |d| d.as_millis() as u64);
+        state.duration_millis.store(duration_millis, Ordering::Relaxed);
+        let file_frames = file.duration.map_or(0, |d| (d.as_secs_f64() * output_rate as f64) as u64);
+        state.file_frames.store(file_frames, Ordering::Relaxed);
+        state.looping.store(looping, Ordering::Relaxed);
+        state.loop_start.store(0, Ordering::Relaxed);
+        state.loop_end.store(0, Ordering::Relaxed);
+        *state.title.lock().unwrap() = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        state.active.store(true, Ordering::Relaxed);
+
+        let capacity = RING_SECONDS * output_rate as usize * output_channels;
+        let (producer, consumer) = RingBuffer::new(capacity).split();
+        *lock(bus) = Some(PlaybackOutput {
+            consumer,
+            state: Arc::clone(state),
+            channels: output_channels,
+            volume,
+        });
+
+        let (commands, receiver) = mpsc::channel();
+        {
+            let state = Arc::clone(state);
+            thread::spawn(move || run_decoder(file, producer, output_channels, output_rate, &state, receiver));
+        }
+        Ok(FilePlayer {
+            bus: Arc::clone(bus),
+            state: Arc::clone(state),
+            commands,
+        })
+    }
+
+    pub fn toggle_pause(&self) {
+        self.state.paused.fetch_xor(true, Ordering::Relaxed);
+    }
+
+    /// Jumps `offset` seconds forwards or backwards from the current position.
+    pub fn seek_by(&self, offset: f64) {
+        let target = (self.state.position().as_secs_f64() + offset).max(0.0);
+        let _ = self.commands.send(DecoderCommand::Seek(target));
+    }
+
+    pub fn edit_loop(&self, edit: LoopEdit) {
+        let state = &self.state;
+        let rate = state.sample_rate.load(Ordering::Relaxed) as f64;
+        let position = (state.position().as_secs_f64() * rate) as u64;
+        let start = state.loop_start.load(Ordering::Relaxed);
+        let end = state.loop_end.load(Ordering::Relaxed);
+        match edit {
+            LoopEdit::Toggle => {
+                state.looping.fetch_xor(true, Ordering::Relaxed);
+            }
+            LoopEdit::MarkStart => {
+                state.loop_start.store(position, Ordering::Relaxed);
+                if end != 0 && end <= position {
+                    state.loop_end.store(0, Ordering::Relaxed);
+                }
+            }
+            LoopEdit::MarkEnd => {
+                if position > start {
+                    state.loop_end.store(position, Ordering::Relaxed);
+                }
+            }
+            LoopEdit::Clear => {
+                state.loop_start.store(0, Ordering::Relaxed);
+                state.loop_end.store(0, Ordering::Relaxed);
+            }
+        }
+        // Re-base the played count so `position` folds it against the new region from here on.
+        state.played_frames.store(position, Ordering::Relaxed);
+    }
+
+    pub fn stop(self) {
+        let _ = self.commands.send(DecoderCommand::Stop);
+        *lock(&self.bus) = None;
+        self.state.active.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Audio files in `directory` the player can open, sorted by name.
+pub fn list_files(directory: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(directory)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| {
+                    path.extension()
+                        .and_then(|ext| ext.to_str())
+                        .is_some_and(|ext| SUPPORTED_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    files.sort();
+    files
+}
+
+/// The channels and rate a file decodes to, as the format of a stream to run it through as it is.
+pub fn file_config(path: &Path) -> Result<StreamConfig, PlaybackError> {
+    let file = DecodedFile::open(path)?;
+    Ok(StreamConfig {
+        channels: file.channels as u16,
+        sample_rate: SampleRate(file.sample_rate),
+        buffer_size: BufferSize::Default,
+    })
+}
+
+/// Decodes a whole file into memory, converted to the output stream's format.
+pub fn decode_all(path: &Path, output_config: &StreamConfig) -> Result<Vec<f32>, PlaybackError> {
+    let mut file = DecodedFile::open(path)?;
+    let output_channels = output_config.channels as usize;
+    let output_rate = output_config.sample_rate.0;
+    let mut resampler = (file.sample_rate != output_rate)
+        .then(|| LinearResampler::new(file.sample_rate, output_rate, output_channels));
+    let file_channels = file.channels;
+    let mut remapped: Vec<f32> = Vec::new();
+    let mut samples: Vec<f32> = Vec::new();
+    while let Some(block) = file.next_block() {
+        remapped.clear();
+        resampler::remap_channels(block, file_channels, output_channels, &mut remapped);
+        match &mut resampler {
+            Some(resampler) => resampler.process(&remapped, &mut samples),
+            None => samples.extend_from_slice(&remapped),
+        }
+    }
+    Ok(samples)
+}
+
+fn run_decoder(
+    mut file: DecodedFile,
+    mut producer: Producer<f32>,
+    output_channels: usize,
+    output_rate: u32,
+    state: &PlaybackState,
+    commands: Receiver<DecoderCommand>,
+) {
+    let new_resampler = |file: &DecodedFile| {
+        (file.sample_rate != output_rate)
+            .then(|| LinearResampler::new(file.sample_rate, output_rate, output_channels))
+    };
+    let mut resampler = new_resampler(&file);
+    let mut remapped: Vec<f32> = Vec::new();
+    let mut converted: Vec<f32> = Vec::new();
+    let mut pending: Vec<f32> = Vec::new();
+    let mut finished = false;
+    // Output frames decoded so far, and how many to drop after a seek landed early.
+    let mut decoded = 0u64;
+    let mut skip = 0u64;
+    let seek = |file: &mut DecodedFile, frames: u64| -> Option<(u64, u64)> {
+        let landed = file.seek(frames as f64 / output_rate as f64)?;
+        let landed = (landed * output_rate as f64) as u64;
+        Some((frames, frames.saturating_sub(landed)))
+    };
+    loop {
+        match commands.try_recv() {
+            Ok(DecoderCommand::Seek(seconds)) => {
+                // Let the callback drop what's already queued before refilling from the new position.
+                state.flush.store(true, Ordering::Release);
+                let started = Instant::now();
+                while state.flush.load(Ordering::Acquire) && started.elapsed() < FLUSH_TIMEOUT {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                if let Some((target, dropped)) = seek(&mut file, (seconds * output_rate as f64) as u64) {
+                    state.played_frames.store(target, Ordering::Relaxed);
+                    (decoded, skip) = (target, dropped);
+                }
+                pending.clear();
+                resampler = new_resampler(&file);
+                finished = false;
+            }
+            Ok(DecoderCommand::Stop) | Err(TryRecvError::Disconnected) => break,
+            Err(TryRecvError::Empty) => {}
+        }
+        if !pending.is_empty() {
+            let pushed = producer.push_slice(&pending);
+            pending.drain(..pushed);
+            if !pending.is_empty() {
+                thread::sleep(IDLE_INTERVAL);
+            }
+            continue;
+        }
+        let looping = state.looping.load(Ordering::Relaxed);
+        let loop_start = state.loop_start.load(Ordering::Relaxed);
+        let loop_end = state.loop_end.load(Ordering::Relaxed);
+        let mut wrap = false;
+        if finished {
+            if !looping || decoded <= loop_start {
+                thread::sleep(IDLE_INTERVAL);
+                continue;
+            }
+            finished = false;
+            wrap = true;
+        } else {
+            let file_channels = file.channels;
+            match file.next_block() {
+                Some(samples) => {
+                    remapped.clear();
+                    resampler::remap_channels(samples, file_channels, output_channels, &mut remapped);
+                    converted.clear();
+                    match &mut resampler {
+                        Some(resampler) => resampler.process(&remapped, &mut converted),
+                        None => converted.extend_from_slice(&remapped),
+                    }
+                    let mut block = &converted[..];
+                    let dropped = (skip as usize).min(block.len() / output_channels);
+                    block = &block[dropped * output_channels..];
+                    skip -= dropped as u64;
+                    let mut frames = (block.len() / output_channels) as u64;
+                    if looping && loop_end != 0 && decoded + frames >= loop_end {
+                        frames = loop_end.saturating_sub(decoded);
+                        block = &block[..frames as usize * output_channels];
+                        wrap = true;
+                    }
+                    pending.extend_from_slice(block);
+                    decoded += frames;
+                }
+                None => {
+                    if loop_end == 0 {
+                        state.file_frames.store(decoded, Ordering::Relaxed);
+                    }
+                    // An empty region would wrap forever without producing anything.
+                    wrap = looping && decoded > loop_start;
+                    finished = !wrap;
+                }
+            }
+        }
+        // Wrapping doesn't flush: the tail of the loop is still queued and plays straight into its start.
+        if wrap {
+            match seek(&mut file, loop_start) {
+                Some((target, dropped)) => (decoded, skip) = (target, dropped),
+                None => finished = true,
+            }
+        }
+    }
+}
+
+struct DecodedFile {
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    channels: usize,
+    duration: Option<Duration>,
+    buffer: Option<SampleBuffer<f32>>,
+}
+
+impl DecodedFile {
+    fn open(path: &Path) -> Result<DecodedFile, PlaybackError> {
+        let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(extension);
+        }
+        let probed = symphonia::default::get_probe().format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+        let format = probed.format;
+        let track = format
+            .tracks()
+            .iter()
+            .find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or("no audio track")?;
+        let params = track.codec_params.clone();
+        let decoder = symphonia::default::get_codecs().make(&params, &DecoderOptions::default())?;
+        let duration = match (params.time_base, params.n_frames) {
+            (Some(time_base), Some(frames)) => {
+                let time = time_base.calc_time(frames);
+                Some(Duration::from_secs_f64(time.seconds as f64 + time.frac))
+            }
+            _ => None,
+        };
+        Ok(DecodedFile {
+            track_id: track.id,
+            format,
+            decoder,
+            time_base: params.time_base,
+            sample_rate: params.sample_rate.ok_or("unknown sample rate")?,
+            channels: params.channels.ok_or("unknown channel layout")?.count(),
+            duration,
+            buffer: None,
+        })
+    }
+
+    /// The next block of interleaved samples, or `None` at the end of the file.
+    fn next_block(&mut self) -> Option<&[f32]> {
+        loop {
+            let packet = match self.format.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphoniaError::IoError(e)) if e.kind() == ErrorKind::UnexpectedEof => return None,
+                Err(e) => {
+                    eprintln!("Cannot read audio file: {}", e);
+                    return None;
+                }
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let needed = decoded.capacity() * decoded.spec().channels.count();
+                    if self.buffer.as_ref().is_some_and(|b| b.capacity() < needed) {
+                        self.buffer = None;
+                    }
+                    let buffer = self
+                        .buffer
+                        .get_or_insert_with(|| SampleBuffer::new(decoded.capacity() as u64, *decoded.spec()));
+                    buffer.copy_interleaved_ref(decoded);
+                    return Some(buffer.samples());
+                }
+                // A corrupt packet is skipped rather than ending playback.
+                Err(SymphoniaError::DecodeError(_)) => continue,
+                Err(e) => {
+                    eprintln!("Cannot decode audio file: {}", e);
+                    return None;
+                }
+            }
+        }
+    }
+
+    /// Seeks as close to `seconds` as the format allows and returns where it actually landed.
+    fn seek(&mut self, seconds: f64) -> Option<f64> {
+        let to = SeekTo::Time {
+            time: Time::from(seconds),
+            track_id: Some(self.track_id),
+        };
+        match self.format.seek(SeekMode::Coarse, to) {
+            Ok(seeked) => {
+                self.decoder.reset();
+                Some(match self.time_base {
+                    Some(time_base) => {
+                        let time = time_base.calc_time(seeked.actual_ts);
+                        time.seconds as f64 + time.frac
+                    }
+                    None => seconds,
+                })
+            }
+            Err(e) => {
+                eprintln!("Cannot seek: {}", e);
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,391 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::{error, fs};
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use chrono::Local;
+use cpal::StreamConfig;
+use ringbuf::{Producer, RingBuffer};
+use serde::Deserialize;
+
+use crate::config::RecordingConfig;
+use crate::lock;
+
+#[cfg(feature = "flac")]
+use self::flac::FlacEncoder;
+#[cfg(feature = "mp3")]
+use self::mp3::Mp3Encoder;
+#[cfg(feature = "opus")]
+use self::opus::OpusEncoder;
+use self::wav::WavEncoder;
+
+#[cfg(feature = "flac")]
+mod flac;
+#[cfg(feature = "mp3")]
+mod mp3;
+#[cfg(feature = "opus")]
+mod opus;
+mod wav;
+
+/// Where the input callback pushes processed samples while a recording is running.
+pub type RecordingTap = Arc<Mutex<Option<Producer<f32>>>>;
+
+pub type RecordingError = Box<dyn error::Error + Send + Sync>;
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordingFormat {
+    #[default]
+    Wav,
+    /// Needs the `flac` feature.
+    Flac,
+    /// Needs the `mp3` feature.
+    Mp3,
+    /// Opus in Ogg; needs the `opus` feature.
+    Opus,
+}
+
+impl RecordingFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            RecordingFormat::Wav => "wav",
+            RecordingFormat::Flac => "flac",
+            RecordingFormat::Mp3 => "mp3",
+            RecordingFormat::Opus => "opus",
+        }
+    }
+}
+
+/// Which signal the recorder captures.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TapPoint {
+    /// The input before gain and effects, into `sound-amp-raw-*` files.
+    Raw,
+    /// The signal as it's sent to the output.
+    #[default]
+    Processed,
+    /// Both, into two files side by side.
+    Both,
+    /// The raw input and the final output mix, into sample-aligned
+    /// `sound-amp-raw-*` and `sound-amp-output-*` files.
+    Multitrack,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct EncoderSettings {
+    pub format: RecordingFormat,
+    /// Target bitrate for the lossy formats.
+    pub bitrate_kbps: u32,
+    /// Opus encoder complexity, 0 (fastest) to 10 (best).
+    pub complexity: u8,
+}
+
+impl Default for EncoderSettings {
+    fn default() -> Self {
+        EncoderSettings {
+            format: RecordingFormat::default(),
+            bitrate_kbps: 128,
+            complexity: 10,
+        }
+    }
+}
+
+impl EncoderSettings {
+    /// Creates an encoder for `path`; `tags` are written by every format except WAV.
+    #[cfg_attr(not(any(feature = "flac", feature = "mp3", feature = "opus")), allow(unused_variables))]
+    pub fn create_encoder(
+        &self,
+        path: &Path,
+        config: &StreamConfig,
+        tags: &Tags,
+    ) -> Result<Box<dyn Encoder>, RecordingError> {
+        Ok(match self.format {
+            RecordingFormat::Wav => Box::new(WavEncoder::create(path, config)?),
+            #[cfg(feature = "flac")]
+            RecordingFormat::Flac => Box::new(FlacEncoder::create(path, config, tags)?),
+            #[cfg(not(feature = "flac"))]
+            RecordingFormat::Flac => return Err("sound-amp was built without the `flac` feature".into()),
+            #[cfg(feature = "mp3")]
+            RecordingFormat::Mp3 => Box::new(Mp3Encoder::create(path, config, self, tags)?),
+            #[cfg(not(feature = "mp3"))]
+            RecordingFormat::Mp3 => return Err("sound-amp was built without the `mp3` feature".into()),
+            #[cfg(feature = "opus")]
+            RecordingFormat::Opus => Box::new(OpusEncoder::create(path, config, self, tags)?),
+            #[cfg(not(feature = "opus"))]
+            RecordingFormat::Opus => return Err("sound-amp was built without the `opus` feature".into()),
+        })
+    }
+
+    /// Creates an encoder that streams to `sink`; only the formats that need no seeking back can.
+    #[cfg_attr(not(any(feature = "mp3", feature = "opus")), allow(unused_variables))]
+    pub fn create_stream_encoder(
+        &self,
+        sink: Box<dyn Write + Send>,
+        config: &StreamConfig,
+        tags: &Tags,
+    ) -> Result<Box<dyn Encoder>, RecordingError> {
+        match self.format {
+            RecordingFormat::Wav | RecordingFormat::Flac => {
+                Err(format!("{} can't be streamed", self.format.extension()).into())
+            }
+            #[cfg(feature = "mp3")]
+            RecordingFormat::Mp3 => Ok(Box::new(Mp3Encoder::new(sink, config, self, tags)?)),
+            #[cfg(not(feature = "mp3"))]
+            RecordingFormat::Mp3 => Err("sound-amp was built without the `mp3` feature".into()),
+            #[cfg(feature = "opus")]
+            RecordingFormat::Opus => Ok(Box::new(OpusEncoder::new(sink, config, self, tags)?)),
+            #[cfg(not(feature = "opus"))]
+            RecordingFormat::Opus => Err("sound-amp was built without the `opus` feature".into()),
+        }
+    }
+}
+
+/// Lines the output track of a multitrack recording up with the raw input track:
+/// both start at the same sample of the stream passed from the input to the output callback.
+/// A track whose writer falls behind has a gap of silence in place of what its ring couldn't
+/// take, rather than being pulled out of line with the others.
+pub struct TrackSync {
+    start: AtomicU64,
+    /// Samples each track's ring couldn't take, still to be made up with silence.
+    owed: [AtomicUsize; 3],
+}
+
+/// Which of a recording's tracks a block goes to.
+#[derive(Debug, Clone, Copy)]
+pub enum Track {
+    Raw,
+    Processed,
+    Output,
+}
+
+impl Default for TrackSync {
+    fn default() -> Self {
+        TrackSync {
+            start: AtomicU64::new(u64::MAX),
+            owed: Default::default(),
+        }
+    }
+}
+
+impl TrackSync {
+    pub fn reset(&self) {
+        self.start.store(u64::MAX, Ordering::Release);
+        for owed in &self.owed {
+            owed.store(0, Ordering::Relaxed);
+        }
+    }
+
+    /// Pushes `samples` onto `track`'s ring, after the silence it owes; returns whether they
+    /// all fit.
+    pub fn push(&self, track: Track, producer: &mut Producer<f32>, samples: &[f32]) -> bool {
+        let owed = &self.owed[track as usize];
+        let mut missing = owed.load(Ordering::Relaxed);
+        missing -= producer.push_iter(&mut iter::repeat_n(0.0, missing));
+        let taken = if missing == 0 { producer.push_slice(samples) } else { 0 };
+        owed.store(missing + samples.len() - taken, Ordering::Relaxed);
+        taken == samples.len()
+    }
+
+    /// Called by the input side with the stream position of the first block it records.
+    pub fn mark_start(&self, position: u64) {
+        let _ = self
+            .start
+            .compare_exchange(u64::MAX, position, Ordering::AcqRel, Ordering::Relaxed);
+    }
+
+    /// Where the recorded part begins in an output block holding `len` stream samples from `position` on.
+    pub fn output_offset(&self, position: u64, len: usize) -> Option<usize> {
+        let start = self.start.load(Ordering::Acquire);
+        (start != u64::MAX && start < position + len as u64).then(|| start.saturating_sub(position) as usize)
+    }
+}
+
+/// Tag templates; `{preset}`, `{datetime}`, `{date}` and `{time}` are filled in per file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TagTemplates {
+    pub title: String,
+    pub artist: String,
+    pub comment: String,
+}
+
+impl Default for TagTemplates {
+    fn default() -> Self {
+        TagTemplates {
+            title: "{preset} {datetime}".to_string(),
+            artist: "sound-amp".to_string(),
+            comment: String::new(),
+        }
+    }
+}
+
+impl TagTemplates {
+    /// Fills in the templates for a file started now while `preset` is active.
+    pub fn render(&self, preset: Option<&str>) -> Tags {
+        let now = Local::now();
+        let fill = |template: &str| {
+            template
+                .replace("{preset}", preset.unwrap_or(""))
+                .replace("{datetime}", &now.format("%Y-%m-%d %H:%M:%S").to_string())
+                .replace("{date}", &now.format("%Y-%m-%d").to_string())
+                .replace("{time}", &now.format("%H:%M:%S").to_string())
+                .trim()
+                .to_string()
+        };
+        Tags {
+            title: fill(&self.title),
+            artist: fill(&self.artist),
+            date: now.format("%Y-%m-%d").to_string(),
+            comment: fill(&self.comment),
+        }
+    }
+}
+
+/// Tags for a single file, ready to be written.
+#[derive(Debug, Clone, Default)]
+pub struct Tags {
+    pub title: String,
+    pub artist: String,
+    pub date: String,
+    pub comment: String,
+}
+
+#[cfg(any(feature = "flac", feature = "opus"))]
+impl Tags {
+    /// The non-empty tags as Vorbis comment `(field, value)` pairs.
+    fn fields(&self) -> impl Iterator<Item = (&'static str, &str)> {
+        [
+            ("TITLE", self.title.as_str()),
+            ("ARTIST", self.artist.as_str()),
+            ("DATE", self.date.as_str()),
+            ("COMMENT", self.comment.as_str()),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_empty())
+    }
+}
+
+/// A Vorbis comment body (vendor string and `FIELD=value` entries), as used by FLAC and Ogg Opus.
+#[cfg(any(feature = "flac", feature = "opus"))]
+fn vorbis_comment(tags: &Tags) -> Vec<u8> {
+    let vendor = concat!("sound-amp ", env!("CARGO_PKG_VERSION")).as_bytes();
+    let fields: Vec<String> = tags.fields().map(|(field, value)| format!("{}={}", field, value)).collect();
+    let mut comment = Vec::new();
+    comment.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    comment.extend_from_slice(vendor);
+    comment.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+    for field in fields {
+        comment.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        comment.extend_from_slice(field.as_bytes());
+    }
+    comment
+}
+
+/// A file format the recorder writes interleaved samples to.
+pub trait Encoder: Send {
+    fn write(&mut self, samples: &[f32]) -> Result<(), RecordingError>;
+    fn finalize(self: Box<Self>) -> Result<(), RecordingError>;
+}
+
+/// Encodes samples arriving on the tap on a background thread,
+/// so the audio callback never touches the disk.
+pub struct Recorder {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+    writer: JoinHandle<Result<(), RecordingError>>,
+}
+
+impl Recorder {
+    /// Starts writing timestamped `<prefix>-*` files into the configured directory,
+    /// moving on to a new file whenever the configured split size or length is reached.
+    pub fn start(
+        recording: &RecordingConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+        prefix: &'static str,
+        preset: Option<&str>,
+    ) -> Result<Recorder, RecordingError> {
+        let settings = recording.encoder;
+        let directory = recording.directory.clone();
+        let split_frames = recording
+            .split_minutes
+            .map(|minutes| minutes as u64 * 60 * config.sample_rate.0 as u64);
+        let split_bytes = recording.split_megabytes.map(|mb| mb * 1024 * 1024);
+        let stream_config = config.clone();
+        let tag_templates = recording.tags.clone();
+        let preset = preset.map(str::to_string);
+
+        fs::create_dir_all(&directory)?;
+        let mut path = timestamped_path(&directory, prefix, settings.format);
+        let mut encoder = settings.create_encoder(&path, config, &tag_templates.render(preset.as_deref()))?;
+        // One second of audio is plenty of slack for the writer thread.
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut segment_frames = 0u64;
+                loop {
+                    let stopping = stop.load(Ordering::Acquire);
+                    let n = consumer.pop_slice(&mut buffer);
+                    encoder.write(&buffer[..n])?;
+                    segment_frames += (n / stream_config.channels as usize) as u64;
+                    if stopping && consumer.is_empty() {
+                        break;
+                    }
+                    let split_due = split_frames.is_some_and(|limit| segment_frames >= limit)
+                        || split_bytes.is_some_and(|limit| {
+                            fs::metadata(&path).is_ok_and(|m| m.len() >= limit)
+                        });
+                    if split_due {
+                        encoder.finalize()?;
+                        path = timestamped_path(&directory, prefix, settings.format);
+                        let tags = tag_templates.render(preset.as_deref());
+                        encoder = settings.create_encoder(&path, &stream_config, &tags)?;
+                        segment_frames = 0;
+                    }
+                    thread::sleep(DRAIN_INTERVAL);
+                }
+                encoder.finalize()
+            })
+        };
+        Ok(Recorder {
+            tap: Arc::clone(tap),
+            stop,
+            writer,
+        })
+    }
+
+    /// Detaches the tap, flushes what's left and finalizes the file.
+    pub fn stop(self) -> Result<(), RecordingError> {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+        self.writer
+            .join()
+            .unwrap_or_else(|_| Err("Recorder thread panicked".into()))
+    }
+}
+
+/// A fresh `<prefix>-<local time>.<ext>` file name in `directory`.
+pub fn timestamped_path(directory: &Path, prefix: &str, format: RecordingFormat) -> PathBuf {
+    let stem = format!("{}-{}", prefix, Local::now().format("%Y-%m-%dT%H-%M-%S"));
+    let mut path = directory.join(format!("{}.{}", stem, format.extension()));
+    let mut n = 1;
+    while path.exists() {
+        path = directory.join(format!("{}-{}.{}", stem, n, format.extension()));
+        n += 1;
+    }
+    path
+}
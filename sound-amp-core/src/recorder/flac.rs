@@ -0,0 +1,107 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use cpal::StreamConfig;
+use flacenc::bitsink::ByteSink;
+use flacenc::component::{BitRepr, MetadataBlockData, Stream};
+use flacenc::config;
+use flacenc::error::{Verified, Verify};
+use flacenc::source::{Fill, FrameBuf};
+
+use super::{Encoder, RecordingError, Tags};
+
+const BITS_PER_SAMPLE: usize = 24;
+const BLOCK_SIZE: usize = 4096;
+const VORBIS_COMMENT: u8 = 4;
+
+/// 24-bit FLAC, encoded one block at a time so long captures never sit in memory.
+/// The STREAMINFO header is rewritten on finalize once the totals are known.
+pub struct FlacEncoder {
+    file: BufWriter<File>,
+    config: Verified<config::Encoder>,
+    header: Stream,
+    framebuf: FrameBuf,
+    pending: Vec<i32>,
+    channels: usize,
+    frame_number: usize,
+}
+
+impl FlacEncoder {
+    pub fn create(path: &Path, config: &StreamConfig, tags: &Tags) -> Result<FlacEncoder, RecordingError> {
+        let channels = config.channels as usize;
+        let mut header = Stream::new(config.sample_rate.0 as usize, channels, BITS_PER_SAMPLE)?;
+        header.add_metadata_block(MetadataBlockData::new_unknown(VORBIS_COMMENT, &super::vorbis_comment(tags))?);
+        let mut encoder = FlacEncoder {
+            file: BufWriter::new(File::create(path)?),
+            config: config::Encoder::default()
+                .into_verified()
+                .map_err(|(_, e)| e)?,
+            header,
+            framebuf: FrameBuf::with_size(channels, BLOCK_SIZE)?,
+            pending: Vec::with_capacity(BLOCK_SIZE * channels),
+            channels,
+            frame_number: 0,
+        };
+        encoder.write_header()?;
+        Ok(encoder)
+    }
+
+    fn write_header(&mut self) -> Result<(), RecordingError> {
+        let mut sink = ByteSink::new();
+        self.header.write(&mut sink).map_err(|e| e.to_string())?;
+        self.file.write_all(sink.as_slice())?;
+        Ok(())
+    }
+
+    fn encode_block(&mut self, len: usize) -> Result<(), RecordingError> {
+        // flacenc's source and encode errors aren't Send, so they travel as strings.
+        self.framebuf
+            .fill_interleaved(&self.pending[..len])
+            .map_err(|e| e.to_string())?;
+        let frame = flacenc::encode_fixed_size_frame(
+            &self.config,
+            &self.framebuf,
+            self.frame_number,
+            self.header.stream_info(),
+        )
+        .map_err(|e| e.to_string())?;
+        self.header.stream_info_mut().update_frame_info(&frame);
+        let mut sink = ByteSink::new();
+        frame.write(&mut sink).map_err(|e| e.to_string())?;
+        self.file.write_all(sink.as_slice())?;
+        self.pending.drain(..len);
+        self.frame_number += 1;
+        Ok(())
+    }
+}
+
+impl Encoder for FlacEncoder {
+    fn write(&mut self, samples: &[f32]) -> Result<(), RecordingError> {
+        let scale = ((1 << (BITS_PER_SAMPLE - 1)) - 1) as f32;
+        self.pending
+            .extend(samples.iter().map(|s| (s.clamp(-1.0, 1.0) * scale) as i32));
+        let block_len = BLOCK_SIZE * self.channels;
+        while self.pending.len() >= block_len {
+            self.encode_block(block_len)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), RecordingError> {
+        let tail = self.pending.len() - self.pending.len() % self.channels;
+        if tail > 0 {
+            self.encode_block(tail)?;
+        }
+        // Every block but the last is BLOCK_SIZE long; the spec leaves the short last block out
+        // of the minimum, and decoders expect min == max for fixed-size streams.
+        self.header
+            .stream_info_mut()
+            .set_block_sizes(BLOCK_SIZE, BLOCK_SIZE)
+            .map_err(|e| e.to_string())?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
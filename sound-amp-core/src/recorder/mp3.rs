@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use cpal::StreamConfig;
+use mp3lame_encoder::{Bitrate, Builder, FlushNoGap, Id3Tag, InterleavedPcm, MonoPcm, Quality};
+
+use super::{Encoder, EncoderSettings, RecordingError, Tags};
+
+/// Constant-bitrate MP3 through LAME; mono or stereo only.
+pub struct Mp3Encoder {
+    file: Box<dyn Write + Send>,
+    lame: mp3lame_encoder::Encoder,
+    channels: u16,
+    buffer: Vec<u8>,
+}
+
+impl Mp3Encoder {
+    pub fn create(
+        path: &Path,
+        config: &StreamConfig,
+        settings: &EncoderSettings,
+        tags: &Tags,
+    ) -> Result<Mp3Encoder, RecordingError> {
+        Mp3Encoder::new(Box::new(BufWriter::new(File::create(path)?)), config, settings, tags)
+    }
+
+    /// Writes the stream to `sink`.
+    pub fn new(
+        sink: Box<dyn Write + Send>,
+        config: &StreamConfig,
+        settings: &EncoderSettings,
+        tags: &Tags,
+    ) -> Result<Mp3Encoder, RecordingError> {
+        if config.channels > 2 {
+            return Err(format!("MP3 can't record {} channels", config.channels).into());
+        }
+        let mut builder = Builder::new().ok_or("Cannot initialize LAME")?;
+        builder.set_num_channels(config.channels as u8)?;
+        builder.set_sample_rate(config.sample_rate.0)?;
+        builder.set_brate(bitrate(settings.bitrate_kbps)?)?;
+        builder.set_quality(Quality::Good)?;
+        // LAME puts the ID3v2 tag in front of the first frames it encodes.
+        builder.set_id3_tag(Id3Tag {
+            title: tags.title.as_bytes(),
+            artist: tags.artist.as_bytes(),
+            album: &[],
+            album_art: &[],
+            year: tags.date.get(..4).unwrap_or_default().as_bytes(),
+            comment: tags.comment.as_bytes(),
+        })
+        .map_err(|e| format!("Cannot set ID3 tags: {:?}", e))?;
+        Ok(Mp3Encoder {
+            file: sink,
+            lame: builder.build()?,
+            channels: config.channels,
+            buffer: Vec::new(),
+        })
+    }
+}
+
+impl Encoder for Mp3Encoder {
+    fn write(&mut self, samples: &[f32]) -> Result<(), RecordingError> {
+        self.buffer.clear();
+        self.buffer
+            .reserve(mp3lame_encoder::max_required_buffer_size(samples.len()));
+        if self.channels == 1 {
+            self.lame.encode_to_vec(MonoPcm(samples), &mut self.buffer)?;
+        } else {
+            self.lame.encode_to_vec(InterleavedPcm(samples), &mut self.buffer)?;
+        }
+        self.file.write_all(&self.buffer)?;
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), RecordingError> {
+        self.buffer.clear();
+        self.buffer.reserve(mp3lame_encoder::max_required_buffer_size(0));
+        self.lame.flush_to_vec::<FlushNoGap>(&mut self.buffer)?;
+        self.file.write_all(&self.buffer)?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+fn bitrate(kbps: u32) -> Result<Bitrate, RecordingError> {
+    Ok(match kbps {
+        8 => Bitrate::Kbps8,
+        16 => Bitrate::Kbps16,
+        24 => Bitrate::Kbps24,
+        32 => Bitrate::Kbps32,
+        40 => Bitrate::Kbps40,
+        48 => Bitrate::Kbps48,
+        64 => Bitrate::Kbps64,
+        80 => Bitrate::Kbps80,
+        96 => Bitrate::Kbps96,
+        112 => Bitrate::Kbps112,
+        128 => Bitrate::Kbps128,
+        160 => Bitrate::Kbps160,
+        192 => Bitrate::Kbps192,
+        224 => Bitrate::Kbps224,
+        256 => Bitrate::Kbps256,
+        320 => Bitrate::Kbps320,
+        _ => return Err(format!("MP3 doesn't support {} kbps", kbps).into()),
+    })
+}
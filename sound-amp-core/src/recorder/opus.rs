@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use cpal::StreamConfig;
+use ogg::writing::{PacketWriteEndInfo, PacketWriter};
+use opus::{Application, Bitrate, Channels};
+
+use super::{Encoder, EncoderSettings, RecordingError, Tags};
+use crate::resampler::LinearResampler;
+
+/// Sample rates libopus accepts directly; anything else is resampled to 48 kHz.
+const OPUS_RATES: [u32; 5] = [8000, 12000, 16000, 24000, 48000];
+/// Ogg Opus granule positions always count 48 kHz samples.
+const GRANULE_RATE: u64 = 48000;
+const FRAMES_PER_SECOND: u32 = 50;
+const MAX_PACKET_SIZE: usize = 4000;
+const SERIAL: u32 = 0x736e_6461;
+
+/// Opus in an Ogg container (RFC 7845), mono or stereo, in 20 ms packets.
+pub struct OpusEncoder {
+    ogg: PacketWriter<'static, Box<dyn Write + Send>>,
+    opus: opus::Encoder,
+    resampler: Option<LinearResampler>,
+    channels: usize,
+    frame_len: usize,
+    encoder_rate: u32,
+    pending: Vec<f32>,
+    resampled: Vec<f32>,
+    pre_skip: u64,
+    /// Per-channel samples handed to the encoder, at `encoder_rate`.
+    encoded_samples: u64,
+    packets: u64,
+}
+
+impl OpusEncoder {
+    pub fn create(
+        path: &Path,
+        config: &StreamConfig,
+        settings: &EncoderSettings,
+        tags: &Tags,
+    ) -> Result<OpusEncoder, RecordingError> {
+        OpusEncoder::new(Box::new(BufWriter::new(File::create(path)?)), config, settings, tags)
+    }
+
+    /// Writes the stream to `sink`, headers first.
+    pub fn new(
+        sink: Box<dyn Write + Send>,
+        config: &StreamConfig,
+        settings: &EncoderSettings,
+        tags: &Tags,
+    ) -> Result<OpusEncoder, RecordingError> {
+        let channels = match config.channels {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            n => return Err(format!("Opus can't record {} channels", n).into()),
+        };
+        let input_rate = config.sample_rate.0;
+        let (encoder_rate, resampler) = if OPUS_RATES.contains(&input_rate) {
+            (input_rate, None)
+        } else {
+            let rate = GRANULE_RATE as u32;
+            (rate, Some(LinearResampler::new(input_rate, rate, config.channels as usize)))
+        };
+        let mut opus = opus::Encoder::new(encoder_rate, channels, Application::Audio)?;
+        opus.set_bitrate(Bitrate::Bits(settings.bitrate_kbps as i32 * 1000))?;
+        opus.set_complexity(settings.complexity as i32)?;
+        let lookahead = opus.get_lookahead()? as u64;
+
+        let mut encoder = OpusEncoder {
+            ogg: PacketWriter::new(sink),
+            opus,
+            resampler,
+            channels: config.channels as usize,
+            frame_len: (encoder_rate / FRAMES_PER_SECOND) as usize,
+            encoder_rate,
+            pending: Vec::new(),
+            resampled: Vec::new(),
+            pre_skip: lookahead * GRANULE_RATE / encoder_rate as u64,
+            encoded_samples: 0,
+            packets: 0,
+        };
+        encoder.write_headers(input_rate, tags)?;
+        Ok(encoder)
+    }
+
+    fn write_headers(&mut self, input_rate: u32, tags: &Tags) -> Result<(), RecordingError> {
+        let mut head = Vec::with_capacity(19);
+        head.extend_from_slice(b"OpusHead");
+        head.push(1);
+        head.push(self.channels as u8);
+        head.extend_from_slice(&(self.pre_skip as u16).to_le_bytes());
+        head.extend_from_slice(&input_rate.to_le_bytes());
+        head.extend_from_slice(&0i16.to_le_bytes());
+        head.push(0);
+        self.ogg.write_packet(head, SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+
+        let mut comment = Vec::new();
+        comment.extend_from_slice(b"OpusTags");
+        comment.extend_from_slice(&super::vorbis_comment(tags));
+        self.ogg.write_packet(comment, SERIAL, PacketWriteEndInfo::EndPage, 0)?;
+        Ok(())
+    }
+
+    fn encode_frame(&mut self, end: PacketWriteEndInfo, granule: u64) -> Result<(), RecordingError> {
+        let len = self.frame_len * self.channels;
+        let mut packet = vec![0u8; MAX_PACKET_SIZE];
+        let size = self.opus.encode_float(&self.pending[..len], &mut packet)?;
+        packet.truncate(size);
+        self.ogg.write_packet(packet, SERIAL, end, granule)?;
+        self.pending.drain(..len);
+        self.packets += 1;
+        Ok(())
+    }
+
+    fn packet_granule(&self) -> u64 {
+        (self.packets + 1) * GRANULE_RATE / FRAMES_PER_SECOND as u64
+    }
+}
+
+impl Encoder for OpusEncoder {
+    fn write(&mut self, samples: &[f32]) -> Result<(), RecordingError> {
+        let samples = match &mut self.resampler {
+            Some(resampler) => {
+                self.resampled.clear();
+                resampler.process(samples, &mut self.resampled);
+                &self.resampled
+            }
+            None => samples,
+        };
+        self.pending.extend_from_slice(samples);
+        self.encoded_samples += (samples.len() / self.channels) as u64;
+        while self.pending.len() >= self.frame_len * self.channels {
+            let granule = self.packet_granule();
+            self.encode_frame(PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<(), RecordingError> {
+        // Pad with silence until the decoded length covers the pre-skip and everything recorded;
+        // the last granule tells decoders where the audio really ends.
+        let end = self.pre_skip + self.encoded_samples * GRANULE_RATE / self.encoder_rate as u64;
+        loop {
+            self.pending.resize(self.frame_len * self.channels, 0.0);
+            if self.packet_granule() >= end {
+                return self.encode_frame(PacketWriteEndInfo::EndStream, end);
+            }
+            let granule = self.packet_granule();
+            self.encode_frame(PacketWriteEndInfo::NormalPacket, granule)?;
+        }
+    }
+}
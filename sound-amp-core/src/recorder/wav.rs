@@ -0,0 +1,40 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+use cpal::StreamConfig;
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use super::{Encoder, RecordingError};
+
+/// 32-bit float WAV, written exactly as the callback produced it.
+pub struct WavEncoder {
+    writer: WavWriter<BufWriter<File>>,
+}
+
+impl WavEncoder {
+    pub fn create(path: &Path, config: &StreamConfig) -> Result<WavEncoder, RecordingError> {
+        let spec = WavSpec {
+            channels: config.channels,
+            sample_rate: config.sample_rate.0,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float,
+        };
+        Ok(WavEncoder {
+            writer: WavWriter::create(path, spec)?,
+        })
+    }
+}
+
+impl Encoder for WavEncoder {
+    fn write(&mut self, samples: &[f32]) -> Result<(), RecordingError> {
+        for &sample in samples {
+            self.writer.write_sample(sample)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<(), RecordingError> {
+        Ok(self.writer.finalize()?)
+    }
+}
@@ -0,0 +1,244 @@
+//! Fitting a link's input to an output with a different number of channels. Left alone, the
+//! channels both have are copied across and the rest of the output stays silent, and a mono input
+//! is panned across the front pair at constant power; with the upmix on, a stereo input is spread over a 5.1 or 7.1
+//! output, and a 5.1 or 7.1 input is always folded down onto a stereo one the ITU-R BS.775 way.
+//! A mono input given a [`Position`] is spatialized on a stereo output, for headphones.
+//! Surround layouts are taken in the WAVE order: front left and right, center, LFE, then the back
+//! pair and, for 7.1, the side pair.
+//!
+//! The pan and position can change while a link runs: a new pan is swept to like the
+//! [volume](crate::chain::EffectChain::volume), and a new position crossfaded to like new effects.
+
+use std::collections::VecDeque;
+use std::f32::consts::{FRAC_1_SQRT_2, FRAC_PI_4, TAU};
+use std::mem;
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+use crate::chain::{CROSSFADE, GAIN_SMOOTHING};
+use crate::spatial::{Position, Spatializer};
+
+/// How far the rear speakers lag the front ones, so the sound is still heard from the front.
+const REAR_DELAY_MS: f32 = 12.0;
+/// The ambience is rolled off above this in the rears, where it's mostly hiss and smear.
+const REAR_CUTOFF_HZ: f32 = 7000.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RemixConfig {
+    /// Whether a stereo input is spread over a 5.1 or 7.1 output, rather than only played from
+    /// the front pair.
+    pub upmix: bool,
+    /// How much of what both channels have in common moves to the center speaker, from 0 to 1.
+    pub center: f32,
+    /// Level of the ambience, what differs between the channels, in the rear speakers.
+    pub rear_db: f32,
+    /// Whether a surround input's LFE is folded into a stereo output too, at -3 dB. The standard
+    /// downmix leaves it out, since the other channels carry the bass already.
+    pub downmix_lfe: bool,
+    /// Where a mono input is heard from in headphones on a stereo output; without it, it's
+    /// panned. Links can be placed apart while they run.
+    pub position: Option<Position>,
+    /// Where a mono input sits between the front left and right speakers, from -1 to 1; it's
+    /// as loud wherever it's put.
+    pub pan: f32,
+}
+
+impl Default for RemixConfig {
+    fn default() -> Self {
+        RemixConfig {
+            upmix: false,
+            center: 0.7,
+            rear_db: -6.0,
+            downmix_lfe: false,
+            position: None,
+            pan: 0.0,
+        }
+    }
+}
+
+/// Turns blocks in a link's input format into its output's.
+pub struct Remix {
+    inputs: usize,
+    outputs: usize,
+    rate: u32,
+    kind: Kind,
+    /// What it's crossfading from after a change of position, and frames into the crossfade.
+    outgoing: Option<(Kind, usize)>,
+    /// A frame of the outgoing remix.
+    faded: Vec<f32>,
+}
+
+enum Kind {
+    /// The channels both formats have.
+    Copy,
+    /// Mono to the front pair at constant power, the angle between them sweeping to its target.
+    Pan { angle: f32, target: f32, smoothing: f32 },
+    Upmix(Upmix),
+    /// 5.1 or 7.1 to stereo, with the LFE at this gain.
+    Downmix { lfe: f32 },
+    /// Mono to stereo through a spatializer.
+    Binaural(Spatializer),
+}
+
+impl Remix {
+    /// The remix from `input` to `output`, or `None` when they have as many channels as each
+    /// other and the samples go across as they are.
+    pub fn new(config: &RemixConfig, input: &StreamConfig, output: &StreamConfig) -> Option<Remix> {
+        let (inputs, outputs) = (input.channels as usize, output.channels as usize);
+        if inputs == outputs {
+            return None;
+        }
+        let rate = output.sample_rate.0;
+        Some(Remix {
+            inputs,
+            outputs,
+            rate,
+            kind: Kind::new(config, inputs, outputs, rate),
+            outgoing: None,
+            faded: vec![0.0; outputs],
+        })
+    }
+
+    /// Moves to `config`'s pan and position, the only parts that change while a link runs.
+    pub fn retarget(&mut self, config: &RemixConfig) {
+        match (&mut self.kind, Kind::new(config, self.inputs, self.outputs, self.rate)) {
+            (Kind::Pan { target, .. }, Kind::Pan { target: pan, .. }) => *target = pan,
+            (Kind::Pan { .. } | Kind::Binaural(_), kind @ (Kind::Pan { .. } | Kind::Binaural(_))) => {
+                let outgoing = mem::replace(&mut self.kind, kind);
+                self.outgoing = Some((outgoing, 0));
+            }
+            _ => {}
+        }
+    }
+
+    /// How much of the input went into the first `len` samples of the output, in whole frames.
+    pub fn input_len(&self, len: usize) -> usize {
+        len / self.outputs * self.inputs
+    }
+
+    /// Replaces what's in `output` with `input` in the output's format.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let crossfade = ((CROSSFADE.as_secs_f32() * self.rate as f32) as usize).max(1);
+        output.clear();
+        for frame in input.chunks_exact(self.inputs) {
+            let start = output.len();
+            output.resize(start + self.outputs, 0.0);
+            let remixed = &mut output[start..];
+            self.kind.process(frame, remixed);
+            if let Some((outgoing, position)) = &mut self.outgoing {
+                outgoing.process(frame, &mut self.faded);
+                let new = *position as f32 / crossfade as f32;
+                for (sample, old) in remixed.iter_mut().zip(&self.faded) {
+                    *sample = *sample * new + old * (1.0 - new);
+                }
+                *position += 1;
+                if *position >= crossfade {
+                    self.outgoing = None;
+                }
+            }
+        }
+    }
+}
+
+impl Kind {
+    fn new(config: &RemixConfig, inputs: usize, outputs: usize, rate: u32) -> Kind {
+        match (inputs, outputs) {
+            (2, 6 | 8) if config.upmix => Kind::Upmix(Upmix::new(config, rate)),
+            (6 | 8, 2) => Kind::Downmix {
+                lfe: if config.downmix_lfe { FRAC_1_SQRT_2 } else { 0.0 },
+            },
+            (1, 2) => match config.position {
+                Some(position) => Kind::Binaural(Spatializer::new(position, rate)),
+                None => Kind::pan(config.pan, rate),
+            },
+            (1, _) => Kind::pan(config.pan, rate),
+            _ => Kind::Copy,
+        }
+    }
+
+    fn pan(pan: f32, rate: u32) -> Kind {
+        let angle = (pan.clamp(-1.0, 1.0) + 1.0) * FRAC_PI_4;
+        Kind::Pan {
+            angle,
+            target: angle,
+            smoothing: (-1.0 / (GAIN_SMOOTHING.as_secs_f32() * rate as f32)).exp(),
+        }
+    }
+
+    /// Fills `remixed`, a frame of the output, from `frame`, one of the input.
+    fn process(&mut self, frame: &[f32], remixed: &mut [f32]) {
+        match self {
+            Kind::Upmix(upmix) => upmix.process(frame[0], frame[1], remixed),
+            Kind::Downmix { lfe } => {
+                // Center and every surround channel at -3 dB; the 7.1 side pair goes with the
+                // back pair on its side.
+                let common = frame[2] * FRAC_1_SQRT_2 + frame[3] * *lfe;
+                let surround = |first: usize| frame[first..].iter().step_by(2).sum::<f32>();
+                remixed[0] = frame[0] + common + surround(4) * FRAC_1_SQRT_2;
+                remixed[1] = frame[1] + common + surround(5) * FRAC_1_SQRT_2;
+            }
+            Kind::Binaural(spatializer) => remixed.copy_from_slice(&spatializer.process(frame[0])),
+            Kind::Pan { angle, target, smoothing } => {
+                if angle != target {
+                    *angle = *target + (*angle - *target) * *smoothing;
+                    if (*angle - *target).abs() < 1e-5 {
+                        *angle = *target;
+                    }
+                }
+                remixed[0] = frame[0] * angle.cos();
+                remixed[1] = frame[0] * angle.sin();
+            }
+            Kind::Copy => {
+                let shared = frame.len().min(remixed.len());
+                remixed[..shared].copy_from_slice(&frame[..shared]);
+            }
+        }
+    }
+}
+
+/// A passive matrix: the center gets what the channels have in common, and the rears what differs
+/// between them, delayed and darkened.
+struct Upmix {
+    center: f32,
+    rear: f32,
+    delay: VecDeque<f32>,
+    /// One-pole low-pass on the ambience.
+    smoothing: f32,
+    ambience: f32,
+}
+
+impl Upmix {
+    fn new(config: &RemixConfig, rate: u32) -> Upmix {
+        let delay = (REAR_DELAY_MS / 1000.0 * rate as f32) as usize;
+        Upmix {
+            center: config.center.clamp(0.0, 1.0),
+            rear: 10f32.powf(config.rear_db / 20.0),
+            delay: VecDeque::from(vec![0.0; delay.max(1)]),
+            smoothing: 1.0 - (-TAU * REAR_CUTOFF_HZ / rate as f32).exp(),
+            ambience: 0.0,
+        }
+    }
+
+    fn process(&mut self, left: f32, right: f32, output: &mut [f32]) {
+        let center = self.center * (left + right) * 0.5;
+        output[0] = left - center;
+        output[1] = right - center;
+        output[2] = center;
+        // The LFE is left to the receiver's bass management.
+        self.delay.push_back((left - right) * 0.5);
+        let side = self.delay.pop_front().unwrap_or_default();
+        self.ambience += (side - self.ambience) * self.smoothing;
+        let mut ambience = self.ambience * self.rear;
+        if output.len() == 8 {
+            // Shared between the back and side pairs at the same power as one pair.
+            ambience *= FRAC_1_SQRT_2;
+            output[6] = ambience;
+            output[7] = -ambience;
+        }
+        // Out of phase, so it's heard as around the listener rather than from between the rears.
+        output[4] = ambience;
+        output[5] = -ambience;
+    }
+}
@@ -0,0 +1,105 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::config::RecordingConfig;
+use crate::lock;
+use crate::recorder::{self, RecordingError, RecordingTap};
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+const MIN_SECONDS: u32 = 30;
+const MAX_SECONDS: u32 = 300;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ReplayConfig {
+    pub enabled: bool,
+    /// How much history to keep, clamped to 30–300 seconds.
+    pub seconds: u32,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        ReplayConfig {
+            enabled: false,
+            seconds: 60,
+        }
+    }
+}
+
+/// Keeps the last few minutes of processed audio in memory so they can be saved after the fact.
+pub struct ReplayBuffer {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+    history: Arc<Mutex<VecDeque<f32>>>,
+    stream_config: StreamConfig,
+}
+
+impl ReplayBuffer {
+    pub fn start(replay: &ReplayConfig, config: &StreamConfig, tap: &RecordingTap) -> ReplayBuffer {
+        let channels = config.channels;
+        let samples_per_second = config.sample_rate.0 as usize * channels as usize;
+        let capacity = replay.seconds.clamp(MIN_SECONDS, MAX_SECONDS) as usize * samples_per_second;
+        let (producer, mut consumer) = RingBuffer::new(samples_per_second).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let history = Arc::new(Mutex::new(VecDeque::with_capacity(capacity)));
+        {
+            let stop = Arc::clone(&stop);
+            let history = Arc::clone(&history);
+            thread::spawn(move || {
+                let channels = channels as usize;
+                let mut buffer = vec![0f32; samples_per_second];
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    let mut history = history.lock().unwrap();
+                    history.extend(&buffer[..n]);
+                    let len = history.len();
+                    let excess = len.saturating_sub(capacity).div_ceil(channels) * channels;
+                    history.drain(..excess.min(len));
+                    drop(history);
+                    thread::sleep(DRAIN_INTERVAL);
+                }
+            });
+        }
+        ReplayBuffer {
+            tap: Arc::clone(tap),
+            stop,
+            history,
+            stream_config: config.clone(),
+        }
+    }
+
+    /// Writes the buffered history to a new `sound-amp-replay-<time>` file in the background.
+    pub fn save(&self, recording: &RecordingConfig, preset: Option<&str>) {
+        let samples: Vec<f32> = self.history.lock().unwrap().iter().copied().collect();
+        let tags = recording.tags.render(preset);
+        let recording = recording.clone();
+        let stream_config = self.stream_config.clone();
+        thread::spawn(move || {
+            let write = || -> Result<(), RecordingError> {
+                fs::create_dir_all(&recording.directory)?;
+                let path = recorder::timestamped_path(&recording.directory, "sound-amp-replay", recording.encoder.format);
+                let mut encoder = recording.encoder.create_encoder(&path, &stream_config, &tags)?;
+                encoder.write(&samples)?;
+                encoder.finalize()
+            };
+            if let Err(e) = write() {
+                eprintln!("Cannot save replay: {}", e);
+            }
+        });
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
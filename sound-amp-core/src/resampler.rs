@@ -0,0 +1,51 @@
+/// Streaming linear-interpolation resampler for interleaved samples.
+pub struct LinearResampler {
+    step: f64,
+    position: f64,
+    previous: Vec<f32>,
+}
+
+impl LinearResampler {
+    pub fn new(from_rate: u32, to_rate: u32, channels: usize) -> LinearResampler {
+        LinearResampler {
+            step: from_rate as f64 / to_rate as f64,
+            position: 0.0,
+            previous: vec![0.0; channels],
+        }
+    }
+
+    /// Appends the resampled `input` to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let channels = self.previous.len();
+        let frames = input.len() / channels;
+        if frames == 0 {
+            return;
+        }
+        // Frame 0 is the last frame of the previous block, frame k is input frame k - 1.
+        let frame = |k: usize, ch: usize| {
+            if k == 0 {
+                self.previous[ch]
+            } else {
+                input[(k - 1) * channels + ch]
+            }
+        };
+        while (self.position as usize) < frames {
+            let k = self.position as usize;
+            let fraction = (self.position - k as f64) as f32;
+            for ch in 0..channels {
+                output.push(frame(k, ch) * (1.0 - fraction) + frame(k + 1, ch) * fraction);
+            }
+            self.position += self.step;
+        }
+        self.position -= frames as f64;
+        self.previous
+            .copy_from_slice(&input[(frames - 1) * channels..frames * channels]);
+    }
+}
+
+/// Maps `input` onto `output_channels`, repeating source channels when there are fewer of them.
+pub fn remap_channels(input: &[f32], input_channels: usize, output_channels: usize, output: &mut Vec<f32>) {
+    for frame in input.chunks_exact(input_channels) {
+        output.extend((0..output_channels).map(|ch| frame[ch % input_channels]));
+    }
+}
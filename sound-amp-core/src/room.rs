@@ -0,0 +1,158 @@
+//! Room correction: an EQ on the output that evens out how the speakers and the room colour it,
+//! worked out from an [impulse response](crate::impulse) captured where the listener sits. The
+//! response's level in each third of an octave is compared with its average over the range
+//! corrected, and a peaking filter at each band makes up the difference.
+//!
+//! The corrections are held within limits. Boosts are kept small, since a dip is usually the room
+//! cancelling itself at the microphone, which no boost fills in and only uses up headroom; cuts can
+//! go deeper, taming the resonances that make a room boom. Below the range, the speakers can't go
+//! any lower, and above it the response depends too much on exactly where the microphone was.
+
+use std::path::{Path, PathBuf};
+
+use cpal::StreamConfig;
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+use serde::Deserialize;
+
+use crate::chain::Processor;
+use crate::effects::Biquad;
+use crate::playback;
+
+/// Bands per octave, and the Q of a peaking filter as wide as one.
+const BANDS_PER_OCTAVE: f32 = 3.0;
+const BAND_Q: f32 = 4.318;
+/// Corrections smaller than this aren't worth a filter.
+const MIN_CORRECTION_DB: f32 = 0.5;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoomCorrectionConfig {
+    /// The impulse response to correct for when sound-amp starts; nothing's corrected without one.
+    pub impulse_response: Option<PathBuf>,
+    /// The range corrected, in Hz.
+    pub low_hz: f32,
+    pub high_hz: f32,
+    pub max_boost_db: f32,
+    pub max_cut_db: f32,
+}
+
+impl Default for RoomCorrectionConfig {
+    fn default() -> Self {
+        RoomCorrectionConfig {
+            impulse_response: None,
+            low_hz: 40.0,
+            high_hz: 12000.0,
+            max_boost_db: 6.0,
+            max_cut_db: 12.0,
+        }
+    }
+}
+
+/// The correction for one measurement, in dB at the centre of each band that needs one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RoomCorrection {
+    pub bands: Vec<(f32, f32)>,
+}
+
+impl RoomCorrection {
+    /// Works out the correction for the impulse response in `path`, each channel's level in a band
+    /// averaged with the others'.
+    pub fn measure(path: &Path, config: &RoomCorrectionConfig) -> Result<RoomCorrection, String> {
+        let error = |e| format!("Cannot load impulse response {}: {}", path.display(), e);
+        let stream = playback::file_config(path).map_err(error)?;
+        let response = playback::decode_all(path, &stream).map_err(error)?;
+        let (channels, rate) = (stream.channels.max(1) as usize, stream.sample_rate.0 as f32);
+        if response.len() < channels {
+            return Err(format!("{} is empty", path.display()));
+        }
+
+        let size = (response.len() / channels).next_power_of_two();
+        let fft = FftPlanner::new().plan_fft_forward(size);
+        let mut power = vec![0f32; size / 2 + 1];
+        for channel in 0..channels {
+            let mut buffer: Vec<Complex<f32>> = response
+                .iter()
+                .skip(channel)
+                .step_by(channels)
+                .map(|&s| Complex::new(s, 0.0))
+                .collect();
+            buffer.resize(size, Complex::default());
+            fft.process(&mut buffer);
+            for (power, bin) in power.iter_mut().zip(&buffer) {
+                *power += bin.norm_sqr() / channels as f32;
+            }
+        }
+
+        let high_hz = config.high_hz.min(0.45 * rate);
+        let bin = |hz: f32| ((hz * size as f32 / rate).round() as usize).min(size / 2);
+        let half_band = 2f32.powf(0.5 / BANDS_PER_OCTAVE);
+        let levels: Vec<(f32, f32)> = centres(config.low_hz, high_hz)
+            .map(|centre| {
+                let (low, high) = (bin(centre / half_band), bin(centre * half_band));
+                let bins = &power[low..=high.max(low)];
+                let mean = bins.iter().sum::<f32>() / bins.len() as f32;
+                (centre, 10.0 * mean.max(1e-20).log10())
+            })
+            .collect();
+        if levels.is_empty() {
+            return Err(format!("Nothing to correct between {} and {} Hz", config.low_hz, high_hz));
+        }
+
+        let target = levels.iter().map(|(_, level)| level).sum::<f32>() / levels.len() as f32;
+        let (max_cut, max_boost) = (config.max_cut_db.abs(), config.max_boost_db.abs());
+        let bands = levels
+            .into_iter()
+            .map(|(centre, level)| (centre, (target - level).clamp(-max_cut, max_boost)))
+            .filter(|(_, gain)| gain.abs() >= MIN_CORRECTION_DB)
+            .collect();
+        Ok(RoomCorrection { bands })
+    }
+
+    /// The filters for a stream of `config`.
+    pub fn filter(&self, config: &StreamConfig) -> RoomFilter {
+        let nyquist = config.sample_rate.0 as f32 / 2.0;
+        let boost = self.bands.iter().map(|&(_, gain)| gain).fold(0f32, f32::max);
+        RoomFilter {
+            bands: self
+                .bands
+                .iter()
+                .filter(|&&(centre, _)| centre < nyquist)
+                .map(|&(centre, gain)| Biquad::peaking(centre, BAND_Q, gain, config))
+                .collect(),
+            headroom: 10f32.powf(-boost / 20.0),
+        }
+    }
+
+    /// The biggest boost and cut, for telling the user what it does.
+    pub fn extremes(&self) -> (f32, f32) {
+        self.bands
+            .iter()
+            .fold((0f32, 0f32), |(boost, cut), &(_, gain)| (boost.max(gain), cut.min(gain)))
+    }
+}
+
+/// The centres of the bands from `low` to `high`, on the standard third-octave series around
+/// 1 kHz.
+fn centres(low: f32, high: f32) -> impl Iterator<Item = f32> {
+    let step = |n: i32| 1000.0 * 2f32.powf(n as f32 / BANDS_PER_OCTAVE);
+    (-40..=40).map(step).filter(move |&centre| centre >= low && centre <= high)
+}
+
+/// A [`RoomCorrection`] set up for a stream, turned down by its biggest boost so it can't push
+/// anything into the ceiling.
+pub struct RoomFilter {
+    bands: Vec<Biquad>,
+    headroom: f32,
+}
+
+impl Processor for RoomFilter {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            *sample *= self.headroom;
+        }
+        for band in &mut self.bands {
+            band.process(samples);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::dither::Dither;
+use crate::lock;
+use crate::recorder::RecordingTap;
+use crate::resampler::{self, LinearResampler};
+
+const RTP_VERSION: u8 = 2;
+const HEADER_LEN: usize = 12;
+const PAYLOAD_TYPE_L16: u8 = 96;
+const PAYLOAD_TYPE_OPUS: u8 = 97;
+/// Opus always runs at 48 kHz on the wire, whatever the stream rate.
+#[cfg(feature = "opus")]
+const OPUS_RATE: u32 = 48000;
+const MAX_DATAGRAM: usize = 65536;
+/// Consecutive concealed frames after which playout stops and the jitter buffer refills.
+const MAX_CONCEALED: u32 = 25;
+
+pub type RtpError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RtpPayload {
+    /// Uncompressed 16-bit PCM.
+    #[default]
+    L16,
+    /// Needs the `opus` feature.
+    Opus,
+}
+
+/// Settings both ends of an RTP link have to agree on; there's no session negotiation.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RtpConfig {
+    pub payload: RtpPayload,
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Audio per packet; Opus accepts 2.5, 5, 10, 20, 40 or 60 ms.
+    pub frame_ms: f32,
+    /// How much audio the receiver holds back to ride out network jitter.
+    pub jitter_ms: u32,
+    /// Opus bitrate.
+    pub bitrate_kbps: u32,
+    /// Shape the [dither](crate::dither) of L16 towards the top of the band; the sender's alone.
+    pub noise_shaping: bool,
+}
+
+impl Default for RtpConfig {
+    fn default() -> Self {
+        RtpConfig {
+            payload: RtpPayload::default(),
+            sample_rate: 48000,
+            channels: 2,
+            frame_ms: 10.0,
+            jitter_ms: 40,
+            bitrate_kbps: 96,
+            noise_shaping: false,
+        }
+    }
+}
+
+impl RtpConfig {
+    pub fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: SampleRate(self.sample_rate),
+            buffer_size: BufferSize::Default,
+        }
+    }
+
+    fn frame_len(&self) -> usize {
+        (self.sample_rate as f32 * self.frame_ms / 1000.0) as usize
+    }
+
+    fn frame_duration(&self) -> Duration {
+        Duration::from_secs_f32(self.frame_ms / 1000.0)
+    }
+
+    /// RTP timestamp units per frame.
+    fn timestamp_step(&self) -> u32 {
+        match self.payload {
+            RtpPayload::L16 => self.frame_len() as u32,
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => (OPUS_RATE as f32 * self.frame_ms / 1000.0) as u32,
+            #[cfg(not(feature = "opus"))]
+            RtpPayload::Opus => 0,
+        }
+    }
+
+    fn payload_type(&self) -> u8 {
+        match self.payload {
+            RtpPayload::L16 => PAYLOAD_TYPE_L16,
+            RtpPayload::Opus => PAYLOAD_TYPE_OPUS,
+        }
+    }
+}
+
+/// Turns frames of the stream format into packet payloads.
+enum PayloadEncoder {
+    L16(Dither),
+    #[cfg(feature = "opus")]
+    Opus(opus::Encoder),
+}
+
+impl PayloadEncoder {
+    fn new(config: &RtpConfig) -> Result<PayloadEncoder, RtpError> {
+        match config.payload {
+            RtpPayload::L16 => Ok(PayloadEncoder::L16(Dither::new(config.channels, config.noise_shaping))),
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => {
+                let mut encoder = opus::Encoder::new(
+                    opus_rate(config)?,
+                    opus_channels(config)?,
+                    opus::Application::LowDelay,
+                )?;
+                encoder.set_bitrate(opus::Bitrate::Bits(config.bitrate_kbps as i32 * 1000))?;
+                encoder.set_inband_fec(true)?;
+                Ok(PayloadEncoder::Opus(encoder))
+            }
+            #[cfg(not(feature = "opus"))]
+            RtpPayload::Opus => Err("sound-amp was built without the `opus` feature".into()),
+        }
+    }
+
+    fn encode(&mut self, frame: &[f32], out: &mut Vec<u8>) -> Result<(), RtpError> {
+        match self {
+            PayloadEncoder::L16(dither) => {
+                for &s in frame {
+                    out.extend_from_slice(&dither.quantize(s).to_be_bytes());
+                }
+            }
+            #[cfg(feature = "opus")]
+            PayloadEncoder::Opus(encoder) => {
+                out.extend_from_slice(&encoder.encode_vec_float(frame, 4000)?)
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Turns packet payloads back into frames, and makes something up for the ones that never arrived.
+enum PayloadDecoder {
+    L16 {
+        last: Vec<f32>,
+        gain: f32,
+    },
+    #[cfg(feature = "opus")]
+    Opus(opus::Decoder),
+}
+
+impl PayloadDecoder {
+    fn new(config: &RtpConfig) -> Result<PayloadDecoder, RtpError> {
+        match config.payload {
+            RtpPayload::L16 => Ok(PayloadDecoder::L16 {
+                last: vec![0.0; config.frame_len() * config.channels as usize],
+                gain: 1.0,
+            }),
+            #[cfg(feature = "opus")]
+            RtpPayload::Opus => Ok(PayloadDecoder::Opus(opus::Decoder::new(
+                opus_rate(config)?,
+                opus_channels(config)?,
+            )?)),
+            #[cfg(not(feature = "opus"))]
+            RtpPayload::Opus => Err("sound-amp was built without the `opus` feature".into()),
+        }
+    }
+
+    fn decode(&mut self, payload: &[u8], frame: &mut [f32]) {
+        match self {
+            PayloadDecoder::L16 { last, gain } => {
+                for (sample, b) in frame.iter_mut().zip(payload.chunks_exact(2)) {
+                    *sample = i16::from_be_bytes([b[0], b[1]]) as f32 / i16::MAX as f32;
+                }
+                last.copy_from_slice(frame);
+                *gain = 1.0;
+            }
+            #[cfg(feature = "opus")]
+            PayloadDecoder::Opus(decoder) => {
+                if decoder.decode_float(payload, frame, false).is_err() {
+                    frame.fill(0.0);
+                }
+            }
+        }
+    }
+
+    /// Fills in for a lost packet.
+    fn conceal(&mut self, frame: &mut [f32]) {
+        match self {
+            // Repeat the last frame, fading out over a few losses in a row.
+            PayloadDecoder::L16 { last, gain } => {
+                *gain *= 0.5;
+                for (sample, s) in frame.iter_mut().zip(last.iter()) {
+                    *sample = s * *gain;
+                }
+            }
+            // An empty packet asks libopus for its own loss concealment.
+            #[cfg(feature = "opus")]
+            PayloadDecoder::Opus(decoder) => {
+                if decoder.decode_float(&[], frame, false).is_err() {
+                    frame.fill(0.0);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "opus")]
+fn opus_rate(config: &RtpConfig) -> Result<u32, RtpError> {
+    if config.sample_rate != OPUS_RATE {
+        return Err(format!("Opus over RTP needs sample_rate = {}", OPUS_RATE).into());
+    }
+    Ok(OPUS_RATE)
+}
+
+#[cfg(feature = "opus")]
+fn opus_channels(config: &RtpConfig) -> Result<opus::Channels, RtpError> {
+    match config.channels {
+        1 => Ok(opus::Channels::Mono),
+        2 => Ok(opus::Channels::Stereo),
+        n => Err(format!("Opus can't send {} channels", n).into()),
+    }
+}
+
+/// Packetizes samples arriving on the tap and sends them to `address` over UDP.
+pub struct RtpSender {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl RtpSender {
+    pub fn start(
+        address: &str,
+        rtp: &RtpConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<RtpSender, RtpError> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(address)?;
+        let mut encoder = PayloadEncoder::new(rtp)?;
+
+        let input_channels = config.channels as usize;
+        let capacity = config.sample_rate.0 as usize * input_channels;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let rtp = rtp.clone();
+            let input_rate = config.sample_rate.0;
+            thread::spawn(move || {
+                let channels = rtp.channels as usize;
+                let frame_len = rtp.frame_len() * channels;
+                let mut resampler = (input_rate != rtp.sample_rate)
+                    .then(|| LinearResampler::new(input_rate, rtp.sample_rate, channels));
+                let mut buffer = vec![0f32; capacity];
+                let mut remapped: Vec<f32> = Vec::new();
+                let mut pending: Vec<f32> = Vec::new();
+                let mut packet: Vec<u8> = Vec::new();
+                let ssrc = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.subsec_nanos());
+                let mut sequence = 0u16;
+                let mut timestamp = 0u32;
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    remapped.clear();
+                    resampler::remap_channels(
+                        &buffer[..n],
+                        input_channels,
+                        channels,
+                        &mut remapped,
+                    );
+                    match &mut resampler {
+                        Some(resampler) => resampler.process(&remapped, &mut pending),
+                        None => pending.extend_from_slice(&remapped),
+                    }
+                    while pending.len() >= frame_len {
+                        packet.clear();
+                        packet.push(RTP_VERSION << 6);
+                        packet.push(rtp.payload_type());
+                        packet.extend_from_slice(&sequence.to_be_bytes());
+                        packet.extend_from_slice(&timestamp.to_be_bytes());
+                        packet.extend_from_slice(&ssrc.to_be_bytes());
+                        if let Err(e) = encoder.encode(&pending[..frame_len], &mut packet) {
+                            eprintln!("Cannot encode RTP packet: {}", e);
+                        } else if let Err(e) = socket.send(&packet) {
+                            // Nobody listening yet is normal for UDP; keep going.
+                            if e.kind() != ErrorKind::ConnectionRefused {
+                                eprintln!("Cannot send RTP packet: {}", e);
+                            }
+                        }
+                        pending.drain(..frame_len);
+                        sequence = sequence.wrapping_add(1);
+                        timestamp = timestamp.wrapping_add(rtp.timestamp_step());
+                    }
+                    thread::sleep(rtp.frame_duration() / 4);
+                }
+            });
+        }
+        Ok(RtpSender {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// A bound socket waiting for an RTP stream, with the format it's expected in.
+pub struct RtpInput {
+    socket: UdpSocket,
+    decoder: PayloadDecoder,
+    pub config: RtpConfig,
+}
+
+impl RtpInput {
+    pub fn bind(port: u16, config: &RtpConfig) -> Result<RtpInput, RtpError> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        // The timeout keeps playout ticking while no packets arrive.
+        socket.set_read_timeout(Some(config.frame_duration() / 4))?;
+        Ok(RtpInput {
+            socket,
+            decoder: PayloadDecoder::new(config)?,
+            config: config.clone(),
+        })
+    }
+}
+
+/// Plays an RTP stream out of a jitter buffer at a steady pace, feeding every frame to `process`
+/// like an input stream callback would; the link resamples it from the configured rate to the
+/// output's. Stops once dropped.
+pub struct RtpReceiver {
+    stop: Arc<AtomicBool>,
+}
+
+impl RtpReceiver {
+    pub fn spawn<F>(input: RtpInput, mut process: F) -> RtpReceiver
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let RtpInput {
+            socket,
+            mut decoder,
+            config,
+        } = input;
+        let frame_duration = config.frame_duration();
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let target = (config.jitter_ms as f32 / config.frame_ms).ceil().max(1.0) as usize;
+                let mut jitter = JitterBuffer::new(config.payload_type(), target);
+                let mut datagram = vec![0u8; MAX_DATAGRAM];
+                let mut frame = vec![0f32; config.frame_len() * config.channels as usize];
+                // When the next frame is due, once playout has started.
+                let mut next_due: Option<Instant> = None;
+                while !stop.load(Ordering::Acquire) {
+                    match socket.recv(&mut datagram) {
+                        Ok(len) => jitter.insert(&datagram[..len]),
+                        Err(e)
+                            if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                        Err(e) => {
+                            eprintln!("Cannot receive RTP: {}", e);
+                            break;
+                        }
+                    }
+                    let due = match next_due {
+                        Some(due) => due,
+                        None if jitter.ready() => *next_due.insert(Instant::now()),
+                        None => continue,
+                    };
+                    // Catch up on every frame that's due.
+                    let mut due = due;
+                    while Instant::now() >= due {
+                        match jitter.pop() {
+                            Some(payload) => decoder.decode(&payload, &mut frame),
+                            None => decoder.conceal(&mut frame),
+                        }
+                        process(&frame);
+                        due += frame_duration;
+                    }
+                    next_due = Some(due);
+                    if jitter.stalled() {
+                        next_due = None;
+                        jitter.reset();
+                    }
+                }
+            });
+        }
+        RtpReceiver { stop }
+    }
+}
+
+impl Drop for RtpReceiver {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Received payloads ordered by sequence number, handed out one per frame period.
+struct JitterBuffer {
+    payload_type: u8,
+    packets: BTreeMap<u64, Vec<u8>>,
+    /// Extended sequence number of the next frame to play.
+    next: Option<u64>,
+    /// Frames held back before playout starts.
+    target: usize,
+    /// Frames missing in a row.
+    concealed: u32,
+}
+
+impl JitterBuffer {
+    fn new(payload_type: u8, target: usize) -> JitterBuffer {
+        JitterBuffer {
+            payload_type,
+            packets: BTreeMap::new(),
+            next: None,
+            target,
+            concealed: 0,
+        }
+    }
+
+    fn insert(&mut self, datagram: &[u8]) {
+        if datagram.len() < HEADER_LEN || datagram[0] >> 6 != RTP_VERSION {
+            return;
+        }
+        if datagram[1] & 0x7f != self.payload_type {
+            return;
+        }
+        let csrc_count = (datagram[0] & 0x0f) as usize;
+        let start = HEADER_LEN + csrc_count * 4;
+        if datagram.len() < start {
+            return;
+        }
+        let sequence = u16::from_be_bytes([datagram[2], datagram[3]]);
+        // Sequence numbers wrap at 16 bits; place each one relative to the frame due next.
+        let reference = *self.next.get_or_insert((1 << 32) + sequence as u64);
+        let offset = sequence.wrapping_sub(reference as u16) as i16;
+        let extended = (reference as i64 + offset as i64) as u64;
+        // Too late to play.
+        if extended < reference {
+            return;
+        }
+        self.packets.insert(extended, datagram[start..].to_vec());
+    }
+
+    /// Whether enough is buffered to start playing out.
+    fn ready(&self) -> bool {
+        self.len() >= self.target
+    }
+
+    /// The payload of the next frame, or `None` if it's missing.
+    fn pop(&mut self) -> Option<Vec<u8>> {
+        let payload = self.take();
+        match payload {
+            Some(_) => self.concealed = 0,
+            None => self.concealed += 1,
+        }
+        // A sender running fast piles up frames; drop the oldest to keep latency down.
+        while self.len() > self.target * 2 {
+            self.take();
+        }
+        payload
+    }
+
+    fn take(&mut self) -> Option<Vec<u8>> {
+        let next = self.next.as_mut()?;
+        let payload = self.packets.remove(next);
+        *next += 1;
+        payload
+    }
+
+    /// Whether so many frames went missing in a row that playout should stop and refill.
+    fn stalled(&self) -> bool {
+        self.concealed >= MAX_CONCEALED
+    }
+
+    /// Frames buffered from the next one on, counting gaps.
+    fn len(&self) -> usize {
+        match (self.next, self.packets.last_key_value()) {
+            (Some(next), Some((&last, _))) if last >= next => (last - next + 1) as usize,
+            _ => 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.packets.clear();
+        self.next = None;
+        self.concealed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An L16 packet carrying just `sequence`'s low byte, to tell it apart by.
+    fn packet(sequence: u16) -> Vec<u8> {
+        let mut packet = vec![RTP_VERSION << 6, PAYLOAD_TYPE_L16];
+        packet.extend_from_slice(&sequence.to_be_bytes());
+        packet.extend_from_slice(&[0; 8]);
+        packet.push(sequence as u8);
+        packet
+    }
+
+    fn buffer(target: usize, sequences: &[u16]) -> JitterBuffer {
+        let mut jitter = JitterBuffer::new(PAYLOAD_TYPE_L16, target);
+        for &sequence in sequences {
+            jitter.insert(&packet(sequence));
+        }
+        jitter
+    }
+
+    fn played(jitter: &mut JitterBuffer, frames: usize) -> Vec<Option<u8>> {
+        (0..frames).map(|_| jitter.pop().map(|payload| payload[0])).collect()
+    }
+
+    #[test]
+    fn plays_reordered_packets_in_order_and_conceals_lost_ones() {
+        let mut jitter = buffer(3, &[10, 13, 11]);
+        assert!(jitter.ready());
+        assert_eq!(played(&mut jitter, 4), [Some(10), Some(11), None, Some(13)]);
+    }
+
+    #[test]
+    fn follows_the_sequence_number_across_its_wrap() {
+        let mut jitter = buffer(4, &[65534, 0, 65535, 1]);
+        assert_eq!(jitter.len(), 4);
+        assert_eq!(played(&mut jitter, 4), [Some(254), Some(255), Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn drops_a_packet_that_arrives_after_its_frame_played() {
+        let mut jitter = buffer(1, &[10, 12]);
+        assert_eq!(played(&mut jitter, 2), [Some(10), None]);
+        jitter.insert(&packet(11));
+        jitter.insert(&packet(9));
+        assert_eq!(jitter.len(), 1);
+        assert_eq!(played(&mut jitter, 1), [Some(12)]);
+    }
+
+    #[test]
+    fn drops_the_oldest_frames_past_twice_the_target() {
+        let mut jitter = buffer(2, &[0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(played(&mut jitter, 1), [Some(0)]);
+        assert_eq!(jitter.len(), 4);
+        assert_eq!(played(&mut jitter, 4), [Some(3), Some(4), Some(5), Some(6)]);
+    }
+
+    #[test]
+    fn stalls_after_too_many_lost_frames_in_a_row_and_starts_over() {
+        let mut jitter = buffer(20, &[10, 30]);
+        played(&mut jitter, 1);
+        // Nineteen lost, then one that made it, which starts the count over.
+        played(&mut jitter, 20);
+        assert_eq!(jitter.concealed, 0);
+        played(&mut jitter, MAX_CONCEALED as usize - 1);
+        assert!(!jitter.stalled());
+        played(&mut jitter, 1);
+        assert!(jitter.stalled());
+
+        jitter.reset();
+        assert!(!jitter.stalled() && !jitter.ready());
+        // Whatever the sender is up to now, far from where it left off.
+        for sequence in 1000..1020 {
+            jitter.insert(&packet(sequence));
+        }
+        assert!(jitter.ready());
+        assert_eq!(played(&mut jitter, 1), [Some(1000u16 as u8)]);
+    }
+}
@@ -0,0 +1,308 @@
+//! A ceiling on how loud the output gets at the listener's ears, set in dB SPL and turned into a
+//! sample level by a calibration of the user's own output and headphones. It's the last thing the
+//! output graph runs, so no volume, effect or mix can push past it. It holds the true peak under
+//! the ceiling, the loudest the wave gets once the DAC has drawn it between the samples, which
+//! can be a few dB over the loudest sample.
+//!
+//! Under the ceiling, a [`LoudnessGuard`] can warn when the output stays loud for a long time, and
+//! turn it down a little until it quietens.
+
+use std::collections::VecDeque;
+use std::f32::consts::{PI, TAU};
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+/// Level of the tone `--calibrate` plays, well below full scale so measuring it is comfortable.
+pub const CALIBRATION_DBFS: f32 = -20.0;
+pub const CALIBRATION_HZ: f32 = 1000.0;
+
+/// How long the limiter takes to come back up once the output is below the ceiling again.
+const RELEASE_MS: f32 = 200.0;
+/// Gain under which the limiter counts as turning the output down, rather than all but done
+/// coming back up.
+const LIMITING_GAIN: f32 = 0.99;
+/// How many times over the signal is sampled to find its true peak, as BS.1770 does it.
+const OVERSAMPLING: usize = 4;
+/// Samples either side of a point the interpolation looks at, which is how far behind the
+/// [`TruePeak`] detector is, and the limiter holds the output back.
+const TRUE_PEAK_DELAY: usize = 6;
+/// What the loudness guard averages the output's level over.
+const LOUDNESS_WINDOW_SECONDS: f32 = 1.0;
+/// How long the output has to stay under the alert level for the alert to end.
+const LOUDNESS_RELEASE_SECONDS: f32 = 5.0;
+/// How fast the guard turns the output down and back up.
+const LOUDNESS_RAMP_SECONDS: f32 = 1.0;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SafetyConfig {
+    /// Loudest the output may get, in estimated dB SPL; no ceiling when it's left out.
+    pub ceiling_db_spl: Option<f32>,
+    /// dB SPL a full-scale sine reaches through the user's output, as `--calibrate` measured it.
+    pub full_scale_db_spl: Option<f32>,
+    /// How long a link takes to come up from silence when it starts, so the first of the input
+    /// doesn't come as a blast.
+    pub fade_in_ms: f32,
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            ceiling_db_spl: None,
+            full_scale_db_spl: None,
+            fade_in_ms: 300.0,
+        }
+    }
+}
+
+impl SafetyConfig {
+    /// Peak sample level of a sine at the ceiling, or `None` if there's no ceiling.
+    pub fn ceiling(&self) -> Result<Option<f32>, String> {
+        match (self.ceiling_db_spl, self.full_scale_db_spl) {
+            (None, _) => Ok(None),
+            (Some(ceiling), Some(full_scale)) => Ok(Some(10f32.powf((ceiling - full_scale) / 20.0))),
+            (Some(_), None) => Err(
+                "safety.ceiling_db_spl needs safety.full_scale_db_spl; run sound-amp --calibrate to measure it"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoudnessAlertConfig {
+    /// RMS level of the output, in dBFS, that counts as loud; no alert when it's left out.
+    pub level_db: Option<f32>,
+    /// How long the output has to stay that loud before the alert goes off.
+    pub seconds: f32,
+    /// Turn the output down while the alert is on, rather than only warning.
+    pub attenuate: bool,
+    /// How far to turn it down.
+    pub attenuation_db: f32,
+}
+
+impl Default for LoudnessAlertConfig {
+    fn default() -> Self {
+        LoudnessAlertConfig {
+            level_db: None,
+            seconds: 30.0,
+            attenuate: false,
+            attenuation_db: 6.0,
+        }
+    }
+}
+
+/// What to set `full_scale_db_spl` to, given the dB SPL the calibration tone measured at.
+pub fn full_scale_db_spl(measured_db_spl: f32) -> f32 {
+    measured_db_spl - CALIBRATION_DBFS
+}
+
+/// Fills blocks of a stream of `config` with the calibration tone on every channel.
+pub fn calibration_tone(config: &StreamConfig) -> impl FnMut(&mut [f32]) + Send + 'static {
+    let channels = config.channels as usize;
+    let step = TAU * CALIBRATION_HZ / config.sample_rate.0 as f32;
+    let amplitude = 10f32.powf(CALIBRATION_DBFS / 20.0);
+    let mut phase = 0f32;
+    move |block: &mut [f32]| {
+        for frame in block.chunks_mut(channels) {
+            frame.fill(amplitude * phase.sin());
+            phase = (phase + step) % TAU;
+        }
+    }
+}
+
+/// Finds the true peak of a signal by interpolating three points between every two samples, with
+/// a windowed sinc, and taking the loudest of them and the samples.
+pub struct TruePeak {
+    channels: usize,
+    /// The filter for each of the three points, to run over the last samples, newest first.
+    phases: [[f32; 2 * TRUE_PEAK_DELAY]; OVERSAMPLING - 1],
+    /// Each channel's last `2 * TRUE_PEAK_DELAY` samples, newest first.
+    history: Vec<f32>,
+}
+
+impl TruePeak {
+    pub fn new(config: &StreamConfig) -> TruePeak {
+        // A sinc over the taps of the filter at four times the rate, centred where the input
+        // sample `TRUE_PEAK_DELAY` back falls; each point takes every fourth tap.
+        let centre = (2 * TRUE_PEAK_DELAY * OVERSAMPLING) as f32 / 2.0;
+        let tap = |t: usize| {
+            let x = (t as f32 - centre) / OVERSAMPLING as f32;
+            let sinc = if x == 0.0 { 1.0 } else { (PI * x).sin() / (PI * x) };
+            let window = 0.5 + 0.5 * (PI * (t as f32 - centre) / (centre + 1.0)).cos();
+            sinc * window
+        };
+        let mut phases = [[0f32; 2 * TRUE_PEAK_DELAY]; OVERSAMPLING - 1];
+        for (i, phase) in phases.iter_mut().enumerate() {
+            for (j, coefficient) in phase.iter_mut().enumerate() {
+                *coefficient = tap(i + 1 + OVERSAMPLING * j);
+            }
+            // Unity gain, so a steady signal reads the same between the samples as on them.
+            let sum: f32 = phase.iter().sum();
+            phase.iter_mut().for_each(|coefficient| *coefficient /= sum);
+        }
+        TruePeak {
+            channels: config.channels as usize,
+            phases,
+            history: vec![0.0; config.channels as usize * 2 * TRUE_PEAK_DELAY],
+        }
+    }
+
+    /// Adds a frame, and gives the true peak of the stretch from the frame `TRUE_PEAK_DELAY`
+    /// back to the one after it, over all the channels.
+    pub fn push(&mut self, frame: &[f32]) -> f32 {
+        let len = 2 * TRUE_PEAK_DELAY;
+        let mut peak = 0f32;
+        for (channel, history) in frame.iter().zip(self.history.chunks_mut(len)) {
+            history.copy_within(..len - 1, 1);
+            history[0] = *channel;
+            peak = peak.max(history[TRUE_PEAK_DELAY].abs());
+            for phase in &self.phases {
+                let point: f32 = phase.iter().zip(history.iter()).map(|(c, s)| c * s).sum();
+                peak = peak.max(point.abs());
+            }
+        }
+        peak
+    }
+
+    /// The true peak of a block, `TRUE_PEAK_DELAY` frames behind it.
+    pub fn process(&mut self, samples: &[f32]) -> f32 {
+        samples
+            .chunks(self.channels)
+            .fold(0f32, |peak, frame| peak.max(self.push(frame)))
+    }
+}
+
+/// A brickwall limiter on the true peak: the gain drops the moment the wave around a frame would
+/// go over the ceiling, so it never does, even between the samples, and comes back up slowly. The
+/// output is held back [`TRUE_PEAK_DELAY`] frames, for the detector to see what's coming. It
+/// follows the loudest channel so the image holds still.
+pub struct SafetyLimiter {
+    ceiling: f32,
+    release: f32,
+    channels: usize,
+    gain: f32,
+    detector: TruePeak,
+    /// The input waiting out the detector's delay.
+    delay: VecDeque<f32>,
+    /// The true peak from the frame going out back to the one before it.
+    previous: f32,
+}
+
+impl SafetyLimiter {
+    pub fn new(ceiling: f32, config: &StreamConfig) -> SafetyLimiter {
+        let rate = config.sample_rate.0 as f32;
+        let channels = config.channels as usize;
+        SafetyLimiter {
+            ceiling,
+            release: (-1.0 / (RELEASE_MS / 1000.0 * rate)).exp(),
+            channels,
+            gain: 1.0,
+            detector: TruePeak::new(config),
+            delay: VecDeque::from(vec![0.0; channels * TRUE_PEAK_DELAY]),
+            previous: 0.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(self.channels) {
+            // The wave either side of the frame going out, since its gain shapes both.
+            let ahead = self.detector.push(frame);
+            let peak = ahead.max(self.previous);
+            self.previous = ahead;
+            self.delay.extend(frame.iter());
+            let target = if peak > self.ceiling {
+                self.ceiling / peak
+            } else {
+                1.0
+            };
+            self.gain = if target < self.gain {
+                target
+            } else {
+                target + (self.gain - target) * self.release
+            };
+            for sample in frame {
+                *sample = self.delay.pop_front().unwrap_or(0.0) * self.gain;
+            }
+        }
+    }
+
+    pub fn limiting(&self) -> bool {
+        self.gain < LIMITING_GAIN
+    }
+}
+
+/// Watches for the output staying over a level for too long. The level is taken before the guard
+/// turns anything down, so turning it down doesn't end the alert; the output quietening does.
+pub struct LoudnessGuard {
+    /// The alert level as a mean square.
+    threshold: f32,
+    /// Frames over the level it takes to set the alert off, and under it to end it.
+    onset_frames: u64,
+    release_frames: u64,
+    attenuation: Option<f32>,
+    smoothing: f32,
+    ramp: f32,
+    channels: usize,
+    mean_square: f32,
+    /// Frames in a row the output has been over the level, or under it.
+    loud_frames: u64,
+    quiet_frames: u64,
+    alert: bool,
+    gain: f32,
+}
+
+impl LoudnessGuard {
+    /// A guard for `config`, or `None` if it sets no alert level.
+    pub fn new(config: &LoudnessAlertConfig, stream: &StreamConfig) -> Option<LoudnessGuard> {
+        let level_db = config.level_db?;
+        let rate = stream.sample_rate.0 as f32;
+        Some(LoudnessGuard {
+            threshold: 10f32.powf(level_db / 10.0),
+            onset_frames: (config.seconds.max(0.0) * rate) as u64,
+            release_frames: (LOUDNESS_RELEASE_SECONDS * rate) as u64,
+            attenuation: config
+                .attenuate
+                .then(|| 10f32.powf(-config.attenuation_db.abs() / 20.0)),
+            smoothing: (-1.0 / (LOUDNESS_WINDOW_SECONDS * rate)).exp(),
+            ramp: (-1.0 / (LOUDNESS_RAMP_SECONDS / 3.0 * rate)).exp(),
+            channels: stream.channels as usize,
+            mean_square: 0.0,
+            loud_frames: 0,
+            quiet_frames: 0,
+            alert: false,
+            gain: 1.0,
+        })
+    }
+
+    /// Takes a block of the output and says whether the alert is on as of its end.
+    pub fn process(&mut self, samples: &mut [f32]) -> bool {
+        for frame in samples.chunks_mut(self.channels) {
+            let power = frame.iter().map(|s| s * s).sum::<f32>() / self.channels as f32;
+            self.mean_square = power + (self.mean_square - power) * self.smoothing;
+            if self.mean_square > self.threshold {
+                self.loud_frames += 1;
+                self.quiet_frames = 0;
+            } else {
+                self.quiet_frames += 1;
+                self.loud_frames = 0;
+            }
+            if self.loud_frames >= self.onset_frames {
+                self.alert = true;
+            } else if self.quiet_frames >= self.release_frames {
+                self.alert = false;
+            }
+            let target = match self.attenuation {
+                Some(attenuation) if self.alert => attenuation,
+                _ => 1.0,
+            };
+            self.gain = target + (self.gain - target) * self.ramp;
+            for sample in frame {
+                *sample *= self.gain;
+            }
+        }
+        self.alert
+    }
+}
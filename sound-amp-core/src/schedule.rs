@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Datelike, Local};
+
+use crate::config::{Profile, ScheduleRule};
+use crate::PlayerCommand;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Applies the profile of the first matching schedule rule whenever it changes.
+/// While `manual_override` is set the schedule is paused; once it is cleared
+/// the scheduled profile is re-applied on the next check.
+pub fn spawn(
+    rules: Vec<ScheduleRule>,
+    profiles: Vec<Profile>,
+    manual_override: Arc<AtomicBool>,
+    player_channel: Sender<PlayerCommand>,
+) {
+    if rules.is_empty() {
+        return;
+    }
+    thread::spawn(move || {
+        let mut applied: Option<String> = None;
+        loop {
+            if manual_override.load(Ordering::Relaxed) {
+                applied = None;
+            } else {
+                let now = Local::now();
+                let scheduled = rules
+                    .iter()
+                    .find(|rule| rule.matches(now.weekday(), now.time()))
+                    .and_then(|rule| profiles.iter().find(|p| p.name == rule.profile));
+                if let Some(profile) = scheduled {
+                    if applied.as_ref() != Some(&profile.name) {
+                        if player_channel.send(PlayerCommand::ApplyProfile(profile.clone())).is_err() {
+                            break;
+                        }
+                        applied = Some(profile.name.clone());
+                    }
+                }
+            }
+            thread::sleep(CHECK_INTERVAL);
+        }
+    });
+}
@@ -0,0 +1,135 @@
+use std::io::ErrorKind;
+#[cfg(feature = "network")]
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::{error, fs, io};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{Profile, RecordingConfig};
+#[cfg(feature = "network")]
+use crate::discovery::{Peer, Transport};
+use crate::{find_input_device, InputSource, PlayerCommand};
+
+pub type SessionError = Box<dyn error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SessionConfig {
+    /// Where the running link is kept while it runs.
+    pub path: PathBuf,
+    pub resume: ResumeMode,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            path: PathBuf::from("sound-amp-session.toml"),
+            resume: ResumeMode::default(),
+        }
+    }
+}
+
+/// What to do at launch with a session the last run left behind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ResumeMode {
+    /// Don't keep the session at all.
+    Off,
+    /// Offer to restore it.
+    #[default]
+    Ask,
+    /// Restore it right away.
+    Auto,
+}
+
+/// The routing and settings of a running device link, kept on disk so the next launch can bring it
+/// back if this run crashes or is killed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    /// Name of the input device; its position in the device list can change between runs.
+    pub input: String,
+    pub volume: f32,
+    /// Missing from sessions saved before there was a trim.
+    #[serde(default = "default_trim")]
+    pub trim: f32,
+    pub muted: bool,
+    pub preset: Option<String>,
+    pub recording: bool,
+    pub send_to: Option<String>,
+    pub rtp_send_to: Option<String>,
+}
+
+impl Session {
+    /// The session a run that didn't exit cleanly left at `path`, if any.
+    pub fn load(path: &Path) -> Result<Option<Session>, SessionError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Some(toml::from_str(&text)?)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the session at `path` in one step, so a crash mid-write can't leave half of it.
+    pub fn save(&self, path: &Path) -> Result<(), SessionError> {
+        let partial = path.with_extension("partial");
+        fs::write(&partial, toml::to_string(self)?)?;
+        fs::rename(partial, path)?;
+        Ok(())
+    }
+
+    /// Forgets the session at `path`, as on a clean exit or once nothing is linked.
+    pub fn clear(path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    /// The commands that bring the session back, or `None` when its input device is gone.
+    pub fn restore(
+        &self,
+        profiles: &[Profile],
+        recording: &RecordingConfig,
+    ) -> Option<Vec<PlayerCommand>> {
+        let device = find_input_device(&self.input)?;
+        let mut commands = Vec::new();
+        // The profile goes first so the device and volume below win over the ones it brings.
+        let profile = self
+            .preset
+            .as_deref()
+            .and_then(|name| profiles.iter().find(|p| p.name == name));
+        if let Some(profile) = profile {
+            commands.push(PlayerCommand::ApplyProfile(profile.clone()));
+        }
+        commands.push(PlayerCommand::Start(InputSource::Device(device)));
+        commands.push(PlayerCommand::SetVolume(self.volume));
+        commands.push(PlayerCommand::SetTrim(self.trim));
+        commands.push(PlayerCommand::SetMuted(self.muted));
+        // A receiver picked at runtime is one or the other; both at once came from the command line.
+        #[cfg(feature = "network")]
+        let target = match (&self.send_to, &self.rtp_send_to) {
+            (Some(address), None) => Some((address, Transport::Tcp)),
+            (None, Some(address)) => Some((address, Transport::Rtp)),
+            _ => None,
+        };
+        #[cfg(feature = "network")]
+        if let Some((address, transport)) = target {
+            if let Ok(address) = address.parse::<SocketAddr>() {
+                commands.push(PlayerCommand::SendTo(Some(Peer {
+                    name: address.to_string(),
+                    address,
+                    transport,
+                })));
+            }
+        }
+        if self.recording {
+            commands.push(PlayerCommand::StartRecording(recording.clone()));
+        }
+        Some(commands)
+    }
+}
+
+fn default_trim() -> f32 {
+    1.0
+}
@@ -0,0 +1,50 @@
+//! The sleep timer, for listening at night: once it runs out, the links and whatever's playing
+//! along with them fade out slowly and stop.
+
+use std::time::{Duration, Instant};
+
+/// How long the output takes to fade out before the timer stops the links.
+pub const FADE: Duration = Duration::from_secs(30);
+
+/// What the timer wants done as of a check.
+pub enum Sleep {
+    Wait,
+    /// Start fading out, to be silent in this long; said only once.
+    FadeOut(Duration),
+    Stop,
+}
+
+pub struct SleepTimer {
+    stop_at: Instant,
+    fading: bool,
+}
+
+impl SleepTimer {
+    pub fn new(after: Duration) -> SleepTimer {
+        SleepTimer {
+            stop_at: Instant::now() + after,
+            fading: false,
+        }
+    }
+
+    pub fn remaining(&self) -> Duration {
+        self.stop_at.saturating_duration_since(Instant::now())
+    }
+
+    /// Whether the fade has started.
+    pub fn fading(&self) -> bool {
+        self.fading
+    }
+
+    pub fn check(&mut self) -> Sleep {
+        let remaining = self.remaining();
+        if remaining.is_zero() {
+            Sleep::Stop
+        } else if remaining <= FADE && !self.fading {
+            self.fading = true;
+            Sleep::FadeOut(remaining)
+        } else {
+            Sleep::Wait
+        }
+    }
+}
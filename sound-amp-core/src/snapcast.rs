@@ -0,0 +1,207 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::dither::Dither;
+use crate::lock;
+use crate::net;
+use crate::pipe::PcmFormat;
+use crate::recorder::RecordingTap;
+use crate::resampler::{self, LinearResampler};
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+pub type SnapcastError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SnapcastConfig {
+    /// The server's `pipe` source as a FIFO path, or its `tcp` source as `tcp://host:port`.
+    pub target: Option<String>,
+    /// The `sampleformat` of that source, as `rate:bits:channels`; only 16-bit samples are written.
+    pub sample_format: String,
+    /// Shape the [dither](crate::dither) towards the top of the band.
+    pub noise_shaping: bool,
+}
+
+impl Default for SnapcastConfig {
+    fn default() -> Self {
+        SnapcastConfig {
+            target: None,
+            sample_format: "48000:16:2".to_string(),
+            noise_shaping: false,
+        }
+    }
+}
+
+/// Where the server reads its source from.
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Pipe(PathBuf),
+    /// A `tcp` source in server mode, which we connect to.
+    Tcp(String),
+}
+
+impl Target {
+    fn parse(target: &str) -> Target {
+        match target.strip_prefix("tcp://") {
+            Some(address) => Target::Tcp(address.to_string()),
+            None => Target::Pipe(PathBuf::from(
+                target.strip_prefix("pipe://").unwrap_or(target),
+            )),
+        }
+    }
+
+    /// Blocks on a FIFO until the server opens it for reading.
+    fn open(&self) -> io::Result<Box<dyn Write>> {
+        Ok(match self {
+            // Never create the path: a regular file in place of the FIFO would silently swallow the audio.
+            Target::Pipe(path) => Box::new(OpenOptions::new().write(true).open(path)?),
+            Target::Tcp(address) => Box::new(TcpStream::connect(address)?),
+        })
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Target::Pipe(path) => path.display().to_string(),
+            Target::Tcp(address) => format!("tcp://{}", address),
+        }
+    }
+}
+
+/// Parses a Snapcast `rate:bits:channels` sample format.
+fn parse_sample_format(format: &str) -> Result<(u32, u16), SnapcastError> {
+    let invalid = || {
+        format!(
+            "Invalid Snapcast sample format '{}', expected rate:bits:channels",
+            format
+        )
+    };
+    let mut parts = format.split(':').map(str::parse::<u32>);
+    let (Some(Ok(rate)), Some(Ok(bits)), Some(Ok(channels)), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return Err(invalid().into());
+    };
+    if bits != 16 {
+        return Err(format!("Snapcast sources have to be 16-bit, not {}-bit", bits).into());
+    }
+    if rate == 0 || channels == 0 || channels > u16::MAX as u32 {
+        return Err(invalid().into());
+    }
+    Ok((rate, channels as u16))
+}
+
+/// Feeds samples arriving on the tap to a Snapcast server, converted to the format its source expects,
+/// and reopens the pipe or connection whenever the server goes away.
+pub struct SnapcastSink {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl SnapcastSink {
+    pub fn start(
+        snapcast: &SnapcastConfig,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<SnapcastSink, SnapcastError> {
+        let target = Target::parse(
+            snapcast
+                .target
+                .as_deref()
+                .ok_or("No Snapcast target is set")?,
+        );
+        let (rate, channels) = parse_sample_format(&snapcast.sample_format)?;
+        let input_channels = config.channels as usize;
+        let channels = channels as usize;
+        let mut resampler = (config.sample_rate.0 != rate)
+            .then(|| LinearResampler::new(config.sample_rate.0, rate, channels));
+        let mut dither = Dither::new(channels as u16, snapcast.noise_shaping);
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut remapped: Vec<f32> = Vec::new();
+                let mut resampled: Vec<f32> = Vec::new();
+                let mut bytes: Vec<u8> = Vec::new();
+                let mut reported = false;
+                while !stop.load(Ordering::Acquire) {
+                    let mut output = match target.open() {
+                        Ok(output) => {
+                            eprintln!("Feeding Snapcast at {}", target.describe());
+                            reported = false;
+                            output
+                        }
+                        Err(e) => {
+                            if !reported {
+                                eprintln!(
+                                    "Cannot open Snapcast source {}, retrying: {}",
+                                    target.describe(),
+                                    e
+                                );
+                                reported = true;
+                            }
+                            net::wait_to_reconnect(RECONNECT_INTERVAL, &mut consumer);
+                            continue;
+                        }
+                    };
+                    while !stop.load(Ordering::Acquire) {
+                        let n = consumer.pop_slice(&mut buffer);
+                        if n > 0 {
+                            remapped.clear();
+                            resampler::remap_channels(
+                                &buffer[..n],
+                                input_channels,
+                                channels,
+                                &mut remapped,
+                            );
+                            let samples = match &mut resampler {
+                                Some(resampler) => {
+                                    resampled.clear();
+                                    resampler.process(&remapped, &mut resampled);
+                                    &resampled
+                                }
+                                None => &remapped,
+                            };
+                            bytes.clear();
+                            PcmFormat::S16.encode(samples, &mut dither, &mut bytes);
+                            if output
+                                .write_all(&bytes)
+                                .and_then(|_| output.flush())
+                                .is_err()
+                            {
+                                eprintln!("Lost Snapcast source {}", target.describe());
+                                break;
+                            }
+                        }
+                        thread::sleep(DRAIN_INTERVAL);
+                    }
+                }
+            });
+        }
+        Ok(SnapcastSink {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+use crate::lock;
+use crate::playback;
+
+/// Where the output callback picks up triggered samples to mix in.
+pub type SoundboardBus = Arc<Mutex<SoundboardMix>>;
+
+/// How many samples can overlap before the oldest one is cut off.
+const MAX_VOICES: usize = 16;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SoundboardConfig {
+    /// Level of the whole soundboard in the output mix.
+    pub volume: f32,
+    pub samples: Vec<SampleBinding>,
+}
+
+impl Default for SoundboardConfig {
+    fn default() -> Self {
+        SoundboardConfig {
+            volume: 1.0,
+            samples: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SampleBinding {
+    pub key: Hotkey,
+    pub file: PathBuf,
+    /// Level of this sample, on top of the soundboard volume.
+    #[serde(default = "default_sample_volume")]
+    pub volume: f32,
+}
+
+fn default_sample_volume() -> f32 {
+    1.0
+}
+
+/// A key written as a single character like `a` or a function key like `F1`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Hotkey {
+    Char(char),
+    F(u8),
+}
+
+impl TryFrom<String> for Hotkey {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut chars = value.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Ok(Hotkey::Char(c));
+        }
+        value
+            .strip_prefix(['F', 'f'])
+            .and_then(|n| n.parse::<u8>().ok())
+            .filter(|n| (1..=12).contains(n))
+            .map(Hotkey::F)
+            .ok_or_else(|| format!("invalid key '{}', expected a single character or F1-F12", value))
+    }
+}
+
+struct Voice {
+    samples: Arc<[f32]>,
+    position: usize,
+    volume: f32,
+}
+
+/// Samples currently sounding; the output callback mixes them on top of the live signal.
+#[derive(Default)]
+pub struct SoundboardMix {
+    voices: Vec<Voice>,
+}
+
+impl SoundboardMix {
+    pub fn mix_into(&mut self, output: &mut [f32]) {
+        for voice in &mut self.voices {
+            let remaining = &voice.samples[voice.position..];
+            for (sample, s) in output.iter_mut().zip(remaining) {
+                *sample += s * voice.volume;
+            }
+            voice.position += remaining.len().min(output.len());
+        }
+        self.voices.retain(|voice| voice.position < voice.samples.len());
+    }
+
+    /// A voice played past the end of its sample, which can't happen outside a test, so that
+    /// mixing it in falls over.
+    #[cfg(test)]
+    pub(crate) fn overrun() -> SoundboardMix {
+        SoundboardMix {
+            voices: vec![Voice {
+                samples: Arc::from([]),
+                position: 1,
+                volume: 1.0,
+            }],
+        }
+    }
+}
+
+/// The configured samples, decoded and converted for one output stream.
+pub struct Soundboard {
+    samples: Vec<Option<(Arc<[f32]>, f32)>>,
+    bus: SoundboardBus,
+}
+
+impl Soundboard {
+    /// Decodes every sample for `output_config`; a sample that can't be loaded is reported and left silent.
+    pub fn load(config: &SoundboardConfig, output_config: &StreamConfig, bus: &SoundboardBus) -> Soundboard {
+        let samples = config
+            .samples
+            .iter()
+            .map(|binding| match playback::decode_all(&binding.file, output_config) {
+                Ok(samples) => Some((Arc::from(samples), config.volume * binding.volume)),
+                Err(e) => {
+                    eprintln!("Cannot load sample {}: {}", binding.file.display(), e);
+                    None
+                }
+            })
+            .collect();
+        let mut mix = lock(bus);
+        mix.voices.clear();
+        mix.voices.reserve(MAX_VOICES);
+        Soundboard {
+            samples,
+            bus: Arc::clone(bus),
+        }
+    }
+
+    /// Starts sample `index` playing, on top of anything already sounding.
+    pub fn trigger(&self, index: usize) {
+        if let Some(Some((samples, volume))) = self.samples.get(index) {
+            let mut mix = lock(&self.bus);
+            if mix.voices.len() >= MAX_VOICES {
+                mix.voices.remove(0);
+            }
+            mix.voices.push(Voice {
+                samples: Arc::clone(samples),
+                position: 0,
+                volume: *volume,
+            });
+        }
+    }
+}
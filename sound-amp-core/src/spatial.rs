@@ -0,0 +1,145 @@
+//! Placing a mono input around the listener in headphones, with Brown and Duda's structural model
+//! of the head-related transfer function: each ear hears the source late by the time it takes to
+//! get round a spherical head and dulled by the head's shadow, after a handful of echoes off the
+//! outer ear that move with the elevation.
+
+use std::f32::consts::{FRAC_PI_2, PI};
+
+use serde::{Deserialize, Serialize};
+
+/// Radius of the modelled head, in meters.
+const HEAD_RADIUS: f32 = 0.0875;
+const SPEED_OF_SOUND: f32 = 343.0;
+/// The head shadow's strongest dulling, and the angle from the ear it's reached at.
+const SHADOW_MIN: f32 = 0.1;
+const SHADOW_MIN_ANGLE: f32 = 150.0;
+/// The outer ear's echoes: gain, then the delay's swing with direction, its fixed part, both in
+/// samples at the model's 44.1 kHz, and how much the elevation moves it.
+const PINNA: [(f32, f32, f32, f32); 5] = [
+    (0.5, 1.0, 2.0, 1.0),
+    (-1.0, 5.0, 4.0, 0.5),
+    (0.5, 5.0, 7.0, 0.5),
+    (-0.25, 5.0, 11.0, 0.5),
+    (0.25, 5.0, 13.0, 0.5),
+];
+const PINNA_RATE: f32 = 44100.0;
+
+/// Where a source is heard from, in degrees: azimuth clockwise from straight ahead, elevation up
+/// from the horizon.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub struct Position {
+    pub azimuth: f32,
+    #[serde(default)]
+    pub elevation: f32,
+}
+
+impl Position {
+    /// The same elevation, turned `degrees` further round, kept within -180 to 180.
+    pub fn turned(self, degrees: f32) -> Position {
+        let azimuth = (self.azimuth + degrees + 180.0).rem_euclid(360.0) - 180.0;
+        Position { azimuth, ..self }
+    }
+}
+
+/// Renders a mono stream as the two ears would hear it from a [`Position`].
+pub struct Spatializer {
+    pinna: Vec<(f32, usize)>,
+    /// The input's latest samples, newest first, for the echoes.
+    history: Vec<f32>,
+    next: usize,
+    ears: [Ear; 2],
+}
+
+impl Spatializer {
+    pub fn new(position: Position, rate: u32) -> Spatializer {
+        let azimuth = position.azimuth.to_radians();
+        let elevation = position.elevation.clamp(-90.0, 90.0).to_radians();
+        let scale = rate as f32 / PINNA_RATE;
+        let pinna: Vec<(f32, usize)> = PINNA
+            .iter()
+            .map(|&(gain, swing, fixed, tilt)| {
+                let delay = swing * (azimuth / 2.0).cos() * (tilt * (FRAC_PI_2 - elevation)).sin() + fixed;
+                (gain, (delay * scale).round().max(1.0) as usize)
+            })
+            .collect();
+        let longest = pinna.iter().map(|&(_, delay)| delay).max().unwrap_or(0);
+        // How far the source is off to the right, from -1 to 1.
+        let right = elevation.cos() * azimuth.sin();
+        Spatializer {
+            pinna,
+            history: vec![0.0; longest + 1],
+            next: 0,
+            ears: [Ear::new(-right, rate), Ear::new(right, rate)],
+        }
+    }
+
+    /// The left and right ears' samples for the next input sample.
+    pub fn process(&mut self, sample: f32) -> [f32; 2] {
+        let len = self.history.len();
+        self.history[self.next] = sample;
+        let mut heard = sample;
+        for &(gain, delay) in &self.pinna {
+            heard += gain * self.history[(self.next + len - delay) % len];
+        }
+        self.next = (self.next + 1) % len;
+        let [left, right] = &mut self.ears;
+        [left.process(heard), right.process(heard)]
+    }
+}
+
+/// The delay and head shadow on the way to one ear.
+struct Ear {
+    /// Latest samples, for the delay to read between.
+    line: Vec<f32>,
+    next: usize,
+    delay: f32,
+    /// The one-pole, one-zero shadow filter and its last input and output.
+    b0: f32,
+    b1: f32,
+    a1: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl Ear {
+    /// `facing` is the cosine of the angle between the source and the side this ear is on.
+    fn new(facing: f32, rate: u32) -> Ear {
+        let angle = facing.clamp(-1.0, 1.0).acos();
+        let transit = HEAD_RADIUS / SPEED_OF_SOUND;
+        // Woodworth's formula, shifted so the nearer ear of a source to the side isn't delayed.
+        let delay = if angle < FRAC_PI_2 {
+            transit * (1.0 - angle.cos())
+        } else {
+            transit * (1.0 + angle - FRAC_PI_2)
+        } * rate as f32;
+        let alpha = (1.0 + SHADOW_MIN / 2.0)
+            + (1.0 - SHADOW_MIN / 2.0) * (angle.to_degrees() / SHADOW_MIN_ANGLE * PI).cos();
+        // The bilinear transform of (1 + alpha s / 2w0) / (1 + s / 2w0), with w0 = c / a.
+        let beta = 2.0 * SPEED_OF_SOUND / HEAD_RADIUS;
+        let k = 2.0 * rate as f32;
+        Ear {
+            line: vec![0.0; delay.ceil() as usize + 2],
+            next: 0,
+            delay,
+            b0: (beta + alpha * k) / (beta + k),
+            b1: (beta - alpha * k) / (beta + k),
+            a1: (beta - k) / (beta + k),
+            x1: 0.0,
+            y1: 0.0,
+        }
+    }
+
+    fn process(&mut self, sample: f32) -> f32 {
+        let len = self.line.len();
+        self.line[self.next] = sample;
+        let whole = self.delay as usize;
+        let fraction = self.delay - whole as f32;
+        let at = |back: usize| self.line[(self.next + len - back) % len];
+        let delayed = at(whole) * (1.0 - fraction) + at(whole + 1) * fraction;
+        self.next = (self.next + 1) % len;
+        let shadowed = self.b0 * delayed + self.b1 * self.x1 - self.a1 * self.y1;
+        self.x1 = delayed;
+        self.y1 = shadowed;
+        shadowed
+    }
+}
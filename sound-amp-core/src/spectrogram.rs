@@ -0,0 +1,316 @@
+//! A spectrogram of the raw input or the final output, to find hum, whistles and the frequencies
+//! that feed back by how they stand out over time. Like the [tuner](crate::tuner), it's worked out
+//! on a worker thread from what a callback hands it, and left in the
+//! [`Meters`](crate::meter::Meters) for the UI, a column of bands at a time.
+
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+
+use crate::lock;
+use crate::meter::Meters;
+use crate::recorder::RecordingTap;
+
+/// Bands in a column, spaced evenly in pitch from [`LOW_HZ`] to [`HIGH_HZ`].
+pub const BANDS: usize = 120;
+pub const LOW_HZ: f32 = 20.0;
+pub const HIGH_HZ: f32 = 20000.0;
+/// Columns kept, 20 seconds of them.
+pub const COLUMNS: usize = 400;
+/// Bands above the stream's Nyquist frequency read this.
+pub const FLOOR_DB: f32 = -120.0;
+/// Range of FFT sizes; bigger ones tell frequencies closer together apart, but smear what
+/// happens over a longer stretch, and take longer to show it.
+pub const MIN_FFT_SIZE: usize = 512;
+pub const MAX_FFT_SIZE: usize = 16384;
+/// Time between columns.
+const COLUMN_INTERVAL: Duration = Duration::from_millis(50);
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Which signal the spectrogram shows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SpectrogramTap {
+    /// The raw input, for hum and noise picked up on the way in.
+    Input,
+    /// The final output mix, for what the effects add, and feedback.
+    #[default]
+    Output,
+}
+
+impl SpectrogramTap {
+    pub fn name(self) -> &'static str {
+        match self {
+            SpectrogramTap::Input => "input",
+            SpectrogramTap::Output => "output",
+        }
+    }
+
+    pub fn other(self) -> SpectrogramTap {
+        match self {
+            SpectrogramTap::Input => SpectrogramTap::Output,
+            SpectrogramTap::Output => SpectrogramTap::Input,
+        }
+    }
+}
+
+/// What each FFT's samples are shaped by, trading how far a loud tone leaks into the bands around
+/// it for how narrow it shows.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Window {
+    /// Narrowest, but leaks the most; for tones that fit the FFT exactly.
+    Rectangular,
+    #[default]
+    Hann,
+    /// Leaks less nearby than Hann, more further off.
+    Hamming,
+    /// Leaks hardly at all, for quiet tones next to loud ones, at twice Hann's width.
+    BlackmanHarris,
+}
+
+impl Window {
+    pub const ALL: [Window; 4] = [
+        Window::Rectangular,
+        Window::Hann,
+        Window::Hamming,
+        Window::BlackmanHarris,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Window::Rectangular => "rectangular",
+            Window::Hann => "Hann",
+            Window::Hamming => "Hamming",
+            Window::BlackmanHarris => "Blackman-Harris",
+        }
+    }
+
+    fn shape(self, size: usize) -> Vec<f32> {
+        let cosines = |terms: &[f32]| -> Vec<f32> {
+            (0..size)
+                .map(|i| {
+                    let phase = 2.0 * PI * i as f32 / size as f32;
+                    terms
+                        .iter()
+                        .enumerate()
+                        .map(|(k, a)| if k % 2 == 0 { 1.0 } else { -1.0 } * a * (k as f32 * phase).cos())
+                        .sum()
+                })
+                .collect()
+        };
+        match self {
+            Window::Rectangular => vec![1.0; size],
+            Window::Hann => cosines(&[0.5, 0.5]),
+            Window::Hamming => cosines(&[0.54, 0.46]),
+            Window::BlackmanHarris => cosines(&[0.35875, 0.48829, 0.14128, 0.01168]),
+        }
+    }
+}
+
+/// How the spectrum is worked out for each column.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct SpectrumAnalysis {
+    /// Samples in each FFT, a power of two from [`MIN_FFT_SIZE`] to [`MAX_FFT_SIZE`].
+    pub fft_size: usize,
+    pub window: Window,
+    /// Roughly how many of the spectra before each column's it's averaged with, for a steadier
+    /// picture that's slower to follow; 1 shows each on its own.
+    pub averaging: u32,
+}
+
+impl Default for SpectrumAnalysis {
+    fn default() -> Self {
+        SpectrumAnalysis {
+            fft_size: 2048,
+            window: Window::Hann,
+            averaging: 1,
+        }
+    }
+}
+
+impl SpectrumAnalysis {
+    /// The FFT size, brought to a power of two in range.
+    pub fn fft_size(&self) -> usize {
+        self.fft_size
+            .clamp(MIN_FFT_SIZE, MAX_FFT_SIZE)
+            .next_power_of_two()
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SpectrogramConfig {
+    /// Whether it's on when sound-amp starts.
+    pub enabled: bool,
+    pub tap: SpectrogramTap,
+    #[serde(flatten)]
+    pub analysis: SpectrumAnalysis,
+}
+
+/// The centre of a band, which needn't be a whole one.
+pub fn band_hz(band: f32) -> f32 {
+    LOW_HZ * (HIGH_HZ / LOW_HZ).powf(band / (BANDS - 1) as f32)
+}
+
+/// The latest [`COLUMNS`] of the spectrogram, each the peak level of its [`BANDS`] in dBFS, from
+/// the bottom band up.
+#[derive(Default)]
+pub struct SpectrogramHistory {
+    columns: Mutex<VecDeque<Vec<f32>>>,
+}
+
+impl SpectrogramHistory {
+    pub fn push(&self, column: Vec<f32>) {
+        let mut columns = self.columns.lock().unwrap();
+        if columns.len() == COLUMNS {
+            columns.pop_front();
+        }
+        columns.push_back(column);
+    }
+
+    /// The columns, oldest first.
+    pub fn columns(&self) -> Vec<Vec<f32>> {
+        self.columns.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.columns.lock().unwrap().clear();
+    }
+}
+
+/// Works out the spectrogram of the samples arriving on the tap, for as long as it runs.
+pub struct Spectrogram {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl Spectrogram {
+    pub fn start(
+        stream: &StreamConfig,
+        analysis: &SpectrumAnalysis,
+        tap: &RecordingTap,
+        meters: &Arc<Meters>,
+    ) -> Spectrogram {
+        let channels = stream.channels as usize;
+        let size = analysis.fft_size();
+        let hop = (COLUMN_INTERVAL.as_secs_f64() * stream.sample_rate.0 as f64) as usize;
+        let (producer, mut consumer) =
+            RingBuffer::new(stream.sample_rate.0 as usize * channels).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let meters = Arc::clone(meters);
+            let mut analyser = Analyser::new(stream.sample_rate.0, analysis);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; hop * channels];
+                let mut window: Vec<f32> = Vec::with_capacity(2 * size);
+                let mut fresh = 0;
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    if n == 0 {
+                        thread::sleep(DRAIN_INTERVAL);
+                        continue;
+                    }
+                    for frame in buffer[..n].chunks(channels) {
+                        window.push(frame.iter().sum::<f32>() / channels as f32);
+                        fresh += 1;
+                    }
+                    if window.len() > size {
+                        window.drain(..window.len() - size);
+                    }
+                    if window.len() == size && fresh >= hop {
+                        fresh = 0;
+                        meters.spectrogram.push(analyser.column(&window));
+                    }
+                }
+                meters.spectrogram.clear();
+            });
+        }
+        Spectrogram {
+            tap: Arc::clone(tap),
+            stop,
+        }
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Turns windows of samples into columns of bands.
+struct Analyser {
+    fft: Arc<dyn Fft<f32>>,
+    /// Scaled so a full-scale sine reads 0 dBFS.
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    /// Each bin's power, averaged over the spectra so far.
+    power: Vec<f32>,
+    /// How much of each new spectrum goes into `power`.
+    weight: f32,
+    /// The bins each band takes the loudest of, or none above Nyquist.
+    bands: Vec<Option<(usize, usize)>>,
+}
+
+impl Analyser {
+    fn new(rate: u32, analysis: &SpectrumAnalysis) -> Analyser {
+        let size = analysis.fft_size();
+        let shape = analysis.window.shape(size);
+        let scale = 2.0 / shape.iter().sum::<f32>();
+        let bin = |hz: f32| (hz * size as f32 / rate as f32).round() as usize;
+        let nyquist = size / 2;
+        let bands = (0..BANDS)
+            .map(|band| {
+                let low = bin(band_hz(band as f32 - 0.5));
+                let high = bin(band_hz(band as f32 + 0.5)).max(low + 1).min(nyquist + 1);
+                (low <= nyquist).then_some((low, high))
+            })
+            .collect();
+        Analyser {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window: shape.iter().map(|w| w * scale).collect(),
+            buffer: vec![Complex::default(); size],
+            power: Vec::new(),
+            weight: 1.0 / analysis.averaging.max(1) as f32,
+            bands,
+        }
+    }
+
+    fn column(&mut self, samples: &[f32]) -> Vec<f32> {
+        for ((slot, &sample), &w) in self.buffer.iter_mut().zip(samples).zip(&self.window) {
+            *slot = Complex::new(sample * w, 0.0);
+        }
+        self.fft.process(&mut self.buffer);
+        let spectrum = self.buffer[..=self.buffer.len() / 2].iter().map(|bin| bin.norm_sqr());
+        if self.power.is_empty() {
+            self.power.extend(spectrum);
+        } else {
+            for (average, power) in self.power.iter_mut().zip(spectrum) {
+                *average += (power - *average) * self.weight;
+            }
+        }
+        self.bands
+            .iter()
+            .map(|band| match band {
+                Some((low, high)) => {
+                    let peak = self.power[*low..*high].iter().fold(0f32, |peak, &power| peak.max(power));
+                    (10.0 * peak.log10()).max(FLOOR_DB)
+                }
+                None => FLOOR_DB,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,195 @@
+//! A sound level meter for the input, in dB SPL with the weightings and time responses of a real
+//! one. The microphone isn't a measurement microphone and the calibration is the user's own, so
+//! the reading is rough, but it's enough to tell a quiet room from a loud one.
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+use crate::chain::Processor;
+use crate::effects::Biquad;
+
+/// Corners of the weighting curves in IEC 61672, in Hz.
+const LOW_CORNER: f32 = 20.6;
+const HIGH_CORNER: f32 = 12194.0;
+const A_CORNERS: (f32, f32) = (107.7, 737.9);
+/// What the A and C curves come to at 1 kHz, which the standard takes back out so they read 0 dB
+/// there.
+const A_AT_1KHZ_DB: f32 = -2.0;
+const C_AT_1KHZ_DB: f32 = -0.062;
+/// Lowest reading, so silence doesn't read minus infinity.
+const FLOOR_DB: f32 = 0.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weighting {
+    /// Follows the ear at moderate levels; what most limits are given in.
+    A,
+    /// Nearly flat, for loud sounds and bass.
+    C,
+    /// No weighting.
+    Z,
+}
+
+impl Weighting {
+    pub fn name(self) -> &'static str {
+        match self {
+            Weighting::A => "A",
+            Weighting::C => "C",
+            Weighting::Z => "Z",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Response {
+    /// Averages over 125 ms.
+    Fast,
+    /// Averages over 1 s, for a steadier reading.
+    Slow,
+}
+
+impl Response {
+    fn seconds(self) -> f32 {
+        match self {
+            Response::Fast => 0.125,
+            Response::Slow => 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct SplConfig {
+    pub weighting: Weighting,
+    pub response: Response,
+    /// dB SPL at the microphone that makes a full-scale sine in the input; the calibration offset.
+    /// The default is a guess for a typical headset microphone; hold a sound level meter next to
+    /// it and adjust this until the readings agree.
+    pub full_scale_db_spl: f32,
+}
+
+impl Default for SplConfig {
+    fn default() -> Self {
+        SplConfig {
+            weighting: Weighting::A,
+            response: Response::Fast,
+            full_scale_db_spl: 120.0,
+        }
+    }
+}
+
+pub struct SplMeter {
+    filters: Vec<Biquad>,
+    /// Takes out the weighting's own gain at 1 kHz, as a power ratio.
+    normalise: f32,
+    /// Calibration offset of the mean square, so a full-scale sine reads `full_scale_db_spl`.
+    offset_db: f32,
+    smoothing: f32,
+    channels: usize,
+    weighted: Vec<f32>,
+    mean_square: f32,
+}
+
+impl SplMeter {
+    pub fn new(config: &SplConfig, stream: &StreamConfig) -> SplMeter {
+        let rate = stream.sample_rate.0 as f32;
+        // The curves have double poles at their low and high corners, which are a Butterworth
+        // stage's pair with a quality of a half; A's two poles between are one more high-pass.
+        let c = || {
+            vec![
+                Biquad::high_pass(LOW_CORNER, 0.5, stream),
+                Biquad::low_pass(HIGH_CORNER.min(0.45 * rate), 0.5, stream),
+            ]
+        };
+        let (filters, at_1khz_db) = match config.weighting {
+            Weighting::A => {
+                let (low, high) = A_CORNERS;
+                let centre = (low * high).sqrt();
+                let mut filters = c();
+                filters.push(Biquad::high_pass(centre, centre / (low + high), stream));
+                (filters, A_AT_1KHZ_DB)
+            }
+            Weighting::C => (c(), C_AT_1KHZ_DB),
+            Weighting::Z => (Vec::new(), 0.0),
+        };
+        SplMeter {
+            filters,
+            normalise: 10f32.powf(-at_1khz_db / 10.0),
+            // A full-scale sine has a mean square of a half.
+            offset_db: config.full_scale_db_spl + 10.0 * 2f32.log10(),
+            smoothing: (-1.0 / (config.response.seconds() * rate)).exp(),
+            channels: stream.channels as usize,
+            weighted: Vec::new(),
+            mean_square: 0.0,
+        }
+    }
+
+    /// Takes a block of interleaved input and returns the level at the end of it, in dB SPL.
+    pub fn process(&mut self, samples: &[f32]) -> f32 {
+        self.weighted.clear();
+        self.weighted.extend_from_slice(samples);
+        for filter in &mut self.filters {
+            filter.process(&mut self.weighted);
+        }
+        // The channels' power is averaged, the way two microphones in one place would read.
+        for frame in self.weighted.chunks(self.channels) {
+            let power = frame.iter().map(|s| s * s).sum::<f32>() / self.channels as f32;
+            self.mean_square = power + (self.mean_square - power) * self.smoothing;
+        }
+        let level = 10.0 * (self.mean_square * self.normalise).max(1e-20).log10();
+        (level + self.offset_db).max(FLOOR_DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::TAU;
+
+    use cpal::{BufferSize, SampleRate};
+
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    /// What a full-scale sine at `frequency` reads after ten seconds, well past the slow response.
+    fn reading(weighting: Weighting, frequency: f32) -> f32 {
+        let config = SplConfig {
+            weighting,
+            response: Response::Slow,
+            full_scale_db_spl: 94.0,
+        };
+        let stream = StreamConfig {
+            channels: 1,
+            sample_rate: SampleRate(RATE),
+            buffer_size: BufferSize::Default,
+        };
+        let mut meter = SplMeter::new(&config, &stream);
+        let sine: Vec<f32> = (0..RATE as usize * 10)
+            .map(|i| (TAU * frequency * i as f32 / RATE as f32).sin())
+            .collect();
+        let mut level = 0.0;
+        for block in sine.chunks(480) {
+            level = meter.process(block);
+        }
+        level
+    }
+
+    #[test]
+    fn a_full_scale_sine_at_1_khz_reads_the_calibration_on_every_weighting() {
+        for weighting in [Weighting::A, Weighting::C, Weighting::Z] {
+            let level = reading(weighting, 1000.0);
+            assert!((level - 94.0).abs() < 0.1, "{} reads {} dB", weighting.name(), level);
+        }
+    }
+
+    #[test]
+    fn at_100_hz_a_takes_off_19_db_and_c_a_third_of_one() {
+        let a = reading(Weighting::A, 100.0);
+        assert!((a - (94.0 - 19.1)).abs() < 0.3, "A reads {} dB", a);
+        let c = reading(Weighting::C, 100.0);
+        assert!((c - (94.0 - 0.3)).abs() < 0.2, "C reads {} dB", c);
+        let z = reading(Weighting::Z, 100.0);
+        assert!((z - 94.0).abs() < 0.1, "Z reads {} dB", z);
+    }
+}
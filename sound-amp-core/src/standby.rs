@@ -0,0 +1,72 @@
+//! Standby, to save CPU and battery on laptops: once a device input has been quiet for long
+//! enough, the link is torn down, letting go of the output devices and leaving nothing running
+//! through the effects, and only a bare stream from the input is kept to hear the signal come back
+//! and link it again.
+
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::backend::StreamHandle;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct StandbyConfig {
+    pub enabled: bool,
+    /// Raw input peak below which the input counts as quiet, and above which it wakes the link.
+    pub threshold_db: f32,
+    /// How long the input has to stay quiet before the link goes into standby.
+    pub minutes: f32,
+}
+
+impl Default for StandbyConfig {
+    fn default() -> Self {
+        StandbyConfig {
+            enabled: false,
+            threshold_db: -60.0,
+            minutes: 5.0,
+        }
+    }
+}
+
+/// Times how long a running link's input has been quiet.
+pub struct SilenceWatch {
+    config: StandbyConfig,
+    quiet_since: Option<Instant>,
+}
+
+impl SilenceWatch {
+    pub fn new(config: &StandbyConfig) -> SilenceWatch {
+        SilenceWatch {
+            config: config.clone(),
+            quiet_since: None,
+        }
+    }
+
+    /// Whether a peak in dBFS is loud enough to wake a link in standby.
+    pub fn heard(&self, peak_db: f32) -> bool {
+        peak_db >= self.config.threshold_db
+    }
+
+    /// Whether the input has been quiet for long enough to go into standby, given its peak since
+    /// the last check.
+    pub fn quiet_for_long(&mut self, peak_db: f32) -> bool {
+        if !self.config.enabled || self.heard(peak_db) {
+            self.quiet_since = None;
+            return false;
+        }
+        let since = *self.quiet_since.get_or_insert_with(Instant::now);
+        since.elapsed() >= Duration::from_secs_f32(self.config.minutes.max(0.0) * 60.0)
+    }
+
+    /// Starts over, for when there's no input to watch.
+    pub fn reset(&mut self) {
+        self.quiet_since = None;
+    }
+}
+
+/// The input a link in standby was linked from, listened to until it's loud again.
+pub struct Standby {
+    pub device: usize,
+    pub stream: StreamHandle,
+}
@@ -0,0 +1,121 @@
+//! Counts of what happened to the signal since the player started, or they were last cleared, for
+//! tuning the chain: samples that hit full scale in the raw input and in the output mix, how often
+//! the safety limiter stepped in, how often and how long the gates held the input down, and the
+//! loudest true peak the output reached.
+
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Samples at or past this are taken as clipped.
+const FULL_SCALE: f32 = 1.0;
+
+#[derive(Default)]
+pub struct Stats {
+    input_samples: AtomicU64,
+    input_clipped: AtomicU64,
+    output_samples: AtomicU64,
+    output_clipped: AtomicU64,
+    limiter_engagements: AtomicU64,
+    limited_samples: AtomicU64,
+    gate_closings: AtomicU64,
+    gated_samples: AtomicU64,
+    /// The output's loudest true peak, as `f32` bits.
+    true_peak: AtomicU32,
+    /// How the limiter and the gates were at the end of the last block, to count them stepping in.
+    limiting: AtomicBool,
+    gated: AtomicBool,
+}
+
+/// A reading of [`Stats`]; the shares are from 0 to 1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct StatsReading {
+    pub input_clipped: u64,
+    pub input_clipped_share: f32,
+    /// Samples of the output mix over full scale, before the limiter.
+    pub output_clipped: u64,
+    pub output_clipped_share: f32,
+    pub limiter_engagements: u64,
+    /// Share of the output the limiter was turning down.
+    pub limited_share: f32,
+    pub gate_closings: u64,
+    /// Share of the input a gate was holding down.
+    pub gated_share: f32,
+    /// The output's loudest true peak, in dBTP.
+    pub max_true_peak_db: f32,
+}
+
+impl Stats {
+    /// Adds a block of the raw input, and whether a gate was closed by the end of it.
+    pub fn count_input(&self, raw: &[f32], gated: bool) {
+        self.input_samples.fetch_add(raw.len() as u64, Ordering::Relaxed);
+        self.input_clipped.fetch_add(clipped(raw), Ordering::Relaxed);
+        if gated {
+            self.gated_samples.fetch_add(raw.len() as u64, Ordering::Relaxed);
+        }
+        if gated && !self.gated.swap(gated, Ordering::Relaxed) {
+            self.gate_closings.fetch_add(1, Ordering::Relaxed);
+        }
+        self.gated.store(gated, Ordering::Relaxed);
+    }
+
+    /// Adds a block of the output mix of `samples`, `clipped` of which were over full scale, and
+    /// whether the limiter was turning it down by the end of it.
+    pub fn count_output(&self, samples: usize, clipped: u64, limiting: bool) {
+        self.output_samples.fetch_add(samples as u64, Ordering::Relaxed);
+        self.output_clipped.fetch_add(clipped, Ordering::Relaxed);
+        if limiting {
+            self.limited_samples.fetch_add(samples as u64, Ordering::Relaxed);
+        }
+        if limiting && !self.limiting.swap(limiting, Ordering::Relaxed) {
+            self.limiter_engagements.fetch_add(1, Ordering::Relaxed);
+        }
+        self.limiting.store(limiting, Ordering::Relaxed);
+    }
+
+    /// Adds the output's true peak over a block.
+    pub fn count_true_peak(&self, peak: f32) {
+        self.true_peak.fetch_max(peak.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn read(&self) -> StatsReading {
+        let load = |count: &AtomicU64| count.load(Ordering::Relaxed);
+        let share = |count: &AtomicU64, of: &AtomicU64| match load(of) {
+            0 => 0.0,
+            of => load(count) as f32 / of as f32,
+        };
+        StatsReading {
+            input_clipped: load(&self.input_clipped),
+            input_clipped_share: share(&self.input_clipped, &self.input_samples),
+            output_clipped: load(&self.output_clipped),
+            output_clipped_share: share(&self.output_clipped, &self.output_samples),
+            limiter_engagements: load(&self.limiter_engagements),
+            limited_share: share(&self.limited_samples, &self.output_samples),
+            gate_closings: load(&self.gate_closings),
+            gated_share: share(&self.gated_samples, &self.input_samples),
+            max_true_peak_db: 20.0 * f32::from_bits(self.true_peak.load(Ordering::Relaxed)).max(1e-6).log10(),
+        }
+    }
+
+    /// Starts counting over.
+    pub fn clear(&self) {
+        for count in [
+            &self.input_samples,
+            &self.input_clipped,
+            &self.output_samples,
+            &self.output_clipped,
+            &self.limiter_engagements,
+            &self.limited_samples,
+            &self.gate_closings,
+            &self.gated_samples,
+        ] {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.true_peak.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Samples of `samples` at or past full scale.
+pub fn clipped(samples: &[f32]) -> u64 {
+    samples.iter().filter(|s| s.abs() >= FULL_SCALE).count() as u64
+}
@@ -0,0 +1,204 @@
+//! Talk gates, which keep the main link's input closed except while the user means to be heard.
+//! With push-to-talk it opens while a key, button or pedal is held; with VOX, whenever the input is
+//! loud enough, staying open a while after so it doesn't cut in and out between words. Either
+//! fades in and out so it doesn't click.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use cpal::StreamConfig;
+use serde::Deserialize;
+
+use crate::chain::Processor;
+use crate::soundboard::Hotkey;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct PushToTalkConfig {
+    pub enabled: bool,
+    /// The key to hold in the TUI. A terminal only says when a key goes down, so it counts as held
+    /// while it keeps repeating; outside the TUI, the HTTP, OSC and MIDI controls say when it's
+    /// let go.
+    pub key: Hotkey,
+    /// How long since the key last repeated before it's taken as let go; a little longer than
+    /// the keyboard's repeat delay.
+    pub hold_ms: u64,
+    /// How long the gate takes to open or close.
+    pub fade_ms: f32,
+}
+
+impl Default for PushToTalkConfig {
+    fn default() -> Self {
+        PushToTalkConfig {
+            enabled: false,
+            key: Hotkey::Char('t'),
+            hold_ms: 700,
+            fade_ms: 10.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VoxConfig {
+    pub enabled: bool,
+    /// Peak level, in dBFS, the signal has to reach to open the gate. It's measured after the
+    /// preset's effects, as it'll be heard.
+    pub threshold_db: f32,
+    /// How long the gate stays open after the signal last reached the threshold.
+    pub hang_ms: f32,
+    /// How long the gate takes to open or close.
+    pub fade_ms: f32,
+}
+
+impl Default for VoxConfig {
+    fn default() -> Self {
+        VoxConfig {
+            enabled: false,
+            threshold_db: -40.0,
+            hang_ms: 1000.0,
+            fade_ms: 5.0,
+        }
+    }
+}
+
+/// A gain that moves in straight lines between closed and open, taking `fade_ms` either way.
+struct Fade {
+    step: f32,
+    gain: f32,
+}
+
+impl Fade {
+    fn new(fade_ms: f32, config: &StreamConfig) -> Fade {
+        let fade_frames = fade_ms / 1000.0 * config.sample_rate.0 as f32;
+        Fade {
+            step: 1.0 / fade_frames.max(1.0),
+            gain: 0.0,
+        }
+    }
+
+    /// The gain for the next frame, one step closer to open or closed.
+    fn next(&mut self, open: bool) -> f32 {
+        self.gain = if open {
+            (self.gain + self.step).min(1.0)
+        } else {
+            (self.gain - self.step).max(0.0)
+        };
+        self.gain
+    }
+}
+
+/// Whether the user is holding the talk key, shared between the player, which sets it, and the
+/// gate in the main link's chain.
+#[derive(Debug, Clone)]
+pub struct PushToTalk {
+    talking: Arc<AtomicBool>,
+    fade_ms: f32,
+}
+
+impl PushToTalk {
+    pub fn new(config: &PushToTalkConfig) -> PushToTalk {
+        PushToTalk {
+            talking: Default::default(),
+            fade_ms: config.fade_ms,
+        }
+    }
+
+    pub fn set_talking(&self, talking: bool) {
+        self.talking.store(talking, Ordering::Relaxed);
+    }
+
+    pub fn is_talking(&self) -> bool {
+        self.talking.load(Ordering::Relaxed)
+    }
+
+    /// The gate for a chain running on a stream of `config`.
+    pub fn gate(&self, config: &StreamConfig) -> TalkGate {
+        TalkGate {
+            talking: Arc::clone(&self.talking),
+            fade: Fade::new(self.fade_ms, config),
+            channels: config.channels as usize,
+        }
+    }
+}
+
+/// Passes the input while the user is talking and silences it otherwise.
+pub struct TalkGate {
+    talking: Arc<AtomicBool>,
+    fade: Fade,
+    channels: usize,
+}
+
+impl Processor for TalkGate {
+    fn process(&mut self, samples: &mut [f32]) {
+        let talking = self.talking.load(Ordering::Relaxed);
+        for frame in samples.chunks_mut(self.channels) {
+            let gain = self.fade.next(talking);
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// Whether the VOX gate is open, shared between the gate in the main link's chain and the
+/// player, which reports it.
+#[derive(Debug, Clone)]
+pub struct Vox {
+    config: VoxConfig,
+    open: Arc<AtomicBool>,
+}
+
+impl Vox {
+    pub fn new(config: &VoxConfig) -> Vox {
+        Vox {
+            config: config.clone(),
+            open: Default::default(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// The gate for a chain running on a stream of `config`.
+    pub fn gate(&self, config: &StreamConfig) -> VoxGate {
+        let rate = config.sample_rate.0 as f32;
+        VoxGate {
+            threshold: 10f32.powf(self.config.threshold_db / 20.0),
+            hang_frames: (self.config.hang_ms / 1000.0 * rate) as u32,
+            hang: 0,
+            fade: Fade::new(self.config.fade_ms, config),
+            channels: config.channels as usize,
+            open: Arc::clone(&self.open),
+        }
+    }
+}
+
+/// Opens as soon as a frame reaches the threshold and closes once none has for the hang time.
+pub struct VoxGate {
+    threshold: f32,
+    hang_frames: u32,
+    /// Frames left before the gate closes.
+    hang: u32,
+    fade: Fade,
+    channels: usize,
+    open: Arc<AtomicBool>,
+}
+
+impl Processor for VoxGate {
+    fn process(&mut self, samples: &mut [f32]) {
+        for frame in samples.chunks_mut(self.channels) {
+            if frame.iter().any(|s| s.abs() >= self.threshold) {
+                self.hang = self.hang_frames.max(1);
+            } else {
+                self.hang = self.hang.saturating_sub(1);
+            }
+            let gain = self.fade.next(self.hang > 0);
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+        self.open.store(self.hang > 0, Ordering::Relaxed);
+    }
+}
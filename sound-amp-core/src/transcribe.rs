@@ -0,0 +1,192 @@
+//! Rolling captions of the processed signal, from a whisper.cpp server. The signal is cut into
+//! chunks of a few seconds, each sent to the server's `/inference` endpoint as 16 kHz mono WAV, and
+//! the text that comes back is added to the [`Captions`] the UI shows.
+
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::lock;
+use crate::recorder::RecordingTap;
+use crate::resampler::LinearResampler;
+
+/// The rate whisper models take their input at.
+const MODEL_RATE: u32 = 16000;
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+/// How long the server gets to transcribe a chunk; on a slow machine that's a while.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Chunks with no peak above this, in dBFS, aren't sent; whisper makes words up out of silence.
+const SILENCE_DB: f32 = -50.0;
+const BOUNDARY: &str = "sound-amp-transcription-boundary";
+
+/// The most recent captions, oldest first.
+pub type Captions = Arc<Mutex<VecDeque<String>>>;
+
+pub type TranscriptionError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TranscriptionConfig {
+    /// Address of a whisper.cpp server, as `host:port`; transcribing is on whenever it's set.
+    pub server: Option<String>,
+    /// Length of the chunks sent to be transcribed; longer reads better, shorter lags less.
+    pub chunk_seconds: f32,
+    /// Language of the speech, like `en`, or `auto` to have the server work it out.
+    pub language: String,
+    /// Captions kept for the UI to show.
+    pub lines: usize,
+}
+
+impl Default for TranscriptionConfig {
+    fn default() -> Self {
+        TranscriptionConfig {
+            server: None,
+            chunk_seconds: 5.0,
+            language: "auto".to_string(),
+            lines: 50,
+        }
+    }
+}
+
+/// Transcribes the samples arriving on the tap, chunk by chunk, into the captions.
+pub struct Transcriber {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl Transcriber {
+    pub fn start(
+        config: &TranscriptionConfig,
+        stream: &StreamConfig,
+        tap: &RecordingTap,
+        captions: &Captions,
+    ) -> Result<Transcriber, TranscriptionError> {
+        let server = config
+            .server
+            .clone()
+            .ok_or("No transcription server is set")?;
+        let channels = stream.channels as usize;
+        let chunk_seconds = config.chunk_seconds.max(1.0);
+        let samples_per_second = stream.sample_rate.0 as usize * channels;
+        // Room for the next chunk to come in while the server works on the last one.
+        let capacity = (2.0 * chunk_seconds) as usize * samples_per_second;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut resampler = LinearResampler::new(stream.sample_rate.0, MODEL_RATE, 1);
+        let chunk_len = (chunk_seconds * MODEL_RATE as f32) as usize;
+        let config = config.clone();
+        let captions = Arc::clone(captions);
+        {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; samples_per_second];
+                let mut mono = Vec::new();
+                let mut chunk = Vec::with_capacity(chunk_len);
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    mono.clear();
+                    mono.extend(
+                        buffer[..n]
+                            .chunks(channels)
+                            .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+                    );
+                    resampler.process(&mono, &mut chunk);
+                    if chunk.len() < chunk_len {
+                        thread::sleep(DRAIN_INTERVAL);
+                        continue;
+                    }
+                    let peak = chunk.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+                    if 20.0 * peak.log10() > SILENCE_DB {
+                        match transcribe(&server, &config.language, &chunk) {
+                            Ok(text) if !text.is_empty() => {
+                                let mut captions = captions.lock().unwrap();
+                                captions.push_back(text);
+                                while captions.len() > config.lines.max(1) {
+                                    captions.pop_front();
+                                }
+                            }
+                            Ok(_) => {}
+                            Err(e) => eprintln!("Cannot transcribe: {}", e),
+                        }
+                    }
+                    chunk.clear();
+                }
+            });
+        }
+        Ok(Transcriber {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Sends `samples`, mono at [`MODEL_RATE`], to the server and returns the text it heard.
+fn transcribe(server: &str, language: &str, samples: &[f32]) -> Result<String, TranscriptionError> {
+    let mut wav = Cursor::new(Vec::new());
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: MODEL_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::new(&mut wav, spec)?;
+    for sample in samples {
+        writer.write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+    }
+    writer.finalize()?;
+
+    let mut body = Vec::new();
+    for (name, value) in [("response_format", "text"), ("language", language)] {
+        write!(
+            body,
+            "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+            BOUNDARY, name, value
+        )?;
+    }
+    write!(
+        body,
+        "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"chunk.wav\"\r\n\
+         Content-Type: audio/wav\r\n\r\n",
+        BOUNDARY
+    )?;
+    body.extend_from_slice(wav.get_ref());
+    write!(body, "\r\n--{}--\r\n", BOUNDARY)?;
+
+    let mut stream = TcpStream::connect(server)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    write!(
+        stream,
+        "POST /inference HTTP/1.1\r\nHost: {}\r\nContent-Type: multipart/form-data; boundary={}\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n",
+        server,
+        BOUNDARY,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, text) = response
+        .split_once("\r\n\r\n")
+        .ok_or("The transcription server sent no response body")?;
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(format!("The transcription server answered {}", status).into());
+    }
+    Ok(text.split_whitespace().collect::<Vec<_>>().join(" "))
+}
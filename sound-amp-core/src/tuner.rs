@@ -0,0 +1,211 @@
+//! A chromatic tuner for the raw input, for practising an instrument through the amp. The pitch is
+//! found with the YIN algorithm on a worker thread, from the input brought down to a rate that's
+//! plenty for the fundamentals of instruments and voices, and left in the
+//! [`Meters`](crate::meter::Meters) for the UI to show as a [`Note`].
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::lock;
+use crate::meter::Meters;
+use crate::recorder::RecordingTap;
+
+/// Roughly the rate the input is brought down to, by averaging runs of samples.
+const ANALYSIS_RATE: u32 = 12000;
+/// Samples each analysis looks at, at the analysis rate; two periods of the lowest pitch have to
+/// fit in half of it.
+const WINDOW: usize = 1024;
+/// New samples between analyses.
+const HOP: usize = WINDOW / 4;
+/// Range of pitches looked for, from below a bass's low E to well up a violin's E string.
+const MIN_HZ: f32 = 30.0;
+const MAX_HZ: f32 = 2000.0;
+/// How aperiodic a lag can be and still be taken for the period; YIN's usual threshold.
+const THRESHOLD: f32 = 0.15;
+/// Windows with no peak above this, in dBFS, have no pitch.
+const SILENCE_DB: f32 = -50.0;
+const DRAIN_INTERVAL: Duration = Duration::from_millis(20);
+
+const NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct TunerConfig {
+    /// Whether it's on when sound-amp starts.
+    pub enabled: bool,
+    /// Pitch of the A above middle C.
+    pub reference_hz: f32,
+}
+
+impl Default for TunerConfig {
+    fn default() -> Self {
+        TunerConfig {
+            enabled: false,
+            reference_hz: 440.0,
+        }
+    }
+}
+
+/// The equal-tempered note nearest a pitch, and how far the pitch is from it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Note {
+    pub name: &'static str,
+    /// In scientific pitch notation, where middle C is C4.
+    pub octave: i32,
+    /// Between -50 and 50; sharp when positive.
+    pub cents: f32,
+}
+
+impl Note {
+    pub fn of(frequency: f32, reference_hz: f32) -> Note {
+        let midi = 69.0 + 12.0 * (frequency / reference_hz).log2();
+        let nearest = midi.round();
+        let number = nearest as i32;
+        Note {
+            name: NAMES[number.rem_euclid(12) as usize],
+            octave: number.div_euclid(12) - 1,
+            cents: (midi - nearest) * 100.0,
+        }
+    }
+}
+
+/// Finds the pitch of the samples arriving on the tap, for as long as it runs.
+pub struct Tuner {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl Tuner {
+    pub fn start(stream: &StreamConfig, tap: &RecordingTap, meters: &Arc<Meters>) -> Tuner {
+        let channels = stream.channels as usize;
+        let factor = (stream.sample_rate.0 / ANALYSIS_RATE).max(1) as usize;
+        let rate = stream.sample_rate.0 as f32 / factor as f32;
+        let (producer, mut consumer) =
+            RingBuffer::new(stream.sample_rate.0 as usize * channels).split();
+        *lock(tap) = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let meters = Arc::clone(meters);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; factor * channels * HOP];
+                // Left over from the last read, short of a whole run to average.
+                let mut partial = (0f32, 0usize);
+                let mut window: Vec<f32> = Vec::with_capacity(2 * WINDOW);
+                let mut fresh = 0;
+                let mut detector = Yin::new(rate);
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    if n == 0 {
+                        thread::sleep(DRAIN_INTERVAL);
+                        continue;
+                    }
+                    for frame in buffer[..n].chunks(channels) {
+                        partial.0 += frame.iter().sum::<f32>() / channels as f32;
+                        partial.1 += 1;
+                        if partial.1 == factor {
+                            window.push(partial.0 / factor as f32);
+                            partial = (0.0, 0);
+                            fresh += 1;
+                        }
+                    }
+                    if window.len() > WINDOW {
+                        window.drain(..window.len() - WINDOW);
+                    }
+                    if window.len() == WINDOW && fresh >= HOP {
+                        fresh = 0;
+                        meters.set_pitch(detector.pitch(&window));
+                    }
+                }
+                meters.set_pitch(None);
+            });
+        }
+        Tuner {
+            tap: Arc::clone(tap),
+            stop,
+        }
+    }
+
+    pub fn stop(self) {
+        *lock(&self.tap) = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// The YIN pitch detector, for windows of [`WINDOW`] samples.
+struct Yin {
+    rate: f32,
+    /// How much the window differs from itself at each lag.
+    difference: Vec<f32>,
+    /// The same, normalised by its cumulative mean, so it's comparable across lags.
+    normalised: Vec<f32>,
+}
+
+impl Yin {
+    fn new(rate: f32) -> Yin {
+        Yin {
+            rate,
+            difference: vec![0.0; WINDOW / 2],
+            normalised: vec![0.0; WINDOW / 2],
+        }
+    }
+
+    /// The pitch of `window` in Hz, or `None` if it's too quiet or not periodic enough.
+    fn pitch(&mut self, window: &[f32]) -> Option<f32> {
+        let peak = window.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        if 20.0 * peak.max(1e-9).log10() < SILENCE_DB {
+            return None;
+        }
+        let half = WINDOW / 2;
+        let min_lag = ((self.rate / MAX_HZ) as usize).max(2);
+        let max_lag = ((self.rate / MIN_HZ) as usize).min(half - 1);
+        self.normalised[0] = 1.0;
+        let mut sum = 0.0;
+        for lag in 1..half {
+            let d: f32 = (0..half)
+                .map(|j| {
+                    let delta = window[j] - window[j + lag];
+                    delta * delta
+                })
+                .sum();
+            sum += d;
+            self.difference[lag] = d;
+            self.normalised[lag] = if sum > 0.0 { d * lag as f32 / sum } else { 1.0 };
+        }
+        // The first dip under the threshold, followed down to its bottom, is the period; a later,
+        // deeper one would be a multiple of it.
+        let mut lag = (min_lag..=max_lag).find(|&lag| self.normalised[lag] < THRESHOLD)?;
+        while lag < max_lag && self.normalised[lag + 1] < self.normalised[lag] {
+            lag += 1;
+        }
+        // A parabola through the bottom of the plain difference and its neighbours puts it between
+        // samples; the normalisation would skew it, and can put its own bottom a sample off.
+        while lag > min_lag && self.difference[lag - 1] < self.difference[lag] {
+            lag -= 1;
+        }
+        while lag < max_lag && self.difference[lag + 1] < self.difference[lag] {
+            lag += 1;
+        }
+        let (before, at, after) = (
+            self.difference[lag - 1],
+            self.difference[lag],
+            self.difference[lag + 1],
+        );
+        let curvature = before - 2.0 * at + after;
+        let offset = if curvature > 0.0 {
+            (before - after) / (2.0 * curvature)
+        } else {
+            0.0
+        };
+        Some(self.rate / (lag as f32 + offset))
+    }
+}
@@ -0,0 +1,95 @@
+//! A lightweight voice activity detector, so the UI can show that the microphone is picking the
+//! user up. It compares the level of the speech band in short frames with a floor that follows the
+//! background noise, which is cheap enough to run in the input callback.
+
+use cpal::StreamConfig;
+
+use crate::chain::Processor;
+use crate::effects::Biquad;
+
+/// Length of each frame the decision is made on.
+const FRAME_MS: f32 = 10.0;
+/// The band most of the energy of speech is in.
+const SPEECH_BAND: (f32, f32) = (300.0, 3000.0);
+/// How far above the noise floor a frame has to be to count as speech.
+const SNR_DB: f32 = 9.0;
+/// Quietest a frame can be and still count, in dBFS.
+const MIN_DB: f32 = -55.0;
+/// How fast the noise floor creeps up when it's below the signal, per frame.
+const FLOOR_RISE_DB: f32 = 0.02;
+/// Frames in a row it takes to start detecting speech, so a click doesn't.
+const ONSET_FRAMES: u32 = 2;
+/// Frames speech is held after the last one over the floor, so it doesn't drop out between words.
+const HANG_FRAMES: u32 = 30;
+
+pub struct VoiceDetector {
+    channels: usize,
+    frame_len: usize,
+    band: [Biquad; 2],
+    /// The current frame, mixed down to mono.
+    frame: Vec<f32>,
+    floor_db: f32,
+    loud_frames: u32,
+    hang: u32,
+    speech: bool,
+}
+
+impl VoiceDetector {
+    pub fn new(config: &StreamConfig) -> VoiceDetector {
+        let mono = StreamConfig {
+            channels: 1,
+            ..config.clone()
+        };
+        let q = 1.0 / 2f32.sqrt();
+        let frame_len = (config.sample_rate.0 as f32 * FRAME_MS / 1000.0) as usize;
+        VoiceDetector {
+            channels: config.channels as usize,
+            frame_len,
+            band: [
+                Biquad::high_pass(SPEECH_BAND.0, q, &mono),
+                Biquad::low_pass(SPEECH_BAND.1, q, &mono),
+            ],
+            frame: Vec::with_capacity(frame_len),
+            floor_db: MIN_DB,
+            loud_frames: 0,
+            hang: 0,
+            speech: false,
+        }
+    }
+
+    /// Takes a block of interleaved input and says whether there's speech in it, as of the last
+    /// whole frame.
+    pub fn process(&mut self, samples: &[f32]) -> bool {
+        for frame in samples.chunks(self.channels) {
+            self.frame
+                .push(frame.iter().sum::<f32>() / self.channels as f32);
+            if self.frame.len() == self.frame_len {
+                self.decide();
+                self.frame.clear();
+            }
+        }
+        self.speech
+    }
+
+    fn decide(&mut self) {
+        for filter in &mut self.band {
+            filter.process(&mut self.frame);
+        }
+        let energy = self.frame.iter().map(|s| s * s).sum::<f32>() / self.frame.len() as f32;
+        let level_db = 10.0 * energy.max(1e-12).log10();
+        let loud = level_db > MIN_DB && level_db > self.floor_db + SNR_DB;
+        // The floor drops to any quieter frame at once and rises slowly, so it sits under speech.
+        self.floor_db = if level_db < self.floor_db {
+            level_db
+        } else {
+            self.floor_db + FLOOR_RISE_DB
+        };
+        self.loud_frames = if loud { self.loud_frames + 1 } else { 0 };
+        if self.loud_frames >= ONSET_FRAMES {
+            self.hang = HANG_FRAMES;
+        } else {
+            self.hang = self.hang.saturating_sub(1);
+        }
+        self.speech = self.hang > 0;
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::Write;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use cpal::StreamConfig;
+use ringbuf::RingBuffer;
+use serde::Deserialize;
+
+use crate::pipe;
+use crate::recorder::RecordingTap;
+
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+pub type VirtualDeviceError = Box<dyn std::error::Error + Send + Sync>;
+
+/// A sink and a source on the PulseAudio (or PipeWire's Pulse) server that carry the processed
+/// signal, so other applications can record it without a hardware loopback.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct VirtualDevicesConfig {
+    pub enabled: bool,
+    pub sink_name: String,
+    pub sink_description: String,
+    pub source_name: String,
+    pub source_description: String,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl Default for VirtualDevicesConfig {
+    fn default() -> Self {
+        VirtualDevicesConfig {
+            enabled: false,
+            sink_name: "sound_amp_out".to_string(),
+            sink_description: "sound-amp out".to_string(),
+            source_name: "sound_amp_processed_mic".to_string(),
+            source_description: "sound-amp processed mic".to_string(),
+            sample_rate: 48000,
+            channels: 2,
+        }
+    }
+}
+
+/// The loaded modules: a null sink, whose monitor has the processed signal for recorders like OBS,
+/// and a source remapped from that monitor for apps like Zoom that hide monitors.
+/// Both go away when this is dropped.
+pub struct VirtualDevices {
+    modules: Vec<String>,
+}
+
+impl VirtualDevices {
+    pub fn create(config: &VirtualDevicesConfig) -> Result<VirtualDevices, VirtualDeviceError> {
+        let mut devices = VirtualDevices {
+            modules: Vec::new(),
+        };
+        devices.load(&[
+            "module-null-sink".to_string(),
+            format!("sink_name={}", config.sink_name),
+            format!("rate={}", config.sample_rate),
+            format!("channels={}", config.channels),
+            format!(
+                "sink_properties=\"device.description='{}'\"",
+                config.sink_description
+            ),
+        ])?;
+        devices.load(&[
+            "module-remap-source".to_string(),
+            format!("master={}.monitor", config.sink_name),
+            format!("source_name={}", config.source_name),
+            format!(
+                "source_properties=\"device.description='{}'\"",
+                config.source_description
+            ),
+        ])?;
+        eprintln!(
+            "Created the \"{}\" sink and the \"{}\" source",
+            config.sink_description, config.source_description
+        );
+        Ok(devices)
+    }
+
+    fn load(&mut self, args: &[String]) -> Result<(), VirtualDeviceError> {
+        let output = Command::new("pactl")
+            .arg("load-module")
+            .args(args)
+            .output()
+            .map_err(|e| format!("Cannot run pactl: {}", e))?;
+        if !output.status.success() {
+            let message = String::from_utf8_lossy(&output.stderr);
+            return Err(format!("Cannot load {}: {}", args[0], message.trim()).into());
+        }
+        self.modules
+            .push(String::from_utf8_lossy(&output.stdout).trim().to_string());
+        Ok(())
+    }
+}
+
+impl Drop for VirtualDevices {
+    fn drop(&mut self) {
+        // The source hangs off the sink's monitor, so it has to go first.
+        for module in self.modules.iter().rev() {
+            let _ = Command::new("pactl")
+                .args(["unload-module", module])
+                .status();
+        }
+    }
+}
+
+/// Plays samples arriving on the tap into the virtual sink through `pacat`,
+/// which converts them to the sink's format.
+pub struct VirtualSinkFeed {
+    tap: RecordingTap,
+    stop: Arc<AtomicBool>,
+}
+
+impl VirtualSinkFeed {
+    pub fn start(
+        sink_name: &str,
+        config: &StreamConfig,
+        tap: &RecordingTap,
+    ) -> Result<VirtualSinkFeed, VirtualDeviceError> {
+        let mut pacat = Command::new("pacat")
+            .args([
+                "--playback",
+                "--raw",
+                "--client-name=sound-amp",
+                "--format=float32le",
+                "--latency-msec=20",
+            ])
+            .arg(format!("--device={}", sink_name))
+            .arg(format!("--rate={}", config.sample_rate.0))
+            .arg(format!("--channels={}", config.channels))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| format!("Cannot run pacat: {}", e))?;
+        let mut input = pacat.stdin.take().ok_or("pacat has no stdin")?;
+        let capacity = config.sample_rate.0 as usize * config.channels as usize;
+        let (producer, mut consumer) = RingBuffer::new(capacity).split();
+        *tap.lock().unwrap() = Some(producer);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        {
+            let stop = Arc::clone(&stop);
+            let tap = Arc::clone(tap);
+            thread::spawn(move || {
+                let mut buffer = vec![0f32; capacity];
+                let mut bytes: Vec<u8> = Vec::new();
+                while !stop.load(Ordering::Acquire) {
+                    let n = consumer.pop_slice(&mut buffer);
+                    if n > 0 {
+                        bytes.clear();
+                        pipe::encode_f32(&buffer[..n], &mut bytes);
+                        if input.write_all(&bytes).is_err() {
+                            eprintln!("pacat exited, the virtual sink gets no more audio");
+                            *tap.lock().unwrap() = None;
+                            break;
+                        }
+                    }
+                    thread::sleep(DRAIN_INTERVAL);
+                }
+                drop(input);
+                reap(&mut pacat);
+            });
+        }
+        Ok(VirtualSinkFeed {
+            tap: Arc::clone(tap),
+            stop,
+        })
+    }
+
+    pub fn stop(self) {
+        *self.tap.lock().unwrap() = None;
+        self.stop.store(true, Ordering::Release);
+    }
+}
+
+/// Closing stdin lets pacat drain and exit on its own; it's killed if it's still around.
+fn reap(pacat: &mut Child) {
+    thread::sleep(DRAIN_INTERVAL * 10);
+    if !matches!(pacat.try_wait(), Ok(Some(_))) {
+        let _ = pacat.kill();
+    }
+    let _ = pacat.wait();
+}
@@ -0,0 +1,108 @@
+//! A small pool of threads for the heaviest effects, so a link that convolves or pitch-shifts
+//! every channel doesn't run them one after the other in its input callback. A
+//! [`PerChannel`](crate::effects::PerChannel) hands each channel's chain but the first to a
+//! worker, runs that one itself, and waits for the rest, so the callback takes about as long as
+//! one channel does rather than all of them. Links don't need it: each one already runs in its own
+//! device's callback.
+//!
+//! A lane that panics on a worker is still sent back, with the panic, for the callback to raise
+//! again where the link's guard can catch it and stop the link.
+
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use serde::Deserialize;
+
+use crate::chain::Processor;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct WorkersConfig {
+    /// Threads in the pool, on top of the callbacks' own, and no more than there are other cores
+    /// for; 0 runs everything in the callbacks.
+    pub threads: usize,
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig { threads: 2 }
+    }
+}
+
+/// A chain run on one channel, and the channel's block it runs on.
+#[derive(Default)]
+pub struct Lane {
+    pub effects: Vec<Box<dyn Processor>>,
+    pub samples: Vec<f32>,
+}
+
+impl Lane {
+    pub fn run(&mut self) {
+        for effect in &mut self.effects {
+            effect.process(&mut self.samples);
+        }
+    }
+}
+
+/// What a lane panicked with.
+pub type Panic = Box<dyn Any + Send>;
+
+/// Where a lane goes back to once it's run, with its place among the sender's lanes and the panic
+/// it ran into, if it did.
+pub type Done = SyncSender<(usize, Lane, Option<Panic>)>;
+
+struct Job {
+    index: usize,
+    lane: Lane,
+    done: Done,
+}
+
+/// The pool's threads, which stop once every clone of it is dropped.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: SyncSender<Job>,
+}
+
+impl WorkerPool {
+    /// A pool of the configured size, or none if that's none at all or there's only the one core.
+    pub fn start(config: &WorkersConfig) -> Option<WorkerPool> {
+        let cores = thread::available_parallelism().map_or(1, |cores| cores.get());
+        let threads = config.threads.min(cores.saturating_sub(1));
+        (threads > 0).then(|| WorkerPool::new(threads))
+    }
+
+    /// A pool of `threads`, however many cores there are.
+    pub fn new(threads: usize) -> WorkerPool {
+        // Room for every thread's job and one waiting for each, so a full queue means they're
+        // all behind and the callback is better off running the lane itself.
+        let (jobs, queue) = mpsc::sync_channel::<Job>(threads * 2);
+        let queue = Arc::new(Mutex::new(queue));
+        for _ in 0..threads {
+            let queue: Arc<Mutex<Receiver<Job>>> = Arc::clone(&queue);
+            thread::spawn(move || loop {
+                let job = queue.lock().unwrap().recv();
+                let Ok(mut job) = job else { break };
+                let panic = panic::catch_unwind(AssertUnwindSafe(|| job.lane.run())).err();
+                // The sender only goes away along with the effect waiting on it.
+                let _ = job.done.send((job.index, job.lane, panic));
+            });
+        }
+        WorkerPool { jobs }
+    }
+
+    /// Runs `lane` on a worker, which sends it to `done` when it's finished, or hands it back if
+    /// they're all busy.
+    pub fn run(&self, index: usize, lane: Lane, done: &Done) -> Result<(), Lane> {
+        let job = Job {
+            index,
+            lane,
+            done: done.clone(),
+        };
+        self.jobs.try_send(job).map_err(|e| match e {
+            TrySendError::Full(job) | TrySendError::Disconnected(job) => job.lane,
+        })
+    }
+}
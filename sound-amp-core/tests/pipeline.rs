@@ -0,0 +1,1006 @@
+//! Pushes sine waves through the signal path and checks what comes out the other end.
+
+use std::f32::consts::{FRAC_1_SQRT_2, TAU};
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::{BufferSize, SampleRate, StreamConfig};
+use sound_amp_core::backend::mock::MockBackend;
+use sound_amp_core::config::Config;
+use sound_amp_core::offline;
+use sound_amp_core::recorder::{EncoderSettings, Tags};
+use sound_amp_core::correlation::CorrelationMeter;
+use sound_amp_core::dither::Dither;
+use sound_amp_core::ducking::{self, Ducker, DuckingConfig};
+use sound_amp_core::chain::{EffectChain, Processor};
+use sound_amp_core::config::Profile;
+use sound_amp_core::effects::{
+    Biquad, ConvolutionConfig, EffectConfig, GainConfig, HighPassConfig, PeakingConfig,
+    PerChannel, VoiceChangerConfig,
+};
+use sound_amp_core::loudness::{Loudness, LoudnessMeter};
+use sound_amp_core::remix::{Remix, RemixConfig};
+use sound_amp_core::room::{RoomCorrection, RoomCorrectionConfig};
+use sound_amp_core::meter::Meters;
+use sound_amp_core::recorder::RecordingTap;
+use sound_amp_core::safety::{SafetyLimiter, TruePeak};
+use sound_amp_core::spatial::Position;
+use sound_amp_core::spectrogram::{self, Spectrogram, SpectrumAnalysis, Window};
+use sound_amp_core::standby::StandbyConfig;
+use sound_amp_core::workers::WorkerPool;
+use sound_amp_core::{
+    setup_stream, EngineState, InputSource, LinkSettings, LinkStatus, PlayerCommand,
+};
+
+const RATE: u32 = 48000;
+const FREQUENCY: f32 = 1000.0;
+/// Samples per callback, 10 ms at `RATE`.
+const BLOCK: usize = 480;
+
+fn sine(frequency: f32, amplitude: f32, rate: u32, len: usize) -> Vec<f32> {
+    (0..len)
+        .map(|i| amplitude * (TAU * frequency * i as f32 / rate as f32).sin())
+        .collect()
+}
+
+/// Amplitude of the `frequency` component of `signal`, by the Goertzel algorithm.
+fn amplitude_at(signal: &[f32], frequency: f32, rate: u32) -> f32 {
+    let coefficient = 2.0 * (TAU as f64 * frequency as f64 / rate as f64).cos();
+    let (mut s1, mut s2) = (0f64, 0f64);
+    for &x in signal {
+        let s0 = x as f64 + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s0;
+    }
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+    (2.0 * power.sqrt() / signal.len() as f64) as f32
+}
+
+/// Harmonics two to five relative to the fundamental; `signal` should hold whole cycles.
+fn thd(signal: &[f32], fundamental: f32, rate: u32) -> f32 {
+    let harmonics: f32 = (2..=5)
+        .map(|n| amplitude_at(signal, fundamental * n as f32, rate).powi(2))
+        .sum();
+    harmonics.sqrt() / amplitude_at(signal, fundamental, rate)
+}
+
+/// Frequency from the rising zero crossings, interpolated between samples.
+fn frequency(signal: &[f32], rate: u32) -> f32 {
+    let crossings: Vec<f32> = signal
+        .windows(2)
+        .enumerate()
+        .filter(|(_, w)| w[0] < 0.0 && w[1] >= 0.0)
+        .map(|(i, w)| i as f32 + w[0] / (w[0] - w[1]))
+        .collect();
+    let (first, last) = (crossings[0], crossings[crossings.len() - 1]);
+    (crossings.len() - 1) as f32 * rate as f32 / (last - first)
+}
+
+fn peak(signal: &[f32]) -> f32 {
+    signal.iter().fold(0f32, |peak, s| peak.max(s.abs()))
+}
+
+/// A player on a mono mock backend, with nothing linked yet.
+fn player(settings: LinkSettings) -> (Arc<MockBackend>, Arc<Mutex<LinkStatus>>, Sender<PlayerCommand>) {
+    player_on(MockBackend::new(RATE, 1), settings)
+}
+
+fn player_on(
+    backend: MockBackend,
+    settings: LinkSettings,
+) -> (Arc<MockBackend>, Arc<Mutex<LinkStatus>>, Sender<PlayerCommand>) {
+    let backend = Arc::new(backend);
+    let status: Arc<Mutex<LinkStatus>> = Default::default();
+    let player = setup_stream(
+        backend.clone(),
+        Default::default(),
+        Default::default(),
+        Arc::clone(&status),
+        Default::default(),
+        settings,
+    );
+    (backend, status, player)
+}
+
+/// A player with its input device linked and running.
+fn start_player(settings: LinkSettings) -> (Arc<MockBackend>, Arc<Mutex<LinkStatus>>, Sender<PlayerCommand>) {
+    start_player_on(MockBackend::new(RATE, 1), settings)
+}
+
+fn start_player_on(
+    backend: MockBackend,
+    settings: LinkSettings,
+) -> (Arc<MockBackend>, Arc<Mutex<LinkStatus>>, Sender<PlayerCommand>) {
+    let (backend, status, player) = player_on(backend, settings);
+    player
+        .send(PlayerCommand::Start(InputSource::Device(0)))
+        .unwrap();
+    wait_for(&status, EngineState::Running);
+    (backend, status, player)
+}
+
+/// Runs `step` until `until` holds for the player's status, failing after `limit`.
+#[track_caller]
+fn run_until(
+    status: &Mutex<LinkStatus>,
+    limit: Duration,
+    until: impl Fn(&LinkStatus) -> bool,
+    mut step: impl FnMut(),
+) {
+    let deadline = Instant::now() + limit;
+    while !until(&status.lock().unwrap()) {
+        assert!(Instant::now() < deadline, "The player never got there");
+        step();
+    }
+}
+
+/// Waits up to five seconds for `until` to hold for the player's status.
+#[track_caller]
+fn wait_until(status: &Mutex<LinkStatus>, until: impl Fn(&LinkStatus) -> bool) {
+    run_until(status, Duration::from_secs(5), until, || {
+        thread::sleep(Duration::from_millis(10))
+    });
+}
+
+#[track_caller]
+fn wait_for(status: &Mutex<LinkStatus>, state: EngineState) {
+    wait_until(status, |status| status.state == state);
+}
+
+#[test]
+fn a_sine_comes_through_a_link_at_the_set_volume() {
+    let (backend, status, player) = player(LinkSettings::default());
+    // Set before linking, so it's waiting for the link when it comes up.
+    player.send(PlayerCommand::SetVolume(0.5)).unwrap();
+    player
+        .send(PlayerCommand::Start(InputSource::Device(0)))
+        .unwrap();
+    wait_for(&status, EngineState::Running);
+
+    let input = sine(FREQUENCY, 0.8, RATE, RATE as usize);
+    let mut output = Vec::new();
+    for block in input.chunks(BLOCK) {
+        backend.feed(block);
+        output.extend(backend.pull(BLOCK));
+    }
+
+    assert!((peak(&output) - 0.4).abs() < 1e-3, "peak {}", peak(&output));
+    assert!((amplitude_at(&output, FREQUENCY, RATE) - 0.4).abs() < 1e-3);
+    assert!((frequency(&output, RATE) - FREQUENCY).abs() < 0.1);
+    assert!(thd(&output, FREQUENCY, RATE) < 1e-4);
+
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn a_volume_that_is_not_a_number_is_ignored_and_one_below_zero_is_silence() {
+    let (backend, status, player) = start_player(LinkSettings::default());
+    player.send(PlayerCommand::SetVolume(0.5)).unwrap();
+    player.send(PlayerCommand::SetVolume(f32::NAN)).unwrap();
+    player.send(PlayerCommand::SetVolume(f32::INFINITY)).unwrap();
+    player.send(PlayerCommand::IncreaseVolume(f32::NAN)).unwrap();
+    player.send(PlayerCommand::SetTrim(f32::INFINITY)).unwrap();
+    wait_until(&status, |status| status.volume == 0.5);
+
+    let input = sine(FREQUENCY, 0.8, RATE, RATE as usize);
+    let mut output = Vec::new();
+    for block in input.chunks(BLOCK) {
+        backend.feed(block);
+        output.extend(backend.pull(BLOCK));
+    }
+    let settled = &output[output.len() / 2..];
+    assert!((amplitude_at(settled, FREQUENCY, RATE) - 0.4).abs() < 1e-3);
+
+    player.send(PlayerCommand::IncreaseVolume(-1.0)).unwrap();
+    wait_until(&status, |status| status.volume == 0.0);
+}
+
+#[test]
+fn an_eq_band_gain_set_while_running_reloads_the_profile_with_it() {
+    let (backend, _status, player) = start_player(LinkSettings::default());
+    let profile = Profile {
+        name: "EQ".to_string(),
+        volume: 1.0,
+        trim: 1.0,
+        input_device: None,
+        effects: vec![
+            EffectConfig::HighPass(HighPassConfig { frequency: 20.0 }),
+            EffectConfig::Peaking(PeakingConfig {
+                frequency: 100.0,
+                q: 1.0,
+                gain_db: 0.0,
+            }),
+            EffectConfig::Peaking(PeakingConfig {
+                frequency: FREQUENCY,
+                q: 1.0,
+                gain_db: 0.0,
+            }),
+        ],
+        right_effects: None,
+        link_channels: true,
+    };
+    player.send(PlayerCommand::ApplyProfile(profile)).unwrap();
+    // The second band; the high-pass isn't one.
+    player
+        .send(PlayerCommand::SetEqGain {
+            band: 1,
+            gain_db: 6.0,
+        })
+        .unwrap();
+    player
+        .send(PlayerCommand::SetEqGain {
+            band: 1,
+            gain_db: f32::NAN,
+        })
+        .unwrap();
+
+    let input = sine(FREQUENCY, 0.25, RATE, BLOCK);
+    let boosted = 0.25 * 10f32.powf(6.0 / 20.0);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let mut output = Vec::new();
+    loop {
+        backend.feed(&input);
+        output.extend(backend.pull(BLOCK));
+        let last = &output[output.len().saturating_sub(RATE as usize / 10)..];
+        if last.len() == RATE as usize / 10
+            && (amplitude_at(last, FREQUENCY, RATE) - boosted).abs() < 0.01
+        {
+            break;
+        }
+        assert!(Instant::now() < deadline, "The band never got its gain");
+    }
+}
+
+#[test]
+fn a_quiet_input_goes_into_standby_and_comes_back_when_it_is_loud() {
+    let standby = StandbyConfig {
+        enabled: true,
+        minutes: 0.01,
+        ..Default::default()
+    };
+    let (backend, status, player) = start_player(LinkSettings {
+        standby,
+        ..Default::default()
+    });
+
+    // Nothing fed, so the input is as quiet as it gets.
+    wait_for(&status, EngineState::Idle);
+    assert!(status.lock().unwrap().standby);
+
+    let running = |status: &LinkStatus| status.state == EngineState::Running;
+    run_until(&status, Duration::from_secs(5), running, || {
+        backend.feed(&sine(FREQUENCY, 0.5, RATE, BLOCK));
+        thread::sleep(Duration::from_millis(10));
+    });
+    assert!(!status.lock().unwrap().standby);
+
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn calibrating_sets_the_trim_for_speech_and_saves_the_device_profile() {
+    let config = std::env::temp_dir().join(format!("sound-amp-calibration-{}.toml", std::process::id()));
+    let _ = fs::remove_file(&config);
+    let (backend, status, player) = start_player(LinkSettings {
+        config: Some(config.clone()),
+        ..Default::default()
+    });
+    player.send(PlayerCommand::CalibrateGain).unwrap();
+    wait_until(&status, |status| status.calibrating.is_some());
+
+    // Words peaking at -20 dBFS, with a -60 dBFS hum between them.
+    let word = sine(FREQUENCY, 0.1, RATE, RATE as usize * 3 / 10);
+    let pause = sine(FREQUENCY, 0.001, RATE, RATE as usize / 5);
+    let speech: Vec<f32> = (0..20).flat_map(|_| [&word[..], &pause[..]].concat()).collect();
+    for block in speech.chunks(BLOCK) {
+        backend.feed(block);
+    }
+    wait_until(&status, |status| status.calibrating.is_none());
+
+    // Brought up 8 dB, so the words peak at the wizard's -12 dBFS.
+    let trim = status.lock().unwrap().trim;
+    assert!((trim - 10f32.powf(8.0 / 20.0)).abs() < 0.01, "trim {}", trim);
+    let saved = Config::load(&config).unwrap();
+    let profile = saved.profile("mock input").unwrap();
+    assert_eq!(profile.input_device.as_deref(), Some("mock input"));
+    assert_eq!(profile.trim, trim);
+    let gate = profile.effects.iter().find_map(|effect| match effect {
+        EffectConfig::Gate(gate) => Some(gate.threshold_db),
+        _ => None,
+    });
+    // Between the hum and the words, once they're trimmed.
+    assert!(gate.is_some_and(|db| db > -52.0 && db < -12.0), "gate at {:?}", gate);
+    fs::remove_file(&config).unwrap();
+
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn the_round_trip_test_hears_its_chirp_come_back_over_a_cable() {
+    let (backend, status, player) = start_player(LinkSettings::default());
+    player.send(PlayerCommand::MeasureLatency).unwrap();
+    wait_until(&status, |status| status.measuring_latency);
+
+    // What each block plays goes back into the input 50 ms on from where it went out.
+    let mut cable = vec![0.0; RATE as usize / 20];
+    run_until(&status, Duration::from_secs(5), |status| !status.measuring_latency, || {
+        let block: Vec<f32> = cable.drain(..BLOCK).collect();
+        backend.feed(&block);
+        cable.extend(backend.pull(BLOCK));
+    });
+
+    let measured = status.lock().unwrap().measured_round_trip_ms;
+    assert!(measured.is_some_and(|ms| (ms - 50.0).abs() < 0.5), "measured {:?}", measured);
+
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn an_impulse_response_captured_over_a_cable_has_its_echo() {
+    let (backend, status, player) = start_player(LinkSettings::default());
+    let directory = std::env::temp_dir().join(format!("sound-amp-impulse-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    player.send(PlayerCommand::CaptureImpulse(directory.clone())).unwrap();
+    wait_until(&status, |status| status.capturing_impulse);
+
+    // A room of its own: what's played comes back at half its level 20 ms on, and again at a
+    // quarter of it 10 ms after that.
+    let (direct, echo) = (RATE as usize / 50, RATE as usize / 100);
+    let mut played: Vec<f32> = vec![0.0; direct + echo];
+    run_until(&status, Duration::from_secs(30), |status| !status.capturing_impulse, || {
+        let start = played.len() - direct - echo;
+        let block: Vec<f32> = (start..start + BLOCK)
+            .map(|i| 0.5 * played[i + echo] + 0.25 * played[i])
+            .collect();
+        backend.feed(&block);
+        played.extend(backend.pull(BLOCK));
+    });
+
+    let path = status.lock().unwrap().impulse_response.clone().expect("Nothing was captured");
+    let response: Vec<f32> = hound::WavReader::open(&path)
+        .unwrap()
+        .samples::<f32>()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(response.len(), RATE as usize);
+    let peak = |from: usize, to: usize| {
+        (from..to).max_by(|&a, &b| response[a].abs().total_cmp(&response[b].abs())).unwrap()
+    };
+    // The direct sound comes just after the pre-roll, and the echo 10 ms after it.
+    let first = peak(0, echo / 2);
+    let second = peak(first + echo / 2, RATE as usize);
+    assert_eq!(second - first, echo);
+    let ratio = response[second] / response[first];
+    assert!((ratio - 0.5).abs() < 0.02, "echo at {} of the direct sound", ratio);
+    // Short of 0.5, the response being no wider than the sweep, which stops short of Nyquist.
+    assert!(response[first] > 0.38, "direct sound at {}", response[first]);
+
+    fs::remove_dir_all(&directory).unwrap();
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn room_correction_cuts_a_boom_and_holds_back_on_filling_a_dip() {
+    let mono = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    // A room that booms 8 dB around 125 Hz and swallows 10 dB around 4 kHz.
+    let mut room = vec![0.0; RATE as usize];
+    room[0] = 1.0;
+    Biquad::peaking(125.0, 2.0, 8.0, &mono).process(&mut room);
+    Biquad::peaking(4000.0, 2.0, -10.0, &mono).process(&mut room);
+    let path = std::env::temp_dir().join(format!("sound-amp-room-{}.wav", std::process::id()));
+    let measure = |response: &[f32]| {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: RATE,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &sample in response {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        RoomCorrection::measure(&path, &RoomCorrectionConfig::default()).unwrap()
+    };
+    // Relative to 1 kHz, which the room leaves alone.
+    let relative = |correction: &RoomCorrection, hz: f32| {
+        let gain = |hz: f32| {
+            let near = |&&(centre, _): &&(f32, f32)| (centre / hz).log2().abs() < 0.1;
+            correction.bands.iter().find(near).map_or(0.0, |&(_, gain)| gain)
+        };
+        gain(hz) - gain(1000.0)
+    };
+
+    let correction = measure(&room);
+    assert!((relative(&correction, 125.0) + 8.0).abs() < 1.0, "{:?}", correction.bands);
+    assert_eq!(correction.extremes().0, 6.0);
+    assert!(correction.bands.iter().all(|&(centre, _)| (40.0..=12000.0).contains(&centre)));
+
+    let mut corrected = room.clone();
+    correction.filter(&mono).process(&mut corrected);
+    let remaining = measure(&corrected);
+    assert!(relative(&remaining, 125.0).abs() < 1.5, "{:?}", remaining.bands);
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn surround_folds_down_to_stereo_at_the_itu_coefficients() {
+    let format = |channels| StreamConfig {
+        channels,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    // Each channel on its own, and where it ends up on the left and right.
+    let fold = |channels: u16, downmix_lfe: bool| {
+        let config = RemixConfig {
+            downmix_lfe,
+            ..Default::default()
+        };
+        let mut remix = Remix::new(&config, &format(channels), &format(2)).unwrap();
+        (0..channels as usize)
+            .map(|channel| {
+                let mut frame = vec![0.0; channels as usize];
+                frame[channel] = 1.0;
+                let mut stereo = Vec::new();
+                remix.process(&frame, &mut stereo);
+                (stereo[0], stereo[1])
+            })
+            .collect::<Vec<_>>()
+    };
+    let close = |got: &[(f32, f32)], wanted: &[(f32, f32)]| {
+        assert_eq!(got.len(), wanted.len());
+        for (channel, (got, wanted)) in got.iter().zip(wanted).enumerate() {
+            assert!(
+                (got.0 - wanted.0).abs() < 1e-6 && (got.1 - wanted.1).abs() < 1e-6,
+                "channel {}: {:?}, wanted {:?}",
+                channel,
+                got,
+                wanted
+            );
+        }
+    };
+    // -3 dB, which the LFE is folded in at too when it is.
+    let h = FRAC_1_SQRT_2;
+
+    // Front left and right, center, LFE, back left and right.
+    close(&fold(6, false), &[(1.0, 0.0), (0.0, 1.0), (h, h), (0.0, 0.0), (h, 0.0), (0.0, h)]);
+    close(&fold(6, true), &[(1.0, 0.0), (0.0, 1.0), (h, h), (h, h), (h, 0.0), (0.0, h)]);
+    // Then the side left and right, on the same side as the back pair.
+    let seven_one = [(1.0, 0.0), (0.0, 1.0), (h, h), (0.0, 0.0), (h, 0.0), (0.0, h), (h, 0.0), (0.0, h)];
+    close(&fold(8, false), &seven_one);
+    let mut with_lfe = seven_one;
+    with_lfe[3] = (h, h);
+    close(&fold(8, true), &with_lfe);
+}
+
+#[test]
+fn a_new_pan_or_position_is_swept_to_rather_than_jumped_to() {
+    let format = |channels| StreamConfig {
+        channels,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let mut config = RemixConfig::default();
+    let mut remix = Remix::new(&config, &format(1), &format(2)).unwrap();
+    let mut output = Vec::new();
+    remix.process(&[1.0; BLOCK], &mut output);
+    assert!((output[0] - FRAC_1_SQRT_2).abs() < 1e-6);
+
+    // Hard right: the left channel comes down over a few milliseconds, not between two samples.
+    config.pan = 1.0;
+    remix.retarget(&config);
+    remix.process(&[1.0; BLOCK * 5], &mut output);
+    let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+    assert!(left.windows(2).all(|w| w[1] <= w[0] && w[0] - w[1] < 0.01));
+    assert!(left[left.len() - 1] < 0.01, "ends at {}", left[left.len() - 1]);
+    let power = |i: usize| output[2 * i].powi(2) + output[2 * i + 1].powi(2);
+    assert!((0..left.len()).all(|i| (power(i) - 1.0).abs() < 1e-4));
+
+    // Placed off to the right in headphones, it's crossfaded from the center to the spatializer.
+    let mut config = RemixConfig::default();
+    let mut remix = Remix::new(&config, &format(1), &format(2)).unwrap();
+    remix.process(&[1.0; BLOCK], &mut output);
+    config.position = Some(Position {
+        azimuth: 90.0,
+        elevation: 0.0,
+    });
+    remix.retarget(&config);
+    remix.process(&[1.0; BLOCK], &mut output);
+    let left: Vec<f32> = output.iter().step_by(2).copied().collect();
+    assert!(left.windows(2).all(|w| (w[1] - w[0]).abs() < 0.05));
+}
+
+#[test]
+fn resampling_keeps_the_pitch_and_level_of_a_sine() {
+    let from_rate = 44100;
+    let backend = MockBackend::new(from_rate, 1).with_output_rate(RATE);
+    let (backend, _status, _player) = start_player_on(backend, LinkSettings::default());
+    let input = sine(FREQUENCY, 0.5, from_rate, from_rate as usize);
+    let mut output = Vec::new();
+    // 10 ms blocks on either side.
+    for block in input.chunks(441) {
+        backend.feed(block);
+        output.extend(backend.pull(BLOCK));
+    }
+
+    // Whole cycles, past the first block where the resampler starts from silence; a gap where the
+    // output ran dry would show up as harmonics.
+    let steady = &output[BLOCK..BLOCK + RATE as usize / 2];
+    assert!((frequency(steady, RATE) - FREQUENCY).abs() < 0.1);
+    assert!((amplitude_at(steady, FREQUENCY, RATE) - 0.5).abs() < 0.005);
+    assert!(thd(steady, FREQUENCY, RATE) < 0.01);
+}
+
+#[cfg(feature = "network")]
+#[test]
+fn a_network_sender_is_heard_at_its_own_pitch_past_a_connection_that_says_nothing() {
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+
+    let (backend, status, player) = start_player(LinkSettings::default());
+    let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    sound_amp_core::net::listen(port, player.clone()).unwrap();
+    let _silent = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let mut sender = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    // Mono PCM at 44.1 kHz, to the mock output's 48 kHz.
+    let from_rate = 44100u32;
+    let mut header = b"SAMP".to_vec();
+    header.extend(2u16.to_le_bytes());
+    header.extend(1u16.to_le_bytes());
+    header.extend(from_rate.to_le_bytes());
+    header.extend(0u16.to_le_bytes());
+    sender.write_all(&header).unwrap();
+    wait_until(&status, |status| status.input.as_deref() == Some("network"));
+
+    for packet in sine(FREQUENCY, 0.5, from_rate, from_rate as usize / 4).chunks(441) {
+        sender.write_all(&(packet.len() as u32 * 4).to_le_bytes()).unwrap();
+        for sample in packet {
+            sender.write_all(&sample.to_le_bytes()).unwrap();
+        }
+    }
+    thread::sleep(Duration::from_millis(300));
+    let output = backend.pull(BLOCK + RATE as usize / 10);
+
+    // Whole cycles, past the first block where the resampler starts from silence.
+    let steady = &output[BLOCK..];
+    assert!((frequency(steady, RATE) - FREQUENCY).abs() < 0.5, "{} Hz", frequency(steady, RATE));
+    assert!((amplitude_at(steady, FREQUENCY, RATE) - 0.5).abs() < 0.01);
+    player.send(PlayerCommand::Stop).unwrap();
+    wait_for(&status, EngineState::Idle);
+}
+
+#[test]
+fn dither_keeps_a_sine_quieter_than_a_16_bit_step_free_of_harmonics() {
+    let step = 1.0 / i16::MAX as f32;
+    // Rounded straight to the nearest step, this would be nothing but zeros.
+    let input = sine(FREQUENCY, 0.4 * step, RATE, RATE as usize);
+    for noise_shaping in [false, true] {
+        let mut dither = Dither::new(1, noise_shaping);
+        let output: Vec<f32> = input
+            .iter()
+            .map(|&s| dither.quantize(s) as f32 * step)
+            .collect();
+
+        let amplitude = amplitude_at(&output, FREQUENCY, RATE) / step;
+        assert!((amplitude - 0.4).abs() < 0.05, "{} steps", amplitude);
+        assert!(thd(&output, FREQUENCY, RATE) < 0.05, "THD {}", thd(&output, FREQUENCY, RATE));
+    }
+}
+
+#[test]
+fn the_limiter_keeps_the_peaks_between_samples_under_the_ceiling() {
+    let config = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    // A quarter of the rate, with every sample 45° off the crest, so none gets above 0.42.
+    let input: Vec<f32> = (0..RATE as usize)
+        .map(|i| 0.6 * (TAU * i as f32 / 4.0 + TAU / 8.0).sin())
+        .collect();
+    // Run through the start first, so the detector doesn't ring on the edge of the slice.
+    let steady_true_peak = |signal: &[f32]| {
+        let mut detector = TruePeak::new(&config);
+        detector.process(&signal[..RATE as usize / 2]);
+        detector.process(&signal[RATE as usize / 2..])
+    };
+    assert!((steady_true_peak(&input) - 0.6).abs() < 0.01);
+
+    let mut limiter = SafetyLimiter::new(0.5, &config);
+    let mut output = input.clone();
+    for block in output.chunks_mut(BLOCK) {
+        limiter.process(block);
+    }
+    let true_peak = steady_true_peak(&output);
+    assert!(true_peak < 0.501 && true_peak > 0.49, "true peak {}", true_peak);
+    assert!(peak(&output[RATE as usize / 2..]) < 0.36);
+}
+
+#[test]
+fn each_reader_of_the_live_levels_gets_the_peaks_since_it_last_read() {
+    let db = |gain: f32| 20.0 * gain.log10();
+    let meters = Meters::default();
+    meters.input.update(&[0.5, -0.25]);
+    assert!((meters.input.take().peak - db(0.5)).abs() < 1e-3);
+    meters.input.update(&[0.1]);
+    // Polling the levels doesn't take the streamed ones' peak, or the other way round.
+    assert!((meters.input.take_websocket().peak - db(0.5)).abs() < 1e-3);
+    assert!((meters.input.take().peak - db(0.1)).abs() < 1e-3);
+    assert!((meters.input.take_grpc().peak - db(0.5)).abs() < 1e-3);
+    assert!(meters.input.take_websocket().peak < -100.0);
+    assert!(meters.input.take_grpc().peak < -100.0);
+}
+
+#[test]
+fn the_loudness_of_a_sine_leaves_out_the_silence_and_the_quiet_parts() {
+    let config = StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let stereo = |mono: Vec<f32>| mono.into_iter().flat_map(|s| [s, s]).collect::<Vec<f32>>();
+    let seconds = |n: usize| n * RATE as usize;
+    // A 1 kHz sine at -20 dBFS in both channels is -20 LUFS; the silence is under the absolute
+    // gate and the -40 dBFS stretch 20 LU under the rest, so neither counts.
+    let mut input = stereo(sine(FREQUENCY, 0.1, RATE, seconds(10)));
+    input.extend(stereo(vec![0.0; seconds(5)]));
+    input.extend(stereo(sine(FREQUENCY, 0.01, RATE, seconds(5))));
+
+    let mut meter = LoudnessMeter::new(&config);
+    let mut readings = Vec::new();
+    for block in input.chunks(BLOCK * 2) {
+        readings.extend(meter.process(block));
+    }
+    assert_eq!(readings.len(), 200);
+    let Loudness { momentary, short_term, .. } = readings[99];
+    assert!((momentary.unwrap() + 20.0).abs() < 0.1, "momentary {:?}", momentary);
+    assert!((short_term.unwrap() + 20.0).abs() < 0.1, "short-term {:?}", short_term);
+    assert_eq!(readings[0].momentary, None);
+    assert_eq!(readings[28].short_term, None);
+    let integrated = readings[199].integrated.unwrap();
+    assert!((integrated + 20.0).abs() < 0.1, "integrated {}", integrated);
+    assert!((readings[199].momentary.unwrap() + 40.0).abs() < 0.1);
+
+    // Starting over forgets the -20 LUFS stretch.
+    meter.restart();
+    let louder = stereo(sine(FREQUENCY, 0.03, RATE, seconds(5)));
+    let last = louder.chunks(BLOCK * 2).filter_map(|block| meter.process(block)).last();
+    let integrated = last.unwrap().integrated.unwrap();
+    assert!((integrated + 30.5).abs() < 0.2, "integrated {}", integrated);
+}
+
+#[test]
+fn correlation_tells_channels_in_phase_from_ones_upside_down_and_unrelated_ones() {
+    let stereo = StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let correlation = |left: &[f32], right: &[f32]| {
+        let mut meter = CorrelationMeter::new(&stereo).unwrap();
+        let frames: Vec<f32> = left.iter().zip(right).flat_map(|(&l, &r)| [l, r]).collect();
+        frames.chunks(BLOCK * 2).map(|block| meter.process(block)).last().unwrap()
+    };
+    let tone = sine(FREQUENCY, 0.5, RATE, RATE as usize);
+    let upside_down: Vec<f32> = tone.iter().map(|s| -s * 0.5).collect();
+    let other = sine(FREQUENCY * 1.5, 0.5, RATE, RATE as usize);
+
+    assert!(correlation(&tone, &tone).unwrap() > 0.99);
+    assert!(correlation(&tone, &upside_down).unwrap() < -0.99);
+    assert!(correlation(&tone, &other).unwrap().abs() < 0.05);
+    assert_eq!(correlation(&[0.0; BLOCK], &[0.0; BLOCK]), None);
+
+    let mono = StreamConfig { channels: 1, ..stereo };
+    assert!(CorrelationMeter::new(&mono).is_none());
+}
+
+#[test]
+fn heavy_effects_on_the_workers_sound_the_same_as_in_the_callback() {
+    let stereo = StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let voice = EffectConfig::VoiceChanger(VoiceChangerConfig {
+        pitch_semitones: 4.0,
+        ..Default::default()
+    });
+    let left = sine(FREQUENCY, 0.5, RATE, RATE as usize);
+    let right = sine(FREQUENCY * 0.75, 0.3, RATE, RATE as usize);
+    let input: Vec<f32> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    let run = |workers: Option<WorkerPool>| {
+        let mut chain = EffectChain::default();
+        if let Some(workers) = workers {
+            chain.set_workers(workers);
+        }
+        chain.load(slice::from_ref(&voice), &stereo);
+        let mut output = input.clone();
+        for block in output.chunks_mut(BLOCK * 2) {
+            chain.process(block);
+        }
+        output
+    };
+
+    let inline = run(None);
+    assert!(peak(&inline[RATE as usize..]) > 0.1);
+    assert_eq!(run(Some(WorkerPool::new(2))), inline);
+}
+
+/// Halves what it's given, but panics on its first block.
+struct PanicsOnce(bool);
+
+impl Processor for PanicsOnce {
+    fn process(&mut self, samples: &mut [f32]) {
+        if !self.0 {
+            self.0 = true;
+            panic!("an effect fell over");
+        }
+        for sample in samples {
+            *sample *= 0.5;
+        }
+    }
+
+    fn heavy(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn a_lane_that_panics_on_a_worker_panics_the_callback_rather_than_hanging_it() {
+    let stereo = StreamConfig {
+        channels: 2,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let mut per_channel = PerChannel::new(
+        vec![Vec::new(), vec![Box::new(PanicsOnce(false))]],
+        &stereo,
+    );
+    per_channel.share_workers(&WorkerPool::new(1));
+
+    let (finished, done) = mpsc::channel();
+    thread::spawn(move || {
+        let mut block = vec![1.0; BLOCK * 2];
+        let first = panic::catch_unwind(AssertUnwindSafe(|| per_channel.process(&mut block)));
+        let mut block = vec![1.0; BLOCK * 2];
+        per_channel.process(&mut block);
+        let _ = finished.send((first.is_err(), block));
+    });
+    let (panicked, block) = done
+        .recv_timeout(Duration::from_secs(5))
+        .expect("the callback hung waiting for the worker");
+    assert!(panicked);
+    // The lane came back with the panic, so the next block runs it as before.
+    assert_eq!(&block[..4], &[1.0, 0.5, 1.0, 0.5]);
+}
+
+#[test]
+fn processing_a_file_applies_the_profile_in_line_with_the_input() {
+    let rate = 44100;
+    let directory = std::env::temp_dir().join(format!("sound-amp-offline-{}", std::process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let write = |name: &str, channels: u16, samples: &[f32]| {
+        let path = directory.join(name);
+        let spec = hound::WavSpec {
+            channels,
+            sample_rate: rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for &sample in samples {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        path
+    };
+    let left = sine(FREQUENCY, 0.5, rate, rate as usize);
+    let right = sine(FREQUENCY * 1.5, 0.25, rate, rate as usize);
+    let input: Vec<f32> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+    let input_path = write("input.wav", 2, &input);
+    // A bare impulse changes nothing but the timing, by the convolver's partition.
+    let mut impulse = vec![0.0; 64];
+    impulse[0] = 1.0;
+    let profile = Profile {
+        name: "Offline".to_string(),
+        volume: 0.5,
+        trim: 1.0,
+        input_device: None,
+        effects: vec![
+            EffectConfig::Gain(GainConfig { gain_db: 6.0 }),
+            EffectConfig::Convolution(ConvolutionConfig {
+                file: write("impulse.wav", 1, &impulse),
+                ..Default::default()
+            }),
+        ],
+        right_effects: None,
+        link_channels: true,
+    };
+    let output_path = directory.join("output.wav");
+
+    let length = offline::process_file(
+        &input_path,
+        &output_path,
+        &profile,
+        &EncoderSettings::default(),
+        &Tags::default(),
+        None,
+    )
+    .unwrap();
+
+    assert_eq!(length, Duration::from_secs(1));
+    let mut reader = hound::WavReader::open(&output_path).unwrap();
+    assert_eq!((reader.spec().channels, reader.spec().sample_rate), (2, rate));
+    let output: Vec<f32> = reader.samples::<f32>().map(Result::unwrap).collect();
+    assert_eq!(output.len(), input.len());
+    let gain = 0.5 * 10f32.powf(6.0 / 20.0);
+    let error = output
+        .iter()
+        .zip(&input)
+        .fold(0f32, |error, (out, inp)| error.max((out - inp * gain).abs()));
+    assert!(error < 1e-4, "off by {}", error);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn the_spectrogram_shows_a_tone_and_hum_at_their_levels_through_each_window() {
+    let config = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let hum = sine(60.0, 0.1, RATE, RATE as usize);
+    let signal: Vec<f32> = sine(FREQUENCY, 0.5, RATE, RATE as usize)
+        .iter()
+        .zip(&hum)
+        .map(|(tone, hum)| tone + hum)
+        .collect();
+    // The last column of a second of the signal, and that the columns go once it's stopped.
+    let last_column = |analysis: SpectrumAnalysis| {
+        let meters = Arc::new(Meters::default());
+        let tap: RecordingTap = Arc::new(Mutex::new(None));
+        let spectrogram = Spectrogram::start(&config, &analysis, &tap, &meters);
+        tap.lock().unwrap().as_mut().unwrap().push_slice(&signal);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while meters.spectrogram.columns().len() < 5 {
+            assert!(Instant::now() < deadline, "No columns came");
+            thread::sleep(Duration::from_millis(10));
+        }
+        let column = meters.spectrogram.columns().pop().unwrap();
+        spectrogram.stop();
+        while !meters.spectrogram.columns().is_empty() {
+            assert!(Instant::now() < deadline, "The columns were kept after stopping");
+            thread::sleep(Duration::from_millis(10));
+        }
+        column
+    };
+    let band = |hz: f32| {
+        (0..spectrogram::BANDS)
+            .min_by(|&a, &b| {
+                let off = |band: usize| (spectrogram::band_hz(band as f32) / hz).ln().abs();
+                off(a).total_cmp(&off(b))
+            })
+            .unwrap()
+    };
+    // The loudest of the bands either side, since a tone between two of them shows in both.
+    let around = |column: &[f32], hz: f32| column[band(hz) - 1..=band(hz) + 1].iter().fold(f32::MIN, |a, &b| a.max(b));
+
+    let column = last_column(SpectrumAnalysis::default());
+    // Amplitudes of 0.5 and 0.1 are -6 and -20 dBFS.
+    assert!((around(&column, FREQUENCY) + 6.0).abs() < 1.5, "{:?}", column);
+    assert!((around(&column, 60.0) + 20.0).abs() < 1.5, "{:?}", column);
+    assert!(column[band(300.0)] < -60.0, "{:?}", column);
+    assert!(column[band(5000.0)] < -60.0, "{:?}", column);
+
+    // Without a window the tone leaks out two octaves up; with the widest, bigger FFT and some
+    // averaging it hardly does at all, and still reads the same.
+    let rectangular = last_column(SpectrumAnalysis {
+        window: Window::Rectangular,
+        ..Default::default()
+    });
+    assert!((around(&rectangular, FREQUENCY) + 6.0).abs() < 2.0, "{:?}", rectangular);
+    assert!(rectangular[band(5000.0)] > -80.0, "{:?}", rectangular);
+    let blackman_harris = last_column(SpectrumAnalysis {
+        fft_size: 16384,
+        window: Window::BlackmanHarris,
+        averaging: 4,
+    });
+    assert!((around(&blackman_harris, FREQUENCY) + 6.0).abs() < 1.5, "{:?}", blackman_harris);
+    assert!((around(&blackman_harris, 60.0) + 20.0).abs() < 1.5, "{:?}", blackman_harris);
+    assert!(blackman_harris[band(5000.0)] < -95.0, "{:?}", blackman_harris);
+}
+
+#[test]
+fn ducking_attenuates_the_bus_by_the_configured_amount_while_live_input_is_loud() {
+    let config = DuckingConfig {
+        enabled: true,
+        ..Default::default()
+    };
+    let stream_config = StreamConfig {
+        channels: 1,
+        sample_rate: SampleRate(RATE),
+        buffer_size: BufferSize::Default,
+    };
+    let level = Arc::new(AtomicU32::new(0));
+    let mut ducker = Ducker::new(&config, &stream_config, &level);
+    let live = sine(200.0, 0.5, RATE, BLOCK);
+    let mut bus = sine(FREQUENCY, 0.5, RATE, RATE as usize);
+    for block in bus.chunks_mut(BLOCK) {
+        ducking::store_level(&level, &live);
+        ducker.process(block);
+    }
+
+    // Well past the attack, the gain has settled.
+    let settled = &bus[RATE as usize / 2..];
+    let expected = 0.5 * 10f32.powf(-config.amount_db / 20.0);
+    assert!((amplitude_at(settled, FREQUENCY, RATE) - expected).abs() < 1e-3);
+    assert!(thd(settled, FREQUENCY, RATE) < 1e-3);
+
+    // And it lets go once the live input is quiet, ten release time constants later.
+    level.store(0f32.to_bits(), Ordering::Relaxed);
+    let mut released = sine(FREQUENCY, 0.5, RATE, RATE as usize * 5);
+    ducker.process(&mut released);
+    assert!((peak(&released[RATE as usize * 4..]) - 0.5).abs() < 1e-3);
+}
+
+#[test]
+fn a_file_playing_fades_out_with_the_link_when_the_sleep_timer_runs_out() {
+    let path = std::env::temp_dir().join(format!("sound-amp-sleep-{}.wav", std::process::id()));
+    let spec = hound::WavSpec {
+        channels: 1,
+        sample_rate: RATE,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+    for sample in sine(FREQUENCY, 0.5, RATE, RATE as usize * 10) {
+        writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    let (backend, status, player) = start_player(LinkSettings::default());
+    player.send(PlayerCommand::PlayFile(path.clone())).unwrap();
+    let silence = vec![0.0; BLOCK];
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        backend.feed(&silence);
+        if peak(&backend.pull(BLOCK)) > 0.45 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "The file was never heard");
+        thread::sleep(Duration::from_millis(5));
+    }
+
+    player.send(PlayerCommand::SleepAfter(Some(Duration::from_secs(2)))).unwrap();
+    // Pulled at about twice the pace it plays at, so the fade is over well before the stop.
+    let mut peaks = Vec::new();
+    while status.lock().unwrap().state == EngineState::Running {
+        backend.feed(&silence);
+        peaks.push(peak(&backend.pull(BLOCK)));
+        if peaks.len() >= 10 && peaks[peaks.len() - 10..].iter().all(|&p| p < 1e-3) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    assert!(
+        peaks.len() >= 10 && peaks[peaks.len() - 10..].iter().all(|&p| p < 1e-3),
+        "The file was still playing when the timer stopped the link"
+    );
+    assert!(peaks.iter().any(|&p| p > 0.1 && p < 0.4), "The file was cut rather than faded");
+    wait_for(&status, EngineState::Idle);
+    fs::remove_file(path).unwrap();
+}
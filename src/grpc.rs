@@ -0,0 +1,238 @@
+use std::net::{TcpListener, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use sound_amp_core::config::Profile;
+use sound_amp_core::meter::{self, Meters};
+use sound_amp_core::{find_input_device, EngineState, InputSource, LinkStatus, PlayerCommand};
+
+use self::proto::sound_amp_server::{SoundAmp, SoundAmpServer};
+use self::proto::start_request::Device;
+
+pub mod proto {
+    tonic::include_proto!("soundamp");
+}
+
+pub type GrpcError = Box<dyn std::error::Error + Send + Sync>;
+
+const METER_INTERVAL: Duration = Duration::from_millis(50);
+/// Readings a slow client can fall behind by before it skips ahead.
+const METER_BACKLOG: usize = 16;
+
+type MeterStream = Pin<Box<dyn Stream<Item = Result<proto::Meters, Status>> + Send>>;
+
+struct Service {
+    profiles: Vec<Profile>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: broadcast::Sender<proto::Meters>,
+    player_channel: Sender<PlayerCommand>,
+}
+
+impl Service {
+    // `Status` is what every handler returns anyway.
+    #[allow(clippy::result_large_err)]
+    fn send(&self, command: PlayerCommand) -> Result<Response<proto::Empty>, Status> {
+        self.player_channel
+            .send(command)
+            .map_err(|_| Status::unavailable("The player has stopped"))?;
+        Ok(Response::new(proto::Empty {}))
+    }
+}
+
+#[tonic::async_trait]
+impl SoundAmp for Service {
+    async fn get_status(
+        &self,
+        _: Request<proto::Empty>,
+    ) -> Result<Response<proto::Status>, Status> {
+        let status = self.status.lock().unwrap().clone();
+        Ok(Response::new(proto::Status {
+            input: status.input,
+            output: status.output,
+            sample_rate: status.sample_rate,
+            channels: status.channels.map(u32::from),
+            volume: status.volume,
+            muted: status.muted,
+            preset: status.preset,
+            recording: status.recording,
+            send_to: status.send_to,
+            rtp_send_to: status.rtp_send_to,
+            error: status.error,
+            state: engine_state(status.state).into(),
+            notice: status.notice,
+            dose: status.dose,
+            trim: status.trim,
+        }))
+    }
+
+    async fn list_devices(
+        &self,
+        _: Request<proto::Empty>,
+    ) -> Result<Response<proto::Devices>, Status> {
+        let host = cpal::default_host();
+        let names = |devices: Result<Vec<cpal::Device>, _>| -> Vec<String> {
+            devices
+                .map(|d| d.iter().map(|d| d.name().unwrap_or_default()).collect())
+                .unwrap_or_default()
+        };
+        Ok(Response::new(proto::Devices {
+            inputs: names(host.input_devices().map(|d| d.collect())),
+            outputs: names(host.output_devices().map(|d| d.collect())),
+        }))
+    }
+
+    async fn list_presets(
+        &self,
+        _: Request<proto::Empty>,
+    ) -> Result<Response<proto::Presets>, Status> {
+        Ok(Response::new(proto::Presets {
+            names: self.profiles.iter().map(|p| p.name.clone()).collect(),
+        }))
+    }
+
+    async fn start(
+        &self,
+        request: Request<proto::StartRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let device = match request.into_inner().device {
+            Some(Device::Index(i)) => i as usize,
+            Some(Device::Name(name)) => find_input_device(&name)
+                .ok_or_else(|| Status::not_found(format!("No input device named {}", name)))?,
+            None => return Err(Status::invalid_argument("No device given")),
+        };
+        self.send(PlayerCommand::Start(InputSource::Device(device)))
+    }
+
+    async fn stop(&self, _: Request<proto::Empty>) -> Result<Response<proto::Empty>, Status> {
+        self.send(PlayerCommand::Stop)
+    }
+
+    async fn set_volume(
+        &self,
+        request: Request<proto::VolumeRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let volume = request.into_inner().volume;
+        if !volume.is_finite() {
+            return Err(Status::invalid_argument("The volume isn't a number"));
+        }
+        self.send(PlayerCommand::SetVolume(volume))
+    }
+
+    async fn set_trim(
+        &self,
+        request: Request<proto::TrimRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let trim = request.into_inner().trim;
+        if !trim.is_finite() {
+            return Err(Status::invalid_argument("The trim isn't a number"));
+        }
+        self.send(PlayerCommand::SetTrim(trim))
+    }
+
+    async fn apply_preset(
+        &self,
+        request: Request<proto::PresetRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let name = request.into_inner().name;
+        let profile = self
+            .profiles
+            .iter()
+            .find(|p| p.name == name)
+            .ok_or_else(|| Status::not_found(format!("No preset named {}", name)))?;
+        self.send(PlayerCommand::ApplyProfile(profile.clone()))
+    }
+
+    type StreamMetersStream = MeterStream;
+
+    async fn stream_meters(
+        &self,
+        _: Request<proto::Empty>,
+    ) -> Result<Response<MeterStream>, Status> {
+        // A client that lagged behind just misses the readings it couldn't keep up with.
+        let readings = BroadcastStream::new(self.meters.subscribe())
+            .filter_map(|reading| reading.ok().map(Ok));
+        Ok(Response::new(Box::pin(readings)))
+    }
+}
+
+/// Serves the `SoundAmp` service from `proto/sound_amp.proto` on `address`.
+pub fn serve(
+    address: &str,
+    profiles: Vec<Profile>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    player_channel: Sender<PlayerCommand>,
+) -> Result<(), GrpcError> {
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", address))?;
+    // Bound here so a taken port is reported right away rather than from the server thread.
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let (readings, _) = broadcast::channel(METER_BACKLOG);
+    spawn_meter_reader(meters, readings.clone());
+    let service = Service {
+        profiles,
+        status,
+        meters: readings,
+        player_channel,
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    thread::spawn(move || {
+        let served = runtime.block_on(async {
+            let listener = tokio::net::TcpListener::from_std(listener)?;
+            tonic::transport::Server::builder()
+                .add_service(SoundAmpServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener))
+                .await
+                .map_err(GrpcError::from)
+        });
+        if let Err(e) = served {
+            eprintln!("gRPC server stopped: {}", e);
+        }
+    });
+    Ok(())
+}
+
+fn engine_state(state: EngineState) -> proto::EngineState {
+    match state {
+        EngineState::Idle => proto::EngineState::Idle,
+        EngineState::Linking => proto::EngineState::Linking,
+        EngineState::Running => proto::EngineState::Running,
+        EngineState::Error => proto::EngineState::Error,
+        EngineState::Stopping => proto::EngineState::Stopping,
+    }
+}
+
+fn spawn_meter_reader(meters: Arc<Meters>, readings: broadcast::Sender<proto::Meters>) {
+    thread::spawn(move || loop {
+        thread::sleep(METER_INTERVAL);
+        let level = |level: meter::Level| proto::Level {
+            peak: level.peak,
+            rms: level.rms,
+        };
+        // Nobody listening yet isn't an error; the reading is just dropped.
+        let _ = readings.send(proto::Meters {
+            input: Some(level(meters.input.take_grpc())),
+            output: Some(level(meters.output.take_grpc())),
+            xruns: meters.xruns.load(Ordering::Relaxed),
+            voice: meters.voice(),
+            spl: meters.spl(),
+            loud: meters.loud(),
+            pitch: meters.pitch(),
+        });
+    });
+}
@@ -0,0 +1,606 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use serde::Deserialize;
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+use sound_amp_core::config::Profile;
+use sound_amp_core::effects::VoicePreset;
+use sound_amp_core::looper::LooperAction;
+use sound_amp_core::meter::{CallbackTimer, Meters};
+use sound_amp_core::spatial::Position;
+use sound_amp_core::{find_input_device, InputSource, LinkStatus, PlayerCommand};
+
+pub mod ws;
+
+pub type HttpError = Box<dyn std::error::Error + Send + Sync>;
+
+type JsonResponse = Response<std::io::Cursor<Vec<u8>>>;
+
+/// The web UI, a single page driving the endpoints below.
+const INDEX_HTML: &str = include_str!("http/index.html");
+
+/// An input device picked by its position in `/devices` or by name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeviceRef {
+    Index(usize),
+    Name(String),
+}
+
+#[derive(Deserialize)]
+struct StartRequest {
+    device: DeviceRef,
+}
+
+#[derive(Deserialize)]
+struct VolumeRequest {
+    volume: f32,
+}
+
+#[derive(Deserialize)]
+struct TrimRequest {
+    trim: f32,
+}
+
+#[derive(Deserialize)]
+struct LinkChannelsRequest {
+    linked: bool,
+}
+
+#[derive(Deserialize)]
+struct SpeechBoostRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct KaraokeRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct VoiceRequest {
+    voice: Option<VoicePreset>,
+}
+
+#[derive(Deserialize)]
+struct MetronomeRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct TempoRequest {
+    bpm: f32,
+}
+
+#[derive(Deserialize)]
+struct TalkRequest {
+    talking: bool,
+}
+
+#[derive(Deserialize)]
+struct LowLatencyRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct BypassRequest {
+    on: bool,
+}
+
+#[derive(Deserialize)]
+struct SidechainRequest {
+    link: usize,
+    key: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct PositionRequest {
+    position: Option<Position>,
+}
+
+#[derive(Deserialize)]
+struct PanRequest {
+    pan: f32,
+}
+
+#[derive(Deserialize)]
+struct SleepRequest {
+    minutes: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct LooperRequest {
+    action: LooperAction,
+}
+
+/// A control request, as a WebSocket message or built from a REST call.
+#[derive(Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+enum Control {
+    Start { device: DeviceRef },
+    Stop,
+    SetVolume { volume: f32 },
+    SetTrim { trim: f32 },
+    ApplyPreset { name: String },
+    LinkChannels { linked: bool },
+    SetSpeechBoost { on: bool },
+    SetKaraoke { on: bool },
+    SetVoice { voice: Option<VoicePreset> },
+    SetMetronome { on: bool },
+    SetTempo { bpm: f32 },
+    SetTalking { talking: bool },
+    Looper { action: LooperAction },
+    SetLowLatency { on: bool },
+    SetBypassed { on: bool },
+    Sidechain { link: usize, key: Option<usize> },
+    SetPosition { position: Option<Position> },
+    SetPan { pan: f32 },
+    Sleep { minutes: Option<u64> },
+    CalibrateGain,
+    MeasureLatency,
+}
+
+impl Control {
+    /// The player command for this request, or why there isn't one.
+    fn into_command(self, profiles: &[Profile]) -> Result<PlayerCommand, String> {
+        match self {
+            Control::Start {
+                device: DeviceRef::Index(i),
+            } => Ok(PlayerCommand::Start(InputSource::Device(i))),
+            Control::Start {
+                device: DeviceRef::Name(name),
+            } => find_input_device(&name)
+                .map(|i| PlayerCommand::Start(InputSource::Device(i)))
+                .ok_or_else(|| format!("No input device named {}", name)),
+            Control::Stop => Ok(PlayerCommand::Stop),
+            Control::SetVolume { volume } => Ok(PlayerCommand::SetVolume(volume)),
+            Control::SetTrim { trim } => Ok(PlayerCommand::SetTrim(trim)),
+            Control::ApplyPreset { name } => profiles
+                .iter()
+                .find(|p| p.name == name)
+                .map(|profile| PlayerCommand::ApplyProfile(profile.clone()))
+                .ok_or_else(|| format!("No preset named {}", name)),
+            Control::LinkChannels { linked } => Ok(PlayerCommand::LinkChannels(linked)),
+            Control::SetSpeechBoost { on } => Ok(PlayerCommand::SetSpeechBoost(on)),
+            Control::SetKaraoke { on } => Ok(PlayerCommand::SetKaraoke(on)),
+            Control::SetVoice { voice } => Ok(PlayerCommand::SetVoice(voice)),
+            Control::SetMetronome { on } => Ok(PlayerCommand::SetMetronome(on)),
+            Control::SetTempo { bpm } => Ok(PlayerCommand::SetTempo(bpm)),
+            Control::SetTalking { talking } => Ok(PlayerCommand::SetTalking(talking)),
+            Control::Looper { action } => Ok(PlayerCommand::Looper(action)),
+            Control::SetLowLatency { on } => Ok(PlayerCommand::SetLowLatency(on)),
+            Control::SetBypassed { on } => Ok(PlayerCommand::SetBypassed(on)),
+            Control::Sidechain { link, key } => Ok(PlayerCommand::DuckLink { link, key }),
+            Control::SetPosition { position } => Ok(PlayerCommand::SetPosition(position)),
+            Control::SetPan { pan } => Ok(PlayerCommand::SetPan(pan)),
+            Control::CalibrateGain => Ok(PlayerCommand::CalibrateGain),
+            Control::MeasureLatency => Ok(PlayerCommand::MeasureLatency),
+            Control::Sleep { minutes } => Ok(PlayerCommand::SleepAfter(
+                minutes.map(|minutes| Duration::from_secs(minutes * 60)),
+            )),
+        }
+    }
+}
+
+/// Serves the control API on `address`:
+///
+/// - `GET /`: the web UI
+/// - `GET /status`: the running link, volume, preset and recording state
+/// - `GET /devices`: input and output device names
+/// - `POST /start` with `{"device": <index or name>}`: links that input device
+/// - `POST /stop`: tears the link down
+/// - `GET /volume`, `PUT /volume` with `{"volume": <gain>}`
+/// - `GET /trim`, `PUT /trim` with `{"trim": <gain>}`: the gain ahead of the effects, where the
+///   volume comes after them
+/// - `GET /presets`, `POST /presets/<name>`: lists or applies the configured profiles
+/// - `PUT /channels` with `{"linked": <bool>}`: runs the left and right channels through one chain
+///   or one each
+/// - `PUT /speech-boost` with `{"on": <bool>}`: adds the speech boost after the preset's effects
+/// - `PUT /karaoke` with `{"on": <bool>}`: takes the lead vocal out of a stereo input
+/// - `PUT /voice` with `{"voice": "deep" | "chipmunk" | "robot" | null}`: runs the input through
+///   one of the voice changer's voices, or none
+/// - `PUT /metronome` with `{"on": <bool>}`, `PUT /tempo` with `{"bpm": <tempo>}`: starts or
+///   stops the click track in the output, or changes its tempo
+/// - `PUT /talk` with `{"talking": <bool>}`: opens or closes the push-to-talk gate, for a talk
+///   button or a global hotkey outside the terminal
+/// - `POST /looper` with `{"action": "record" | "clear"}`: records a loop, then plays it, then
+///   overdubs and plays in turn; or clears it
+/// - `PUT /low-latency` with `{"on": <bool>}`: runs the devices with the smallest buffers that keep
+///   up and leaves out the heavy effects; `/status` has the round trip it gets
+/// - `PUT /bypass` with `{"on": <bool>}`: leaves the selected link's effects out, lined up in time
+///   with them, to compare it with and without them
+/// - `PUT /sidechain` with `{"link": <index>, "key": <index or null>}`: ducks that link of
+///   `/status`'s `links` whenever the `key` one's input is loud, or stops ducking it
+/// - `PUT /position` with `{"position": {"azimuth": <degrees>, "elevation": <degrees>} | null}`:
+///   places the selected link's mono input around the listener in headphones, or in the middle
+/// - `PUT /pan` with `{"pan": <-1 to 1>}`: pans the selected link's mono input between the
+///   speakers
+/// - `POST /calibrate`: listens to the input for ten seconds while the user speaks normally, then
+///   sets the trim, gate and compressor for it and saves them as the input device's profile;
+///   `/status` has the seconds left
+/// - `POST /latency`: plays a chirp out of the link in place of its input and times how long it
+///   takes to come back in; `/status` has the measured round trip once it's heard
+/// - `PUT /sleep` with `{"minutes": <minutes or null>}`: fades out and stops the links that much
+///   later, or calls the sleep timer off; `/status` has the seconds left
+/// - `GET /levels`: input and output peak and RMS in dBFS since the last time anyone read them,
+///   and the output's true peak in dBTP, its momentary, short-term and integrated loudness in LUFS,
+///   the correlation between its first two channels from -1 to +1, whether there's speech in the input, its sound level in dB SPL, weighted as configured, and
+///   whether the output has been loud for too long, and the pitch the tuner hears in Hz
+/// - `GET /levels/history`: the output's peak and RMS in dBFS for each of the last 60 seconds,
+///   oldest first
+/// - `GET /stats`: clipped samples in the input and the output mix, safety limiter engagements and
+///   gate closings since the player started, with the share of the signal each took up, and the
+///   output's loudest true peak; `DELETE /stats` starts them over, and the integrated loudness
+/// - `GET /metrics`: Prometheus metrics
+pub fn serve(
+    address: &str,
+    profiles: Vec<Profile>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    player_channel: Sender<PlayerCommand>,
+) -> Result<(), HttpError> {
+    let server = Server::http(address)?;
+    thread::spawn(move || {
+        for mut request in server.incoming_requests() {
+            let response = handle(&mut request, &profiles, &status, &meters, &player_channel);
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+fn handle(
+    request: &mut Request,
+    profiles: &[Profile],
+    status: &Mutex<LinkStatus>,
+    meters: &Meters,
+    player_channel: &Sender<PlayerCommand>,
+) -> JsonResponse {
+    let path = request
+        .url()
+        .split('?')
+        .next()
+        .unwrap_or("")
+        .trim_end_matches('/')
+        .to_string();
+    let control = match (request.method(), path.as_str()) {
+        (Method::Get, "") => {
+            return Response::from_string(INDEX_HTML).with_header(
+                Header::from_bytes("Content-Type", "text/html; charset=utf-8").unwrap(),
+            );
+        }
+        (Method::Get, "/levels") => {
+            return ok(json!({
+                "input": meters.input.take(),
+                "output": meters.output.take(),
+                "voice": meters.voice(),
+                "spl": meters.spl(),
+                "loud": meters.loud(),
+                "pitch": meters.pitch(),
+                "true_peak": meters.take_true_peak(),
+                "loudness": meters.loudness(),
+                "correlation": meters.correlation(),
+            }));
+        }
+        (Method::Get, "/levels/history") => return ok(json!(meters.history.levels())),
+        (Method::Get, "/status") => return ok(json!(*status.lock().unwrap())),
+        (Method::Get, "/stats") => return ok(json!(meters.stats.read())),
+        (Method::Delete, "/stats") => {
+            meters.clear_stats();
+            return ok(json!(meters.stats.read()));
+        }
+        (Method::Get, "/devices") => return devices(),
+        (Method::Get, "/metrics") => return metrics(meters, &status.lock().unwrap()),
+        (Method::Get, "/volume") => return ok(json!({ "volume": status.lock().unwrap().volume })),
+        (Method::Get, "/trim") => return ok(json!({ "trim": status.lock().unwrap().trim })),
+        (Method::Get, "/presets") => {
+            return ok(json!(profiles.iter().map(|p| &p.name).collect::<Vec<_>>()));
+        }
+        (Method::Put, "/volume") => match read_json::<VolumeRequest>(request) {
+            Ok(body) => Control::SetVolume {
+                volume: body.volume,
+            },
+            Err(response) => return response,
+        },
+        (Method::Put, "/trim") => match read_json::<TrimRequest>(request) {
+            Ok(body) => Control::SetTrim { trim: body.trim },
+            Err(response) => return response,
+        },
+        (Method::Put, "/channels") => match read_json::<LinkChannelsRequest>(request) {
+            Ok(body) => Control::LinkChannels {
+                linked: body.linked,
+            },
+            Err(response) => return response,
+        },
+        (Method::Put, "/speech-boost") => match read_json::<SpeechBoostRequest>(request) {
+            Ok(body) => Control::SetSpeechBoost { on: body.on },
+            Err(response) => return response,
+        },
+        (Method::Put, "/karaoke") => match read_json::<KaraokeRequest>(request) {
+            Ok(body) => Control::SetKaraoke { on: body.on },
+            Err(response) => return response,
+        },
+        (Method::Put, "/voice") => match read_json::<VoiceRequest>(request) {
+            Ok(body) => Control::SetVoice { voice: body.voice },
+            Err(response) => return response,
+        },
+        (Method::Put, "/metronome") => match read_json::<MetronomeRequest>(request) {
+            Ok(body) => Control::SetMetronome { on: body.on },
+            Err(response) => return response,
+        },
+        (Method::Put, "/tempo") => match read_json::<TempoRequest>(request) {
+            Ok(body) => Control::SetTempo { bpm: body.bpm },
+            Err(response) => return response,
+        },
+        (Method::Put, "/talk") => match read_json::<TalkRequest>(request) {
+            Ok(body) => Control::SetTalking {
+                talking: body.talking,
+            },
+            Err(response) => return response,
+        },
+        (Method::Put, "/low-latency") => match read_json::<LowLatencyRequest>(request) {
+            Ok(body) => Control::SetLowLatency { on: body.on },
+            Err(response) => return response,
+        },
+        (Method::Put, "/bypass") => match read_json::<BypassRequest>(request) {
+            Ok(body) => Control::SetBypassed { on: body.on },
+            Err(response) => return response,
+        },
+        (Method::Put, "/sidechain") => match read_json::<SidechainRequest>(request) {
+            Ok(body) => Control::Sidechain {
+                link: body.link,
+                key: body.key,
+            },
+            Err(response) => return response,
+        },
+        (Method::Put, "/position") => match read_json::<PositionRequest>(request) {
+            Ok(body) => Control::SetPosition {
+                position: body.position,
+            },
+            Err(response) => return response,
+        },
+        (Method::Put, "/pan") => match read_json::<PanRequest>(request) {
+            Ok(body) => Control::SetPan { pan: body.pan },
+            Err(response) => return response,
+        },
+        (Method::Put, "/sleep") => match read_json::<SleepRequest>(request) {
+            Ok(body) => Control::Sleep {
+                minutes: body.minutes,
+            },
+            Err(response) => return response,
+        },
+        (Method::Post, "/start") => match read_json::<StartRequest>(request) {
+            Ok(body) => Control::Start {
+                device: body.device,
+            },
+            Err(response) => return response,
+        },
+        (Method::Post, "/stop") => Control::Stop,
+        (Method::Post, "/calibrate") => Control::CalibrateGain,
+        (Method::Post, "/latency") => Control::MeasureLatency,
+        (Method::Post, "/looper") => match read_json::<LooperRequest>(request) {
+            Ok(body) => Control::Looper {
+                action: body.action,
+            },
+            Err(response) => return response,
+        },
+        (Method::Post, path) if path.starts_with("/presets/") => Control::ApplyPreset {
+            name: percent_decode(&path["/presets/".len()..]),
+        },
+        _ => return error(404, "Not found"),
+    };
+    let command = match control.into_command(profiles) {
+        Ok(command) => command,
+        Err(message) => return error(404, &message),
+    };
+    if player_channel.send(command).is_err() {
+        return error(503, "The player has stopped");
+    }
+    Response::from_string("").with_status_code(202)
+}
+
+fn devices() -> JsonResponse {
+    let host = cpal::default_host();
+    let names = |devices: Result<Vec<cpal::Device>, _>| -> Vec<String> {
+        devices
+            .map(|d| d.iter().map(|d| d.name().unwrap_or_default()).collect())
+            .unwrap_or_default()
+    };
+    ok(json!({
+        "inputs": names(host.input_devices().map(|d| d.collect())),
+        "outputs": names(host.output_devices().map(|d| d.collect())),
+    }))
+}
+
+/// Renders the meters in the Prometheus text format. Peaks are since the previous scrape.
+fn metrics(meters: &Meters, status: &LinkStatus) -> JsonResponse {
+    let mut text = String::new();
+    // Samples are `(suffix and labels, value)`.
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, f64)]| {
+        let _ = writeln!(text, "# HELP sound_amp_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE sound_amp_{} {}", name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(text, "sound_amp_{}{} {}", name, labels, value);
+        }
+    };
+    metric(
+        "links_active",
+        "gauge",
+        "Running input to output links.",
+        &[("", status.input.is_some() as u8 as f64)],
+    );
+    metric(
+        "buffer_fill_ratio",
+        "gauge",
+        "How full the ring between the input and the output is, from 0 to 1.",
+        &[("", meters.buffer_fill() as f64)],
+    );
+    metric(
+        "xruns_total",
+        "counter",
+        "Input blocks dropped and output blocks left unfilled.",
+        &[("", meters.xruns.load(Ordering::Relaxed) as f64)],
+    );
+    let count = |timer: &CallbackTimer| timer.count.load(Ordering::Relaxed) as f64;
+    metric(
+        "callback_duration_seconds",
+        "summary",
+        "Time spent in the audio callbacks.",
+        &[
+            ("_sum{direction=\"input\"}", meters.input_callbacks.total().as_secs_f64()),
+            ("_count{direction=\"input\"}", count(&meters.input_callbacks)),
+            ("_sum{direction=\"output\"}", meters.output_callbacks.total().as_secs_f64()),
+            ("_count{direction=\"output\"}", count(&meters.output_callbacks)),
+        ],
+    );
+    metric(
+        "peak_dbfs",
+        "gauge",
+        "Peak level since the previous scrape.",
+        &[
+            ("{signal=\"input\"}", meters.input.take_metrics_peak() as f64),
+            ("{signal=\"output\"}", meters.output.take_metrics_peak() as f64),
+        ],
+    );
+    let loudness = meters.loudness();
+    let windows = [
+        ("{window=\"momentary\"}", loudness.momentary),
+        ("{window=\"short_term\"}", loudness.short_term),
+        ("{window=\"integrated\"}", loudness.integrated),
+    ];
+    let readings: Vec<_> = windows
+        .into_iter()
+        .filter_map(|(labels, lufs)| Some((labels, lufs? as f64)))
+        .collect();
+    if !readings.is_empty() {
+        metric("loudness_lufs", "gauge", "Loudness of the output by EBU R 128.", &readings);
+    }
+    if let Some(correlation) = meters.correlation() {
+        metric(
+            "output_correlation",
+            "gauge",
+            "How alike the output's first two channels are, from -1 to +1.",
+            &[("", correlation as f64)],
+        );
+    }
+    let stats = meters.stats.read();
+    metric(
+        "clipped_samples_total",
+        "counter",
+        "Samples at full scale in the raw input, and over it in the output mix before the limiter.",
+        &[
+            ("{signal=\"input\"}", stats.input_clipped as f64),
+            ("{signal=\"output\"}", stats.output_clipped as f64),
+        ],
+    );
+    metric(
+        "limiter_engagements_total",
+        "counter",
+        "Times the safety limiter started turning the output down.",
+        &[("", stats.limiter_engagements as f64)],
+    );
+    metric(
+        "gate_closings_total",
+        "counter",
+        "Times a gate in the chain closed.",
+        &[("", stats.gate_closings as f64)],
+    );
+    metric(
+        "max_true_peak_dbtp",
+        "gauge",
+        "Loudest true peak of the output since the player started.",
+        &[("", stats.max_true_peak_db as f64)],
+    );
+    if let Some(ms) = status.round_trip_ms {
+        metric(
+            "round_trip_seconds",
+            "gauge",
+            "How long the input takes to come out of the default output.",
+            &[("", ms as f64 / 1000.0)],
+        );
+    }
+    if let Some(ms) = status.measured_round_trip_ms {
+        metric(
+            "measured_round_trip_seconds",
+            "gauge",
+            "How long the last round-trip test took to hear its chirp come back.",
+            &[("", ms as f64 / 1000.0)],
+        );
+    }
+    metric("volume", "gauge", "Master gain.", &[("", status.volume as f64)]);
+    metric(
+        "voice_detected",
+        "gauge",
+        "Whether there's speech in the input.",
+        &[("", meters.voice() as u8 as f64)],
+    );
+    metric(
+        "loudness_alert",
+        "gauge",
+        "Whether the output has been over the alert level for too long.",
+        &[("", meters.loud() as u8 as f64)],
+    );
+    if let Some(spl) = meters.spl() {
+        metric(
+            "input_spl_db",
+            "gauge",
+            "Sound level of the input in dB SPL, weighted as configured.",
+            &[("", spl as f64)],
+        );
+    }
+    Response::from_string(text).with_header(
+        Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+    )
+}
+
+/// Undoes the `%XX` escapes in a URL path segment, so presets can have spaces in their names.
+fn percent_decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| segment.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn read_json<T: for<'de> Deserialize<'de>>(request: &mut Request) -> Result<T, JsonResponse> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .read_to_string(&mut body)
+        .map_err(|e| error(400, &e.to_string()))?;
+    serde_json::from_str(&body).map_err(|e| error(400, &e.to_string()))
+}
+
+fn ok(body: serde_json::Value) -> JsonResponse {
+    Response::from_string(body.to_string()).with_header(json_header())
+}
+
+fn error(code: u16, message: &str) -> JsonResponse {
+    ok(json!({ "error": message })).with_status_code(code)
+}
+
+fn json_header() -> Header {
+    Header::from_bytes("Content-Type", "application/json").unwrap()
+}
@@ -0,0 +1,140 @@
+use std::io::ErrorKind;
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::json;
+use tungstenite::Message;
+
+use super::{Control, HttpError};
+use sound_amp_core::config::Profile;
+use sound_amp_core::meter::Meters;
+use sound_amp_core::{LinkStatus, PlayerCommand};
+
+const METER_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a client waits for a message before sending out what's queued for it.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Event queues of the connected clients.
+type Clients = Arc<Mutex<Vec<Sender<String>>>>;
+
+/// Serves the WebSocket API on `address`. Clients receive
+///
+/// - `{"type": "levels", "input": {"peak", "rms"}, "output": {"peak", "rms"}, "voice", "spl", "loud",
+///   "pitch"}` every 50 ms, levels in dBFS, `voice` true while there's speech in the input, `spl`
+///   its sound level in dB SPL, `loud` true while the output has been loud for too long and
+///   `pitch` what the tuner hears, in Hz
+/// - `{"type": "xrun", "count": <total>}` whenever the input or output drops samples
+/// - `{"type": "status", ...}` with the fields of `GET /status` on connecting and on every change
+///
+/// and can send `{"command": "start", "device": <index or name>}`, `{"command": "stop"}`,
+/// `{"command": "set_volume", "volume": <gain>}` or `{"command": "apply_preset", "name": <name>}`.
+/// Commands that can't be carried out are answered with `{"type": "error", "message"}`.
+pub fn serve(
+    address: &str,
+    profiles: Vec<Profile>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    player_channel: Sender<PlayerCommand>,
+) -> Result<(), HttpError> {
+    let listener = TcpListener::bind(address)?;
+    let clients: Clients = Default::default();
+    spawn_broadcaster(Arc::clone(&status), meters, Arc::clone(&clients));
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (events, queue) = mpsc::channel();
+            // Catch the client up; the broadcaster only sends changes.
+            let _ = events.send(status_event(&status.lock().unwrap()));
+            clients.lock().unwrap().push(events);
+            let profiles = profiles.clone();
+            let player_channel = player_channel.clone();
+            thread::spawn(move || {
+                if let Err(e) = run_client(stream, queue, &profiles, &player_channel) {
+                    eprintln!("WebSocket client dropped: {}", e);
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn spawn_broadcaster(status: Arc<Mutex<LinkStatus>>, meters: Arc<Meters>, clients: Clients) {
+    thread::spawn(move || {
+        let mut last_status = status.lock().unwrap().clone();
+        let mut last_xruns = meters.xruns.load(Ordering::Relaxed);
+        loop {
+            thread::sleep(METER_INTERVAL);
+            let mut events = vec![json!({
+                "type": "levels",
+                "input": meters.input.take_websocket(),
+                "output": meters.output.take_websocket(),
+                "voice": meters.voice(),
+                "spl": meters.spl(),
+                "loud": meters.loud(),
+                "pitch": meters.pitch(),
+            })
+            .to_string()];
+            let xruns = meters.xruns.load(Ordering::Relaxed);
+            if xruns != last_xruns {
+                events.push(json!({ "type": "xrun", "count": xruns }).to_string());
+                last_xruns = xruns;
+            }
+            let current = status.lock().unwrap().clone();
+            if current != last_status {
+                events.push(status_event(&current));
+                last_status = current;
+            }
+            // Clients that went away have dropped their queue.
+            clients.lock().unwrap().retain(|client| {
+                events
+                    .iter()
+                    .all(|event| client.send(event.clone()).is_ok())
+            });
+        }
+    });
+}
+
+fn status_event(status: &LinkStatus) -> String {
+    let mut event = json!(status);
+    event["type"] = json!("status");
+    event.to_string()
+}
+
+fn run_client(
+    stream: TcpStream,
+    queue: Receiver<String>,
+    profiles: &[Profile],
+    player_channel: &Sender<PlayerCommand>,
+) -> Result<(), HttpError> {
+    let mut socket = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+    socket.get_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+    loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => {
+                let sent = serde_json::from_str::<Control>(&text)
+                    .map_err(|e| e.to_string())
+                    .and_then(|control| control.into_command(profiles))
+                    .and_then(|command| {
+                        player_channel
+                            .send(command)
+                            .map_err(|_| "The player has stopped".to_string())
+                    });
+                if let Err(message) = sent {
+                    let reply = json!({ "type": "error", "message": message });
+                    socket.send(Message::text(reply.to_string()))?;
+                }
+            }
+            Ok(Message::Close(_)) | Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+        while let Ok(event) = queue.try_recv() {
+            socket.send(Message::text(event))?;
+        }
+    }
+}
@@ -1,40 +1,345 @@
-extern crate ringbuf;
-
-use std::sync::{mpsc, Arc, Mutex};
-use std::{error, io, thread};
-use std::io::Stdout;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{error, fs, io};
+use std::f32::consts::SQRT_2;
+use std::io::Write;
+use std::ops::Range;
 use std::sync::mpsc::{Sender};
+use std::time::{Duration, Instant, SystemTime};
 
 
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, InputCallbackInfo, OutputCallbackInfo};
+use clap::{Parser, Subcommand, ValueEnum};
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::Device;
 use crossterm::event::{self, Event, KeyCode, KeyEvent};
-use ringbuf::RingBuffer;
 use tui::widgets::ListState;
-use tui::{backend::CrosstermBackend, layout::{Constraint, Direction, Layout}, style::{Color, Modifier, Style}, widgets::{List, ListItem}, Terminal, Frame};
+use tui::{backend::CrosstermBackend, layout::{Constraint, Direction, Layout, Rect}, style::{Color, Modifier, Style}, widgets::{Axis, Block, Borders, Chart, Dataset, GraphType, List, ListItem, Paragraph, Tabs}, symbols::Marker, text::{Span, Spans}, Terminal, Frame};
+
+#[cfg(feature = "network")]
+use sound_amp_core::airplay::AirplayConfig;
+#[cfg(windows)]
+use sound_amp_core::app_capture::AppCapture;
+use sound_amp_core::backend::{AudioBackend, CpalBackend};
+use sound_amp_core::config::{Config, Profile, RecordingConfig};
+#[cfg(feature = "network")]
+use sound_amp_core::discovery::{Discovery, Peers, Transport};
+use sound_amp_core::dose::DoseTracker;
+#[cfg(feature = "network")]
+use sound_amp_core::icecast::IcecastConfig;
+use sound_amp_core::correlation::OUT_OF_PHASE;
+use sound_amp_core::spectrogram::{self, SpectrogramTap, SpectrumAnalysis, Window};
+use sound_amp_core::meter::{Level, Meters, HISTORY_SECONDS};
+use sound_amp_core::looper::{LooperAction, LooperConfig, LooperState};
+use sound_amp_core::metronome::MetronomeConfig;
+#[cfg(feature = "midi")]
+use sound_amp_core::midi::{MidiAction, SharedMidi};
+#[cfg(feature = "network")]
+use sound_amp_core::net::NetworkCodec;
+use sound_amp_core::pipe::{InputPipe, OutputPipe, PcmFormat};
+use sound_amp_core::playback::{LoopEdit, PlaybackState, PlayerConfig};
+use sound_amp_core::recorder::{EncoderSettings, RecordingFormat, TapPoint};
+#[cfg(feature = "network")]
+use sound_amp_core::rtp::RtpInput;
+use sound_amp_core::session::{ResumeMode, Session};
+#[cfg(feature = "network")]
+use sound_amp_core::snapcast::SnapcastConfig;
+use sound_amp_core::soundboard::{Hotkey, SoundboardConfig};
+use sound_amp_core::spatial::Position;
+use sound_amp_core::spl::Weighting;
+use sound_amp_core::talk::{PushToTalkConfig, VoxConfig};
+#[cfg(feature = "transcribe")]
+use sound_amp_core::transcribe::{Captions, TranscriptionConfig};
+use sound_amp_core::tuner::{Note, TunerConfig};
+use sound_amp_core::virtual_devices::VirtualDevices;
+use sound_amp_core::workers::WorkerPool;
+use sound_amp_core::{config, offline, playback, safety, schedule};
+#[cfg(feature = "midi")]
+use sound_amp_core::midi;
+#[cfg(feature = "network")]
+use sound_amp_core::net;
+#[cfg(feature = "osc")]
+use sound_amp_core::osc;
+use sound_amp_core::{setup_stream, InputSource, LinkSettings, LinkStatus, LiveSettings, PlayerCommand};
+#[cfg(feature = "network")]
+use sound_amp_core::StreamSettings;
 
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "grpc")]
+mod grpc;
 mod stateful_list;
 
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Path to the config file.
+    #[arg(long, global = true, default_value = config::DEFAULT_CONFIG_PATH)]
+    config: PathBuf,
+    /// Start recording the processed signal as soon as a link is running.
+    #[arg(long)]
+    record: bool,
+    /// Directory for recordings, overriding the config.
+    #[arg(long, value_name = "DIR")]
+    record_dir: Option<PathBuf>,
+    /// Recording file format, overriding the config.
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    record_format: Option<RecordingFormat>,
+    /// Bitrate in kbps for lossy recording formats, overriding the config.
+    #[arg(long, value_name = "KBPS")]
+    record_bitrate: Option<u32>,
+    /// Record the raw input, the processed signal or both, overriding the config.
+    #[arg(long, value_enum, value_name = "POINT")]
+    record_tap: Option<TapPoint>,
+    /// Write the processed signal to stdout as raw PCM; the UI moves to stderr.
+    #[arg(long)]
+    output_pipe: bool,
+    /// Read raw PCM from stdin as the input instead of a device.
+    #[arg(long)]
+    input_pipe: bool,
+    /// Sample rate of the PCM on stdin; it's resampled to the output device's.
+    #[arg(long, value_name = "HZ", default_value_t = 48000, requires = "input_pipe")]
+    input_rate: u32,
+    /// Channel count of the PCM on stdin.
+    #[arg(long, value_name = "N", default_value_t = 2, requires = "input_pipe")]
+    input_channels: u16,
+    /// Stream the processed signal to an instance running with --listen.
+    #[arg(long, value_name = "HOST:PORT")]
+    send: Option<String>,
+    /// Codec for --send, overriding the config.
+    #[cfg(feature = "network")]
+    #[arg(long, value_enum, value_name = "CODEC", requires = "send")]
+    send_codec: Option<NetworkCodec>,
+    /// Accept a stream from --send and use it as the input.
+    #[arg(long, value_name = "PORT")]
+    listen: Option<u16>,
+    /// Stream the processed signal over RTP, in the format set under [rtp].
+    #[arg(long, value_name = "HOST:PORT")]
+    rtp_send: Option<String>,
+    /// Receive an RTP stream on this UDP port and use it as the input.
+    #[arg(long, value_name = "PORT")]
+    rtp_listen: Option<u16>,
+    /// Capture one application's audio as the input, by process ID or executable name (Windows
+    /// only); the processes it started are captured along with it.
+    #[arg(long, value_name = "PID|NAME", conflicts_with = "input_pipe")]
+    capture_app: Option<String>,
+    /// Sample format for --output-pipe and --input-pipe.
+    #[arg(long, value_enum, value_name = "FORMAT", default_value = "f32")]
+    pipe_format: PcmFormat,
+    /// Only write to the pipe, without a hardware output.
+    #[arg(long, requires = "output_pipe")]
+    pipe_only: bool,
+    /// Shape the dither of s16 on the output pipe towards the top of the band.
+    #[arg(long, requires = "output_pipe")]
+    pipe_noise_shaping: bool,
+    /// Serve the HTTP control API and the web UI on this address.
+    #[arg(long, value_name = "HOST:PORT")]
+    http: Option<String>,
+    /// Serve the WebSocket API, with live levels and events, on this address.
+    #[arg(long, value_name = "HOST:PORT")]
+    ws: Option<String>,
+    /// Serve the gRPC API from proto/sound_amp.proto on this address.
+    #[arg(long, value_name = "HOST:PORT")]
+    grpc: Option<String>,
+    /// Take OSC control messages on this UDP port.
+    #[arg(long, value_name = "PORT")]
+    osc: Option<u16>,
+    /// Broadcast the processed signal to this Icecast mountpoint, overriding the config.
+    #[arg(long, value_name = "URL")]
+    icecast: Option<String>,
+    /// Feed the processed signal to a Snapcast source, a FIFO path or tcp://HOST:PORT, overriding the config.
+    #[arg(long, value_name = "TARGET")]
+    snapcast: Option<String>,
+    /// Stream the processed signal to this AirPlay speaker, as HOST or HOST:PORT, overriding the config.
+    /// Only unencrypted AirPlay 1 without a password is sent, which receivers like shairport-sync take.
+    #[arg(long, value_name = "HOST")]
+    airplay: Option<String>,
+    /// Create the virtual sink and source for other applications, as if enabled in the config.
+    #[arg(long)]
+    virtual_devices: bool,
+    /// What to do with the link a crashed or killed run left behind, overriding the config.
+    #[arg(long, value_enum, value_name = "MODE")]
+    resume: Option<ResumeMode>,
+    /// Play a tone to measure at the ear, and work out the `[safety]` calibration from the reading.
+    #[arg(long)]
+    calibrate: bool,
+    /// Show captions of the processed signal, transcribed by this whisper.cpp server, overriding the config.
+    #[arg(long, value_name = "HOST:PORT")]
+    transcribe: Option<String>,
+    /// Only let the input through while the talk key is held, whatever the config says.
+    #[arg(long)]
+    push_to_talk: bool,
+    /// Only let the input through while it's loud enough, whatever the config says.
+    #[arg(long)]
+    vox: bool,
+    /// Show the tuner from the start, whatever the config says.
+    #[arg(long)]
+    tuner: bool,
+    /// Click along at this tempo, in beats per minute, overriding the config.
+    #[arg(long, value_name = "BPM")]
+    metronome: Option<f32>,
+    /// Fade out and stop after this many minutes, for listening at night.
+    #[arg(long, value_name = "MINUTES")]
+    sleep: Option<u64>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a file through a profile's effects, as fast as they go, instead of linking devices.
+    Process {
+        /// The file to process, in any format that can be played.
+        #[arg(long = "in", value_name = "FILE")]
+        input: PathBuf,
+        /// Where to write the result, in the recording format its extension names.
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+        /// Name of the profile whose effects, trim and volume to apply.
+        #[arg(long, value_name = "PROFILE")]
+        preset: String,
+    },
+}
+
 pub struct StatefulList<T> {
     pub state: ListState,
     pub items: Vec<T>,
 }
 
+const SEEK_STEP_SECONDS: f64 = 5.0;
+/// How long to wait for a key before redrawing anyway, about 30 frames a second.
+const FRAME_INTERVAL: Duration = Duration::from_millis(33);
+/// How often the config file is checked for changes to apply while running.
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How close to a note the tuner shows it as in tune.
+const IN_TUNE_CENTS: f32 = 5.0;
+/// How far ',' and '.' move the metronome's tempo.
+const TEMPO_STEP: f32 = 5.0;
+/// The most spectra 'a' has the spectrogram average, doubling up to it and then back to none.
+const MAX_AVERAGING: u32 = 16;
+/// How far '<' and '>' turn the selected link around the listener, in degrees.
+const POSITION_STEP: f32 = 15.0;
+/// How far '{' and '}' move the selected link's trim, in dB.
+const TRIM_STEP_DB: f32 = 1.0;
+/// How far '(' and ')' pan the selected link, of the way from the center to one side.
+const PAN_STEP: f32 = 0.1;
+/// The sleep timers 'S' steps through, in minutes, before turning it off again.
+const SLEEP_MINUTES: [u64; 5] = [15, 30, 45, 60, 90];
+/// Lines of captions shown under the tabs while transcribing.
+#[cfg(feature = "transcribe")]
+const CAPTION_LINES: u16 = 4;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Tab {
+    Devices,
+    Player,
+    Midi,
+    Network,
+    Stats,
+    Analysis,
+}
+
 struct App {
+    tab: Tab,
     input_devices: StatefulList<(Device, usize)>,
     output_devices: StatefulList<(Device, usize)>,
     active_panel_index: u8,
+    profiles: Vec<Profile>,
+    manual_profile: Option<usize>,
+    profile_override: Arc<AtomicBool>,
+    recording_config: RecordingConfig,
+    recording: Arc<AtomicBool>,
+    files: StatefulList<PathBuf>,
+    playback: Arc<PlaybackState>,
+    soundboard_keys: Vec<KeyCode>,
+    /// The looper's keys, and what each does.
+    looper_keys: [(KeyCode, LooperAction); 2],
+    /// The key held to talk, when push-to-talk is on.
+    talk_key: Option<KeyCode>,
+    /// How long the talk key counts as held after it last repeated.
+    talk_hold: Duration,
+    /// When the talk key is taken as let go, while it's held.
+    talk_until: Option<Instant>,
+    status: Arc<Mutex<LinkStatus>>,
+    meters: Arc<Meters>,
+    /// What the sound level in the status line is weighted by.
+    spl_weighting: Weighting,
+    /// What the spectrogram shows, or would if it were on, and how.
+    spectrogram_tap: SpectrogramTap,
+    spectrum_analysis: SpectrumAnalysis,
+    /// Set once a MIDI port is open.
+    #[cfg(feature = "midi")]
+    midi: Option<SharedMidi>,
+    #[cfg(feature = "midi")]
+    midi_actions: StatefulList<MidiAction>,
+    /// Receivers found over mDNS.
+    #[cfg(feature = "network")]
+    peers: Peers,
+    #[cfg(feature = "network")]
+    peer_list: ListState,
+    /// Set while transcribing, for the captions pane.
+    #[cfg(feature = "transcribe")]
+    captions: Option<Captions>,
+    /// Where the player keeps the running link.
+    session_path: PathBuf,
+    /// A link the last run left behind, until it's restored or dismissed.
+    resume: Option<Session>,
+    /// Shown in the status line until the next key.
+    notice: Option<String>,
 }
 
 impl App {
     fn new(
         input_devices: StatefulList<(Device, usize)>,
         output_devices: StatefulList<(Device, usize)>,
+        profiles: Vec<Profile>,
+        recording_config: RecordingConfig,
+        player_config: &PlayerConfig,
+        soundboard_config: &SoundboardConfig,
+        looper_config: &LooperConfig,
     ) -> App {
         App {
+            tab: Tab::Devices,
             input_devices,
             output_devices,
             active_panel_index: 0,
+            profiles,
+            manual_profile: None,
+            profile_override: Arc::new(AtomicBool::new(false)),
+            recording_config,
+            recording: Arc::new(AtomicBool::new(false)),
+            files: StatefulList::with_items(playback::list_files(&player_config.directory)),
+            playback: Arc::new(PlaybackState::default()),
+            soundboard_keys: soundboard_config.samples.iter().map(|s| key_code(s.key)).collect(),
+            looper_keys: [
+                (key_code(looper_config.record_key), LooperAction::Record),
+                (key_code(looper_config.clear_key), LooperAction::Clear),
+            ],
+            talk_key: None,
+            talk_hold: Duration::ZERO,
+            talk_until: None,
+            status: Arc::new(Mutex::new(LinkStatus {
+                volume: 1.0,
+                trim: 1.0,
+                ..Default::default()
+            })),
+            meters: Arc::new(Meters::default()),
+            spl_weighting: Weighting::A,
+            spectrogram_tap: SpectrogramTap::default(),
+            spectrum_analysis: SpectrumAnalysis::default(),
+            #[cfg(feature = "midi")]
+            midi: None,
+            #[cfg(feature = "midi")]
+            midi_actions: StatefulList::with_items(MidiAction::ALL.to_vec()),
+            #[cfg(feature = "network")]
+            peers: Default::default(),
+            #[cfg(feature = "network")]
+            peer_list: ListState::default(),
+            #[cfg(feature = "transcribe")]
+            captions: None,
+            session_path: PathBuf::new(),
+            resume: None,
+            notice: None,
         }
     }
 
@@ -49,14 +354,79 @@ impl App {
     fn next_panel(&mut self) {
         self.active_panel_index = (self.active_panel_index + 1) % 2
     }
+
+    /// Picks the next profile by hand, pausing the schedule until the override is released.
+    fn next_profile(&mut self) -> Option<&Profile> {
+        if self.profiles.is_empty() {
+            return None;
+        }
+        let i = self.manual_profile.map_or(0, |i| (i + 1) % self.profiles.len());
+        self.manual_profile = Some(i);
+        self.profile_override.store(true, Ordering::Relaxed);
+        Some(&self.profiles[i])
+    }
+
+    /// Hands `command` to the player, showing in the status line if it's no longer there to take it.
+    fn send(&mut self, player_channel: &Sender<PlayerCommand>, command: PlayerCommand) {
+        if player_channel.send(command).is_err() {
+            self.status.lock().unwrap().error = Some(sound_amp_core::Error::PlayerStopped.to_string());
+        }
+    }
+
+    /// Brings back the routing and settings of `session`, unless its input device is gone.
+    fn restore_session(&mut self, session: &Session, player_channel: &Sender<PlayerCommand>) {
+        match session.restore(&self.profiles, &self.recording_config) {
+            Some(commands) => {
+                for command in commands {
+                    self.send(player_channel, command);
+                }
+            }
+            None => self.notice = Some(format!("Cannot restore the last session: {} is gone", session.input)),
+        }
+    }
+
+    /// Opens the talk gate, or keeps it open while the key repeats.
+    fn hold_talk(&mut self, player_channel: &Sender<PlayerCommand>) {
+        if self.talk_until.is_none() {
+            self.send(player_channel, PlayerCommand::SetTalking(true));
+        }
+        self.talk_until = Some(Instant::now() + self.talk_hold);
+    }
+
+    /// Closes the talk gate once the talk key has stopped repeating.
+    fn release_talk(&mut self, player_channel: &Sender<PlayerCommand>) {
+        if self.talk_until.is_some_and(|until| Instant::now() >= until) {
+            self.talk_until = None;
+            self.send(player_channel, PlayerCommand::SetTalking(false));
+        }
+    }
+
+    fn release_profile_override(&mut self) {
+        self.manual_profile = None;
+        self.profile_override.store(false, Ordering::Relaxed);
+    }
 }
 
 fn main() -> Result<(), Box<dyn error::Error>> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config)?;
+    if cli.calibrate {
+        return calibrate(&cli.config);
+    }
+    if let Some(Command::Process { input, out, preset }) = &cli.command {
+        return process(&config, input, out, preset);
+    }
     let host = cpal::default_host();
     let input_devices = host.input_devices()?;
     let output_devices = host.output_devices()?;
 
-    let backend = CrosstermBackend::new(io::stdout());
+    // Keep stdout clean for the audio when it's piped somewhere.
+    let ui_output: Box<dyn Write> = if cli.output_pipe {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::stdout())
+    };
+    let backend = CrosstermBackend::new(ui_output);
     let mut terminal = Terminal::new(backend)?;
 
     let l: StatefulList<(Device, usize)> =
@@ -69,10 +439,285 @@ fn main() -> Result<(), Box<dyn error::Error>> {
             .collect(),
     );
 
-    let mut app = App::new(l, r);
-    let player_channel = setup_stream();
+    let mut recording_config = config.recording;
+    if let Some(directory) = cli.record_dir {
+        recording_config.directory = directory;
+    }
+    if let Some(format) = cli.record_format {
+        recording_config.encoder.format = format;
+    }
+    if let Some(kbps) = cli.record_bitrate {
+        recording_config.encoder.bitrate_kbps = kbps;
+    }
+    if let Some(tap) = cli.record_tap {
+        recording_config.tap = tap;
+    }
+    let mut app = App::new(
+        l,
+        r,
+        config.profiles.clone(),
+        recording_config,
+        &config.player,
+        &config.soundboard,
+        &config.looper,
+    );
+    #[cfg(not(feature = "network"))]
+    if cli.send.is_some() || cli.listen.is_some() || cli.rtp_send.is_some() || cli.rtp_listen.is_some()
+        || cli.icecast.is_some() || cli.snapcast.is_some() || cli.airplay.is_some()
+    {
+        return Err("Cannot stream: sound-amp was built without the `network` feature".into());
+    }
+    #[cfg(not(windows))]
+    if cli.capture_app.is_some() {
+        return Err("Cannot capture an application: that takes the process loopback API of Windows".into());
+    }
+    #[cfg(not(feature = "transcribe"))]
+    if cli.transcribe.is_some() {
+        return Err("Cannot transcribe: sound-amp was built without the `transcribe` feature".into());
+    }
+    #[cfg(feature = "transcribe")]
+    let transcription = TranscriptionConfig {
+        server: cli.transcribe.or(config.transcription.server),
+        ..config.transcription
+    };
+    #[cfg(feature = "transcribe")]
+    let captions = Captions::default();
+    #[cfg(feature = "transcribe")]
+    if transcription.server.is_some() {
+        app.captions = Some(Arc::clone(&captions));
+    }
+    #[cfg(feature = "network")]
+    let mut network_config = config.network;
+    #[cfg(feature = "network")]
+    if let Some(codec) = cli.send_codec {
+        network_config.codec = codec;
+    }
+    #[cfg(feature = "network")]
+    let discovery_enabled = network_config.discovery;
+    let mut session_config = config.session;
+    if let Some(mode) = cli.resume {
+        session_config.resume = mode;
+    }
+    app.session_path = session_config.path.clone();
+    app.spl_weighting = config.spl.weighting;
+    app.spectrogram_tap = config.spectrogram.tap;
+    app.spectrum_analysis = config.spectrogram.analysis;
+    let push_to_talk = PushToTalkConfig {
+        enabled: cli.push_to_talk || config.push_to_talk.enabled,
+        ..config.push_to_talk
+    };
+    app.talk_key = push_to_talk.enabled.then(|| key_code(push_to_talk.key));
+    app.talk_hold = Duration::from_millis(push_to_talk.hold_ms);
+    // The modules are unloaded when this is dropped on exit.
+    let virtual_devices = (cli.virtual_devices || config.virtual_devices.enabled)
+        .then(|| VirtualDevices::create(&config.virtual_devices))
+        .transpose()
+        .map_err(|e| e as Box<dyn error::Error>)?;
+    // The dose is the level at the ears over the day, so it's only known with the output calibrated.
+    let dose = config
+        .safety
+        .full_scale_db_spl
+        .map(|full_scale| DoseTracker::load(&config.dose, full_scale))
+        .transpose()
+        .map_err(|e| e as Box<dyn error::Error>)?;
+    let settings = LinkSettings {
+        replay: config.replay,
+        player: config.player,
+        soundboard: config.soundboard,
+        ducking: config.ducking,
+        sidechain: config.sidechain,
+        remix: config.remix,
+        output_pipe: cli.output_pipe.then_some(OutputPipe {
+            format: cli.pipe_format,
+            exclusive: cli.pipe_only,
+            noise_shaping: cli.pipe_noise_shaping,
+        }),
+        #[cfg(feature = "network")]
+        streams: StreamSettings {
+            send_to: cli.send,
+            network: network_config,
+            rtp_send_to: cli.rtp_send,
+            rtp: config.rtp.clone(),
+            icecast: IcecastConfig {
+                url: cli.icecast.or(config.icecast.url),
+                ..config.icecast
+            },
+            snapcast: SnapcastConfig {
+                target: cli.snapcast.or(config.snapcast.target),
+                ..config.snapcast
+            },
+            airplay: AirplayConfig {
+                target: cli.airplay.or(config.airplay.target),
+                ..config.airplay
+            },
+        },
+        virtual_sink: virtual_devices
+            .is_some()
+            .then(|| config.virtual_devices.sink_name.clone()),
+        outputs: config.outputs,
+        session: (session_config.resume != ResumeMode::Off).then(|| session_config.path.clone()),
+        config: Some(cli.config.clone()),
+        ceiling: config.safety.ceiling()?,
+        fade_in: Duration::from_secs_f32(config.safety.fade_in_ms.max(0.0) / 1000.0),
+        spl: config.spl,
+        dose,
+        loudness_alert: config.loudness_alert,
+        push_to_talk,
+        tuner: TunerConfig {
+            enabled: cli.tuner || config.tuner.enabled,
+            ..config.tuner
+        },
+        spectrogram: config.spectrogram,
+        room_correction: config.room_correction,
+        metronome: MetronomeConfig {
+            enabled: cli.metronome.is_some() || config.metronome.enabled,
+            bpm: cli.metronome.unwrap_or(config.metronome.bpm),
+            ..config.metronome
+        },
+        vox: VoxConfig {
+            enabled: cli.vox || config.vox.enabled,
+            ..config.vox
+        },
+        looper: config.looper,
+        low_latency: config.low_latency,
+        standby: config.standby,
+        workers: config.workers,
+        #[cfg(feature = "transcribe")]
+        transcription,
+        #[cfg(feature = "transcribe")]
+        captions,
+    };
+    let meters = Arc::clone(&app.meters);
+    let player_channel = setup_stream(
+        Arc::new(CpalBackend),
+        Arc::clone(&app.recording),
+        Arc::clone(&app.playback),
+        Arc::clone(&app.status),
+        Arc::clone(&meters),
+        settings,
+    );
+    if let Some(minutes) = cli.sleep {
+        app.send(&player_channel, PlayerCommand::SleepAfter(Some(Duration::from_secs(minutes * 60))));
+    }
+    if let Some(address) = cli.http {
+        #[cfg(feature = "http")]
+        http::serve(&address, config.profiles.clone(), Arc::clone(&app.status), Arc::clone(&meters), player_channel.clone())
+            .map_err(|e| e as Box<dyn error::Error>)?;
+        #[cfg(not(feature = "http"))]
+        return Err(format!("Cannot serve on {}: sound-amp was built without the `http` feature", address).into());
+    }
+    if let Some(address) = cli.ws {
+        #[cfg(feature = "http")]
+        http::ws::serve(&address, config.profiles.clone(), Arc::clone(&app.status), Arc::clone(&meters), player_channel.clone())
+            .map_err(|e| e as Box<dyn error::Error>)?;
+        #[cfg(not(feature = "http"))]
+        return Err(format!("Cannot serve on {}: sound-amp was built without the `http` feature", address).into());
+    }
+    if let Some(address) = cli.grpc {
+        #[cfg(feature = "grpc")]
+        grpc::serve(&address, config.profiles.clone(), Arc::clone(&app.status), Arc::clone(&meters), player_channel.clone())
+            .map_err(|e| e as Box<dyn error::Error>)?;
+        #[cfg(not(feature = "grpc"))]
+        return Err(format!("Cannot serve on {}: sound-amp was built without the `grpc` feature", address).into());
+    }
+    #[cfg(feature = "network")]
+    if let Some(port) = cli.listen {
+        net::listen(port, player_channel.clone())?;
+    }
+    // Discovery is a convenience; a network without multicast shouldn't stop the amp.
+    #[cfg(feature = "network")]
+    let discovery = discovery_enabled
+        .then(|| Discovery::start(&app.peers))
+        .and_then(|d| d.map_err(|e| eprintln!("Cannot start mDNS discovery: {}", e)).ok());
+    #[cfg(feature = "network")]
+    if let Some(discovery) = &discovery {
+        let receivers = [(Transport::Tcp, cli.listen), (Transport::Rtp, cli.rtp_listen)];
+        for (transport, port) in receivers {
+            if let Some(port) = port {
+                if let Err(e) = discovery.advertise(transport, port) {
+                    eprintln!("Cannot advertise the {} receiver: {}", transport.name(), e);
+                }
+            }
+        }
+    }
+    #[cfg(feature = "network")]
+    if let Some(port) = cli.rtp_listen {
+        let input = RtpInput::bind(port, &config.rtp).map_err(|e| e as Box<dyn error::Error>)?;
+        player_channel.send(PlayerCommand::Start(InputSource::Rtp(input)))?;
+    }
+    if let Some(port) = cli.osc {
+        #[cfg(feature = "osc")]
+        osc::listen(
+            port,
+            config.profiles.clone(),
+            app.recording_config.clone(),
+            player_channel.clone(),
+        )?;
+        #[cfg(not(feature = "osc"))]
+        return Err(format!("Cannot take OSC on port {}: sound-amp was built without the `osc` feature", port).into());
+    }
+    // Dropping the connection closes the port, so it's held until exit.
+    #[cfg(feature = "midi")]
+    let _midi = if config.midi.enabled {
+        let state = SharedMidi::default();
+        let connection = midi::connect(&config.midi, cli.config.clone(), Arc::clone(&state), player_channel.clone())
+            .map_err(|e| e as Box<dyn error::Error>)?;
+        app.midi = Some(state);
+        Some(connection)
+    } else {
+        None
+    };
+    if cli.input_pipe {
+        let input = InputPipe {
+            format: cli.pipe_format,
+            sample_rate: cli.input_rate,
+            channels: cli.input_channels,
+        };
+        player_channel.send(PlayerCommand::Start(InputSource::Stdin(input)))?;
+    }
+    #[cfg(windows)]
+    if let Some(target) = &cli.capture_app {
+        let capture = AppCapture::find(target)?;
+        player_channel.send(PlayerCommand::Start(InputSource::App(capture)))?;
+    }
+    if cli.record {
+        player_channel.send(PlayerCommand::StartRecording(app.recording_config.clone()))?;
+    }
+    // An input given on the command line is what this run is for, whatever the last one left.
+    if session_config.resume != ResumeMode::Off && !cli.input_pipe && cli.rtp_listen.is_none()
+        && cli.capture_app.is_none()
+    {
+        match Session::load(&session_config.path) {
+            Ok(Some(session)) if session_config.resume == ResumeMode::Auto => {
+                app.restore_session(&session, &player_channel);
+            }
+            Ok(session) => app.resume = session,
+            Err(e) => eprintln!("Cannot read the last session from {}: {}", session_config.path.display(), e),
+        }
+    }
+    schedule::spawn(
+        config.schedule,
+        config.profiles,
+        Arc::clone(&app.profile_override),
+        player_channel.clone(),
+    );
+    let mut config_checked = Instant::now();
+    let mut config_modified = modified(&cli.config);
     loop {
-        terminal.draw(|f| draw_tui(f, &mut app));
+        if config_checked.elapsed() >= CONFIG_CHECK_INTERVAL {
+            config_checked = Instant::now();
+            let modified = modified(&cli.config);
+            if modified != config_modified {
+                config_modified = modified;
+                reload_config(&mut app, &cli.config, &player_channel);
+            }
+        }
+        app.release_talk(&player_channel);
+        terminal.draw(|f| draw_tui(f, &mut app))?;
+        // Meters and status move on their own, so the screen is redrawn whether or not a key comes.
+        if !event::poll(FRAME_INTERVAL)? {
+            continue;
+        }
         if let Ok(Event::Key(key)) = event::read() {
             let should_stop = handle_key(&mut app, key, &player_channel);
             if should_stop {
@@ -81,46 +726,514 @@ fn main() -> Result<(), Box<dyn error::Error>> {
         }
     }
 
+    // A clean exit leaves nothing to restore.
+    if let Err(e) = Session::clear(&app.session_path) {
+        eprintln!("Cannot remove {}: {}", app.session_path.display(), e);
+    }
     terminal.clear()?;
     Ok(())
 }
 
+/// Plays the calibration tone on the default output until the user types what it measured at the ear.
+fn calibrate(config_path: &Path) -> Result<(), Box<dyn error::Error>> {
+    let backend = CpalBackend;
+    let (_, config) = backend.output_format()?;
+    let _tone = backend.open_output(None, &config, Box::new(safety::calibration_tone(&config)))?;
+    println!(
+        "Playing a {} Hz tone at {} dBFS. Set the volume you listen at, measure the tone where your ear is \
+         with an SPL meter, and type the reading in dB SPL:",
+        safety::CALIBRATION_HZ,
+        safety::CALIBRATION_DBFS
+    );
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    let measured: f32 = line.trim().parse().map_err(|_| format!("Not a dB SPL reading: {}", line.trim()))?;
+    println!(
+        "Add this under [safety] in {}, with the ceiling you want:\n\nfull_scale_db_spl = {:.1}\nceiling_db_spl = 85.0",
+        config_path.display(),
+        safety::full_scale_db_spl(measured)
+    );
+    Ok(())
+}
+
+/// Runs `input` through the `preset` profile into `output`, reporting how much faster than real
+/// time that went.
+fn process(config: &Config, input: &Path, output: &Path, preset: &str) -> Result<(), Box<dyn error::Error>> {
+    let profile = config.profile(preset).ok_or_else(|| {
+        let names: Vec<&str> = config.profiles.iter().map(|p| p.name.as_str()).collect();
+        format!("No profile named {}; there are {}", preset, names.join(", "))
+    })?;
+    let extension = output.extension().and_then(|e| e.to_str()).unwrap_or_default();
+    let format = RecordingFormat::from_str(extension, true)
+        .map_err(|_| format!("Cannot tell which format to write {} in from its extension", output.display()))?;
+    let encoder = EncoderSettings {
+        format,
+        ..config.recording.encoder
+    };
+    let tags = config.recording.tags.render(Some(&profile.name));
+    let started = Instant::now();
+    let length = offline::process_file(input, output, profile, &encoder, &tags, WorkerPool::start(&config.workers))
+        .map_err(|e| e as Box<dyn error::Error>)?;
+    let took = started.elapsed().as_secs_f64();
+    println!(
+        "Processed {:.1} s of {} into {} in {:.1} s, {:.0} times real time",
+        length.as_secs_f64(),
+        input.display(),
+        output.display(),
+        took,
+        length.as_secs_f64() / took.max(0.001)
+    );
+    Ok(())
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Hands the settings that can change under running links to the player, once the config file changed.
+fn reload_config(app: &mut App, path: &Path, player_channel: &Sender<PlayerCommand>) {
+    match Config::load(path) {
+        Ok(config) => {
+            let live = LiveSettings {
+                ducking: config.ducking,
+                sidechain: config.sidechain,
+                outputs: config.outputs,
+                player: config.player,
+            };
+            app.send(player_channel, PlayerCommand::Reconfigure(live));
+            app.notice = Some(format!("Applied {}", path.display()));
+        }
+        Err(e) => app.notice = Some(format!("Cannot reload {}: {}", path.display(), e)),
+    }
+}
+
+fn key_code(hotkey: Hotkey) -> KeyCode {
+    match hotkey {
+        Hotkey::Char(c) => KeyCode::Char(c),
+        Hotkey::F(n) => KeyCode::F(n),
+    }
+}
+
 fn handle_key(app: &mut App, key: KeyEvent, player_channel: &Sender<PlayerCommand>) -> bool {
+    app.notice = None;
+    // The restore prompt takes y and n for itself while it's up.
+    if let KeyCode::Char(answer @ ('y' | 'n')) = key.code {
+        if let Some(session) = app.resume.take() {
+            if answer == 'y' {
+                app.restore_session(&session, player_channel);
+            } else if let Err(e) = Session::clear(&app.session_path) {
+                app.notice = Some(format!("Cannot remove {}: {}", app.session_path.display(), e));
+            }
+            return false;
+        }
+    }
+    // A terminal only reports the key going down, so holding it is seen as it repeating.
+    if app.talk_key == Some(key.code) {
+        app.hold_talk(player_channel);
+        return false;
+    }
+    if let Some(&(_, action)) = app.looper_keys.iter().find(|(k, _)| *k == key.code) {
+        app.send(player_channel, PlayerCommand::Looper(action));
+        return false;
+    }
     if key.code == KeyCode::Char('q') {
         true
     } else {
         match key.code {
             KeyCode::Char('+') => {
-                player_channel.send(PlayerCommand::IncreaseVolume(1.0));
+                app.send(player_channel, PlayerCommand::IncreaseVolume(1.0));
             },
             KeyCode::Char('-') => {
-                player_channel.send(PlayerCommand::IncreaseVolume(-1.0));
+                app.send(player_channel, PlayerCommand::IncreaseVolume(-1.0));
             },
-            KeyCode::Down => {
-                app.active_panel().next();
+            KeyCode::Char(key @ ('{' | '}')) => {
+                let step = if key == '}' { TRIM_STEP_DB } else { -TRIM_STEP_DB };
+                let trim = app.status.lock().unwrap().trim;
+                app.send(player_channel, PlayerCommand::SetTrim(trim * 10f32.powf(step / 20.0)));
             },
-            KeyCode::Up => {
-                app.active_panel().previous();
+            KeyCode::Char('m') => {
+                app.send(player_channel, PlayerCommand::ToggleMute);
             },
-            KeyCode::Tab => {
-                app.next_panel();
+            KeyCode::Char('B') => {
+                app.send(player_channel, PlayerCommand::ToggleBypass);
+            },
+            KeyCode::Char('v') => {
+                app.send(player_channel, PlayerCommand::ToggleSpeechBoost);
+            },
+            KeyCode::Char('k') => {
+                app.send(player_channel, PlayerCommand::ToggleKaraoke);
+            },
+            KeyCode::Char('f') => {
+                app.send(player_channel, PlayerCommand::CycleVoice);
+            },
+            KeyCode::Char('M') => {
+                app.send(player_channel, PlayerCommand::ToggleMetronome);
+            },
+            KeyCode::Char('u') => {
+                app.send(player_channel, PlayerCommand::ToggleTuner);
+            },
+            KeyCode::Char('z') => {
+                app.send(player_channel, PlayerCommand::ToggleLowLatency);
+            },
+            KeyCode::Char('A') => {
+                app.send(player_channel, PlayerCommand::CalibrateGain);
+            },
+            KeyCode::Char('T') => {
+                app.send(player_channel, PlayerCommand::MeasureLatency);
+            },
+            KeyCode::Char('I') => {
+                // Saved with the recordings.
+                let directory = app.recording_config.directory.clone();
+                app.send(player_channel, PlayerCommand::CaptureImpulse(directory));
+            },
+            KeyCode::Char('R') => {
+                // Off if it's on, otherwise on for the last capture.
+                let status = app.status.lock().unwrap().clone();
+                match (status.room_correction, status.impulse_response) {
+                    (Some(_), _) => app.send(player_channel, PlayerCommand::SetRoomCorrection(None)),
+                    (None, Some(path)) => app.send(player_channel, PlayerCommand::SetRoomCorrection(Some(path))),
+                    (None, None) => app.notice = Some("Capture an impulse response with I first".to_string()),
+                }
+            },
+            KeyCode::Char('S') => {
+                // The next step past what's left, or off after the last one.
+                let left = app.status.lock().unwrap().sleep_seconds;
+                let next = match left {
+                    Some(seconds) => SLEEP_MINUTES.iter().find(|&&minutes| minutes * 60 > seconds + 60),
+                    None => SLEEP_MINUTES.first(),
+                };
+                let after = next.map(|minutes| Duration::from_secs(minutes * 60));
+                app.send(player_channel, PlayerCommand::SleepAfter(after));
+            },
+            KeyCode::Char(key @ (',' | '.')) => {
+                let step = if key == '.' { TEMPO_STEP } else { -TEMPO_STEP };
+                let bpm = app.status.lock().unwrap().metronome;
+                if let Some(bpm) = bpm {
+                    app.send(player_channel, PlayerCommand::SetTempo(bpm + step));
+                }
+            },
+            KeyCode::Char('1') => {
+                app.tab = Tab::Devices;
+            },
+            KeyCode::Char('2') => {
+                app.tab = Tab::Player;
+            },
+            KeyCode::Char('3') => {
+                app.tab = Tab::Midi;
+            },
+            KeyCode::Char('4') => {
+                app.tab = Tab::Network;
+            },
+            KeyCode::Char('5') => {
+                app.tab = Tab::Stats;
+            },
+            KeyCode::Char('6') => {
+                app.tab = Tab::Analysis;
+            },
+            KeyCode::Char('p') => {
+                if let Some(profile) = app.next_profile().cloned() {
+                    app.send(player_channel, PlayerCommand::ApplyProfile(profile));
+                }
+            },
+            KeyCode::Char('o') => {
+                app.release_profile_override();
+            },
+            KeyCode::Char('r') => {
+                let command = if app.recording.load(Ordering::Relaxed) {
+                    PlayerCommand::StopRecording
+                } else {
+                    PlayerCommand::StartRecording(app.recording_config.clone())
+                };
+                app.send(player_channel, command);
+            },
+            KeyCode::Char('b') => {
+                app.send(player_channel, PlayerCommand::SaveReplay(app.recording_config.clone()));
+            },
+            // Soundboard bindings take precedence over the keys of the current tab.
+            code => match app.soundboard_keys.iter().position(|&k| k == code) {
+                Some(i) => {
+                    app.send(player_channel, PlayerCommand::TriggerSample(i));
+                }
+                None => match app.tab {
+                    Tab::Devices => handle_devices_key(app, key, player_channel),
+                    Tab::Player => handle_player_key(app, key, player_channel),
+                    #[cfg(feature = "midi")]
+                    Tab::Midi => handle_midi_key(app, key),
+                    #[cfg(feature = "network")]
+                    Tab::Network => handle_network_key(app, key, player_channel),
+                    Tab::Stats => handle_stats_key(app, key),
+                    Tab::Analysis => handle_analysis_key(app, key, player_channel),
+                    #[cfg(not(all(feature = "midi", feature = "network")))]
+                    _ => {}
+                },
             },
-            KeyCode::Enter => {
-                player_channel.send(PlayerCommand::Start(
-                    app.input_devices.state.selected().unwrap(),
-                ));
-            }
-            _ => {}
         }
         false
     }
 }
 
-fn draw_tui(f: &mut Frame<CrosstermBackend<Stdout>>, app: &mut App) {
+fn handle_devices_key(app: &mut App, key: KeyEvent, player_channel: &Sender<PlayerCommand>) {
+    match key.code {
+        KeyCode::Down => {
+            app.active_panel().next();
+        },
+        KeyCode::Up => {
+            app.active_panel().previous();
+        },
+        KeyCode::Tab => {
+            app.next_panel();
+        },
+        KeyCode::Enter => {
+            if let Some(i) = app.input_devices.state.selected() {
+                app.send(player_channel, PlayerCommand::Start(InputSource::Device(i)));
+            }
+        }
+        KeyCode::Char('a') => {
+            if let Some(i) = app.input_devices.state.selected() {
+                app.send(player_channel, PlayerCommand::AddLink(InputSource::Device(i)));
+            }
+        }
+        KeyCode::Left | KeyCode::Right => {
+            let (count, selected) = {
+                let status = app.status.lock().unwrap();
+                (status.links.len(), status.selected)
+            };
+            if count > 0 {
+                let step = if key.code == KeyCode::Right { 1 } else { count - 1 };
+                app.send(player_channel, PlayerCommand::SelectLink((selected + step) % count));
+            }
+        }
+        KeyCode::Char('d') => {
+            let selected = app.status.lock().unwrap().selected;
+            app.send(player_channel, PlayerCommand::RemoveLink(selected));
+        }
+        KeyCode::Char('e') => {
+            app.send(player_channel, PlayerCommand::ToggleLinkChannels);
+        }
+        KeyCode::Char('D') => {
+            let (selected, ducked) = {
+                let status = app.status.lock().unwrap();
+                let ducked = status.links.get(status.selected).is_some_and(|link| link.ducked_by.is_some());
+                (status.selected, ducked)
+            };
+            // Added links duck under the main one, and the main one under the first added.
+            let key = if selected == 0 { 1 } else { 0 };
+            app.send(player_channel, PlayerCommand::DuckLink { link: selected, key: (!ducked).then_some(key) });
+        }
+        KeyCode::Char(key @ ('<' | '>')) => {
+            let step = if key == '>' { POSITION_STEP } else { -POSITION_STEP };
+            let position = {
+                let status = app.status.lock().unwrap();
+                status.links.get(status.selected).and_then(|link| link.position)
+            };
+            let position = position.unwrap_or(Position { azimuth: 0.0, elevation: 0.0 }).turned(step);
+            app.send(player_channel, PlayerCommand::SetPosition(Some(position)));
+        }
+        KeyCode::Char('h') => {
+            app.send(player_channel, PlayerCommand::SetPosition(None));
+        }
+        KeyCode::Char(key @ ('(' | ')')) => {
+            let step = if key == ')' { PAN_STEP } else { -PAN_STEP };
+            let pan = {
+                let status = app.status.lock().unwrap();
+                status.links.get(status.selected).map_or(0.0, |link| link.pan)
+            };
+            // Rounded to the step, so it lands back on the center exactly.
+            app.send(player_channel, PlayerCommand::SetPan(((pan + step) / PAN_STEP).round() * PAN_STEP));
+        }
+        _ => {}
+    }
+}
+
+fn handle_player_key(app: &mut App, key: KeyEvent, player_channel: &Sender<PlayerCommand>) {
+    let command = match key.code {
+        KeyCode::Down if !app.files.items.is_empty() => {
+            app.files.next();
+            None
+        },
+        KeyCode::Up if !app.files.items.is_empty() => {
+            app.files.previous();
+            None
+        },
+        KeyCode::Enter => app
+            .files
+            .state
+            .selected()
+            .map(|i| PlayerCommand::PlayFile(app.files.items[i].clone())),
+        KeyCode::Char(' ') => Some(PlayerCommand::TogglePlayback),
+        KeyCode::Left => Some(PlayerCommand::SeekFile(-SEEK_STEP_SECONDS)),
+        KeyCode::Right => Some(PlayerCommand::SeekFile(SEEK_STEP_SECONDS)),
+        KeyCode::Char('l') => Some(PlayerCommand::EditLoop(LoopEdit::Toggle)),
+        KeyCode::Char('[') => Some(PlayerCommand::EditLoop(LoopEdit::MarkStart)),
+        KeyCode::Char(']') => Some(PlayerCommand::EditLoop(LoopEdit::MarkEnd)),
+        KeyCode::Char('c') => Some(PlayerCommand::EditLoop(LoopEdit::Clear)),
+        KeyCode::Char('s') => Some(PlayerCommand::StopFile),
+        _ => None,
+    };
+    if let Some(command) = command {
+        app.send(player_channel, command);
+    }
+}
+
+#[cfg(feature = "midi")]
+fn handle_midi_key(app: &mut App, key: KeyEvent) {
+    match key.code {
+        KeyCode::Down => app.midi_actions.next(),
+        KeyCode::Up => app.midi_actions.previous(),
+        KeyCode::Char('L') => {
+            let focused = app.midi_actions.state.selected().map(|i| app.midi_actions.items[i]);
+            if let (Some(midi), Some(action)) = (&app.midi, focused) {
+                midi.lock().unwrap().learning = Some(action);
+            }
+        }
+        KeyCode::Esc => {
+            if let Some(midi) = &app.midi {
+                midi.lock().unwrap().learning = None;
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(feature = "network")]
+fn handle_network_key(app: &mut App, key: KeyEvent, player_channel: &Sender<PlayerCommand>) {
+    let count = app.peers.lock().unwrap().len();
+    let selected = app.peer_list.selected();
+    match key.code {
+        KeyCode::Down if count > 0 => {
+            app.peer_list.select(Some(selected.map_or(0, |i| (i + 1) % count)));
+        }
+        KeyCode::Up if count > 0 => {
+            app.peer_list.select(Some(selected.map_or(0, |i| (i + count - 1) % count)));
+        }
+        KeyCode::Enter => {
+            if let Some(peer) = selected.and_then(|i| app.peers.lock().unwrap().get(i).cloned()) {
+                app.send(player_channel, PlayerCommand::SendTo(Some(peer)));
+            }
+        }
+        KeyCode::Char('x') => {
+            app.send(player_channel, PlayerCommand::SendTo(None));
+        }
+        _ => {}
+    }
+}
+
+fn draw_tui(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App) {
+    #[cfg(feature = "transcribe")]
+    let caption_rows = if app.captions.is_some() { CAPTION_LINES + 2 } else { 0 };
+    #[cfg(not(feature = "transcribe"))]
+    let caption_rows = 0;
+    let tuner = app.status.lock().unwrap().tuner;
+    let tuner_rows = if tuner.is_some() { 3 } else { 0 };
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints(
+            [
+                Constraint::Length(1),
+                Constraint::Min(1),
+                Constraint::Length(tuner_rows),
+                Constraint::Length(caption_rows),
+                Constraint::Length(1),
+            ]
+            .as_ref(),
+        )
+        .split(f.size());
+
+    let titles = ["1 Devices", "2 Player", "3 MIDI", "4 Network", "5 Stats", "6 Analysis"].iter().cloned().map(Spans::from).collect();
+    let selected = match app.tab {
+        Tab::Devices => 0,
+        Tab::Player => 1,
+        Tab::Midi => 2,
+        Tab::Network => 3,
+        Tab::Stats => 4,
+        Tab::Analysis => 5,
+    };
+    let tabs = Tabs::new(titles)
+        .select(selected)
+        .highlight_style(Style::default().add_modifier(Modifier::BOLD));
+    f.render_widget(tabs, rows[0]);
+
+    match app.tab {
+        Tab::Devices => draw_devices(f, app, rows[1]),
+        Tab::Player => draw_player(f, app, rows[1]),
+        Tab::Midi => draw_midi(f, app, rows[1]),
+        Tab::Network => draw_network(f, app, rows[1]),
+        Tab::Stats => draw_stats(f, app, rows[1]),
+        Tab::Analysis => draw_analysis(f, app, rows[1]),
+    }
+
+    if let Some(reference_hz) = tuner {
+        draw_tuner(f, app.meters.pitch(), reference_hz, rows[2]);
+    }
+    #[cfg(feature = "transcribe")]
+    if let Some(captions) = &app.captions {
+        draw_captions(f, captions, rows[3]);
+    }
+    // A loud-for-too-long alert flashes the status line, twice a second.
+    let phase = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() / 250;
+    let flash = app.meters.loud() && phase.is_multiple_of(2);
+    let style = if flash { Style::default().fg(Color::White).bg(Color::Red) } else { Style::default() };
+    f.render_widget(Paragraph::new(status_line(app)).style(style), rows[4]);
+}
+
+/// The note nearest the input's pitch and a needle showing how far off it is, flat to the left.
+fn draw_tuner(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, pitch: Option<f32>, reference_hz: f32, area: Rect) {
+    let block = Block::default().borders(Borders::ALL).title("Tuner");
+    let Some(pitch) = pitch else {
+        f.render_widget(Paragraph::new("-").block(block), area);
+        return;
+    };
+    let note = Note::of(pitch, reference_hz);
+    let label = format!("{:<3}{:+4.0} cents  {:7.1} Hz  ", format!("{}{}", note.name, note.octave), note.cents, pitch);
+    let width = (area.width as usize).saturating_sub(label.len() + 2).max(3) | 1;
+    let centre = width / 2;
+    let needle = (centre as f32 + note.cents / 50.0 * centre as f32).round().clamp(0.0, (width - 1) as f32) as usize;
+    let scale: String = (0..width)
+        .map(|i| match i {
+            _ if i == needle => '|',
+            _ if i == centre => '+',
+            _ => '-',
+        })
+        .collect();
+    let colour = if note.cents.abs() <= IN_TUNE_CENTS { Color::Green } else { Color::Yellow };
+    let text = Spans::from(vec![Span::raw(label), Span::styled(scale, Style::default().fg(colour))]);
+    f.render_widget(Paragraph::new(text).block(block), area);
+}
+
+/// The latest captions, newest at the bottom, wrapped to the pane.
+#[cfg(feature = "transcribe")]
+fn draw_captions(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, captions: &Captions, area: Rect) {
+    let captions = captions.lock().unwrap();
+    let width = area.width.saturating_sub(2).max(1) as usize;
+    let mut lines: Vec<String> = Vec::new();
+    for caption in captions.iter() {
+        let mut line = String::new();
+        for word in caption.split_whitespace() {
+            if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(word);
+        }
+        lines.push(line);
+    }
+    let shown = lines.len().saturating_sub(CAPTION_LINES as usize);
+    let text: Vec<Spans> = lines.drain(shown..).map(Spans::from).collect();
+    f.render_widget(Paragraph::new(text).block(Block::default().borders(Borders::ALL).title("Captions")), area);
+}
+
+fn draw_devices(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let status = app.status.lock().unwrap().clone();
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(status.links.len().max(status.outputs.len()) as u16 + 2), Constraint::Length(1)].as_ref())
+        .split(area);
     let chunks = Layout::default()
         .direction(Direction::Horizontal)
         .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-        .split(f.size());
+        .split(rows[0]);
 
     let left_items: Vec<ListItem> = make_devices_widget_items(&app.input_devices.items);
 
@@ -147,99 +1260,581 @@ fn draw_tui(f: &mut Frame<CrosstermBackend<Stdout>>, app: &mut App) {
         output_devices_widget,
         chunks[1],
         &mut app.output_devices.state,
-    )
+    );
+
+    let links: Vec<ListItem> = status
+        .links
+        .iter()
+        .enumerate()
+        .map(|(i, link)| {
+            let marker = if i == status.selected { "> " } else { "  " };
+            let muted = match (link.muted, link.bypassed) {
+                (true, true) => " muted bypassed",
+                (true, false) => " muted",
+                (false, true) => " bypassed",
+                (false, false) => "",
+            };
+            let ducked = link.ducked_by.map_or_else(String::new, |key| format!(" ducked under {}", key));
+            let position = link.position.map_or_else(String::new, |p| format!(" at {:.0}°/{:.0}°", p.azimuth, p.elevation));
+            let pan = if link.pan == 0.0 { String::new() } else { format!(" pan {:+.1}", link.pan) };
+            ListItem::new(format!("{}{} {:.2}{}{}{}{}", marker, link.input, link.volume, muted, ducked, position, pan))
+        })
+        .collect();
+    let outputs: Vec<ListItem> = status
+        .outputs
+        .iter()
+        .map(|output| {
+            let latency = output.latency_ms.map_or_else(String::new, |ms| format!(", plays {:.1} ms late", ms));
+            ListItem::new(format!("{} +{:.1} ms{}", output.name, output.delay_ms, latency))
+        })
+        .collect();
+    let bottom = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(rows[1]);
+    f.render_widget(List::new(links).block(Block::default().borders(Borders::ALL).title("Links")), bottom[0]);
+    f.render_widget(List::new(outputs).block(Block::default().borders(Borders::ALL).title("Outputs")), bottom[1]);
+    let help = "Enter link, a add as another link, Left/Right select a link, d unlink the selected one, \
+                e link/unlink the left and right channels, D duck the selected link under the main one \
+                (the main one under the next), < > place it around you in headphones, h back in the middle, ( ) pan it";
+    f.render_widget(Paragraph::new(help), rows[2]);
 }
 
-fn make_devices_widget_items(devices: &Vec<(Device, usize)>) -> Vec<ListItem> {
-    let input_devices_list_style = Style::default().fg(Color::Black).bg(Color::White);
-    devices
+fn draw_player(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+        .split(area);
+
+    let file_style = Style::default().fg(Color::Black).bg(Color::White);
+    let items: Vec<ListItem> = app
+        .files
+        .items
         .iter()
-        .map(|(dev, _i)| {
-            ListItem::new(dev.name().unwrap()).style(input_devices_list_style)
+        .map(|path| {
+            let name = path.file_name().map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+            ListItem::new(name).style(file_style)
         })
-        .collect()
+        .collect();
+    let files_widget = List::new(items).highlight_style(
+        Style::default()
+            .bg(Color::LightGreen)
+            .add_modifier(Modifier::BOLD),
+    );
+    f.render_stateful_widget(files_widget, chunks[0], &mut app.files.state);
+
+    let now_playing = Paragraph::new(player_status(&app.playback))
+        .block(Block::default().borders(Borders::ALL).title("Now playing"));
+    f.render_widget(now_playing, chunks[1]);
+}
+
+#[cfg(not(feature = "midi"))]
+fn draw_midi(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, _: &mut App, area: Rect) {
+    f.render_widget(Paragraph::new("sound-amp was built without the `midi` feature"), area);
+}
+
+#[cfg(feature = "midi")]
+fn draw_midi(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let Some(midi) = &app.midi else {
+        let help = "MIDI is off; set enabled = true under [midi] in the config";
+        f.render_widget(Paragraph::new(help), area);
+        return;
+    };
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
+        .split(area);
+    let state = midi.lock().unwrap();
+    let style = Style::default().fg(Color::Black).bg(Color::White);
+    let items: Vec<ListItem> = app
+        .midi_actions
+        .items
+        .iter()
+        .map(|&action| {
+            let binding = if state.learning == Some(action) {
+                "move a control...".to_string()
+            } else {
+                let bindings: Vec<String> = state
+                    .mappings
+                    .iter()
+                    .filter(|m| m.action == action)
+                    .map(|m| match m.channel {
+                        Some(channel) => format!("CC {} ch {}", m.cc, channel),
+                        None => format!("CC {}", m.cc),
+                    })
+                    .collect();
+                if bindings.is_empty() {
+                    "unbound".to_string()
+                } else {
+                    bindings.join(", ")
+                }
+            };
+            ListItem::new(format!("{:<12} {}", action.name(), binding)).style(style)
+        })
+        .collect();
+    drop(state);
+    let actions_widget = List::new(items).highlight_style(
+        Style::default()
+            .bg(Color::LightGreen)
+            .add_modifier(Modifier::BOLD),
+    );
+    f.render_stateful_widget(actions_widget, chunks[0], &mut app.midi_actions.state);
+    f.render_widget(Paragraph::new("L learn the selected action, Esc cancel"), chunks[1]);
+}
+
+#[cfg(not(feature = "network"))]
+fn draw_network(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, _: &mut App, area: Rect) {
+    f.render_widget(Paragraph::new("sound-amp was built without the `network` feature"), area);
+}
+
+#[cfg(feature = "network")]
+fn draw_network(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
+        .split(area);
+    let status = app.status.lock().unwrap().clone();
+    let style = Style::default().fg(Color::Black).bg(Color::White);
+    let items: Vec<ListItem> = app
+        .peers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|peer| {
+            let address = peer.address.to_string();
+            let target = match peer.transport {
+                Transport::Tcp => &status.send_to,
+                Transport::Rtp => &status.rtp_send_to,
+            };
+            let marker = if target.as_deref() == Some(address.as_str()) { "> " } else { "  " };
+            let line = format!("{}{} {} ({})", marker, peer.name, address, peer.transport.name());
+            ListItem::new(line).style(style)
+        })
+        .collect();
+    let peers_widget = List::new(items)
+        .block(Block::default().borders(Borders::ALL).title("Receivers"))
+        .highlight_style(
+            Style::default()
+                .bg(Color::LightGreen)
+                .add_modifier(Modifier::BOLD),
+        );
+    f.render_stateful_widget(peers_widget, chunks[0], &mut app.peer_list);
+    f.render_widget(Paragraph::new("Enter send to the selected receiver, x stop sending"), chunks[1]);
+}
+
+fn handle_stats_key(app: &mut App, key: KeyEvent) {
+    if key.code == KeyCode::Char('x') {
+        app.meters.clear_stats();
+    }
+}
+
+fn draw_stats(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Length(9), Constraint::Min(1), Constraint::Length(1)].as_ref())
+        .split(area);
+    let stats = app.meters.stats.read();
+    let loudness = app.meters.loudness();
+    let percent = |share: f32| share * 100.0;
+    let lufs = |reading: Option<f32>| reading.map_or("-".to_string(), |lufs| format!("{:.1}", lufs));
+    let text = format!(
+        "Input clipped    {} samples ({:.3}%)\n\
+         Output clipped   {} samples ({:.3}%), before the limiter\n\
+         Limiter          stepped in {} times, limiting {:.1}% of the output\n\
+         Gate             closed {} times, holding down {:.1}% of the input\n\
+         True peak        {:.1} dBTP at the loudest\n\
+         Loudness         {} integrated, {} short-term, {} momentary LUFS\n\
+         Correlation      {} between the output's channels, now",
+        stats.input_clipped,
+        percent(stats.input_clipped_share),
+        stats.output_clipped,
+        percent(stats.output_clipped_share),
+        stats.limiter_engagements,
+        percent(stats.limited_share),
+        stats.gate_closings,
+        percent(stats.gated_share),
+        stats.max_true_peak_db,
+        lufs(loudness.integrated),
+        lufs(loudness.short_term),
+        lufs(loudness.momentary),
+        app.meters.correlation().map_or("-".to_string(), |c| format!("{:+.2}", c)),
+    );
+    f.render_widget(
+        Paragraph::new(text).block(Block::default().borders(Borders::ALL).title("Over the session")),
+        chunks[0],
+    );
+    draw_level_history(f, &app.meters.history.levels(), chunks[1]);
+    f.render_widget(Paragraph::new("x start counting over"), chunks[2]);
 }
 
-enum PlayerCommand {
-    Start(usize),
-    IncreaseVolume(f32),
+/// The output's peak and RMS a second at a time, the newest at the right edge.
+fn draw_level_history(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, levels: &[Level], area: Rect) {
+    const FLOOR_DB: f64 = -60.0;
+    let points = |db: fn(&Level) -> f32| -> Vec<(f64, f64)> {
+        let start = (HISTORY_SECONDS - levels.len()) as f64;
+        levels.iter().enumerate().map(|(i, level)| (start + i as f64, (db(level) as f64).max(FLOOR_DB))).collect()
+    };
+    let (peaks, rms) = (points(|level| level.peak), points(|level| level.rms));
+    let datasets = vec![
+        Dataset::default()
+            .name("peak")
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Yellow))
+            .data(&peaks),
+        Dataset::default()
+            .name("RMS")
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::Green))
+            .data(&rms),
+    ];
+    let chart = Chart::new(datasets)
+        .block(Block::default().borders(Borders::ALL).title("Output over the last minute"))
+        .x_axis(Axis::default().bounds([0.0, HISTORY_SECONDS as f64 - 1.0]))
+        .y_axis(
+            Axis::default()
+                .bounds([FLOOR_DB, 0.0])
+                .labels(vec![Span::raw("-60"), Span::raw("-30"), Span::raw("0 dB")]),
+        );
+    f.render_widget(chart, area);
 }
 
-fn setup_stream() -> mpsc::Sender<PlayerCommand> {
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || {
-        let mut link: Vec<cpal::Stream> = vec![];
-        let volume_factor = Arc::new(Mutex::new(1f32));
-        let command_handler = |command: PlayerCommand| {
-            match command {
-                PlayerCommand::Start(input_device_i) => {
-                    link = create_link(input_device_i, &volume_factor);
+fn handle_analysis_key(app: &mut App, key: KeyEvent, player_channel: &Sender<PlayerCommand>) {
+    match key.code {
+        KeyCode::Char('s') => app.send(player_channel, PlayerCommand::ToggleSpectrogram),
+        KeyCode::Char('i') => {
+            app.spectrogram_tap = app.spectrogram_tap.other();
+            app.send(player_channel, PlayerCommand::SetSpectrogramTap(app.spectrogram_tap));
+        }
+        KeyCode::Char(key @ ('[' | ']' | 'w' | 'a')) => {
+            let analysis = &mut app.spectrum_analysis;
+            let size = analysis.fft_size();
+            match key {
+                '[' => analysis.fft_size = (size / 2).max(spectrogram::MIN_FFT_SIZE),
+                ']' => analysis.fft_size = (size * 2).min(spectrogram::MAX_FFT_SIZE),
+                'w' => {
+                    let next = Window::ALL.iter().position(|&w| w == analysis.window).map_or(0, |i| i + 1);
+                    analysis.window = Window::ALL[next % Window::ALL.len()];
                 }
-                PlayerCommand::IncreaseVolume(amount) => {
-                    *volume_factor.lock().unwrap() += amount;
+                _ => {
+                    let next = analysis.averaging.max(1) * 2;
+                    analysis.averaging = if next > MAX_AVERAGING { 1 } else { next };
                 }
             }
-        };
-        rx.iter().for_each(command_handler);
-    });
-    tx
+            app.send(player_channel, PlayerCommand::SetSpectrumAnalysis(app.spectrum_analysis));
+        }
+        _ => {}
+    }
 }
 
-fn create_link(
-    input_device_id: usize,
-    volume_factor: &Arc<Mutex<f32>>,
-) -> Vec<cpal::Stream> {
-    let host = cpal::default_host();
-    let output_device = host
-        .default_output_device()
-        .expect("Failed to get default output device");
-    println!("Sound device: {}", output_device.name().unwrap());
-
-    let format = output_device
-        .default_output_config()
-        .expect("Failed to get default output format");
-
-    println!("Format: {:?}", format);
-    let ring: RingBuffer<f32> = RingBuffer::new(48000);
-    let (mut producer, mut consumer) = ring.split();
-    let input_device = &host.input_devices().unwrap().collect::<Vec<Device>>()[input_device_id];
-    let input_stream = {
-        let factor = Arc::clone(volume_factor);
-        let data_callback = move |data: &[f32], _: &InputCallbackInfo| {
-            let factor_value = *factor.lock().unwrap();
-            for &sample in data {
-                producer.push(sample * factor_value);
-            };
-        };
-        let s = input_device
-            .build_input_stream(
-                &input_device.default_input_config().unwrap().into(),
-                data_callback,
-                err_fn,
-            )
-            .expect("Cannot create input stream");
-        s.play().expect("Cannot start input stream");
-        s
-    };
-    let output_stream = {
-        let data_callback = move |data: &mut [f32], _: &OutputCallbackInfo| {
-            for sample in data {
-                *sample = consumer.pop().unwrap_or(0.0);
+fn draw_analysis(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, app: &mut App, area: Rect) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Min(1), Constraint::Length(1)].as_ref())
+        .split(area);
+    // Cells are about twice as tall as they're wide, so this keeps the scope square.
+    let side = rows[0].width.min(rows[0].height * 2);
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Length(side), Constraint::Min(1)].as_ref())
+        .split(rows[0]);
+    draw_goniometer(f, &app.meters.scope.frames(), columns[0]);
+    let correlation = app.meters.correlation();
+    let width = columns[1].width.saturating_sub(2) as usize;
+    let text = format!(
+        "{}\n{}",
+        correlation_bar(correlation, width),
+        correlation.map_or("Silent or mono".to_string(), |c| format!("{:+.2}", c)),
+    );
+    f.render_widget(
+        Paragraph::new(text).block(Block::default().borders(Borders::ALL).title("Correlation")),
+        columns[1],
+    );
+    let shown = app.status.lock().unwrap().spectrogram;
+    draw_spectrogram(f, &app.meters.spectrogram.columns(), shown, &app.spectrum_analysis, rows[1]);
+    f.render_widget(
+        Paragraph::new("s spectrogram on/off, i input/output, [ ] FFT size, w window, a averaging"),
+        rows[2],
+    );
+}
+
+/// The spectrogram scrolling leftwards, the newest column at the right edge. Each cell is two
+/// bands, the upper half block in the colour of the upper one and its background in the lower's.
+fn draw_spectrogram(
+    f: &mut Frame<CrosstermBackend<Box<dyn Write>>>,
+    columns: &[Vec<f32>],
+    shown: Option<SpectrogramTap>,
+    analysis: &SpectrumAnalysis,
+    area: Rect,
+) {
+    const LABELS: [(f32, &str); 8] =
+        [(50.0, "50"), (100.0, "100"), (200.0, "200"), (500.0, "500"), (1000.0, "1k"), (2000.0, "2k"), (5000.0, "5k"), (10000.0, "10k")];
+    const LABEL_WIDTH: u16 = 4;
+    let averaging = match analysis.averaging {
+        0 | 1 => String::new(),
+        n => format!(", averaging {}", n),
+    };
+    let how = format!("{}-point {}{}", analysis.fft_size(), analysis.window.name(), averaging);
+    let title = match shown {
+        Some(tap) => format!("Spectrogram of the {}, {}", tap.name(), how),
+        None => format!("Spectrogram (off), {}", how),
+    };
+    let block = Block::default().borders(Borders::ALL).title(title);
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let (width, height) = (inner.width.saturating_sub(LABEL_WIDTH) as usize, inner.height as usize);
+    if width == 0 || height == 0 {
+        return;
+    }
+    // The bands in each half-cell row, counting up from the bottom.
+    let halves = 2 * height;
+    let bands = |half: usize| {
+        let low = half * spectrogram::BANDS / halves;
+        low..((half + 1) * spectrogram::BANDS / halves).max(low + 1)
+    };
+    let band_of = |hz: f32| {
+        ((spectrogram::BANDS - 1) as f32 * (hz / spectrogram::LOW_HZ).ln()
+            / (spectrogram::HIGH_HZ / spectrogram::LOW_HZ).ln()) as usize
+    };
+    let level = |column: &[f32], bands: &Range<usize>| column[bands.clone()].iter().fold(spectrogram::FLOOR_DB, |a, &b| a.max(b));
+    let shown_columns = &columns[columns.len().saturating_sub(width)..];
+    let lines: Vec<Spans> = (0..height)
+        .map(|row| {
+            let (upper, lower) = (bands(halves - 1 - 2 * row), bands(halves - 2 - 2 * row));
+            let label = LABELS
+                .iter()
+                .find(|(hz, _)| upper.contains(&band_of(*hz)) || lower.contains(&band_of(*hz)))
+                .map_or("", |(_, label)| label);
+            let mut spans = vec![Span::raw(format!("{:>3} ", label))];
+            spans.push(Span::raw(" ".repeat(width - shown_columns.len())));
+            spans.extend(shown_columns.iter().map(|column| {
+                Span::styled("▀", Style::default().fg(heat(level(column, &upper))).bg(heat(level(column, &lower))))
+            }));
+            Spans::from(spans)
+        })
+        .collect();
+    f.render_widget(Paragraph::new(lines), inner);
+}
+
+/// Black through blue, red and yellow to white, from -100 dBFS to full scale.
+fn heat(db: f32) -> Color {
+    const STOPS: [(u8, u8, u8); 5] = [(0, 0, 0), (0, 0, 160), (200, 0, 80), (255, 200, 0), (255, 255, 255)];
+    let position = ((db + 100.0) / 100.0).clamp(0.0, 1.0) * (STOPS.len() - 1) as f32;
+    let i = (position as usize).min(STOPS.len() - 2);
+    let t = position - i as f32;
+    let (from, to) = (STOPS[i], STOPS[i + 1]);
+    let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t) as u8;
+    Color::Rgb(mix(from.0, to.0), mix(from.1, to.1), mix(from.2, to.2))
+}
+
+/// The output's stereo field, turned so what's in both channels goes up the middle and what's in
+/// only one goes along the diagonal on its side; out-of-phase sound spreads sideways. It's scaled
+/// to the loudest of the frames, so quiet passages show as well.
+fn draw_goniometer(f: &mut Frame<CrosstermBackend<Box<dyn Write>>>, frames: &[(f32, f32)], area: Rect) {
+    const FLOOR: f32 = 0.001;
+    let points: Vec<(f64, f64)> = frames
+        .iter()
+        .map(|&(left, right)| (((right - left) / SQRT_2) as f64, ((left + right) / SQRT_2) as f64))
+        .collect();
+    let loudest = points.iter().fold(0f64, |loudest, &(x, y)| loudest.max(x.abs()).max(y.abs()));
+    let points: Vec<(f64, f64)> = if loudest < FLOOR as f64 {
+        Vec::new()
+    } else {
+        points.iter().map(|&(x, y)| (x / loudest, y / loudest)).collect()
+    };
+    // The diagonals sound only in the left channel and only in the right land on.
+    let (left, right) = ([(-1.0, 1.0), (0.0, 0.0)], [(0.0, 0.0), (1.0, 1.0)]);
+    let guide = |data| {
+        Dataset::default()
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Line)
+            .style(Style::default().fg(Color::DarkGray))
+            .data(data)
+    };
+    let datasets = vec![
+        guide(&left),
+        guide(&right),
+        Dataset::default()
+            .marker(Marker::Braille)
+            .graph_type(GraphType::Scatter)
+            .style(Style::default().fg(Color::Green))
+            .data(&points),
+    ];
+    let chart = Chart::new(datasets)
+        .block(Block::default().borders(Borders::ALL).title("Stereo field"))
+        .x_axis(Axis::default().bounds([-1.0, 1.0]))
+        .y_axis(Axis::default().bounds([-1.0, 1.0]));
+    f.render_widget(chart, area);
+}
+
+/// A bar from -1 on the left to +1 on the right, with a mark at the correlation.
+fn correlation_bar(correlation: Option<f32>, width: usize) -> String {
+    if width < 3 {
+        return String::new();
+    }
+    let position = |c: f32| ((c + 1.0) / 2.0 * (width - 1) as f32).round() as usize;
+    let (centre, mark) = (position(0.0), correlation.map(position));
+    (0..width)
+        .map(|i| {
+            if Some(i) == mark {
+                '█'
+            } else if i == centre {
+                '│'
+            } else {
+                '─'
             }
-        };
-        let s = output_device
-            .build_output_stream(
-                &output_device.default_output_config().unwrap().into(),
-                data_callback,
-                err_fn,
-            )
-            .expect("Cannot create output stream");
-        s.play().expect("Cannot start output stream");
-        s
+        })
+        .collect()
+}
+
+fn player_status(playback: &PlaybackState) -> String {
+    let help = "Enter play, Space pause, Left/Right seek, s stop\nl loop, [ set A, ] set B, c clear A-B";
+    if !playback.active.load(Ordering::Relaxed) {
+        return format!("Stopped\n\n{}", help);
+    }
+    let state = if playback.paused.load(Ordering::Relaxed) { "Paused" } else { "Playing" };
+    let duration = playback
+        .duration()
+        .map_or_else(|| "?".to_string(), format_duration);
+    let looping = if playback.looping.load(Ordering::Relaxed) {
+        match playback.loop_points() {
+            (start, Some(end)) => format!("Loop {} - {}", format_duration(start), format_duration(end)),
+            (start, None) if start.is_zero() => "Loop whole file".to_string(),
+            (start, None) => format!("Loop {} - end", format_duration(start)),
+        }
+    } else {
+        "Loop off".to_string()
     };
-    vec![input_stream, output_stream]
+    format!(
+        "{} {}\n{} / {}\n{}\n\n{}",
+        state,
+        playback.title.lock().unwrap(),
+        format_duration(playback.position()),
+        duration,
+        looping,
+        help,
+    )
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn status_line(app: &App) -> String {
+    let profile = match app.manual_profile {
+        Some(i) => format!("Profile: {} (manual, 'o' to resume schedule)", app.profiles[i].name),
+        None => "Profile: scheduled".to_string(),
+    };
+    let status = app.status.lock().unwrap();
+    let mut line = format!("{} | {}", status.state.name().to_uppercase(), profile);
+    if status.muted {
+        line.push_str(" | MUTED");
+    }
+    if status.bypassed {
+        line.push_str(" | BYPASSED");
+    }
+    if status.trim != 1.0 {
+        line = format!("{} | TRIM {:+.0} dB", line, 20.0 * status.trim.log10());
+    }
+    if let Some(spl) = app.meters.spl() {
+        line = format!("{} | {:.0} dB({})", line, spl, app.spl_weighting.name());
+    }
+    if app.meters.voice() {
+        line.push_str(" | SPEECH");
+    }
+    if app.meters.loud() {
+        line.push_str(" | TOO LOUD");
+    }
+    if app.meters.correlation().is_some_and(|c| c < OUT_OF_PHASE) {
+        line.push_str(" | OUT OF PHASE");
+    }
+    if let Some(dose) = status.dose {
+        line = format!("{} | DOSE {:.0}%", line, dose * 100.0);
+    }
+    match status.talking {
+        Some(true) => line.push_str(" | TALKING"),
+        Some(false) => line.push_str(" | PTT"),
+        None => {}
+    }
+    match status.vox_open {
+        Some(true) => line.push_str(" | VOX OPEN"),
+        Some(false) => line.push_str(" | VOX"),
+        None => {}
+    }
+    if status.speech_boost {
+        line.push_str(" | SPEECH BOOST");
+    }
+    if status.karaoke {
+        line.push_str(" | KARAOKE");
+    }
+    if let Some(voice) = status.voice {
+        line = format!("{} | VOICE {}", line, voice.name().to_uppercase());
+    }
+    if let Some(bpm) = status.metronome {
+        line = format!("{} | {:.0} BPM", line, bpm);
+    }
+    match status.looper {
+        LooperState::Empty => {}
+        LooperState::Recording => line.push_str(" | LOOP REC"),
+        LooperState::Playing => line.push_str(" | LOOP"),
+        LooperState::Overdubbing => line.push_str(" | LOOP DUB"),
+    }
+    if !status.channels_linked {
+        line.push_str(" | L/R UNLINKED");
+    }
+    if let Some(frames) = status.low_latency {
+        line = format!("{} | LOW LATENCY {}", line, frames);
+        if let Some(ms) = status.round_trip_ms {
+            line = format!("{} ({:.1} ms)", line, ms);
+        }
+    }
+    if status.standby {
+        line.push_str(" | STANDBY");
+    }
+    if let Some(seconds) = status.calibrating {
+        line = format!("{} | CALIBRATING, speak normally for {} s", line, seconds);
+    }
+    if status.measuring_latency {
+        line.push_str(" | MEASURING ROUND TRIP");
+    } else if let Some(ms) = status.measured_round_trip_ms {
+        line = format!("{} | ROUND TRIP {:.1} ms", line, ms);
+    }
+    if status.capturing_impulse {
+        line.push_str(" | CAPTURING IMPULSE RESPONSE");
+    } else if status.room_correction.is_some() {
+        line.push_str(" | ROOM CORRECTED");
+    } else if status.impulse_response.is_some() {
+        line.push_str(" | R CORRECTS THE ROOM");
+    }
+    if let Some(seconds) = status.sleep_seconds {
+        line = format!("{} | SLEEP {} min", line, seconds.div_ceil(60));
+    }
+    if app.recording.load(Ordering::Relaxed) {
+        line = format!("{} | REC {}", line, app.recording_config.directory.display());
+    }
+    if let Some(error) = &status.error {
+        line = format!("{} | {}", line, error);
+    }
+    if let Some(notice) = &status.notice {
+        line = format!("{} | {}", line, notice);
+    }
+    if let Some(notice) = &app.notice {
+        line = format!("{} | {}", line, notice);
+    }
+    if let Some(session) = &app.resume {
+        line = format!("{} | Restore the link from {} the last run left behind? y/n", line, session.input);
+    }
+    line
 }
 
-fn err_fn(err: cpal::StreamError) {
-    eprintln!("an error occurred on stream: {:?}", err);
+fn make_devices_widget_items(devices: &[(Device, usize)]) -> Vec<ListItem<'_>> {
+    let input_devices_list_style = Style::default().fg(Color::Black).bg(Color::White);
+    devices
+        .iter()
+        .map(|(dev, _i)| {
+            ListItem::new(dev.name().unwrap_or_else(|e| format!("<{}>", e))).style(input_devices_list_style)
+        })
+        .collect()
 }
@@ -49,3 +49,9 @@ impl<T> StatefulList<T> {
         self.state.select(None);
     }
 }
+
+impl<T> Default for StatefulList<T> {
+    fn default() -> Self {
+        StatefulList::new()
+    }
+}